@@ -0,0 +1,36 @@
+//! Copying a 100 MiB file byte by byte, what `copy_file_range` does for a partial copy, against cloning it,
+//! what `MemFs::reflink` and a whole file `copy_file_range` do. Run with `cargo bench --bench clone`.
+//! The FUSE methods need a `Request` only a session can make, so it goes through the programmatic API behind them.
+
+use std::time::{Duration, Instant};
+
+use in_mem_fs::mem_fs::MemFs;
+
+const FILE_SIZE: usize = 100 * 1024 * 1024;
+const ROUNDS: u32 = 5;
+
+fn main() {
+    let mut fs = MemFs::new(false, false);
+    let data: Vec<u8> = (0..FILE_SIZE).map(|i| (i % 251) as u8 + 1).collect();
+    fs.create_file("/src", &data, 0o644, 0, 0).unwrap();
+
+    let copy = time(|round| {
+        fs.copy_file("/src", &format!("/copy{round}")).unwrap();
+    });
+    let clone = time(|round| {
+        fs.reflink("/src", &format!("/clone{round}")).unwrap();
+    });
+    assert_eq!(fs.read_file("/clone0").unwrap(), data);
+
+    println!("copy  {:>12?} per 100 MiB file", copy);
+    println!("clone {:>12?} per 100 MiB file", clone);
+}
+
+fn time(mut f: impl FnMut(u32)) -> Duration {
+    let start = Instant::now();
+    for round in 0..ROUNDS {
+        f(round);
+    }
+
+    start.elapsed() / ROUNDS
+}
@@ -0,0 +1,30 @@
+//! Request handler calls on a fresh filesystem, decoded from the input. Each run has to end without a panic and with
+//! `verify_consistency` passing, whatever the calls replied.
+//!
+//! `cargo fuzz run fuse_ops` from the crate root.
+
+#![no_main]
+
+use std::panic::{self, AssertUnwindSafe};
+
+use in_mem_fs::mem_fs::fuzz;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let calls = fuzz::decode(data);
+    let mut replies = vec![];
+    let res = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut fs = fuzz::mounted();
+        for call in &calls {
+            replies.push(call.apply(&mut fs));
+        }
+        fs.verify_consistency()
+    }));
+
+    match res {
+        Ok(Ok(())) => {}
+        Ok(Err(errors)) => panic!("inconsistent after {calls:#?}\nreplies {replies:#?}\n{errors:#?}"),
+        // the replies before the panicking call
+        Err(_) => panic!("panicked in {calls:#?}\nreplies {replies:#?}"),
+    }
+});
@@ -0,0 +1,256 @@
+//! POSIX ACLs in the Linux xattr format: a version header followed by `(tag, perm, id)` entries.
+
+use std::os::raw::c_int;
+
+use crate::sys;
+
+pub const ACL_ACCESS_XATTR: &str = "system.posix_acl_access";
+pub const ACL_DEFAULT_XATTR: &str = "system.posix_acl_default";
+
+const ACL_VERSION: u32 = 2;
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+const ENTRY_SIZE: usize = 8;
+
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AclTag {
+    UserObj,
+    User(u32),
+    GroupObj,
+    Group(u32),
+    Mask,
+    Other,
+}
+
+impl AclTag {
+    fn code(&self) -> u16 {
+        match self {
+            AclTag::UserObj => ACL_USER_OBJ,
+            AclTag::User(_) => ACL_USER,
+            AclTag::GroupObj => ACL_GROUP_OBJ,
+            AclTag::Group(_) => ACL_GROUP,
+            AclTag::Mask => ACL_MASK,
+            AclTag::Other => ACL_OTHER,
+        }
+    }
+}
+
+/// One entry of an ACL, `qualifier` is the uid or gid of `User` and `Group` entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AclEntry {
+    pub tag: AclTag,
+    pub qualifier: u32,
+    pub perm: u16,
+}
+
+impl AclEntry {
+    pub fn new(tag: AclTag, perm: u16) -> Self {
+        let qualifier = match tag {
+            AclTag::User(id) | AclTag::Group(id) => id,
+            _ => ACL_UNDEFINED_ID,
+        };
+
+        AclEntry { tag, qualifier, perm: perm & 0o7 }
+    }
+}
+
+/// Parses and validates an ACL like `setfacl` writes it, `EINVAL` if it is malformed or misses a required entry.
+pub fn parse_posix_acl(bytes: &[u8]) -> Result<Vec<AclEntry>, c_int> {
+    if bytes.len() < 4 || !(bytes.len() - 4).is_multiple_of(ENTRY_SIZE) {
+        return Err(sys::EINVAL);
+    }
+    if u32::from_le_bytes(bytes[0..4].try_into().unwrap()) != ACL_VERSION {
+        return Err(sys::EINVAL);
+    }
+
+    let mut entries = vec![];
+    for entry in bytes[4..].chunks_exact(ENTRY_SIZE) {
+        let code = u16::from_le_bytes([entry[0], entry[1]]);
+        let perm = u16::from_le_bytes([entry[2], entry[3]]);
+        let id = u32::from_le_bytes(entry[4..8].try_into().unwrap());
+        let tag = match code {
+            ACL_USER_OBJ => AclTag::UserObj,
+            ACL_USER => AclTag::User(id),
+            ACL_GROUP_OBJ => AclTag::GroupObj,
+            ACL_GROUP => AclTag::Group(id),
+            ACL_MASK => AclTag::Mask,
+            ACL_OTHER => AclTag::Other,
+            _ => return Err(sys::EINVAL),
+        };
+        if perm & !0o7 != 0 {
+            return Err(sys::EINVAL);
+        }
+        entries.push(AclEntry::new(tag, perm));
+    }
+
+    let count = |code: u16| entries.iter().filter(|entry| entry.tag.code() == code).count();
+    let named = count(ACL_USER) + count(ACL_GROUP);
+    if count(ACL_USER_OBJ) != 1 || count(ACL_GROUP_OBJ) != 1 || count(ACL_OTHER) != 1 || count(ACL_MASK) > 1
+        || (named > 0 && count(ACL_MASK) == 0) {
+        return Err(sys::EINVAL);
+    }
+
+    Ok(entries)
+}
+
+/// Encodes `entries` in the order the kernel expects, by tag then qualifier.
+pub fn serialize_posix_acl(entries: &[AclEntry]) -> Vec<u8> {
+    let mut entries = entries.to_vec();
+    entries.sort_by_key(|entry| (entry.tag.code(), entry.qualifier));
+
+    let mut bytes = ACL_VERSION.to_le_bytes().to_vec();
+    for entry in entries {
+        bytes.extend_from_slice(&entry.tag.code().to_le_bytes());
+        bytes.extend_from_slice(&entry.perm.to_le_bytes());
+        bytes.extend_from_slice(&entry.qualifier.to_le_bytes());
+    }
+
+    bytes
+}
+
+/// The POSIX ACL access check: the owner entry, then a named user masked by `Mask`,
+/// then any matching group masked by `Mask`, then other.
+pub fn acl_permits(entries: &[AclEntry], file_uid: u32, file_gid: u32, uid: u32, gid: u32, supplementary_gids: &[u32], access_mask: i32) -> bool {
+    let want = (access_mask & 0o7) as u16;
+    let mask = entries.iter().find(|entry| entry.tag == AclTag::Mask).map_or(0o7, |entry| entry.perm);
+    let in_group = |group: u32| gid == group || supplementary_gids.contains(&group);
+
+    if uid == file_uid {
+        return entries.iter().any(|entry| entry.tag == AclTag::UserObj && entry.perm & want == want);
+    }
+    if let Some(entry) = entries.iter().find(|entry| entry.tag == AclTag::User(uid)) {
+        return entry.perm & mask & want == want;
+    }
+
+    let mut groups = entries.iter().filter(|entry| match entry.tag {
+        AclTag::GroupObj => in_group(file_gid),
+        AclTag::Group(group) => in_group(group),
+        _ => false,
+    }).peekable();
+    if groups.peek().is_some() {
+        return groups.any(|entry| entry.perm & mask & want == want);
+    }
+
+    entries.iter().any(|entry| entry.tag == AclTag::Other && entry.perm & want == want)
+}
+
+/// Permission bits of the mode matching the ACL, the group bits come from `Mask` when there is one.
+pub fn acl_mode(entries: &[AclEntry]) -> u16 {
+    let perm = |tag: AclTag| entries.iter().find(|entry| entry.tag == tag).map(|entry| entry.perm);
+    let group = perm(AclTag::Mask).or(perm(AclTag::GroupObj)).unwrap_or(0);
+
+    (perm(AclTag::UserObj).unwrap_or(0) << 6) | (group << 3) | perm(AclTag::Other).unwrap_or(0)
+}
+
+/// Updates the entries mirrored by the mode bits after a `chmod`.
+pub fn acl_chmod(entries: &mut [AclEntry], mode: u16) {
+    let has_mask = entries.iter().any(|entry| entry.tag == AclTag::Mask);
+    for entry in entries.iter_mut() {
+        match entry.tag {
+            AclTag::UserObj => entry.perm = (mode >> 6) & 0o7,
+            AclTag::Mask => entry.perm = (mode >> 3) & 0o7,
+            AclTag::GroupObj if !has_mask => entry.perm = (mode >> 3) & 0o7,
+            AclTag::Other => entry.perm = mode & 0o7,
+            _ => {}
+        }
+    }
+}
+
+/// Access ACL of an entry created in a directory with the `default` ACL, limited by the requested `mode`.
+pub fn inherit_acl(default: &[AclEntry], mode: u16) -> Vec<AclEntry> {
+    let mut entries = default.to_vec();
+    let has_mask = entries.iter().any(|entry| entry.tag == AclTag::Mask);
+    for entry in entries.iter_mut() {
+        match entry.tag {
+            AclTag::UserObj => entry.perm &= (mode >> 6) & 0o7,
+            AclTag::Mask => entry.perm &= (mode >> 3) & 0o7,
+            AclTag::GroupObj if !has_mask => entry.perm &= (mode >> 3) & 0o7,
+            AclTag::Other => entry.perm &= mode & 0o7,
+            _ => {}
+        }
+    }
+
+    entries
+}
+
+/// Only the owner, group and other entries, which the mode bits express on their own.
+pub fn is_minimal(entries: &[AclEntry]) -> bool {
+    entries.len() == 3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // what `setfacl -m u:1001:rw,g:200:r` leaves on a 0640 file of 1000:100
+    fn acl() -> Vec<AclEntry> {
+        vec![
+            AclEntry::new(AclTag::UserObj, 0o6),
+            AclEntry::new(AclTag::User(1001), 0o6),
+            AclEntry::new(AclTag::GroupObj, 0o4),
+            AclEntry::new(AclTag::Group(200), 0o4),
+            AclEntry::new(AclTag::Mask, 0o6),
+            AclEntry::new(AclTag::Other, 0),
+        ]
+    }
+
+    #[test]
+    fn acls_round_trip_through_the_xattr_format() {
+        let mut entries = acl();
+        entries.reverse();
+        let bytes = serialize_posix_acl(&entries);
+        assert_eq!(bytes.len(), 4 + 6 * ENTRY_SIZE);
+        assert_eq!(parse_posix_acl(&bytes), Ok(acl()));
+    }
+
+    #[test]
+    fn malformed_acls_are_rejected() {
+        let bytes = serialize_posix_acl(&acl());
+        assert_eq!(parse_posix_acl(&bytes[..bytes.len() - 1]), Err(sys::EINVAL));
+        let mut wrong_version = bytes.clone();
+        wrong_version[0] = 1;
+        assert_eq!(parse_posix_acl(&wrong_version), Err(sys::EINVAL));
+
+        // named entries need a mask, and every ACL the owner, group and other entries
+        let without_mask: Vec<_> = acl().into_iter().filter(|entry| entry.tag != AclTag::Mask).collect();
+        assert_eq!(parse_posix_acl(&serialize_posix_acl(&without_mask)), Err(sys::EINVAL));
+        let without_other: Vec<_> = acl().into_iter().filter(|entry| entry.tag != AclTag::Other).collect();
+        assert_eq!(parse_posix_acl(&serialize_posix_acl(&without_other)), Err(sys::EINVAL));
+    }
+
+    #[test]
+    fn access_follows_the_first_matching_entry() {
+        let mut entries = acl();
+        let permits = |entries: &[AclEntry], uid, gid, groups: &[u32], mask| acl_permits(entries, 1000, 100, uid, gid, groups, mask);
+        assert!(permits(&entries, 1000, 1000, &[], sys::R_OK | sys::W_OK));
+        assert!(permits(&entries, 1001, 1001, &[], sys::R_OK | sys::W_OK));
+        assert!(permits(&entries, 1002, 1002, &[200], sys::R_OK));
+        assert!(!permits(&entries, 1002, 1002, &[200], sys::W_OK));
+        assert!(!permits(&entries, 1002, 1002, &[], sys::R_OK));
+        // the owner isn't limited by the mask, named entries are
+        acl_chmod(&mut entries, 0o600);
+        assert!(permits(&entries, 1000, 1000, &[], sys::W_OK));
+        assert!(!permits(&entries, 1001, 1001, &[], sys::R_OK));
+        assert_eq!(acl_mode(&entries), 0o600);
+    }
+
+    #[test]
+    fn new_entries_inherit_the_default_acl_within_their_mode() {
+        let inherited = inherit_acl(&acl(), 0o644);
+        assert_eq!(acl_mode(&inherited), 0o640);
+        // named entries are kept, the mask limits them
+        assert!(inherited.contains(&AclEntry::new(AclTag::User(1001), 0o6)));
+        assert!(inherited.contains(&AclEntry::new(AclTag::Mask, 0o4)));
+        assert!(!is_minimal(&inherited));
+
+        let minimal: Vec<_> = acl().into_iter().filter(|entry| matches!(entry.tag, AclTag::UserObj | AclTag::GroupObj | AclTag::Other)).collect();
+        assert_eq!(acl_mode(&inherit_acl(&minimal, 0o777)), 0o640);
+        assert!(is_minimal(&minimal));
+    }
+}
@@ -0,0 +1,170 @@
+//! In-memory record of the latest FUSE operations, kept in a ring buffer.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::os::raw::c_int;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// FUSE operation an audit entry is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OpKind {
+    Access,
+    CopyFileRange,
+    Create,
+    Fallocate,
+    Flush,
+    Fsync,
+    Getattr,
+    Getlk,
+    Getxattr,
+    Ioctl,
+    Link,
+    Listxattr,
+    Lookup,
+    Lseek,
+    Mkdir,
+    Mknod,
+    Open,
+    Opendir,
+    Poll,
+    Read,
+    Readdir,
+    Readdirplus,
+    Readlink,
+    Release,
+    Releasedir,
+    Removexattr,
+    Rename,
+    Rmdir,
+    Setattr,
+    Setlk,
+    Setxattr,
+    Statfs,
+    Symlink,
+    Unlink,
+    Write,
+}
+
+impl OpKind {
+    /// Name of the `Filesystem` method, as written in the audit log file.
+    pub fn name(&self) -> &'static str {
+        match self {
+            OpKind::Access => "access",
+            OpKind::CopyFileRange => "copy_file_range",
+            OpKind::Create => "create",
+            OpKind::Fallocate => "fallocate",
+            OpKind::Flush => "flush",
+            OpKind::Fsync => "fsync",
+            OpKind::Getattr => "getattr",
+            OpKind::Getlk => "getlk",
+            OpKind::Getxattr => "getxattr",
+            OpKind::Ioctl => "ioctl",
+            OpKind::Link => "link",
+            OpKind::Listxattr => "listxattr",
+            OpKind::Lookup => "lookup",
+            OpKind::Lseek => "lseek",
+            OpKind::Mkdir => "mkdir",
+            OpKind::Mknod => "mknod",
+            OpKind::Open => "open",
+            OpKind::Opendir => "opendir",
+            OpKind::Poll => "poll",
+            OpKind::Read => "read",
+            OpKind::Readdir => "readdir",
+            OpKind::Readdirplus => "readdirplus",
+            OpKind::Readlink => "readlink",
+            OpKind::Release => "release",
+            OpKind::Releasedir => "releasedir",
+            OpKind::Removexattr => "removexattr",
+            OpKind::Rename => "rename",
+            OpKind::Rmdir => "rmdir",
+            OpKind::Setattr => "setattr",
+            OpKind::Setlk => "setlk",
+            OpKind::Setxattr => "setxattr",
+            OpKind::Statfs => "statfs",
+            OpKind::Symlink => "symlink",
+            OpKind::Unlink => "unlink",
+            OpKind::Write => "write",
+        }
+    }
+}
+
+impl fmt::Display for OpKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEntry {
+    pub timestamp: SystemTime,
+    pub op: OpKind,
+    pub uid: u32,
+    pub gid: u32,
+    pub ino: u64,
+    pub path: String,
+    /// The errno replied on failure.
+    pub result: Result<(), c_int>,
+}
+
+// same columns as the audit log file, without the pid
+impl fmt::Display for AuditEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let timestamp = self.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+        write!(f, "{}.{:09}\t{}\t{}\t{}\t{}\t{}\t{}",
+               timestamp.as_secs(), timestamp.subsec_nanos(),
+               self.uid, self.gid, self.op, self.ino, self.path, self.result.err().unwrap_or(0))
+    }
+}
+
+/// The last `capacity` operations, oldest first. Once full, recording an entry drops the oldest one.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    capacity: usize,
+    entries: VecDeque<AuditEntry>,
+}
+
+impl AuditLog {
+    pub fn new(capacity: usize) -> Self {
+        AuditLog { capacity, entries: VecDeque::with_capacity(capacity) }
+    }
+
+    pub fn record(&mut self, entry: AuditEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &AuditEntry> {
+        self.entries.iter()
+    }
+
+    /// Entries recorded at `t` or later.
+    pub fn since(&self, t: SystemTime) -> impl Iterator<Item = &AuditEntry> {
+        self.entries.iter().filter(move |entry| entry.timestamp >= t)
+    }
+
+    pub fn filter_uid(&self, uid: u32) -> impl Iterator<Item = &AuditEntry> {
+        self.entries.iter().filter(move |entry| entry.uid == uid)
+    }
+
+    /// The newest `n` entries, oldest first.
+    pub fn last(&self, n: usize) -> impl Iterator<Item = &AuditEntry> {
+        self.entries.iter().skip(self.entries.len().saturating_sub(n))
+    }
+}
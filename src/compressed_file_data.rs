@@ -0,0 +1,310 @@
+use std::cmp::min;
+
+use crate::file_data::PAGE_SIZE;
+
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompressedChunk {
+    data: Vec<u8>,
+}
+
+/// File content kept as LZ4 compressed 64 KiB chunks, same interface as the plain `FileData`.
+/// Chunks holding only zeros are not stored.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompressedFileData {
+    chunks: Vec<Option<CompressedChunk>>,
+    len: u64,
+}
+
+impl CompressedFileData {
+    pub fn new() -> Self {
+        CompressedFileData::default()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Self {
+        let mut file_data = CompressedFileData::new();
+        file_data.write(0, data);
+
+        file_data
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Fills `buf` from `offset`, holes read as zeros. Returns how many bytes were read, less than `buf.len()` at the end of the file.
+    pub fn read(&self, offset: u64, buf: &mut [u8]) -> usize {
+        if offset >= self.len {
+            return 0;
+        }
+        let read_len = min(buf.len() as u64, self.len - offset) as usize;
+
+        let mut done = 0;
+        while done < read_len {
+            let pos = offset + done as u64;
+            let (chunk, start) = (pos / CHUNK_SIZE as u64, (pos % CHUNK_SIZE as u64) as usize);
+            let len = min(CHUNK_SIZE - start, read_len - done);
+            match self.chunk(chunk) {
+                Some(data) => buf[done..done + len].copy_from_slice(&data[start..start + len]),
+                None => buf[done..done + len].fill(0),
+            }
+            done += len;
+        }
+
+        read_len
+    }
+
+    /// Writes `data` at `offset`, extending the file if needed. A gap before `offset` reads as zeros.
+    pub fn write(&mut self, offset: u64, data: &[u8]) {
+        let mut done = 0;
+        while done < data.len() {
+            let pos = offset + done as u64;
+            let (chunk, start) = (pos / CHUNK_SIZE as u64, (pos % CHUNK_SIZE as u64) as usize);
+            let len = min(CHUNK_SIZE - start, data.len() - done);
+            let src = &data[done..done + len];
+
+            // a missing chunk already reads as zeros
+            if self.chunk_index(chunk).is_some() || !is_zero(src) {
+                let mut dst = self.chunk(chunk).unwrap_or_else(|| vec![0; CHUNK_SIZE]);
+                dst[start..start + len].copy_from_slice(src);
+                self.store(chunk, &dst);
+            }
+            done += len;
+        }
+
+        self.len = self.len.max(offset + data.len() as u64);
+    }
+
+    /// Shrinks or grows the file to `new_size`, growing adds zeros.
+    pub fn truncate(&mut self, new_size: u64) {
+        if new_size < self.len {
+            self.chunks.truncate(new_size.div_ceil(CHUNK_SIZE as u64) as usize);
+            // bytes after the end of a partial last chunk must read as zeros if the file grows again
+            let tail = (new_size % CHUNK_SIZE as u64) as usize;
+            let chunk = new_size / CHUNK_SIZE as u64;
+            if tail != 0 {
+                if let Some(mut data) = self.chunk(chunk) {
+                    data[tail..].fill(0);
+                    self.store(chunk, &data);
+                }
+            }
+        }
+
+        self.len = new_size;
+    }
+
+    /// Drops all content, same as `truncate(0)`.
+    pub fn clear(&mut self) {
+        self.chunks.clear();
+        self.len = 0;
+    }
+
+    /// Zeroes `len` bytes from `offset` without changing the size, whole chunks are freed.
+    pub fn zero_range(&mut self, offset: u64, len: u64) {
+        // trailing holes aren't stored, nothing past the last chunk to zero
+        let end = min(offset.saturating_add(len), self.len).min(self.chunks.len() as u64 * CHUNK_SIZE as u64);
+        let mut pos = offset;
+        while pos < end {
+            let (chunk, start) = (pos / CHUNK_SIZE as u64, (pos % CHUNK_SIZE as u64) as usize);
+            let len = min(CHUNK_SIZE as u64 - start as u64, end - pos) as usize;
+            if let Some(mut data) = self.chunk(chunk) {
+                data[start..start + len].fill(0);
+                self.store(chunk, &data);
+            }
+            pos += len as u64;
+        }
+    }
+
+    /// Inserts `len` zero bytes at `offset`, moving what follows forward. An `offset` past the end inserts at the end.
+    pub fn insert_at(&mut self, offset: u64, len: u64) {
+        let offset = min(offset, self.len);
+        // copied from the end so nothing is overwritten before it's read
+        let old_len = self.len;
+        let mut buf = vec![0; CHUNK_SIZE];
+        let mut end = old_len;
+        while end > offset {
+            let start = end.saturating_sub(CHUNK_SIZE as u64).max(offset);
+            let chunk = (end - start) as usize;
+            self.read(start, &mut buf[..chunk]);
+            self.write(start + len, &buf[..chunk]);
+            end = start;
+        }
+        self.len = old_len + len;
+        self.zero_range(offset, len);
+    }
+
+    /// Removes `len` bytes from `offset`, moving what follows back. Only the part before the end is removed.
+    pub fn remove_at(&mut self, offset: u64, len: u64) {
+        let end = min(offset.saturating_add(len), self.len);
+        if offset >= end {
+            return;
+        }
+        let removed = end - offset;
+        let mut buf = vec![0; CHUNK_SIZE];
+        let mut pos = end;
+        while pos < self.len {
+            let chunk = min(CHUNK_SIZE as u64, self.len - pos) as usize;
+            self.read(pos, &mut buf[..chunk]);
+            self.write(pos - removed, &buf[..chunk]);
+            pos += chunk as u64;
+        }
+        self.truncate(self.len - removed);
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut data = vec![0; self.len as usize];
+        self.read(0, &mut data);
+
+        data
+    }
+
+    /// Start of the first data at or after `offset`, `None` when only holes follow. A chunk is data as a whole.
+    pub fn next_data(&self, offset: u64) -> Option<u64> {
+        if offset >= self.len {
+            return None;
+        }
+        let first = (offset / CHUNK_SIZE as u64) as usize;
+        let chunk = (first..self.chunks.len()).find(|chunk| self.chunks[*chunk].is_some())?;
+        let start = (chunk as u64 * CHUNK_SIZE as u64).max(offset);
+
+        (start < self.len).then_some(start)
+    }
+
+    /// Start of the first hole at or after `offset`, the end of the file counts as one. `None` past the end.
+    pub fn next_hole(&self, offset: u64) -> Option<u64> {
+        if offset >= self.len {
+            return None;
+        }
+        let mut chunk = (offset / CHUNK_SIZE as u64) as usize;
+        while self.chunks.get(chunk).is_some_and(|data| data.is_some()) {
+            chunk += 1;
+        }
+
+        Some((chunk as u64 * CHUNK_SIZE as u64).clamp(offset, self.len))
+    }
+
+    /// Pages holding non-zero bytes in order, with their index. Pages not listed are holes.
+    pub fn pages(&self) -> impl Iterator<Item = (u64, Box<[u8; PAGE_SIZE]>)> {
+        let mut pages = vec![];
+        for chunk in 0..self.chunks.len() as u64 {
+            let Some(data) = self.chunk(chunk) else { continue };
+            for (i, page) in data.chunks_exact(PAGE_SIZE).enumerate() {
+                if !is_zero(page) {
+                    let index = chunk * (CHUNK_SIZE / PAGE_SIZE) as u64 + i as u64;
+                    pages.push((index, Box::new(page.try_into().unwrap())));
+                }
+            }
+        }
+
+        pages.into_iter()
+    }
+
+    /// Memory taken by the compressed chunks.
+    pub fn memory_footprint(&self) -> u64 {
+        self.chunks.iter().flatten().map(|chunk| chunk.data.len() as u64).sum()
+    }
+
+    /// Size of the stored chunks once decompressed.
+    pub fn uncompressed_footprint(&self) -> u64 {
+        (self.chunks.iter().flatten().count() * CHUNK_SIZE) as u64
+    }
+
+    fn chunk_index(&self, chunk: u64) -> Option<&CompressedChunk> {
+        self.chunks.get(chunk as usize)?.as_ref()
+    }
+
+    fn chunk(&self, chunk: u64) -> Option<Vec<u8>> {
+        let compressed = self.chunk_index(chunk)?;
+
+        Some(lz4_flex::block::decompress(&compressed.data, CHUNK_SIZE).expect("corrupt chunk"))
+    }
+
+    fn store(&mut self, chunk: u64, data: &[u8]) {
+        let chunk = chunk as usize;
+        if chunk >= self.chunks.len() {
+            self.chunks.resize(chunk + 1, None);
+        }
+        self.chunks[chunk] = if is_zero(data) { None } else { Some(CompressedChunk { data: lz4_flex::block::compress(data) }) };
+        // trailing holes take no room
+        while self.chunks.last().is_some_and(|chunk| chunk.is_none()) {
+            self.chunks.pop();
+        }
+    }
+}
+
+fn is_zero(data: &[u8]) -> bool {
+    data.iter().all(|b| *b == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(data: &[u8]) {
+        let file_data = CompressedFileData::from_bytes(data);
+        assert_eq!(file_data.len(), data.len() as u64);
+        let mut buf = vec![0xff; data.len() + 10];
+        assert_eq!(file_data.read(0, &mut buf), data.len());
+        assert_eq!(&buf[..data.len()], data);
+    }
+
+    // xorshift, enough to defeat the compressor
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545f4914f6cdd1d_u64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn empty() {
+        round_trip(&[]);
+        assert_eq!(CompressedFileData::new().memory_footprint(), 0);
+    }
+
+    #[test]
+    fn incompressible() {
+        round_trip(&noise(3 * CHUNK_SIZE + 17));
+    }
+
+    #[test]
+    fn repetitive() {
+        let data = b"abcd".repeat(CHUNK_SIZE);
+        round_trip(&data);
+        let file_data = CompressedFileData::from_bytes(&data);
+        assert!(file_data.memory_footprint() < data.len() as u64 / 10);
+    }
+
+    #[test]
+    fn chunk_boundary() {
+        for len in [CHUNK_SIZE - 1, CHUNK_SIZE, CHUNK_SIZE + 1, 2 * CHUNK_SIZE] {
+            round_trip(&noise(len));
+        }
+
+        let mut file_data = CompressedFileData::from_bytes(&noise(2 * CHUNK_SIZE));
+        file_data.write(CHUNK_SIZE as u64 - 2, b"span");
+        let mut buf = [0; 4];
+        file_data.read(CHUNK_SIZE as u64 - 2, &mut buf);
+        assert_eq!(&buf, b"span");
+    }
+
+    #[test]
+    fn zero_chunks_are_not_stored() {
+        let mut file_data = CompressedFileData::new();
+        file_data.write(3 * CHUNK_SIZE as u64, b"x");
+        assert_eq!(file_data.uncompressed_footprint(), CHUNK_SIZE as u64);
+        let mut buf = vec![0xff; CHUNK_SIZE];
+        assert_eq!(file_data.read(CHUNK_SIZE as u64, &mut buf), CHUNK_SIZE);
+        assert!(is_zero(&buf));
+    }
+}
@@ -0,0 +1,52 @@
+//! Attributes of an inode. With the `fuse` feature they are fuser's own types, without it the same fields are defined
+//! here so `MemFs` builds without fuser.
+
+#[cfg(feature = "fuse")]
+pub use fuser::{FileAttr, FileType, TimeOrNow};
+
+#[cfg(not(feature = "fuse"))]
+use std::time::SystemTime;
+
+/// Kind of an inode, like the `S_IFMT` bits of its mode.
+#[cfg(not(feature = "fuse"))]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum FileType {
+    NamedPipe,
+    CharDevice,
+    BlockDevice,
+    Directory,
+    RegularFile,
+    Symlink,
+    Socket,
+}
+
+/// What `stat` returns for an inode.
+#[cfg(not(feature = "fuse"))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FileAttr {
+    pub ino: u64,
+    pub size: u64,
+    pub blocks: u64,
+    pub atime: SystemTime,
+    pub mtime: SystemTime,
+    pub ctime: SystemTime,
+    /// Creation time, only macOS exposes it.
+    pub crtime: SystemTime,
+    pub kind: FileType,
+    pub perm: u16,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub rdev: u32,
+    pub blksize: u32,
+    /// `chflags(2)` flags, only on macOS.
+    pub flags: u32,
+}
+
+/// Time `setattr` stores, `Now` reads the clock when the change is made.
+#[cfg(not(feature = "fuse"))]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum TimeOrNow {
+    SpecificTime(SystemTime),
+    Now,
+}
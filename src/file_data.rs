@@ -0,0 +1,301 @@
+#[cfg(not(feature = "compression"))]
+use std::cmp::min;
+#[cfg(not(feature = "compression"))]
+use std::collections::BTreeMap;
+
+pub const PAGE_SIZE: usize = 4096;
+
+#[cfg(feature = "compression")]
+pub use crate::compressed_file_data::CompressedFileData as FileData;
+
+/// Sparse file content, only pages holding non-zero bytes are allocated.
+#[cfg(not(feature = "compression"))]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileData {
+    pages: BTreeMap<u64, Box<[u8; PAGE_SIZE]>>,
+    len: u64,
+}
+
+#[cfg(not(feature = "compression"))]
+impl FileData {
+    pub fn new() -> Self {
+        FileData::default()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Self {
+        let mut file_data = FileData::new();
+        file_data.write(0, data);
+
+        file_data
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Fills `buf` from `offset`, holes read as zeros. Returns how many bytes were read, less than `buf.len()` at the end of the file.
+    pub fn read(&self, offset: u64, buf: &mut [u8]) -> usize {
+        if offset >= self.len {
+            return 0;
+        }
+        let read_len = min(buf.len() as u64, self.len - offset) as usize;
+
+        let mut done = 0;
+        while done < read_len {
+            let pos = offset + done as u64;
+            let (page, start) = (pos / PAGE_SIZE as u64, (pos % PAGE_SIZE as u64) as usize);
+            let chunk = min(PAGE_SIZE - start, read_len - done);
+            match self.pages.get(&page) {
+                Some(data) => buf[done..done + chunk].copy_from_slice(&data[start..start + chunk]),
+                None => buf[done..done + chunk].fill(0),
+            }
+            done += chunk;
+        }
+
+        read_len
+    }
+
+    /// Writes `data` at `offset`, extending the file if needed. A gap before `offset` reads as zeros.
+    pub fn write(&mut self, offset: u64, data: &[u8]) {
+        let mut done = 0;
+        while done < data.len() {
+            let pos = offset + done as u64;
+            let (page, start) = (pos / PAGE_SIZE as u64, (pos % PAGE_SIZE as u64) as usize);
+            let chunk = min(PAGE_SIZE - start, data.len() - done);
+            let src = &data[done..done + chunk];
+
+            match self.pages.get_mut(&page) {
+                Some(dst) => {
+                    dst[start..start + chunk].copy_from_slice(src);
+                    if is_zero(&dst[..]) {
+                        self.pages.remove(&page);
+                    }
+                }
+                // a missing page already reads as zeros
+                None if is_zero(src) => {}
+                None => {
+                    let mut dst = Box::new([0; PAGE_SIZE]);
+                    dst[start..start + chunk].copy_from_slice(src);
+                    self.pages.insert(page, dst);
+                }
+            }
+            done += chunk;
+        }
+
+        self.len = self.len.max(offset + data.len() as u64);
+    }
+
+    /// Shrinks or grows the file to `new_size`, growing adds zeros.
+    pub fn truncate(&mut self, new_size: u64) {
+        if new_size < self.len {
+            let first_dropped = new_size.div_ceil(PAGE_SIZE as u64);
+            self.pages.split_off(&first_dropped);
+            // bytes after the end of a partial last page must read as zeros if the file grows again
+            let tail = (new_size % PAGE_SIZE as u64) as usize;
+            if tail != 0 {
+                let page = new_size / PAGE_SIZE as u64;
+                if let Some(data) = self.pages.get_mut(&page) {
+                    data[tail..].fill(0);
+                    if is_zero(&data[..]) {
+                        self.pages.remove(&page);
+                    }
+                }
+            }
+        }
+
+        self.len = new_size;
+    }
+
+    /// Drops all content, same as `truncate(0)`.
+    pub fn clear(&mut self) {
+        self.pages.clear();
+        self.len = 0;
+    }
+
+    /// Zeroes `len` bytes from `offset` without changing the size, whole pages are freed.
+    pub fn zero_range(&mut self, offset: u64, len: u64) {
+        let end = min(offset.saturating_add(len), self.len);
+        if offset >= end {
+            return;
+        }
+        // only the allocated pages, the range can span far more holes than memory
+        let mut emptied = vec![];
+        for (page, data) in self.pages.range_mut(offset / PAGE_SIZE as u64..=(end - 1) / PAGE_SIZE as u64) {
+            let page_start = page * PAGE_SIZE as u64;
+            let start = (offset.max(page_start) - page_start) as usize;
+            let stop = (min(end, page_start + PAGE_SIZE as u64) - page_start) as usize;
+            data[start..stop].fill(0);
+            if is_zero(&data[..]) {
+                emptied.push(*page);
+            }
+        }
+        for page in emptied {
+            self.pages.remove(&page);
+        }
+    }
+
+    /// Inserts `len` zero bytes at `offset`, moving what follows forward. An `offset` past the end inserts at the end.
+    pub fn insert_at(&mut self, offset: u64, len: u64) {
+        let offset = min(offset, self.len);
+        if offset.is_multiple_of(PAGE_SIZE as u64) && len.is_multiple_of(PAGE_SIZE as u64) {
+            // whole pages only change their index
+            let moved = self.pages.split_off(&(offset / PAGE_SIZE as u64));
+            self.pages.extend(moved.into_iter().map(|(page, data)| (page + len / PAGE_SIZE as u64, data)));
+            self.len += len;
+            return;
+        }
+
+        // copied from the end so nothing is overwritten before it's read
+        let old_len = self.len;
+        let mut buf = vec![0; PAGE_SIZE];
+        let mut end = old_len;
+        while end > offset {
+            let start = end.saturating_sub(PAGE_SIZE as u64).max(offset);
+            let chunk = (end - start) as usize;
+            self.read(start, &mut buf[..chunk]);
+            self.write(start + len, &buf[..chunk]);
+            end = start;
+        }
+        self.len = old_len + len;
+        self.zero_range(offset, len);
+    }
+
+    /// Removes `len` bytes from `offset`, moving what follows back. Only the part before the end is removed.
+    pub fn remove_at(&mut self, offset: u64, len: u64) {
+        let end = min(offset.saturating_add(len), self.len);
+        if offset >= end {
+            return;
+        }
+        let removed = end - offset;
+        if end == self.len {
+            self.truncate(offset);
+            return;
+        }
+        if offset.is_multiple_of(PAGE_SIZE as u64) && removed.is_multiple_of(PAGE_SIZE as u64) {
+            // whole pages only change their index
+            let mut dropped = self.pages.split_off(&(offset / PAGE_SIZE as u64));
+            let moved = dropped.split_off(&(end / PAGE_SIZE as u64));
+            self.pages.extend(moved.into_iter().map(|(page, data)| (page - removed / PAGE_SIZE as u64, data)));
+            self.len -= removed;
+            return;
+        }
+
+        let mut buf = vec![0; PAGE_SIZE];
+        let mut pos = end;
+        while pos < self.len {
+            let chunk = min(PAGE_SIZE as u64, self.len - pos) as usize;
+            self.read(pos, &mut buf[..chunk]);
+            self.write(pos - removed, &buf[..chunk]);
+            pos += chunk as u64;
+        }
+        self.truncate(self.len - removed);
+    }
+
+    /// Start of the first data at or after `offset`, `None` when only holes follow. A page is data as a whole.
+    pub fn next_data(&self, offset: u64) -> Option<u64> {
+        if offset >= self.len {
+            return None;
+        }
+        let (page, _) = self.pages.range(offset / PAGE_SIZE as u64..).next()?;
+        let start = (page * PAGE_SIZE as u64).max(offset);
+
+        (start < self.len).then_some(start)
+    }
+
+    /// Start of the first hole at or after `offset`, the end of the file counts as one. `None` past the end.
+    pub fn next_hole(&self, offset: u64) -> Option<u64> {
+        if offset >= self.len {
+            return None;
+        }
+        let mut page = offset / PAGE_SIZE as u64;
+        while self.pages.contains_key(&page) {
+            page += 1;
+        }
+
+        Some((page * PAGE_SIZE as u64).clamp(offset, self.len))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut data = vec![0; self.len as usize];
+        self.read(0, &mut data);
+
+        data
+    }
+
+    /// Allocated pages in order, with their index. Pages not listed are holes.
+    pub fn pages(&self) -> impl Iterator<Item = (u64, &[u8; PAGE_SIZE])> {
+        self.pages.iter().map(|(page, data)| (*page, &**data))
+    }
+
+    /// Memory taken by the allocated pages.
+    pub fn memory_footprint(&self) -> u64 {
+        (self.pages.len() * PAGE_SIZE) as u64
+    }
+}
+
+#[cfg(not(feature = "compression"))]
+fn is_zero(data: &[u8]) -> bool {
+    data.iter().all(|b| *b == 0)
+}
+
+// run against whichever `FileData` the features pick
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writing_past_the_end_zero_fills_the_gap() {
+        let mut data = FileData::new();
+        data.write(4096, b"end");
+        assert_eq!(data.len(), 4099);
+        let mut buf = vec![0xff; 4099];
+        assert_eq!(data.read(0, &mut buf), 4099);
+        assert!(buf[..4096].iter().all(|b| *b == 0));
+        assert_eq!(&buf[4096..], b"end");
+
+        let mut data = FileData::from_bytes(&[1; 100]);
+        data.write(200, &[2; 10]);
+        assert_eq!(data.to_vec(), [vec![1; 100], vec![0; 100], vec![2; 10]].concat());
+    }
+
+    #[test]
+    fn growing_after_a_shrink_reads_zeros() {
+        let mut data = FileData::from_bytes(&[7; 3 * PAGE_SIZE]);
+        data.truncate(10);
+        data.truncate(2 * PAGE_SIZE as u64);
+        assert_eq!(data.to_vec(), [vec![7; 10], vec![0; 2 * PAGE_SIZE - 10]].concat());
+    }
+
+    #[test]
+    fn ranges_are_zeroed_inserted_and_removed() {
+        let content: Vec<u8> = (0..2 * PAGE_SIZE).map(|i| (i % 251) as u8 + 1).collect();
+        let mut data = FileData::from_bytes(&content);
+        data.zero_range(100, 50);
+        data.zero_range(2 * PAGE_SIZE as u64 - 1, 100);
+        let mut expected = content.clone();
+        expected[100..150].fill(0);
+        *expected.last_mut().unwrap() = 0;
+        assert_eq!(data.to_vec(), expected);
+
+        // within pages the bytes move, whole pages are moved at once
+        for (offset, len) in [(10, 3), (PAGE_SIZE, PAGE_SIZE)] {
+            let mut data = FileData::from_bytes(&content);
+            data.insert_at(offset as u64, len as u64);
+            let mut expected = content.clone();
+            expected.splice(offset..offset, vec![0; len]);
+            assert_eq!(data.to_vec(), expected, "insert {len} at {offset}");
+
+            data.remove_at(offset as u64, len as u64);
+            assert_eq!(data.to_vec(), content, "remove {len} at {offset}");
+        }
+
+        // removing past the end only removes up to it
+        let mut data = FileData::from_bytes(&content);
+        data.remove_at(PAGE_SIZE as u64 + 10, u64::MAX);
+        assert_eq!(data.to_vec(), &content[..PAGE_SIZE + 10]);
+    }
+}
@@ -0,0 +1,218 @@
+//! Inode numbers of paths kept in a file, so entries created again after a remount get the inode they had before.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::snapshot::{read_u32, read_u64, write_u32, write_u64};
+
+const MAGIC: &[u8; 4] = b"IMIN";
+const VERSION: u32 = 1;
+
+/// Path to inode map backed by a file. Entries are never dropped, a removed path keeps its inode for when it comes back.
+#[derive(Debug)]
+pub struct InodeMap {
+    file: PathBuf,
+    inodes: HashMap<String, u64>,
+    // inodes some path in the map holds, new paths don't get them
+    reserved: HashSet<u64>,
+    dirty: bool,
+}
+
+impl InodeMap {
+    /// Reads the map saved at `file`, an empty one if the file doesn't exist yet.
+    pub fn load(file: &Path) -> io::Result<Self> {
+        let mut map = InodeMap {
+            file: file.to_path_buf(),
+            inodes: HashMap::new(),
+            reserved: HashSet::new(),
+            dirty: false,
+        };
+        let mut r = match File::open(file) {
+            Ok(f) => BufReader::new(f),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(map),
+            Err(err) => return Err(err),
+        };
+
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not an inode map"));
+        }
+        let version = read_u32(&mut r)?;
+        if version != VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported inode map version {version}")));
+        }
+        let count = read_u64(&mut r)?;
+        for _ in 0..count {
+            let len = read_u32(&mut r)?;
+            let mut path = vec![];
+            r.by_ref().take(len as u64).read_to_end(&mut path)?;
+            if path.len() != len as usize {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let path = String::from_utf8(path).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "path is not UTF-8"))?;
+            let ino = read_u64(&mut r)?;
+            // the root is 1 and never in the map
+            if ino <= 1 || !path.starts_with('/') || !map.reserved.insert(ino) || map.inodes.insert(path, ino).is_some() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid or duplicated entry for inode {ino}")));
+            }
+        }
+
+        Ok(map)
+    }
+
+    pub fn get(&self, path: &str) -> Option<u64> {
+        self.inodes.get(path).copied()
+    }
+
+    pub fn insert(&mut self, path: String, ino: u64) {
+        if self.inodes.insert(path, ino) != Some(ino) {
+            self.reserved.insert(ino);
+            self.dirty = true;
+        }
+    }
+
+    /// Whether a path in the map holds `ino`, removed ones included.
+    pub fn is_reserved(&self, ino: u64) -> bool {
+        self.reserved.contains(&ino)
+    }
+
+    /// Highest inode in the map, 0 when empty.
+    pub fn max_inode(&self) -> u64 {
+        self.reserved.iter().copied().max().unwrap_or(0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.inodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inodes.is_empty()
+    }
+
+    /// Writes the map back to its file if it changed since it was loaded or last saved.
+    /// It goes to a temporary file first, so a crash keeps the previous map.
+    pub fn save(&mut self) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let mut tmp = self.file.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        {
+            let mut w = BufWriter::new(File::create(&tmp)?);
+            w.write_all(MAGIC)?;
+            write_u32(&mut w, VERSION)?;
+            write_u64(&mut w, self.inodes.len() as u64)?;
+            for (path, ino) in &self.inodes {
+                write_u32(&mut w, path.len() as u32)?;
+                w.write_all(path.as_bytes())?;
+                write_u64(&mut w, *ino)?;
+            }
+            w.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+        }
+        fs::rename(&tmp, &self.file)?;
+        self.dirty = false;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn saved(entries: &[(&str, u64)]) -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("inodes");
+        let mut map = InodeMap::load(&file).unwrap();
+        for (path, ino) in entries {
+            map.insert(path.to_string(), *ino);
+        }
+        map.save().unwrap();
+
+        (dir, file)
+    }
+
+    #[test]
+    fn missing_file_is_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let map = InodeMap::load(&dir.path().join("inodes")).unwrap();
+        assert!(map.is_empty());
+        assert_eq!(map.max_inode(), 0);
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let (_dir, file) = saved(&[("/a", 2), ("/a/b", 7), ("/c", 3)]);
+        let map = InodeMap::load(&file).unwrap();
+        assert_eq!(map.len(), 3);
+        assert_eq!(map.get("/a/b"), Some(7));
+        assert_eq!(map.get("/c"), Some(3));
+        assert_eq!(map.get("/d"), None);
+        assert!(map.is_reserved(2));
+        assert!(!map.is_reserved(4));
+        assert_eq!(map.max_inode(), 7);
+        assert!(!file.with_extension("tmp").exists());
+    }
+
+    #[test]
+    fn unchanged_map_is_not_written() {
+        let (_dir, file) = saved(&[("/a", 2)]);
+        let mut map = InodeMap::load(&file).unwrap();
+        map.insert(String::from("/a"), 2);
+        fs::remove_file(&file).unwrap();
+        map.save().unwrap();
+        assert!(!file.exists());
+    }
+
+    #[test]
+    fn bad_magic_and_version_are_rejected() {
+        let (_dir, file) = saved(&[("/a", 2)]);
+        let data = fs::read(&file).unwrap();
+
+        let mut bad_magic = data.clone();
+        bad_magic[0] = b'X';
+        fs::write(&file, &bad_magic).unwrap();
+        assert_eq!(InodeMap::load(&file).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let mut bad_version = data.clone();
+        bad_version[4..8].copy_from_slice(&2u32.to_le_bytes());
+        fs::write(&file, &bad_version).unwrap();
+        assert_eq!(InodeMap::load(&file).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn truncated_file_is_rejected() {
+        let (_dir, file) = saved(&[("/a", 2), ("/bb", 3)]);
+        let data = fs::read(&file).unwrap();
+        for len in 0..data.len() {
+            fs::write(&file, &data[..len]).unwrap();
+            assert!(InodeMap::load(&file).is_err(), "cut at {len}");
+        }
+    }
+
+    #[test]
+    fn huge_count_and_length_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("inodes");
+        let mut data = MAGIC.to_vec();
+        data.extend(VERSION.to_le_bytes());
+        data.extend(u64::MAX.to_le_bytes());
+        data.extend(u32::MAX.to_le_bytes());
+        data.extend(b"/a");
+        fs::write(&file, &data).unwrap();
+        assert_eq!(InodeMap::load(&file).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn duplicated_inode_is_rejected() {
+        let (_dir, file) = saved(&[("/a", 2), ("/b", 2)]);
+        assert_eq!(InodeMap::load(&file).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let (_dir, file) = saved(&[("/a", 1)]);
+        assert_eq!(InodeMap::load(&file).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
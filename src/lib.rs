@@ -1,3 +1,13 @@
+pub mod acl;
+pub mod audit;
 pub mod tree;
 pub mod tree_fs;
-pub mod mem_fs;
\ No newline at end of file
+pub mod file_attr;
+pub mod file_data;
+pub mod pipe;
+pub mod inode_map;
+#[cfg(feature = "compression")]
+pub mod compressed_file_data;
+pub mod mem_fs;
+pub mod snapshot;
+pub mod sys;
@@ -1,11 +1,46 @@
 use std::fs::File;
-use std::io;
-use std::io::{BufRead, BufReader};
+use std::io::{self, BufWriter};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
 
-use clap::{Arg, ArgAction, Command, crate_version};
+use clap::{Arg, ArgAction, Command, crate_version, value_parser};
 use fuser::MountOption;
+use log::info;
 
-use in_mem_fs::mem_fs::MemFs;
+use in_mem_fs::mem_fs::{fuse_allow_other_enabled, AtimeMode, ImportOptions, MemFs, MemFsBuilder};
+
+mod shell;
+
+// set by SIGUSR1 and the --stats-interval thread, the filesystem prints its statistics on the next request
+static STATS_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_stats(_signal: libc::c_int) {
+    STATS_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Parses a byte count with an optional `K`, `M`, `G` or `T` suffix, powers of 1024, like `256M`.
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, shift) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 10),
+        Some((i, 'm' | 'M')) => (&s[..i], 20),
+        Some((i, 'g' | 'G')) => (&s[..i], 30),
+        Some((i, 't' | 'T')) => (&s[..i], 40),
+        _ => (s, 0),
+    };
+    let size: u64 = digits.parse().map_err(|_| format!("invalid size {s:?}"))?;
+
+    size.checked_mul(1 << shift).ok_or_else(|| format!("size {s:?} is too large"))
+}
+
+fn parse_block_size(s: &str) -> Result<u32, String> {
+    match u32::try_from(parse_size(s)?) {
+        Ok(block_size) if block_size > 0 && block_size % 512 == 0 => Ok(block_size),
+        _ => Err(format!("block size {s:?} is not a multiple of 512")),
+    }
+}
 
 fn main() {
     let matches = Command::new("hello")
@@ -43,6 +78,131 @@ fn main() {
                 .action(ArgAction::SetTrue)
                 .help("Enable setuid support when run as root"),
         )
+        .arg(
+            Arg::new("entry-ttl")
+                .long("entry-ttl")
+                .value_name("SECS")
+                .default_value("0")
+                .value_parser(value_parser!(u64))
+                .help("How long the kernel may cache directory entries"),
+        )
+        .arg(
+            Arg::new("attr-ttl")
+                .long("attr-ttl")
+                .value_name("SECS")
+                .default_value("0")
+                .value_parser(value_parser!(u64))
+                .help("How long the kernel may cache file attributes"),
+        )
+        .arg(
+            Arg::new("max-memory")
+                .long("max-memory")
+                .value_name("BYTES")
+                .value_parser(parse_size)
+                .help("Maximum space files can take, writes beyond it fail with ENOSPC. Takes K, M, G or T suffixes, like 256M"),
+        )
+        .arg(
+            Arg::new("max-files")
+                .long("max-files")
+                .value_name("COUNT")
+                .value_parser(value_parser!(usize))
+                .help("Maximum number of files and directories, creating more fails with ENOSPC"),
+        )
+        .arg(
+            Arg::new("max-file-size")
+                .long("max-file-size")
+                .value_name("BYTES")
+                .value_parser(parse_size)
+                .help("Maximum size of a file, growing it further fails with EFBIG"),
+        )
+        .arg(
+            Arg::new("stats-interval")
+                .long("stats-interval")
+                .value_name("SECS")
+                .value_parser(value_parser!(u64))
+                .help("Print filesystem statistics to stderr every SECS seconds, SIGUSR1 prints them on demand"),
+        )
+        .arg(
+            Arg::new("audit-ring")
+                .long("audit-ring")
+                .value_name("ENTRIES")
+                .value_parser(value_parser!(usize))
+                .help("Keep the last ENTRIES operations in memory, the statistics end with the latest 100 of them"),
+        )
+        .arg(
+            Arg::new("populate-from")
+                .long("populate-from")
+                .value_name("PATH")
+                .help("Start with a copy of the directory at PATH"),
+        )
+        .arg(
+            Arg::new("populate-max-size")
+                .long("populate-max-size")
+                .value_name("BYTES")
+                .value_parser(parse_size)
+                .requires("populate-from")
+                .help("Abort if the files copied by --populate-from add up to more than BYTES"),
+        )
+        .arg(
+            Arg::new("dump-tree")
+                .long("dump-tree")
+                .action(ArgAction::SetTrue)
+                .help("Print the filesystem tree, as filled by --populate-from, and exit without mounting"),
+        )
+        .arg(
+            Arg::new("export-tar")
+                .long("export-tar")
+                .value_name("PATH")
+                .conflicts_with("dump-tree")
+                .help("Write the filesystem, as filled by --populate-from, as a tar archive to PATH, - for stdout, and exit without mounting"),
+        )
+        .arg(
+            Arg::new("no-mount")
+                .long("no-mount")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["dump-tree", "export-tar"])
+                .help("Run commands like ls, cat and mkdir read from stdin against the filesystem, as filled by --populate-from, instead of mounting it. Type help for the list"),
+        )
+        .arg(
+            Arg::new("noatime")
+                .long("noatime")
+                .action(ArgAction::SetTrue)
+                .help("Don't update access times on read"),
+        )
+        .arg(
+            Arg::new("case-insensitive")
+                .long("case-insensitive")
+                .action(ArgAction::SetTrue)
+                .help("Match file names ignoring ASCII case, they keep the case they were created with"),
+        )
+        .arg(
+            Arg::new("umask")
+                .long("umask")
+                .value_name("OCTAL")
+                .value_parser(|umask: &str| u32::from_str_radix(umask, 8))
+                .help("Permission bits cleared from new files and directories when the kernel sends no umask [default: 022]"),
+        )
+        .arg(
+            Arg::new("block-size")
+                .long("block-size")
+                .value_name("BYTES")
+                .value_parser(parse_block_size)
+                .help("Block size reported by stat and statfs, a multiple of 512 [default: 4096]"),
+        )
+        .arg(
+            Arg::new("max-symlink-depth")
+                .long("max-symlink-depth")
+                .value_name("N")
+                .value_parser(value_parser!(u8))
+                .help("Symlinks followed while resolving a path before failing with ELOOP [default: 40]"),
+        )
+        .arg(
+            Arg::new("strictatime")
+                .long("strictatime")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("noatime")
+                .help("Update access times on every read instead of only when older than the last change or a day"),
+        )
         .get_matches();
 
     env_logger::init();
@@ -82,15 +242,101 @@ fn main() {
         options.push(MountOption::AllowRoot);
     }
 
-    fuser::mount2(MemFs::new(matches.get_flag("direct-io"), matches.get_flag("suid")), mountpoint, &options).unwrap();
-}
-
-fn fuse_allow_other_enabled() -> io::Result<bool> {
-    let file = File::open("/etc/fuse.conf")?;
-    for line in BufReader::new(file).lines() {
-        if line?.trim_start().starts_with("user_allow_other") {
-            return Ok(true);
+    let mut builder = MemFsBuilder::default()
+        .direct_io(matches.get_flag("direct-io"))
+        .suid(matches.get_flag("suid"))
+        .atime_mode(if matches.get_flag("noatime") {
+            AtimeMode::NoAtime
+        } else if matches.get_flag("strictatime") {
+            AtimeMode::Strict
+        } else {
+            AtimeMode::Relatime
+        })
+        .entry_ttl(Duration::from_secs(*matches.get_one::<u64>("entry-ttl").unwrap()))
+        .attr_ttl(Duration::from_secs(*matches.get_one::<u64>("attr-ttl").unwrap()))
+        .case_sensitive(!matches.get_flag("case-insensitive"));
+    if let Some(capacity) = matches.get_one::<u64>("max-memory") {
+        builder = builder.capacity(*capacity);
+    }
+    if let Some(max_files) = matches.get_one::<usize>("max-files") {
+        builder = builder.max_files(*max_files);
+    }
+    if let Some(max_file_size) = matches.get_one::<u64>("max-file-size") {
+        builder = builder.max_file_size(*max_file_size);
+    }
+    if let Some(entries) = matches.get_one::<usize>("audit-ring") {
+        builder = builder.audit_ring(*entries);
+    }
+    if let Some(umask) = matches.get_one::<u32>("umask") {
+        builder = builder.umask(*umask);
+    }
+    if let Some(block_size) = matches.get_one::<u32>("block-size") {
+        builder = builder.block_size(*block_size);
+    }
+    if let Some(depth) = matches.get_one::<u8>("max-symlink-depth") {
+        builder = builder.max_symlink_depth(*depth);
+    }
+    let mut fs = match matches.get_one::<String>("populate-from") {
+        Some(path) => {
+            let options = ImportOptions {
+                max_total_size: matches.get_one::<u64>("populate-max-size").copied().unwrap_or(u64::MAX),
+                ..ImportOptions::default()
+            };
+            match builder.import_from_directory(Path::new(path), options) {
+                Ok(fs) => {
+                    let stats = fs.statistics();
+                    info!("imported {} files and {} directories, {} bytes, from {}", stats.total_files, stats.total_dirs, stats.total_bytes_used, path);
+                    fs
+                }
+                Err(err) => {
+                    eprintln!("Cannot populate from {path}: {err}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => builder.build(),
+    };
+    if matches.get_flag("dump-tree") {
+        if let Err(err) = fs.dump_tree(&mut io::stdout().lock()) {
+            eprintln!("Cannot dump the tree: {err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(path) = matches.get_one::<String>("export-tar") {
+        let res = if path == "-" {
+            fs.stream_tar(&mut io::stdout().lock())
+        } else {
+            File::create(path).and_then(|file| fs.stream_tar(&mut BufWriter::new(file)))
+        };
+        if let Err(err) = res {
+            eprintln!("Cannot export to {path}: {err}");
+            std::process::exit(1);
         }
+        return;
+    }
+    if matches.get_flag("no-mount") {
+        if !shell::run(&mut fs, io::stdin().lock()) {
+            std::process::exit(1);
+        }
+        return;
+    }
+    fs.set_stats_trigger(&STATS_REQUESTED);
+    unsafe {
+        libc::signal(libc::SIGUSR1, request_stats as extern "C" fn(libc::c_int) as libc::sighandler_t);
     }
-    Ok(false)
+    if let Some(interval) = matches.get_one::<u64>("stats-interval") {
+        let interval = Duration::from_secs(*interval);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            STATS_REQUESTED.store(true, Ordering::Relaxed);
+        });
+    }
+
+    if let Err(err) = MemFs::check_mount_prerequisites(&options) {
+        eprintln!("Cannot mount {mountpoint}: {err}");
+        std::process::exit(1);
+    }
+
+    fuser::mount2(fs, mountpoint, &options).unwrap();
 }
@@ -1,962 +1,4016 @@
+// without the fuse feature the helpers of the request handlers in `filesystem` go unused
+#![cfg_attr(not(feature = "fuse"), allow(dead_code))]
+
+use std::borrow::Cow;
 use std::cmp::min;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::{fmt, io};
 use std::ffi::OsStr;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Read, Write};
+#[cfg(unix)]
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::BufWriter;
+use std::io::{BufRead, BufReader};
+use std::io::{Read, Write};
+use std::hash::{Hash, Hasher};
+use std::ops::{BitOr, RangeInclusive};
 use std::os::raw::c_int;
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+#[cfg(unix)]
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::path::Path;
+#[cfg(unix)]
+use std::path::PathBuf;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use bytebuffer::ByteBuffer;
-use fuser::{FileAttr, Filesystem, FileType, KernelConfig, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyWrite, Request, TimeOrNow};
-use fuser::consts::FOPEN_DIRECT_IO;
-use fuser::TimeOrNow::Now;
-use libc::{ENOENT, ENOSYS};
+use crossbeam_queue::SegQueue;
 use log::{debug, warn};
-
-use crate::tree_fs::{Item, TreeFs};
-
-const BLOCK_SIZE: u64 = 512;
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::audit::AuditLog;
+#[cfg(feature = "audit")]
+use crate::audit::OpKind;
+use crate::acl::{acl_chmod, acl_mode, acl_permits, inherit_acl, is_minimal, parse_posix_acl, serialize_posix_acl, ACL_ACCESS_XATTR, ACL_DEFAULT_XATTR};
+use crate::file_attr::{FileAttr, FileType, TimeOrNow};
+use crate::file_data::FileData;
+use crate::inode_map::InodeMap;
+use crate::snapshot::{is_supported_version, MemFsSnapshot, SnapshotEntry, SnapshotError, MAGIC, VERSION, read_u32, read_u64, read_u8, write_u32, write_u64, write_u8};
+use crate::pipe::{PipeBuffer, PipeReader, PipeWriter};
+use crate::sys::{self, ENOENT};
+use crate::tree_fs::{Item, ItemNode, TreeFs, TreeFsError};
+
+#[cfg(feature = "fuse")]
+mod filesystem;
+#[cfg(any(test, feature = "fuzzing"))]
+#[doc(hidden)]
+pub mod fuzz;
+
+// st_blocks counts 512 byte units whatever the block size
+const STAT_BLOCK_SIZE: u64 = 512;
+const DEFAULT_BLOCK_SIZE: u32 = 4096;
 
 const FMODE_EXEC: i32 = 0x20;
 
-pub struct MemFs {
-    tree_fs: TreeFs<FileAttr>,
-    direct_io: bool,
-    suid_support: bool,
-    current_inode: u64,
-    current_file_handle: u64,
-}
-
-impl MemFs {
-    // pub fn new_sample(direct_io: bool, suid_support: bool) -> Self {
-    //     MemFs {
-    //         tree_fs: generate_sample_tree(),
-    //         direct_io,
-    //         suid_support,
-    //     }
-    // }
-
-    pub fn new(direct_io: bool, _suid_support: bool) -> Self {
-        #[cfg(feature = "abi-7-26")]
-        {
-            MemFs {
-                tree_fs: TreeFs::new(),
-                direct_io,
-                suid_support: _suid_support,
-                current_inode: 1,
-                current_file_handle: 0,
-            }
-        }
-        #[cfg(not(feature = "abi-7-26"))] {
-            MemFs {
-                tree_fs: TreeFs::new(),
-                direct_io,
-                suid_support: false,
-                current_inode: 1,
-                current_file_handle: 0,
-            }
-        }
-    }
+// mode_t is u32 on Linux but u16 on macOS
+#[allow(clippy::unnecessary_cast)]
+const SETID_BITS: u32 = (sys::S_ISUID | sys::S_ISGID) as u32;
+#[allow(clippy::unnecessary_cast)]
+const SETGID_BIT: u32 = sys::S_ISGID as u32;
 
-    fn creation_mode(&self, mode: u32) -> u16 {
-        if !self.suid_support {
-            (mode & !(libc::S_ISUID | libc::S_ISGID) as u32) as u16
-        } else {
-            mode as u16
-        }
-    }
 
-    fn allocate_next_inode(&mut self) -> u64 {
-        self.current_inode += 1;
+const FALLOC_FL_KEEP_SIZE: i32 = 0x01;
 
-        self.current_inode
-    }
+const FALLOC_FL_PUNCH_HOLE: i32 = 0x02;
 
-    fn create_nod(&mut self, parent: u64, mut mode: u32, req: &Request, name: &OsStr) -> Result<FileAttr, c_int> {
-        match self.tree_fs.get_item_mut(parent) {
-            Some(parent) => {
-                if !parent.is_dir {
-                    return Err(ENOENT);
-                }
+const FALLOC_FL_COLLAPSE_RANGE: i32 = 0x08;
 
-                if parent.find_child_mut(name.to_str().unwrap()).is_some() {
-                    return Err(libc::EEXIST);
-                }
+const FALLOC_FL_ZERO_RANGE: i32 = 0x10;
 
-                let parent_attr = parent.extra.as_mut().unwrap();
-
-                if !check_access(
-                    parent_attr.uid,
-                    parent_attr.gid,
-                    parent_attr.perm,
-                    req.uid(),
-                    req.gid(),
-                    libc::W_OK,
-                ) {
-                    return Err(libc::EACCES);
-                }
+const FALLOC_FL_INSERT_RANGE: i32 = 0x20;
 
-                parent_attr.mtime = SystemTime::now();
-                parent_attr.ctime = SystemTime::now();
+const RENAME_NOREPLACE: u32 = 1;
 
-                if req.uid() != 0 {
-                    mode &= !(libc::S_ISUID | libc::S_ISGID) as u32;
-                }
+const RENAME_EXCHANGE: u32 = 2;
+// linux/fs.h
+const RENAME_WHITEOUT: u32 = 4;
 
-                let kind = as_file_kind(mode);
-                let ino = self.allocate_next_inode();
-                let mut attr = if kind == FileType::Directory {
-                    dir_attr(ino)
-                } else {
-                    file_attr(ino, 0)
-                };
-                attr.perm = self.creation_mode(mode);
-                attr.uid = req.uid();
-                attr.gid = creation_gid(&parent_attr, req.gid());
+const MAX_DIR_ENTRIES: usize = 1 << 16;
 
-                self.tree_fs.push(&parent, Item::new(ino, name.to_str().unwrap().to_string(), kind == FileType::Directory, Some(attr)));
+const PAGE_SIZE: u64 = 4096;
 
-                Ok(attr)
-            }
-            None => Err(ENOENT),
-        }
-    }
+// MAXSYMLINKS of Linux
+const MAX_SYMLINK_DEPTH: u8 = 40;
 
-    fn allocate_next_file_handle(&mut self) -> u64 {
-        self.current_file_handle += 1;
+const MAX_XATTR_SIZE: usize = 64 * 1024;
 
-        self.current_file_handle
-    }
-}
+const MAX_INODES: u64 = u32::MAX as u64;
 
-impl Filesystem for MemFs {
-    fn init(
-        &mut self,
-        _req: &Request,
-        #[allow(unused_variables)] config: &mut KernelConfig,
-    ) -> Result<(), c_int> {
-        #[cfg(feature = "abi-7-26")]
-        config.add_capabilities(FUSE_HANDLE_KILLPRIV).unwrap();
+const MAX_NAME_LENGTH: u32 = 255;
 
-        if self.tree_fs.get_root().is_none() {
-            let root = Item::new(1, String::from("root"), true, Some(dir_attr(1)));
-            self.tree_fs.set_root(root);
-        }
-        Ok(())
-    }
+const CAP_SYS_ADMIN: u32 = 21;
 
-    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        debug!("lookup {}, {}", parent, name.to_str().unwrap());
-
-        match self.tree_fs.get_item_mut(parent) {
-            Some(parent_item) => {
-                let parent_attr = parent_item.extra.as_ref().unwrap();
-                if !check_access(
-                    parent_attr.uid,
-                    parent_attr.gid,
-                    parent_attr.perm,
-                    req.uid(),
-                    req.gid(),
-                    libc::X_OK,
-                ) {
-                    reply.error(libc::EACCES);
-                    return;
-                }
+const USRQUOTA: c_int = 0;
 
-                match parent_item.find_child_mut(name.to_str().unwrap()) {
-                    Some(child) => {
-                        if child.is_dir {
-                            debug!("  dir {}", child.ino);
-                            reply.entry(&Duration::new(0, 0), &&child.extra.as_ref().unwrap(), 0);
-                        } else {
-                            debug!("  file {}", child.ino);
-                            reply.entry(&Duration::new(0, 0), &&child.extra.as_ref().unwrap(), 0);
-                        }
-                    }
-                    None => {
-                        debug!("  not found");
-                        reply.error(ENOENT);
-                    }
-                }
-            }
-            None => {
-                debug!("  not found");
-                reply.error(ENOENT)
-            }
-        }
-    }
+const GRPQUOTA: c_int = 1;
 
-    fn forget(&mut self, _req: &Request<'_>, _ino: u64, _nlookup: u64) {
-        debug!("forget() called with {:?} {:?}", _ino, _nlookup);
-    }
+// printed after the statistics when they are requested
+const STATS_AUDIT_ENTRIES: usize = 100;
 
-    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        debug!("getattr {}", ino);
+// relatime still updates access times older than this
+const RELATIME_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
 
-        match self.tree_fs.get_item_mut(ino) {
-            Some(item) => {
-                if item.is_dir {
-                    debug!("  dir {}", ino);
-                    reply.attr(&Duration::new(0, 0), &item.extra.as_ref().unwrap());
-                } else {
-                    debug!("  file {}", ino);
-                    reply.attr(&Duration::new(0, 0), &item.extra.as_ref().unwrap());
-                }
-            }
-            None => {
-                debug!("  not found");
-                reply.error(ENOENT)
-            }
-        }
-    }
-
-    fn setattr(
-        &mut self,
-        req: &Request,
-        inode: u64,
-        mode: Option<u32>,
-        uid: Option<u32>,
-        gid: Option<u32>,
-        size: Option<u64>,
-        atime: Option<TimeOrNow>,
-        mtime: Option<TimeOrNow>,
-        _ctime: Option<SystemTime>,
-        fh: Option<u64>,
-        _crtime: Option<SystemTime>,
-        _chgtime: Option<SystemTime>,
-        _bkuptime: Option<SystemTime>,
-        _flags: Option<u32>,
-        reply: ReplyAttr,
-    ) {
-        debug!("setattr() called with {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?}", inode, mode, uid, gid, size, atime, mtime, fh);
-
-        let item = match self.tree_fs.get_item_mut(inode) {
-            Some(item) => item,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
-        let mut attr = item.extra.as_mut().unwrap();
+// unit of the limits in `struct dqblk`
+const QIF_DQBLKSIZE: u64 = 1024;
+const DEFAULT_UMASK: u32 = 0o022;
+// ioctls of chattr and lsattr, the FS_IOC32 ones come from 32-bit processes
+const FS_IOC_GETFLAGS: u32 = 0x80086601;
+const FS_IOC_SETFLAGS: u32 = 0x40086602;
+const FS_IOC32_GETFLAGS: u32 = 0x80046601;
+const FS_IOC32_SETFLAGS: u32 = 0x40046602;
+const FS_IMMUTABLE_FL: u32 = 0x00000010;
+const FS_APPEND_FL: u32 = 0x00000020;
+const FS_NOATIME_FL: u32 = 0x00000080;
+const SUPPORTED_INODE_FLAGS: u32 = FS_IMMUTABLE_FL | FS_APPEND_FL | FS_NOATIME_FL;
 
-        if let Some(mode) = mode {
-            debug!("chmod() called with {:?}, {:o}", inode, mode);
+#[derive(Debug, Clone, PartialEq)]
+pub enum DirEvent {
+    Created { path: String, kind: FileType },
+    Deleted { path: String },
+    Modified { path: String },
+    Renamed { from: String, to: String },
+}
 
-            if req.uid() != 0 && req.uid() != attr.uid {
-                reply.error(libc::EPERM);
-                return;
-            }
-            if req.uid() != 0
-                && req.gid() != attr.gid
-                && !get_groups(req.pid()).contains(&attr.gid)
-            {
-                // If SGID is set and the file belongs to a group that the caller is not part of
-                // then the SGID bit is suppose to be cleared during chmod
-                attr.perm = (mode & !libc::S_ISGID as u32) as u16;
-            } else {
-                attr.perm = mode as u16;
-            }
-            attr.ctime = SystemTime::now();
-            reply.attr(&Duration::new(0, 0), &attr);
-            return;
-        }
+/// Change made through a FUSE operation, or an entry created or renamed through the API, sent to the listeners added
+/// with `MemFs::add_listener`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FsEvent {
+    Created { ino: u64, path: String, kind: FileType },
+    Deleted { ino: u64, path: String },
+    Written { ino: u64, offset: i64, len: usize },
+    Renamed { old_path: String, new_path: String },
+    /// Attributes or extended attributes changed.
+    AttrChanged { ino: u64 },
+}
 
-        if uid.is_some() || gid.is_some() {
-            debug!("chown() called with {:?} {:?} {:?}", inode, uid, gid);
+/// inotify(7) events a watch reports, combined with `|`. Same bit values as inotify.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchMask(u32);
+
+impl WatchMask {
+    pub const IN_MODIFY: WatchMask = WatchMask(0x2);
+    pub const IN_ATTRIB: WatchMask = WatchMask(0x4);
+    pub const IN_MOVED_FROM: WatchMask = WatchMask(0x40);
+    pub const IN_MOVED_TO: WatchMask = WatchMask(0x80);
+    pub const IN_CREATE: WatchMask = WatchMask(0x100);
+    pub const IN_DELETE: WatchMask = WatchMask(0x200);
+    pub const IN_ALL_EVENTS: WatchMask = WatchMask(0x2 | 0x4 | 0x40 | 0x80 | 0x100 | 0x200);
+
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
 
-            if let Some(gid) = gid {
-                // Non-root users can only change gid to a group they're in
-                if req.uid() != 0 && !get_groups(req.pid()).contains(&gid) {
-                    reply.error(libc::EPERM);
-                    return;
-                }
-            }
-            if let Some(uid) = uid {
-                if req.uid() != 0
-                    // but no-op changes by the owner are not an error
-                    && !(uid == attr.uid && req.uid() == attr.uid)
-                {
-                    reply.error(libc::EPERM);
-                    return;
-                }
-            }
-            // Only owner may change the group
-            if gid.is_some() && req.uid() != 0 && req.uid() != attr.uid {
-                reply.error(libc::EPERM);
-                return;
-            }
+    pub fn contains(&self, other: WatchMask) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
 
-            if attr.perm & (libc::S_IXUSR | libc::S_IXGRP | libc::S_IXOTH) as u16 != 0 {
-                // SUID & SGID are suppose to be cleared when chown'ing an executable file
-                clear_suid_sgid(&mut attr);
-            }
+impl BitOr for WatchMask {
+    type Output = WatchMask;
 
-            if let Some(uid) = uid {
-                attr.uid = uid;
-                // Clear SETUID on owner change
-                attr.perm &= !libc::S_ISUID as u16;
-            }
-            if let Some(gid) = gid {
-                attr.gid = gid;
-                // Clear SETGID unless user is root
-                if req.uid() != 0 {
-                    attr.perm &= !libc::S_ISGID as u16;
-                }
-            }
-            attr.ctime = SystemTime::now();
-            reply.attr(&Duration::new(0, 0), &attr);
-            return;
-        }
+    fn bitor(self, rhs: WatchMask) -> WatchMask {
+        WatchMask(self.0 | rhs.0)
+    }
+}
 
-        if let Some(size) = size {
-            debug!("truncate() called with {:?} {:?}", inode, size);
+/// Event of a watch, `name` is the entry inside the watched directory, `None` when about the watched entry itself.
+/// The `IN_MOVED_FROM` and `IN_MOVED_TO` of one rename share their `cookie`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InotifyEvent {
+    pub mask: WatchMask,
+    pub cookie: u32,
+    pub name: Option<String>,
+}
 
-            if size == 0 {
-                item.data.as_mut().unwrap().clear();
-            } else {
-                let old_data = item.data.take().unwrap();
-                let old_data_vec = old_data.into_vec();
+/// Watch added with `MemFs::watch`, dropping it (or the receiver taken from it) removes the watch.
+pub struct WatchHandle {
+    receiver: Receiver<InotifyEvent>,
+}
 
-                let mut new_data = ByteBuffer::new();
-                let _ = new_data.write(&old_data_vec[..(size as usize)]);
-                item.data = Some(new_data);
+impl WatchHandle {
+    /// The next queued event, without waiting.
+    pub fn next_event(&self) -> Option<InotifyEvent> {
+        self.receiver.try_recv().ok()
+    }
 
-                attr.size = size;
-                attr.ctime = SystemTime::now();
-                attr.mtime = SystemTime::now();
+    pub fn receiver(self) -> Receiver<InotifyEvent> {
+        self.receiver
+    }
+}
 
-                // Clear SETUID & SETGID on truncate
-                clear_suid_sgid(&mut attr);
-            }
-        }
+/// Listener writing one tab-separated line per event: `timestamp event fields...`.
+pub struct AuditLogger {
+    log: Mutex<BufWriter<File>>,
+}
 
-        if let Some(atime) = atime {
-            debug!("utimens() called with {:?}, atime={:?}", inode, atime);
+impl AuditLogger {
+    /// Appends to the file at `path`.
+    pub fn new(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
 
-            if attr.uid != req.uid() && req.uid() != 0 && atime != Now {
-                reply.error(libc::EPERM);
-                return;
-            }
+        Ok(AuditLogger { log: Mutex::new(BufWriter::new(file)) })
+    }
 
-            if attr.uid != req.uid()
-                && !check_access(
-                attr.uid,
-                attr.gid,
-                attr.perm,
-                req.uid(),
-                req.gid(),
-                libc::W_OK,
-            ) {
-                reply.error(libc::EACCES);
-                return;
-            }
+    pub fn log(&self, event: &FsEvent) {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let fields = match event {
+            FsEvent::Created { ino, path, kind } => format!("created\t{ino}\t{path}\t{kind:?}"),
+            FsEvent::Deleted { ino, path } => format!("deleted\t{ino}\t{path}"),
+            FsEvent::Written { ino, offset, len } => format!("written\t{ino}\t{offset}\t{len}"),
+            FsEvent::Renamed { old_path, new_path } => format!("renamed\t{old_path}\t{new_path}"),
+            FsEvent::AttrChanged { ino } => format!("attr_changed\t{ino}"),
+        };
 
-            attr.atime = match atime {
-                TimeOrNow::SpecificTime(time) => time,
-                Now => SystemTime::now(),
-            };
-            attr.ctime = SystemTime::now();
+        let mut log = self.log.lock().unwrap();
+        let res = writeln!(log, "{}.{:09}\t{}", timestamp.as_secs(), timestamp.subsec_nanos(), fields)
+            .and_then(|_| log.flush());
+        if let Err(err) = res {
+            warn!("cannot write audit log: {}", err);
         }
-        if let Some(mtime) = mtime {
-            debug!("utimens() called with {:?}, mtime={:?}", inode, mtime);
+    }
 
-            if attr.uid != req.uid() && req.uid() != 0 && mtime != Now {
-                reply.error(libc::EPERM);
-                return;
-            }
+    pub fn into_listener(self) -> Box<dyn Fn(FsEvent) + Send + Sync> {
+        Box::new(move |event| self.log(&event))
+    }
+}
 
-            if attr.uid != req.uid()
-                && !check_access(
-                attr.uid,
-                attr.gid,
-                attr.perm,
-                req.uid(),
-                req.gid(),
-                libc::W_OK,
-            ) {
-                reply.error(libc::EACCES);
-                return;
-            }
+/// Errors of the programmatic API, which works without a FUSE mount, and of the operations behind the FUSE replies.
+#[derive(Debug)]
+pub enum FsError {
+    NotFound,
+    AccessDenied,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    NotEmpty,
+    NoSpace,
+    FileTooLarge,
+    QuotaExceeded,
+    InvalidPath,
+    InvalidArgument,
+    NotSupported,
+    Io(io::Error),
+    /// Any other errno.
+    Custom(c_int),
+}
 
-            attr.mtime = match mtime {
-                TimeOrNow::SpecificTime(time) => time,
-                Now => SystemTime::now(),
-            };
-            attr.ctime = SystemTime::now();
+impl FsError {
+    pub fn errno(&self) -> c_int {
+        match self {
+            FsError::NotFound => ENOENT,
+            FsError::AccessDenied => sys::EACCES,
+            FsError::NotADirectory => sys::ENOTDIR,
+            FsError::IsADirectory => sys::EISDIR,
+            FsError::AlreadyExists => sys::EEXIST,
+            FsError::NotEmpty => sys::ENOTEMPTY,
+            FsError::NoSpace => sys::ENOSPC,
+            FsError::FileTooLarge => sys::EFBIG,
+            FsError::QuotaExceeded => sys::EDQUOT,
+            FsError::InvalidPath | FsError::InvalidArgument => sys::EINVAL,
+            FsError::NotSupported => sys::ENOTSUP,
+            FsError::Io(err) => err.raw_os_error().unwrap_or(sys::EIO),
+            FsError::Custom(errno) => *errno,
         }
+    }
 
-        reply.attr(&Duration::new(0, 0), &attr);
-        return;
+    fn from_errno(errno: c_int) -> Self {
+        match errno {
+            ENOENT => FsError::NotFound,
+            sys::EACCES => FsError::AccessDenied,
+            sys::ENOTDIR => FsError::NotADirectory,
+            sys::EISDIR => FsError::IsADirectory,
+            sys::EEXIST => FsError::AlreadyExists,
+            sys::ENOTEMPTY => FsError::NotEmpty,
+            sys::ENOSPC => FsError::NoSpace,
+            sys::EFBIG => FsError::FileTooLarge,
+            sys::EDQUOT => FsError::QuotaExceeded,
+            sys::EINVAL => FsError::InvalidArgument,
+            sys::ENOTSUP => FsError::NotSupported,
+            _ => FsError::Custom(errno),
+        }
     }
+}
 
-    fn mknod(
-        &mut self,
-        req: &Request,
-        parent: u64,
-        name: &OsStr,
-        mode: u32,
-        _umask: u32,
-        _rdev: u32,
-        reply: ReplyEntry,
-    ) {
-        debug!("mknod() called with {:?} {:?} {:o}", parent, name, mode);
+// io::Error has no PartialEq, those compare by kind
+impl PartialEq for FsError {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (FsError::Io(a), FsError::Io(b)) => a.kind() == b.kind(),
+            (FsError::Custom(a), FsError::Custom(b)) => a == b,
+            _ => std::mem::discriminant(self) == std::mem::discriminant(other),
+        }
+    }
+}
 
-        let file_type = mode & libc::S_IFMT as u32;
+impl From<FsError> for c_int {
+    fn from(err: FsError) -> c_int {
+        err.errno()
+    }
+}
 
-        if file_type != libc::S_IFREG as u32
-            // && file_type != libc::S_IFLNK as u32
-            && file_type != libc::S_IFDIR as u32
-        {
-            // TODO
-            warn!("mknod() implementation is incomplete. Only supports regular files and directories. Got {:o}", mode);
-            reply.error(libc::ENOSYS);
-            return;
+impl From<TreeFsError> for FsError {
+    fn from(err: TreeFsError) -> Self {
+        match err {
+            TreeFsError::NotADirectory => FsError::NotADirectory,
+            TreeFsError::InodeAlreadyExists => FsError::AlreadyExists,
+            TreeFsError::ParentNotFound | TreeFsError::ChildNotFound => FsError::NotFound,
         }
+    }
+}
 
-        match self.create_nod(parent, mode, req, name) {
-            Ok(attr) => {
-                // TODO: implement flags
-                reply.entry(&Duration::new(0, 0), &attr, 0);
-            }
-            Err(err) => reply.error(err)
-        }
+impl From<io::Error> for FsError {
+    fn from(err: io::Error) -> Self {
+        FsError::Io(err)
     }
-    fn mkdir(
-        &mut self,
-        req: &Request,
-        parent: u64,
-        name: &OsStr,
-        mut mode: u32,
-        _umask: u32,
-        reply: ReplyEntry,
-    ) {
-        debug!("mkdir() called with {:?} {:?} {:o}", parent, name, mode);
+}
 
-        let parent_o = self.tree_fs.get_item_mut(parent);
-        if parent_o
-            .map_or(None,
-                    |parent_item| parent_item.find_child_mut(name.to_str().unwrap()))
-            .is_some() {
-            reply.error(libc::EEXIST);
-            return;
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsError::NotFound => write!(f, "no such file or directory"),
+            FsError::AccessDenied => write!(f, "permission denied"),
+            FsError::NotADirectory => write!(f, "not a directory"),
+            FsError::IsADirectory => write!(f, "is a directory"),
+            FsError::AlreadyExists => write!(f, "file exists"),
+            FsError::NotEmpty => write!(f, "directory not empty"),
+            FsError::NoSpace => write!(f, "no space left"),
+            FsError::FileTooLarge => write!(f, "file too large"),
+            FsError::QuotaExceeded => write!(f, "disk quota exceeded"),
+            FsError::InvalidPath => write!(f, "invalid path"),
+            FsError::InvalidArgument => write!(f, "invalid argument"),
+            FsError::NotSupported => write!(f, "operation not supported"),
+            FsError::Io(err) => write!(f, "{err}"),
+            FsError::Custom(errno) => write!(f, "{}", io::Error::from_raw_os_error(*errno)),
         }
+    }
+}
 
-        let parent = self.tree_fs.get_item_mut(parent).unwrap();
-        let parent_attr = parent.extra.as_ref().unwrap();
-        if !check_access(
-            parent_attr.uid,
-            parent_attr.gid,
-            parent_attr.perm,
-            req.uid(),
-            req.gid(),
-            libc::W_OK,
-        ) {
-            reply.error(libc::EACCES);
-            return;
+impl std::error::Error for FsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FsError::Io(err) => Some(err),
+            _ => None,
         }
+    }
+}
 
-        let ino = self.allocate_next_inode();
-        let mut attr = dir_attr(ino);
-        self.tree_fs.push(&parent, Item::new(ino, name.to_str().unwrap().to_string(), true, Some(attr)));
-        let parent_attr = parent.extra.as_mut().unwrap();
-
-        parent_attr.mtime = SystemTime::now();
-        parent_attr.ctime = SystemTime::now();
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirEntry {
+    pub ino: u64,
+    pub name: String,
+    pub kind: FileType,
+}
 
-        attr.size = BLOCK_SIZE;
-        attr.atime = SystemTime::now();
-        attr.mtime = SystemTime::now();
-        attr.ctime = SystemTime::now();
+#[derive(Debug)]
+pub enum PrereqError {
+    DevFuse(io::Error),
+    FusermountNotFound,
+    MissingCapSysAdmin,
+    UserAllowOtherDisabled,
+}
 
-        if req.uid() != 0 {
-            mode &= !(libc::S_ISUID | libc::S_ISGID) as u32;
+impl fmt::Display for PrereqError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PrereqError::DevFuse(err) => write!(f, "cannot open /dev/fuse for reading and writing: {err}"),
+            PrereqError::FusermountNotFound => write!(f, "fusermount3 or fusermount not found in PATH, install fuse3 or run as root"),
+            PrereqError::MissingCapSysAdmin => write!(f, "mounting requires CAP_SYS_ADMIN or a setuid fusermount"),
+            PrereqError::UserAllowOtherDisabled => write!(f, "allow_other requested but user_allow_other is not set in /etc/fuse.conf"),
         }
-        if parent_attr.perm & libc::S_ISGID as u16 != 0 {
-            mode |= libc::S_ISGID as u32;
-        }
-        attr.perm = self.creation_mode(mode);
+    }
+}
 
-        attr.uid = req.uid();
-        attr.gid = creation_gid(&parent_attr, req.gid());
+impl std::error::Error for PrereqError {}
+
+/// Invariant of the tree found broken by `MemFs::verify_consistency`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConsistencyError {
+    /// In the inode map but not reachable from the root.
+    Unreachable { ino: u64 },
+    /// The inode map has the node under another inode than its own.
+    InodeMismatch { key: u64, ino: u64 },
+    /// More entries in the tree than the inode has links.
+    DuplicateInode { ino: u64, entries: usize },
+    MissingAttr { ino: u64 },
+    /// `is_dir` doesn't agree with the kind in the attributes.
+    KindMismatch { ino: u64, kind: FileType },
+    NlinkMismatch { ino: u64, nlink: u32, expected: u32 },
+    DirSizeMismatch { ino: u64, size: u64, expected: u64 },
+    FileSizeMismatch { ino: u64, size: u64, data_len: u64 },
+    /// An inode above the counter, the next allocation could hand it out again.
+    InodeAboveCounter { ino: u64, current_inode: u64 },
+    /// The running count of used bytes doesn't match the inodes.
+    UsageMismatch { counted: u64, actual: u64 },
+    /// The running usage of a user, or a group with `is_group`, doesn't match the inodes it owns.
+    OwnerUsageMismatch { id: u32, is_group: bool, counted: UsageRecord, actual: UsageRecord },
+}
 
-        reply.entry(&Duration::new(0, 0), &attr, 0);
+impl fmt::Display for ConsistencyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConsistencyError::Unreachable { ino } => write!(f, "inode {ino} is not reachable from the root"),
+            ConsistencyError::InodeMismatch { key, ino } => write!(f, "inode {ino} is mapped as {key}"),
+            ConsistencyError::DuplicateInode { ino, entries } => write!(f, "inode {ino} has {entries} entries but fewer links"),
+            ConsistencyError::MissingAttr { ino } => write!(f, "inode {ino} has no attributes"),
+            ConsistencyError::KindMismatch { ino, kind } => write!(f, "inode {ino} is_dir doesn't match its kind {kind:?}"),
+            ConsistencyError::NlinkMismatch { ino, nlink, expected } => write!(f, "nlink of inode {ino} is {nlink}, expected {expected}"),
+            ConsistencyError::DirSizeMismatch { ino, size, expected } => write!(f, "directory {ino} has size {size}, expected {expected}"),
+            ConsistencyError::FileSizeMismatch { ino, size, data_len } => write!(f, "inode {ino} has size {size} but {data_len} bytes of data"),
+            ConsistencyError::InodeAboveCounter { ino, current_inode } => write!(f, "inode {ino} is above the inode counter {current_inode}"),
+            ConsistencyError::UsageMismatch { counted, actual } => write!(f, "{counted} bytes counted as used, the inodes take {actual}"),
+            ConsistencyError::OwnerUsageMismatch { id, is_group, counted, actual } => {
+                let owner = if *is_group { "group" } else { "user" };
+                write!(f, "usage of {owner} {id} counted as {counted:?}, the inodes take {actual:?}")
+            }
+        }
     }
+}
 
-    fn rename(
-        &mut self,
-        _req: &Request,
-        parent: u64,
-        name: &OsStr,
-        new_parent: u64,
-        new_name: &OsStr,
-        _flags: u32,
-        reply: ReplyEmpty,
-    ) {
-        debug!("rename() called with {:?} {:?} {:?} {:?}", parent, name, new_parent, new_name);
+impl std::error::Error for ConsistencyError {}
 
-        if parent != new_parent {
-            reply.error(ENOSYS);
-            return;
-        }
+/// Serves the contents of a virtual file instead of its data buffer.
+pub trait VfsHook: Send + Sync {
+    fn read(&mut self, offset: u64, size: u32) -> Vec<u8>;
+    fn write(&mut self, offset: u64, data: &[u8]) -> u32;
+}
 
-        let parent = match self.tree_fs.get_item_mut(parent) {
-            Some(parent) => parent,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
+/// Selects the files a `VfsHook` serves, either one absolute path or a range of inodes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InodePattern {
+    Path(String),
+    InodeRange(RangeInclusive<u64>),
+}
 
-        if parent.find_child_mut(new_name.to_str().unwrap()).is_some() {
-            reply.error(libc::EEXIST);
-            return;
-        }
+/// Computes the content of a virtual file, called on every read.
+pub type VirtualFileProvider = Box<dyn Fn() -> Vec<u8> + Send + Sync>;
 
-        let child = match parent.find_child_mut(name.to_str().unwrap()) {
-            Some(child) => child,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
+// the built-in ones need the filesystem, so they can't be plain providers
+enum VirtualFile {
+    Provider(VirtualFileProvider),
+    Statistics,
+    TreeDump,
+}
 
-        child.name = new_name.to_str().unwrap().to_string();
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OpenFileState {
+    pub ino: u64,
+    pub flags: i32,
+    pub read: bool,
+    pub write: bool,
+    /// Generation of the inode when it was opened, the handle is stale once it changes.
+    pub generation: u64,
+}
 
-        let parent_attr = parent.extra.as_mut().unwrap();
-        parent_attr.ctime = SystemTime::now();
-        parent_attr.mtime = SystemTime::now();
+/// Order of the entries returned by `readdir`, `.` and `..` always come first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DirEntryOrder {
+    #[default]
+    InsertionOrder,
+    ByName,
+    ByInode,
+    /// Oldest first.
+    ByMtime,
+}
 
-        let attr = child.extra.as_mut().unwrap();
-        attr.ctime = SystemTime::now();
-        attr.mtime = SystemTime::now();
+/// When `read` updates the access time, like the `strictatime`, `relatime` and `noatime` mount options.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AtimeMode {
+    /// On every read.
+    Strict,
+    /// Only when the access time is older than the last modification or change, or more than a day old.
+    #[default]
+    Relatime,
+    NoAtime,
+}
 
-        reply.ok();
+impl AtimeMode {
+    fn needs_update(&self, attr: &FileAttr, now: SystemTime) -> bool {
+        match self {
+            AtimeMode::Strict => true,
+            AtimeMode::Relatime => attr.atime < attr.mtime || attr.atime < attr.ctime
+                || now.duration_since(attr.atime).is_ok_and(|age| age > RELATIME_MAX_AGE),
+            AtimeMode::NoAtime => false,
+        }
     }
+}
 
-    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        debug!("unlink() called with {:?} {:?}", parent, name);
+/// What `fsync` does besides acknowledging the call, lets callers hook real persistence.
+pub enum SyncPolicy {
+    Noop,
+    /// Takes a `MemFs::snapshot`, kept in `last_snapshot`. Skipped for `fdatasync`.
+    TriggerSnapshot,
+    /// Called with the inode being synced.
+    Custom(Box<dyn Fn(u64) + Send + Sync>),
+}
 
-        match self.tree_fs.get_item_mut(parent) {
-            Some(parent) => {
-                if !parent.is_dir {
-                    reply.error(ENOENT);
-                    return;
-                }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaLimit {
+    pub max_bytes: u64,
+    pub max_files: u64,
+}
 
-                let child = parent.find_child_mut(name.to_str().unwrap());
-                match child {
-                    Some(child) => {
-                        let parent_attr = parent.extra.as_mut().unwrap();
-                        let attr = child.extra.as_mut().unwrap();
-
-                        let uid = req.uid();
-                        // "Sticky bit" handling
-                        if parent_attr.perm & libc::S_ISVTX as u16 != 0
-                            && uid != 0
-                            && uid != parent_attr.uid
-                            && uid != attr.uid
-                        {
-                            reply.error(libc::EACCES);
-                            return;
-                        }
-
-                        parent_attr.ctime = SystemTime::now();
-                        parent_attr.mtime = SystemTime::now();
-
-                        self.tree_fs.remove_child(parent, child);
-
-                        reply.ok();
-                    }
-                    None => reply.error(ENOENT)
-                }
-            }
-            _ => reply.error(ENOENT)
-        }
-    }
+/// Limits per owner, going over them fails with `EDQUOT`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QuotaConfig {
+    pub per_uid: HashMap<u32, QuotaLimit>,
+    pub per_gid: HashMap<u32, QuotaLimit>,
+}
 
-    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        debug!("rmdir() called with {:?} {:?}", parent, name);
+/// Space and inodes owned by a user or group, space counted in whole blocks like `st_blocks`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UsageRecord {
+    pub bytes: u64,
+    pub files: u64,
+}
 
-        match self.tree_fs.get_item_mut(parent) {
-            Some(parent) => {
-                let parent_attr = parent.extra.as_ref().unwrap();
-                if !check_access(
-                    parent_attr.uid,
-                    parent_attr.gid,
-                    parent_attr.perm,
-                    req.uid(),
-                    req.gid(),
-                    libc::W_OK,
-                ) {
-                    reply.error(libc::EACCES);
-                    return;
-                }
+/// Space and inodes in use, in total and per owner, kept up to date as inodes are added, removed, resized or change owner
+/// so checking space and quotas doesn't scan the tree.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Usage {
+    bytes: u64,
+    per_uid: HashMap<u32, UsageRecord>,
+    per_gid: HashMap<u32, UsageRecord>,
+}
 
-                match parent.find_child_mut(name.to_str().unwrap()) {
-                    Some(child) => {
-                        if !child.is_dir {
-                            reply.error(libc::EACCES);
-                            return;
-                        }
-                        if child.children().len() != 0 {
-                            reply.error(libc::ENOTEMPTY);
-                            return;
-                        }
-
-                        let parent_attr = parent.extra.as_mut().unwrap();
-                        let attrs = child.extra.as_mut().unwrap();
-
-                        // "Sticky bit" handling
-                        if parent_attr.perm & libc::S_ISVTX as u16 != 0
-                            && req.uid() != 0
-                            && req.uid() != parent_attr.uid
-                            && req.uid() != attrs.uid
-                        {
-                            reply.error(libc::EACCES);
-                            return;
-                        }
-
-                        parent_attr.ctime = SystemTime::now();
-                        parent_attr.mtime = SystemTime::now();
-
-                        self.tree_fs.remove_child(parent, child);
-
-                        reply.ok();
-                    }
-                    None => reply.error(ENOENT)
-                }
-            }
-            None => reply.error(ENOENT)
+impl Usage {
+    fn add(&mut self, uid: u32, gid: u32, bytes: u64, files: u64) {
+        self.bytes += bytes;
+        for record in [self.per_uid.entry(uid).or_default(), self.per_gid.entry(gid).or_default()] {
+            record.bytes += bytes;
+            record.files += files;
         }
     }
 
-    fn read(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        size: u32,
-        _flags: i32,
-        _lock: Option<u64>,
-        reply: ReplyData,
-    ) {
-        debug!("read {} {} {}", ino, offset, size);
-
-        match self.tree_fs.get_item_mut(ino) {
-            Some(item) => {
-                if item.is_dir {
-                    reply.error(ENOENT);
-                    return;
+    fn sub(&mut self, uid: u32, gid: u32, bytes: u64, files: u64) {
+        self.bytes = self.bytes.saturating_sub(bytes);
+        for (records, id) in [(&mut self.per_uid, uid), (&mut self.per_gid, gid)] {
+            if let Some(record) = records.get_mut(&id) {
+                record.bytes = record.bytes.saturating_sub(bytes);
+                record.files = record.files.saturating_sub(files);
+                if *record == UsageRecord::default() {
+                    records.remove(&id);
                 }
-
-                let read_size = min(size, item.data.as_ref().unwrap().len() as u32);
-                debug!("  read_size={}", read_size);
-                let mut buffer = vec![0; read_size as usize];
-                item.data.as_mut().unwrap().set_rpos(offset as usize);
-                let read_len = item.data.as_mut().unwrap().read(&mut buffer).unwrap();
-                debug!("  read_len={}", read_len);
-
-                reply.data(&buffer[..read_len]);
             }
-            None => reply.error(ENOENT),
         }
     }
+}
 
-    fn write(
-        &mut self,
-        _req: &Request,
-        inode: u64,
-        _fh: u64,
-        offset: i64,
-        data: &[u8],
-        _write_flags: u32,
-        #[allow(unused_variables)] flags: i32,
-        _lock_owner: Option<u64>,
-        reply: ReplyWrite,
-    ) {
-        debug!("write() called with {:?} size={:?}", inode, data.len());
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FsStatistics {
+    pub total_files: u64,
+    pub total_dirs: u64,
+    pub total_symlinks: u64,
+    /// Sum of the sizes of files and symlinks.
+    pub total_bytes_used: u64,
+    /// Memory taken by the allocated pages, data shared between files counted once.
+    pub total_bytes_allocated: u64,
+    /// Levels below the root, 0 for an empty filesystem.
+    pub max_depth: u32,
+    pub open_file_handles: u32,
+    pub active_locks: u32,
+}
 
-        assert!(offset >= 0);
+impl FsStatistics {
+    /// The statistics as one JSON object, keys named like the fields.
+    pub fn to_json(&self) -> String {
+        format!("{{\"total_files\":{},\"total_dirs\":{},\"total_symlinks\":{},\"total_bytes_used\":{},\"total_bytes_allocated\":{},\"max_depth\":{},\"open_file_handles\":{},\"active_locks\":{}}}",
+                self.total_files, self.total_dirs, self.total_symlinks, self.total_bytes_used,
+                self.total_bytes_allocated, self.max_depth, self.open_file_handles, self.active_locks)
+    }
+}
 
-        match self.tree_fs.get_item_mut(inode) {
-            Some(item) => {
-                if item.is_dir {
-                    reply.error(ENOENT);
-                    return;
-                }
+impl fmt::Display for FsStatistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "files {}, dirs {}, symlinks {}, bytes used {}, bytes allocated {}, max depth {}, open handles {}, locks {}",
+               self.total_files, self.total_dirs, self.total_symlinks, self.total_bytes_used,
+               self.total_bytes_allocated, self.max_depth, self.open_file_handles, self.active_locks)
+    }
+}
 
-                item.data.as_mut().unwrap().set_wpos(offset as usize);
-                let _ = item.data.as_mut().unwrap().write(data);
+// reads file content from `offset` on, holes read as zeros
+struct DataReader<'a> {
+    data: &'a FileData,
+    offset: u64,
+}
 
-                item.extra.as_mut().unwrap().mtime = SystemTime::now();
-                item.extra.as_mut().unwrap().ctime = SystemTime::now();
-                item.extra.as_mut().unwrap().size = item.data.as_mut().unwrap().len() as u64;
-                clear_suid_sgid(&mut item.extra.as_mut().unwrap());
+impl Read for DataReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.data.read(self.offset, buf);
+        self.offset += read as u64;
 
-                reply.written(data.len() as u32);
-            }
-            _ => reply.error(ENOENT)
-        }
+        Ok(read)
     }
+}
 
-    fn flush(&mut self, _req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
-        debug!("flush() called with {:?} {:?} {:?}", ino, fh, lock_owner);
+/// Controls what `MemFs::from_directory` copies.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImportOptions {
+    /// Copy what symlinks point to instead of the links themselves.
+    pub follow_symlinks: bool,
+    /// Levels of subdirectories to descend into, 0 only copies the entries of the source directory.
+    pub max_depth: usize,
+    /// Larger files are skipped.
+    pub max_file_size: u64,
+    /// The import fails with `EFBIG` once the files copied add up to more.
+    pub max_total_size: u64,
+}
 
-        reply.ok();
+impl Default for ImportOptions {
+    fn default() -> Self {
+        ImportOptions {
+            follow_symlinks: false,
+            max_depth: usize::MAX,
+            max_file_size: u64::MAX,
+            max_total_size: u64::MAX,
+        }
     }
+}
 
-    fn release(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _flags: i32, _lock_owner: Option<u64>, _flush: bool, reply: ReplyEmpty) {
-        debug!("release() called with {:?} {:?} {:?}", _ino, _fh, _lock_owner);
+/// What `MemFs::merge` does when a top level entry of the merged filesystem already exists at the destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MergeConflict {
+    /// The existing entry and everything under it is replaced.
+    Overwrite,
+    /// The existing entry is kept and the merged one dropped.
+    SkipExisting,
+    /// Nothing is merged and `FsError::AlreadyExists` is returned.
+    #[default]
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FileLock {
+    pub owner: u64,
+    pub pid: u32,
+    pub start: u64,
+    // inclusive
+    pub end: u64,
+    pub lock_type: i32,
+}
 
-        reply.ok();
+impl FileLock {
+    fn conflicts_with(&self, other: &FileLock) -> bool {
+        self.owner != other.owner
+            && self.start <= other.end && other.start <= self.end
+            && (self.lock_type == sys::F_WRLCK || other.lock_type == sys::F_WRLCK)
     }
+}
 
-    fn opendir(&mut self, req: &Request, inode: u64, flags: i32, reply: ReplyOpen) {
-        debug!("opendir() called on {:?}", inode);
+/// POSIX advisory byte-range locks, per inode.
+#[derive(Default)]
+pub struct LockTable {
+    locks: HashMap<u64, Vec<FileLock>>,
+}
 
-        let (access_mask, _read, _write) = match flags & libc::O_ACCMODE {
-            libc::O_RDONLY => {
-                // Behavior is undefined, but most filesystems return EACCES
-                if flags & libc::O_TRUNC != 0 {
-                    reply.error(libc::EACCES);
-                    return;
-                }
-                (libc::R_OK, true, false)
+impl LockTable {
+    pub fn conflict(&self, ino: u64, lock: &FileLock) -> Option<&FileLock> {
+        self.locks.get(&ino)?.iter().find(|held| held.conflicts_with(lock))
+    }
+
+    /// Sets or, with `F_UNLCK`, releases the range for the owner, replacing what it held there.
+    /// The caller must check for conflicts first.
+    pub fn set(&mut self, ino: u64, lock: FileLock) {
+        let locks = self.locks.entry(ino).or_default();
+        let mut kept = vec![];
+        for held in locks.drain(..) {
+            if held.owner != lock.owner || held.end < lock.start || lock.end < held.start {
+                kept.push(held);
+                continue;
             }
-            libc::O_WRONLY => (libc::W_OK, false, true),
-            libc::O_RDWR => (libc::R_OK | libc::W_OK, true, true),
-            // Exactly one access mode flag must be specified
-            _ => {
-                reply.error(libc::EINVAL);
-                return;
+            // keep the parts outside the new range
+            if held.start < lock.start {
+                kept.push(FileLock { end: lock.start - 1, ..held });
             }
-        };
-
-        match self.tree_fs.get_item_mut(inode) {
-            Some(item) => {
-                let attr = item.extra.as_ref().unwrap();
-                if check_access(
-                    attr.uid,
-                    attr.gid,
-                    attr.perm,
-                    req.uid(),
-                    req.gid(),
-                    access_mask,
-                ) {
-                    let open_flags = if self.direct_io { FOPEN_DIRECT_IO } else { 0 };
-                    reply.opened(self.allocate_next_file_handle(), open_flags);
-                }
+            if held.end > lock.end {
+                kept.push(FileLock { start: lock.end + 1, ..held });
             }
-            None => reply.error(ENOENT)
         }
-    }
-
+        if lock.lock_type != sys::F_UNLCK {
+            kept.push(lock);
+        }
 
-    fn readdir(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
-    ) {
-        debug!("readdir {} {} {}", ino, _fh, offset);
+        if kept.is_empty() {
+            self.locks.remove(&ino);
+        } else {
+            *locks = kept;
+        }
+    }
 
-        match self.tree_fs.get_item_mut(ino) {
-            Some(item) => {
-                if !item.is_dir {
-                    reply.error(ENOENT);
-                    return;
-                }
-                let mut entries = vec![
-                    (item.ino, FileType::Directory, "."),
-                ];
-                // root doesn't have parent
-                if item.get_parent().is_some() {
-                    entries.push((item.get_parent().unwrap().ino, FileType::Directory, ".."));
-                }
-                for item in item.children() {
-                    entries.push((item.ino, if item.is_dir { FileType::Directory } else { FileType::RegularFile }, &item.name));
-                }
+    /// Number of locked ranges held on all inodes.
+    pub fn len(&self) -> usize {
+        self.locks.values().map(|locks| locks.len()).sum()
+    }
 
-                for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
-                    if reply.add(entry.0, (i + 1) as i64, entry.1, entry.2) {
-                        break;
-                    }
-                }
+    pub fn is_empty(&self) -> bool {
+        self.locks.is_empty()
+    }
 
-                reply.ok();
+    pub fn release_owner(&mut self, ino: u64, owner: u64) {
+        if let Some(locks) = self.locks.get_mut(&ino) {
+            locks.retain(|lock| lock.owner != owner);
+            if locks.is_empty() {
+                self.locks.remove(&ino);
             }
-            None => reply.error(ENOENT),
         }
     }
+}
+
+/// `flock(2)` lock held through a file handle, shared locks count how many times they were taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlockState {
+    Shared(usize),
+    Exclusive,
+}
 
-    fn releasedir(
-        &mut self,
-        _req: &Request<'_>,
-        inode: u64,
-        _fh: u64,
-        _flags: i32,
-        reply: ReplyEmpty,
-    ) {
-        debug!("releasedir() called with {:?} {:?}", inode, _fh);
+// fuser doesn't pass FUSE_LK_FLOCK on, flock requests are told apart by always spanning the whole file
+const FLOCK_END: u64 = i64::MAX as u64;
 
-        match self.tree_fs.get_item_mut(inode) {
-            Some(_) => reply.ok(),
-            None => reply.error(ENOENT)
-        }
+fn is_flock(start: u64, end: u64) -> bool {
+    start == 0 && end == FLOCK_END
+}
+
+pub struct MemFs {
+    tree_fs: TreeFs<FileAttr>,
+    direct_io: bool,
+    suid_support: bool,
+    // atomics so allocating doesn't need a lock once the filesystem is shared
+    current_inode: Arc<AtomicU64>,
+    current_file_handle: Arc<AtomicU64>,
+    open_dirs: HashMap<u64, Vec<(u64, FileType, String)>>,
+    open_files: HashMap<u64, OpenFileState>,
+    max_dir_entries: usize,
+    dir_entry_order: DirEntryOrder,
+    entry_ttl: Duration,
+    attr_ttl: Duration,
+    dirty_inodes: HashSet<u64>,
+    // poll(2) events reported once by the next poll of the inode, on top of it always being readable and writable
+    pending_events: HashMap<u64, u32>,
+    dir_watchers: Vec<(u64, u32, Sender<DirEvent>)>,
+    watches: Vec<(u64, WatchMask, Sender<InotifyEvent>)>,
+    move_cookie: u32,
+    listeners: Vec<Box<dyn Fn(FsEvent) + Send + Sync>>,
+    stats_trigger: Option<&'static AtomicBool>,
+    page_size: u64,
+    vfs_hooks: Vec<(InodePattern, Box<dyn VfsHook>)>,
+    virtual_files: HashMap<u64, VirtualFile>,
+    max_xattr_size: usize,
+    locks: LockTable,
+    flock_table: HashMap<u64, FlockState>,
+    capacity_bytes: u64,
+    max_inodes: u64,
+    max_file_size: u64,
+    max_name_len: usize,
+    max_symlink_depth: u8,
+    quotas: QuotaConfig,
+    dedup: bool,
+    // content hash to the buffer files with that content share, a buffer that got written to no longer upgrades
+    content_map: HashMap<u128, Weak<FileData>>,
+    // freed inodes, taken before the counter grows. An inode the inode map hands out again stays in it and is skipped when popped
+    free_inodes: Arc<SegQueue<u64>>,
+    sync_policy: SyncPolicy,
+    atime_mode: AtimeMode,
+    last_snapshot: Option<MemFsSnapshot>,
+    audit_ring: Option<AuditLog>,
+    whiteouts: bool,
+    umask: u32,
+    block_size: u32,
+    // unique ids of requests the kernel asked to interrupt, dropped once the request is answered
+    interrupted: HashSet<u64>,
+    inode_map: Option<InodeMap>,
+    // behind a lock so `update_attr` can keep it current through `&self`
+    usage: Mutex<Usage>,
+    #[cfg(feature = "audit")]
+    audit_log: Option<BufWriter<File>>,
+}
+
+// the tree can be shared between threads, keep it that way for the filesystem as a whole
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<MemFs>();
+};
+
+#[derive(Default)]
+pub struct MemFsBuilder {
+    direct_io: bool,
+    suid: bool,
+    capacity: Option<u64>,
+    atime_mode: AtimeMode,
+    max_files: Option<usize>,
+    max_file_size: Option<u64>,
+    max_name_len: Option<usize>,
+    max_path_len: Option<usize>,
+    max_symlink_depth: Option<u8>,
+    root_uid: u32,
+    root_gid: u32,
+    root_mode: Option<u16>,
+    quotas: QuotaConfig,
+    preserve_ownership: bool,
+    dedup: bool,
+    entry_ttl: Duration,
+    attr_ttl: Duration,
+    audit_ring: Option<usize>,
+    case_insensitive: bool,
+    whiteouts: bool,
+    umask: Option<u32>,
+    block_size: Option<u32>,
+}
+
+impl MemFsBuilder {
+    pub fn direct_io(mut self, direct_io: bool) -> Self {
+        self.direct_io = direct_io;
+        self
     }
 
-    fn access(&mut self, req: &Request, inode: u64, mask: i32, reply: ReplyEmpty) {
-        debug!("access() called with {:?} {:?}", inode, mask);
+    /// Only takes effect with the `abi-7-26` feature.
+    pub fn suid(mut self, suid: bool) -> Self {
+        self.suid = suid;
+        self
+    }
 
-        match self.tree_fs.get_item_mut(inode) {
-            Some(item) => {
-                let attr = item.extra.as_ref().unwrap();
-                if check_access(attr.uid, attr.gid, attr.perm, req.uid(), req.gid(), mask) {
-                    reply.ok();
-                } else {
-                    reply.error(libc::EACCES);
-                }
-            }
-            None => reply.error(ENOENT),
-        }
+    /// Space files can take, writes beyond it fail with `ENOSPC`.
+    pub fn capacity(mut self, capacity: u64) -> Self {
+        self.capacity = Some(capacity);
+        self
     }
 
-    fn open(&mut self, req: &Request, inode: u64, flags: i32, reply: ReplyOpen) {
-        debug!("open() called for {:?}", inode);
+    /// Shorthand for `atime_mode(AtimeMode::NoAtime)`, `false` keeps the default `Relatime`.
+    pub fn noatime(mut self, noatime: bool) -> Self {
+        self.atime_mode = if noatime { AtimeMode::NoAtime } else { AtimeMode::default() };
+        self
+    }
 
-        let (access_mask, _read, _write) = match flags & libc::O_ACCMODE {
-            libc::O_RDONLY => {
-                // Behavior is undefined, but most filesystems return EACCES
-                if flags & libc::O_TRUNC != 0 {
-                    reply.error(libc::EACCES);
-                    return;
+    pub fn atime_mode(mut self, mode: AtimeMode) -> Self {
+        self.atime_mode = mode;
+        self
+    }
+
+    /// Number of inodes, the root included, creating more fails with `ENOSPC`.
+    pub fn max_files(mut self, max_files: usize) -> Self {
+        self.max_files = Some(max_files);
+        self
+    }
+
+    /// Growing a file past this fails with `EFBIG`.
+    pub fn max_file_size(mut self, max_file_size: u64) -> Self {
+        self.max_file_size = Some(max_file_size);
+        self
+    }
+
+    /// Keeps the last `capacity` FUSE operations in memory, see `MemFs::audit_ring`.
+    pub fn audit_ring(mut self, capacity: usize) -> Self {
+        self.audit_ring = Some(capacity);
+        self
+    }
+
+    /// With `false` names match ignoring ASCII case, like on macOS or Windows shares. Entries keep the case they were created with.
+    pub fn case_sensitive(mut self, case_sensitive: bool) -> Self {
+        self.case_insensitive = !case_sensitive;
+        self
+    }
+
+    /// Lets `rename` take `RENAME_WHITEOUT`, which overlayfs needs of its upper directory. Off by default, like on filesystems without whiteouts.
+    pub fn whiteouts(mut self, whiteouts: bool) -> Self {
+        self.whiteouts = whiteouts;
+        self
+    }
+
+    /// Permission bits cleared from the mode of nodes created through FUSE when the kernel doesn't send a umask. 0o022 by default.
+    pub fn umask(mut self, umask: u32) -> Self {
+        self.umask = Some(umask);
+        self
+    }
+
+    /// Size of the blocks files are stored in as reported by `stat` and `statfs`, rounded up to a multiple of 512. 4096 by default.
+    pub fn block_size(mut self, block_size: u32) -> Self {
+        self.block_size = Some(block_size);
+        self
+    }
+
+    /// Longest entry name, longer ones fail with `ENAMETOOLONG`. 255 by default.
+    pub fn max_name_len(mut self, max_name_len: usize) -> Self {
+        self.max_name_len = Some(max_name_len);
+        self
+    }
+
+    /// Longest path the programmatic API resolves, 4096 by default.
+    pub fn max_path_len(mut self, max_path_len: usize) -> Self {
+        self.max_path_len = Some(max_path_len);
+        self
+    }
+
+    /// Symlinks followed while resolving one path, more fail with `ELOOP`. 40 by default, like Linux.
+    pub fn max_symlink_depth(mut self, max_symlink_depth: u8) -> Self {
+        self.max_symlink_depth = Some(max_symlink_depth);
+        self
+    }
+
+    pub fn root_uid(mut self, uid: u32) -> Self {
+        self.root_uid = uid;
+        self
+    }
+
+    pub fn root_gid(mut self, gid: u32) -> Self {
+        self.root_gid = gid;
+        self
+    }
+
+    pub fn root_mode(mut self, mode: u16) -> Self {
+        self.root_mode = Some(mode);
+        self
+    }
+
+    pub fn quotas(mut self, quotas: QuotaConfig) -> Self {
+        self.quotas = quotas;
+        self
+    }
+
+    /// See `MemFs::set_entry_ttl`, 0 by default so every lookup reaches the filesystem.
+    pub fn entry_ttl(mut self, ttl: Duration) -> Self {
+        self.entry_ttl = ttl;
+        self
+    }
+
+    /// See `MemFs::set_attr_ttl`, 0 by default.
+    pub fn attr_ttl(mut self, ttl: Duration) -> Self {
+        self.attr_ttl = ttl;
+        self
+    }
+
+    /// Share the data of files with identical content as soon as `write` or `create_file` completes.
+    pub fn dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    /// Keep the owner stored in imported archives, otherwise entries belong to the owner of the root.
+    pub fn preserve_ownership(mut self, preserve_ownership: bool) -> Self {
+        self.preserve_ownership = preserve_ownership;
+        self
+    }
+
+    /// Builds the filesystem and fills it with a copy of the directory `src`, see `MemFs::from_directory`.
+    #[cfg(unix)]
+    pub fn import_from_directory(self, src: &Path, options: ImportOptions) -> io::Result<MemFs> {
+        let mut fs = self.build();
+        fs.import_directory(src, options)?;
+
+        Ok(fs)
+    }
+
+    /// Builds the filesystem and fills it with the entries of the tar archive read from `reader`.
+    pub fn import_from_tar(self, reader: impl Read) -> io::Result<MemFs> {
+        let owner = (!self.preserve_ownership).then_some((self.root_uid, self.root_gid));
+        let mut fs = self.build();
+        fs.import_tar(reader, owner)?;
+
+        Ok(fs)
+    }
+
+    /// Builds the filesystem starting from the tree in `snapshot`, the root options are then ignored. Fails like
+    /// `MemFs::restore_from_snapshot`.
+    pub fn build_from_snapshot(self, snapshot: &MemFsSnapshot) -> Result<MemFs, SnapshotError> {
+        let mut fs = self.build();
+        fs.restore_from_snapshot(snapshot)?;
+
+        Ok(fs)
+    }
+
+    pub fn build(self) -> MemFs {
+        let mut fs = MemFs {
+            tree_fs: TreeFs::new(),
+            direct_io: self.direct_io,
+            suid_support: cfg!(feature = "abi-7-26") && self.suid,
+            current_inode: Arc::new(AtomicU64::new(1)),
+            current_file_handle: Arc::new(AtomicU64::new(0)),
+            open_dirs: HashMap::new(),
+            open_files: HashMap::new(),
+            max_dir_entries: MAX_DIR_ENTRIES,
+            dir_entry_order: DirEntryOrder::InsertionOrder,
+            entry_ttl: self.entry_ttl,
+            attr_ttl: self.attr_ttl,
+            dirty_inodes: HashSet::new(),
+            pending_events: HashMap::new(),
+            dir_watchers: vec![],
+            watches: vec![],
+            move_cookie: 0,
+            listeners: vec![],
+            stats_trigger: None,
+            page_size: PAGE_SIZE,
+            vfs_hooks: vec![],
+            virtual_files: HashMap::new(),
+            max_xattr_size: MAX_XATTR_SIZE,
+            locks: LockTable::default(),
+            flock_table: HashMap::new(),
+            capacity_bytes: self.capacity.unwrap_or(u64::MAX),
+            max_inodes: self.max_files.map_or(MAX_INODES, |max_files| max_files as u64),
+            max_file_size: self.max_file_size.unwrap_or(u64::MAX),
+            max_name_len: self.max_name_len.unwrap_or(MAX_NAME_LENGTH as usize),
+            max_symlink_depth: self.max_symlink_depth.unwrap_or(MAX_SYMLINK_DEPTH),
+            quotas: self.quotas,
+            dedup: self.dedup,
+            content_map: HashMap::new(),
+            free_inodes: Arc::new(SegQueue::new()),
+            sync_policy: SyncPolicy::Noop,
+            atime_mode: self.atime_mode,
+            last_snapshot: None,
+            audit_ring: self.audit_ring.map(AuditLog::new),
+            whiteouts: self.whiteouts,
+            umask: self.umask.unwrap_or(DEFAULT_UMASK) & 0o777,
+            interrupted: HashSet::new(),
+            inode_map: None,
+            usage: Mutex::new(Usage::default()),
+            block_size: self.block_size.map_or(DEFAULT_BLOCK_SIZE, |block_size| block_size.max(1).next_multiple_of(STAT_BLOCK_SIZE as u32)),
+            #[cfg(feature = "audit")]
+            audit_log: None,
+        };
+        if let Some(max_path_len) = self.max_path_len {
+            fs.tree_fs.set_max_path_len(max_path_len);
+        }
+        fs.tree_fs.set_case_sensitive(!self.case_insensitive);
+
+        fs.ensure_root();
+        fs.update_attr(1, |attr| {
+            attr.uid = self.root_uid;
+            attr.gid = self.root_gid;
+            if let Some(mode) = self.root_mode {
+                attr.perm = mode;
+            }
+        });
+
+        fs
+    }
+}
+
+impl MemFs {
+    pub fn new(direct_io: bool, suid_support: bool) -> Self {
+        MemFsBuilder::default().direct_io(direct_io).suid(suid_support).build()
+    }
+
+    /// Limits the space files can take to `capacity` bytes, writes beyond it fail with `ENOSPC`.
+    pub fn new_with_capacity(direct_io: bool, suid_support: bool, capacity: u64) -> Self {
+        MemFsBuilder::default().direct_io(direct_io).suid(suid_support).capacity(capacity).build()
+    }
+
+    /// A filesystem to use as the upper directory of an overlayfs mount, `rename` leaves whiteouts with `RENAME_WHITEOUT`.
+    pub fn new_as_overlay_upper() -> Self {
+        MemFsBuilder::default().whiteouts(true).build()
+    }
+
+    /// Entries created at a path listed in the map saved at `path` get the inode they had there, new paths are added to it.
+    /// Removed paths stay in the map. It is saved on `fsync` and when the filesystem is dropped, like after unmounting.
+    /// Entries are only looked up when created, a renamed one keeps the inode of the path it was created at.
+    pub fn with_persistent_inode_map(path: &Path) -> io::Result<Self> {
+        let inode_map = InodeMap::load(path)?;
+        let mut fs = MemFsBuilder::default().build();
+        // new inodes come after the ones in the map
+        fs.current_inode.fetch_max(inode_map.max_inode(), Ordering::Relaxed);
+        fs.inode_map = Some(inode_map);
+
+        Ok(fs)
+    }
+
+    /// Whether `ino` is an overlayfs whiteout, a character device with device number 0/0.
+    pub fn is_whiteout(&self, ino: u64) -> bool {
+        self.attr_of(ino).is_some_and(|attr| attr.kind == FileType::CharDevice && attr.rdev == 0)
+    }
+
+    /// Writes the whole tree to `w`, including data, xattrs, hard links and the inode counter.
+    pub fn save(&mut self, mut w: impl Write) -> Result<(), SnapshotError> {
+        self.ensure_root();
+        w.write_all(MAGIC)?;
+        write_u32(&mut w, VERSION)?;
+        write_u8(&mut w, self.direct_io as u8)?;
+        write_u8(&mut w, self.suid_support as u8)?;
+        write_u64(&mut w, self.current_file_handle.load(Ordering::Relaxed))?;
+        self.snapshot().write(&mut w)?;
+        w.flush()?;
+
+        Ok(())
+    }
+
+    /// Reads a filesystem written by `save`, inodes keep their numbers and new ones continue after them.
+    pub fn load(mut r: impl Read) -> Result<Self, SnapshotError> {
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = read_u32(&mut r)?;
+        if !is_supported_version(version) {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let direct_io = read_u8(&mut r)? != 0;
+        let suid_support = read_u8(&mut r)? != 0;
+        let mut fs = MemFs::new(direct_io, suid_support);
+        fs.current_file_handle.store(read_u64(&mut r)?, Ordering::Relaxed);
+        let snapshot = MemFsSnapshot::read(&mut r, version)?;
+        fs.tree_fs = tree_from_snapshot(&snapshot, fs.tree_fs.empty_like())?;
+        fs.recount_usage();
+        fs.current_inode.store(snapshot.current_inode, Ordering::Relaxed);
+        // a well formed tree can still break an invariant, like a directory with the size of a file
+        fs.verify_consistency().map_err(|errors| SnapshotError::Corrupt(errors[0].to_string()))?;
+
+        Ok(fs)
+    }
+
+    /// Copies the whole tree, to compare it later or to go back to it with `restore_from_snapshot`.
+    pub fn snapshot(&self) -> MemFsSnapshot {
+        let mut entries = vec![];
+        let mut links = vec![];
+        let mut stack: Vec<_> = self.tree_fs.get_root().into_iter().map(|root| (0, root)).collect();
+        while let Some((parent, node)) = stack.pop() {
+            let item = node.value.read().unwrap();
+            if !self.tree_fs.get_item(item.ino).is_some_and(|primary| Arc::ptr_eq(&primary, &node)) {
+                links.push(SnapshotEntry::Link { parent, ino: item.ino, name: item.name.clone() });
+                continue;
+            }
+
+            entries.push(SnapshotEntry::Inode {
+                parent,
+                name: item.name.clone(),
+                is_dir: item.is_dir,
+                generation: item.generation,
+                attr: *item.extra.as_ref().unwrap(),
+                data: item.data.clone(),
+                xattrs: item.xattrs.clone(),
+            });
+            for child in node.children().into_iter().rev() {
+                stack.push((item.ino, child));
+            }
+        }
+        entries.extend(links);
+
+        MemFsSnapshot { entries, current_inode: self.current_inode.load(Ordering::Relaxed) }
+    }
+
+    /// Replaces the tree with the one in `snapshot`. Inodes allocated since it was taken are not handed out again.
+    /// An inconsistent snapshot leaves the tree as it was and returns `SnapshotError::Corrupt`. Virtual files, locks,
+    /// watches and pending poll events go with the inodes they were on.
+    pub fn restore_from_snapshot(&mut self, snapshot: &MemFsSnapshot) -> Result<(), SnapshotError> {
+        self.tree_fs = tree_from_snapshot(snapshot, self.tree_fs.empty_like())?;
+        self.recount_usage();
+        self.current_inode.fetch_max(snapshot.current_inode, Ordering::Relaxed);
+        while self.free_inodes.pop().is_some() {}
+        self.open_dirs.clear();
+        // state kept by inode number, the inodes it was for are gone or hold something else now
+        self.virtual_files.clear();
+        self.pending_events.clear();
+        self.locks = LockTable::default();
+        self.flock_table.clear();
+        self.dir_watchers.clear();
+        self.watches.clear();
+        // whatever the kernel cached may have changed
+        self.dirty_inodes = self.tree_fs.items().iter().map(|node| node.value.read().unwrap().ino).collect();
+
+        Ok(())
+    }
+
+    /// Space taken by all inodes, each rounded up to whole blocks.
+    pub fn total_used_bytes(&self) -> u64 {
+        self.usage.lock().unwrap().bytes
+    }
+
+    pub fn statistics(&self) -> FsStatistics {
+        let mut stats = FsStatistics {
+            open_file_handles: (self.open_files.len() + self.open_dirs.len()) as u32,
+            active_locks: self.locks.len() as u32,
+            ..FsStatistics::default()
+        };
+        let mut seen_inodes = HashSet::new();
+        let mut seen_data = HashSet::new();
+        for node in self.tree_fs.iter_dfs() {
+            let mut depth = 0;
+            let mut parent = node.get_parent();
+            while let Some(current) = parent {
+                depth += 1;
+                parent = current.get_parent();
+            }
+            stats.max_depth = stats.max_depth.max(depth);
+
+            // links visit the same inode again
+            let ino = node.value.read().unwrap().ino;
+            if !seen_inodes.insert(ino) {
+                continue;
+            }
+            let Some(primary) = self.tree_fs.get_item(ino) else { continue };
+            let item = primary.value.read().unwrap();
+            let attr = item.extra.as_ref().unwrap();
+            match attr.kind {
+                FileType::Directory => stats.total_dirs += 1,
+                FileType::Symlink => stats.total_symlinks += 1,
+                _ => stats.total_files += 1,
+            }
+            if attr.kind != FileType::Directory {
+                stats.total_bytes_used += attr.size;
+            }
+            if let Some(data) = item.data.as_ref().filter(|data| seen_data.insert(Arc::as_ptr(data))) {
+                stats.total_bytes_allocated += data.memory_footprint();
+            }
+        }
+
+        stats
+    }
+
+    /// Writes the tree like `TreeFs::serialize_text`, followed by the number of directories and files.
+    pub fn print_tree(&self, mut writer: impl Write) -> io::Result<()> {
+        if self.tree_fs.get_root().is_none() {
+            return Ok(());
+        }
+        self.tree_fs.serialize_text(&mut writer)?;
+        // the root isn't counted, like `tree(1)`
+        let (dirs, files) = self.tree_fs.iter_dfs().skip(1).fold((0, 0), |(dirs, files), node| {
+            if node.value.read().unwrap().is_dir { (dirs + 1, files) } else { (dirs, files + 1) }
+        });
+
+        writeln!(writer, "\n{} directories, {} files", dirs, files)
+    }
+
+    /// Writes the tree with file sizes and symlink targets, see `TreeFs::serialize_text`.
+    pub fn dump_tree(&self, writer: &mut impl Write) -> io::Result<()> {
+        self.tree_fs.serialize_text(writer)
+    }
+
+    /// Prints `statistics()` to stderr while handling the next request after `trigger` is set, then clears it.
+    pub fn set_stats_trigger(&mut self, trigger: &'static AtomicBool) {
+        self.stats_trigger = Some(trigger);
+    }
+
+    /// Compressed size of the stored file data over its uncompressed size, 1 when there is none.
+    #[cfg(feature = "compression")]
+    pub fn compression_ratio(&self) -> f64 {
+        let mut seen = HashSet::new();
+        let (mut compressed, mut uncompressed) = (0, 0);
+        for data in self.tree_fs.items().iter().filter_map(|node| node.value.read().unwrap().data.clone()) {
+            if seen.insert(Arc::as_ptr(&data)) {
+                compressed += data.memory_footprint();
+                uncompressed += data.uncompressed_footprint();
+            }
+        }
+        if uncompressed == 0 {
+            return 1.0;
+        }
+
+        compressed as f64 / uncompressed as f64
+    }
+
+    /// Memory taken by file data, data shared between files is counted once.
+    pub fn memory_footprint(&self) -> u64 {
+        let mut seen = HashSet::new();
+        self.tree_fs.items().iter()
+            .filter_map(|node| node.value.read().unwrap().data.clone())
+            .filter(|data| seen.insert(Arc::as_ptr(data)))
+            .map(|data| data.memory_footprint())
+            .sum()
+    }
+
+    fn check_space(&mut self, old_size: u64, new_size: u64) -> Result<(), c_int> {
+        // an offset past the largest off_t can't be addressed, like MAX_LFS_FILESIZE
+        if (new_size > self.max_file_size || new_size > i64::MAX as u64) && new_size > old_size {
+            return Err(sys::EFBIG);
+        }
+        if new_size <= old_size {
+            return Ok(());
+        }
+        let block_size = self.block_size as u64;
+        let needed = (new_size.div_ceil(block_size) - old_size.div_ceil(block_size)) * block_size;
+        // without a capacity the usage still has to fit its counter, sparse files can add up past it
+        if self.total_used_bytes().checked_add(needed).is_none_or(|used| used > self.capacity_bytes) {
+            return Err(sys::ENOSPC);
+        }
+
+        Ok(())
+    }
+
+    /// Limits what files owned by `uid` can take, existing files over it stay but can't grow.
+    pub fn set_quota(&mut self, uid: u32, limit: QuotaLimit) {
+        self.quotas.per_uid.insert(uid, limit);
+    }
+
+    pub fn set_group_quota(&mut self, gid: u32, limit: QuotaLimit) {
+        self.quotas.per_gid.insert(gid, limit);
+    }
+
+    pub fn get_usage(&self, uid: u32) -> UsageRecord {
+        self.usage.lock().unwrap().per_uid.get(&uid).copied().unwrap_or_default()
+    }
+
+    pub fn get_group_usage(&self, gid: u32) -> UsageRecord {
+        self.usage.lock().unwrap().per_gid.get(&gid).copied().unwrap_or_default()
+    }
+
+    // usage counted from the inodes, what the running count should match
+    fn scan_usage(&self) -> Usage {
+        let mut usage = Usage::default();
+        for node in self.tree_fs.items() {
+            let attr = *node.value.read().unwrap().extra.as_ref().unwrap();
+            usage.add(attr.uid, attr.gid, self.used_bytes(attr.size), 1);
+        }
+
+        usage
+    }
+
+    // after the whole tree was replaced
+    fn recount_usage(&mut self) {
+        *self.usage.get_mut().unwrap() = self.scan_usage();
+    }
+
+    fn used_bytes(&self, size: u64) -> u64 {
+        size.div_ceil(self.block_size as u64) * self.block_size as u64
+    }
+
+    // an inode with `attr` was added to the tree, or removed from it
+    fn charge_inode(&self, attr: &FileAttr, added: bool) {
+        let bytes = self.used_bytes(attr.size);
+        let mut usage = self.usage.lock().unwrap();
+        if added {
+            usage.add(attr.uid, attr.gid, bytes, 1);
+        } else {
+            usage.sub(attr.uid, attr.gid, bytes, 1);
+        }
+    }
+
+    // a file of `uid` and `gid` went from `old_size` to `new_size`
+    fn charge_resize(&self, uid: u32, gid: u32, old_size: u64, new_size: u64) {
+        let (old, new) = (self.used_bytes(old_size), self.used_bytes(new_size));
+        if old == new {
+            return;
+        }
+        let mut usage = self.usage.lock().unwrap();
+        usage.sub(uid, gid, old, 0);
+        usage.add(uid, gid, new, 0);
+    }
+
+    // an inode in the tree went from `old` to `new`
+    fn charge_attr_change(&self, old: &FileAttr, new: &FileAttr) {
+        if old.uid == new.uid && old.gid == new.gid {
+            self.charge_resize(new.uid, new.gid, old.size, new.size);
+        } else {
+            self.charge_inode(old, false);
+            self.charge_inode(new, true);
+        }
+    }
+
+    // charges the growth of a file from `old_size` to `new_size` and `new_files` inodes to the owner
+    fn check_quota(&self, uid: u32, gid: u32, old_size: u64, new_size: u64, new_files: u64) -> Result<(), c_int> {
+        let block_size = self.block_size as u64;
+        let needed = (new_size.div_ceil(block_size).saturating_sub(old_size.div_ceil(block_size))) * block_size;
+        if needed == 0 && new_files == 0 {
+            return Ok(());
+        }
+        let limits = [
+            (self.quotas.per_uid.get(&uid), uid, true),
+            (self.quotas.per_gid.get(&gid), gid, false),
+        ];
+        for (limit, id, is_uid) in limits {
+            let Some(limit) = limit else { continue };
+            let usage = if is_uid { self.get_usage(id) } else { self.get_group_usage(id) };
+            if (needed > 0 && usage.bytes.saturating_add(needed) > limit.max_bytes)
+                || (new_files > 0 && usage.files.saturating_add(new_files) > limit.max_files) {
+                return Err(sys::EDQUOT);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// How long the kernel may cache directory entries returned by `lookup`, `mknod`, `mkdir` and `create`.
+    pub fn set_entry_ttl(&mut self, ttl: Duration) {
+        self.entry_ttl = ttl;
+    }
+
+    /// Order of directory listings. Handles opened before the change keep the order they were opened with.
+    pub fn set_dir_entry_order(&mut self, order: DirEntryOrder) {
+        self.dir_entry_order = order;
+    }
+
+    /// How long the kernel may cache attributes returned by `getattr` and `setattr`.
+    pub fn set_attr_ttl(&mut self, ttl: Duration) {
+        self.attr_ttl = ttl;
+    }
+
+    // inodes changed since the kernel last asked for their attributes aren't cached until it does
+    fn attr_ttl_of(&self, ino: u64) -> Duration {
+        if self.dirty_inodes.contains(&ino) { Duration::ZERO } else { self.attr_ttl }
+    }
+
+    fn entry_ttl_of(&self, ino: u64) -> Duration {
+        if self.dirty_inodes.contains(&ino) { Duration::ZERO } else { self.entry_ttl }
+    }
+
+    fn generation_of(&self, ino: u64) -> u64 {
+        self.tree_fs.get_generation(ino).unwrap_or(0)
+    }
+
+    /// Gives `ino` a new generation, see `TreeFs::bump_generation`. Its open handles then fail with `ESTALE`.
+    pub fn bump_generation(&mut self, ino: u64) -> Option<u64> {
+        self.tree_fs.bump_generation(ino)
+    }
+
+    /// Stops `read` from updating the access time, like the `noatime` mount option. `false` goes back to `Relatime`.
+    pub fn set_noatime(&mut self, noatime: bool) {
+        self.atime_mode = if noatime { AtimeMode::NoAtime } else { AtimeMode::default() };
+    }
+
+    pub fn set_atime_mode(&mut self, mode: AtimeMode) {
+        self.atime_mode = mode;
+    }
+
+    /// Permission bits cleared from the mode of new nodes when the kernel sends no umask of its own.
+    pub fn set_umask(&mut self, umask: u32) {
+        self.umask = umask & 0o777;
+    }
+
+    /// Snapshot taken by the last `fsync` under `SyncPolicy::TriggerSnapshot`.
+    pub fn last_snapshot(&self) -> Option<&MemFsSnapshot> {
+        self.last_snapshot.as_ref()
+    }
+
+    pub fn set_sync_policy(&mut self, policy: SyncPolicy) {
+        self.sync_policy = policy;
+    }
+
+    /// Largest extended attribute value `setxattr` accepts, bigger ones get `ERANGE`.
+    pub fn set_max_xattr_size(&mut self, size: usize) {
+        self.max_xattr_size = size;
+    }
+
+    /// Granularity used to track dirty pages, anything but a power of two is `FsError::InvalidArgument`.
+    pub fn set_page_size(&mut self, page_size: u64) -> Result<(), FsError> {
+        if !page_size.is_power_of_two() {
+            return Err(FsError::InvalidArgument);
+        }
+        self.page_size = page_size;
+
+        Ok(())
+    }
+
+    pub fn page_size(&self) -> u64 {
+        self.page_size
+    }
+
+    /// Pages written since the last `fsync`, across all files.
+    pub fn dirty_page_count(&self) -> usize {
+        self.tree_fs.items().iter().map(|node| node.value.read().unwrap().dirty_pages.len()).sum()
+    }
+
+    pub fn dirty_pages_of(&self, ino: u64) -> usize {
+        self.tree_fs.get_item(ino).map_or(0, |node| node.value.read().unwrap().dirty_pages.len())
+    }
+
+    // the umask of the creating process when the kernel sends one, ours otherwise
+    fn creation_umask(&self, umask: u32) -> u32 {
+        if umask != 0 {
+            umask & 0o777
+        } else {
+            self.umask
+        }
+    }
+
+    fn creation_mode(&self, mode: u32) -> u16 {
+        // the kind is kept apart from the permission bits
+        let mode = mode & 0o7777;
+        if !self.suid_support {
+            (mode & !SETID_BITS) as u16
+        } else {
+            mode as u16
+        }
+    }
+
+    fn allocate_next_inode(&self) -> Result<u64, c_int> {
+        if self.tree_fs.len() as u64 >= self.max_inodes {
+            return Err(sys::ENOSPC);
+        }
+        // reuse a freed inode before growing the counter
+        while let Some(ino) = self.free_inodes.pop() {
+            if !self.inode_in_use(ino) {
+                return Ok(ino);
+            }
+        }
+        // stops at u64::MAX instead of wrapping
+        match self.current_inode.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |ino| ino.checked_add(1)) {
+            Ok(ino) => Ok(ino + 1),
+            Err(_) => Err(sys::ENOSPC),
+        }
+    }
+
+    fn inode_in_use(&self, ino: u64) -> bool {
+        self.tree_fs.get_item(ino).is_some() || self.open_files.values().any(|state| state.ino == ino)
+    }
+
+    /// Inode for the new entry `name` of `parent`, the one the persistent inode map has for its path when it is free.
+    fn allocate_inode_for(&mut self, parent: u64, name: &str) -> Result<u64, c_int> {
+        if self.inode_map.is_none() {
+            return self.allocate_next_inode();
+        }
+        let path = self.entry_path(parent, Some(OsStr::new(name)));
+        let stored = self.inode_map.as_ref().and_then(|map| map.get(&path));
+        // the inode may have gone to another path, or still be open after its entry was removed
+        if let Some(ino) = stored.filter(|ino| !self.inode_in_use(*ino)) {
+            if self.tree_fs.len() as u64 >= self.max_inodes {
+                return Err(sys::ENOSPC);
+            }
+            return Ok(ino);
+        }
+
+        let ino = loop {
+            let ino = self.allocate_next_inode()?;
+            // inodes of removed paths stay theirs
+            if !self.inode_map.as_ref().is_some_and(|map| map.is_reserved(ino)) {
+                break ino;
+            }
+        };
+        if let Some(map) = &mut self.inode_map {
+            map.insert(path, ino);
+        }
+
+        Ok(ino)
+    }
+
+    /// `flock` held through another handle on `ino` that `typ` can't be taken alongside, the inode is shared by all its handles.
+    fn flock_conflict(&self, ino: u64, fh: u64, typ: i32) -> Option<FlockState> {
+        if typ == sys::F_UNLCK {
+            return None;
+        }
+        self.flock_table.iter()
+            .filter(|(held_fh, _)| **held_fh != fh && self.open_files.get(held_fh).is_some_and(|file| file.ino == ino))
+            .map(|(_, state)| *state)
+            .find(|state| typ == sys::F_WRLCK || *state == FlockState::Exclusive)
+    }
+
+    /// Raw `system.posix_acl_access` of the inode, checked by `check_access` along with the mode.
+    fn acl_of(&self, ino: u64) -> Option<Vec<u8>> {
+        self.tree_fs.get_item(ino)?.value.read().unwrap().xattrs.get(ACL_ACCESS_XATTR).cloned()
+    }
+
+    fn inode_flags_of(&self, ino: u64) -> u32 {
+        self.tree_fs.get_item(ino).map_or(0, |node| node.value.read().unwrap().inode_flags)
+    }
+
+    /// `EPERM` when the inode flags forbid writing through `fh`: never for immutable inodes, append-only ones need `O_APPEND`.
+    fn check_inode_flags_write(&self, ino: u64, fh: u64) -> Result<(), c_int> {
+        let flags = self.inode_flags_of(ino);
+        let append = self.open_files.get(&fh).is_some_and(|file| file.flags & sys::O_APPEND != 0);
+        if flags & FS_IMMUTABLE_FL != 0 || (flags & FS_APPEND_FL != 0 && !append) {
+            return Err(sys::EPERM);
+        }
+
+        Ok(())
+    }
+
+    /// Returns `ino` to the free pool once no entry and no open handle refers to it anymore.
+    fn recycle_inode(&mut self, ino: u64) {
+        if ino == 1 || self.inode_in_use(ino) {
+            return;
+        }
+        self.free_inodes.push(ino);
+        self.dirty_inodes.remove(&ino);
+        self.virtual_files.remove(&ino);
+        self.pending_events.remove(&ino);
+        self.dir_watchers.retain(|(watched, _, _)| *watched != ino);
+    }
+
+    fn allocate_next_file_handle(&self) -> u64 {
+        self.current_file_handle.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Panics if a directory's `nlink` is not 2 plus the number of its subdirectories, meant for tests.
+    pub fn assert_nlink_consistent(&mut self) {
+        for node in self.tree_fs.items() {
+            let subdirs = node.children().iter().filter(|child| child.value.read().unwrap().is_dir).count() as u32;
+            let item = node.value.read().unwrap();
+            if !item.is_dir {
+                continue;
+            }
+            let nlink = item.extra.as_ref().unwrap().nlink;
+            assert_eq!(nlink, 2 + subdirs, "nlink of directory {} is {}, expected {}", item.ino, nlink, 2 + subdirs);
+        }
+    }
+
+    /// Checks the invariants of the tree and inode map, returning every violation found. Meant for tests and debugging.
+    pub fn verify_consistency(&self) -> Result<(), Vec<ConsistencyError>> {
+        let mut errors = vec![];
+
+        // entries per inode and subdirectories per directory, as reachable from the root
+        let mut entries: HashMap<u64, usize> = HashMap::new();
+        let mut subdirs: HashMap<u64, u32> = HashMap::new();
+        for node in self.tree_fs.iter_dfs() {
+            let (ino, is_dir) = {
+                let item = node.value.read().unwrap();
+                (item.ino, item.is_dir)
+            };
+            *entries.entry(ino).or_default() += 1;
+            if let (true, Some(parent)) = (is_dir, node.get_parent()) {
+                *subdirs.entry(parent.value.read().unwrap().ino).or_default() += 1;
+            }
+        }
+
+        for (key, node) in self.tree_fs.inodes() {
+            let item = node.value.read().unwrap();
+            let ino = item.ino;
+            if key != ino {
+                errors.push(ConsistencyError::InodeMismatch { key, ino });
+            }
+            let current_inode = self.current_inode.load(Ordering::Relaxed);
+            if ino > current_inode {
+                errors.push(ConsistencyError::InodeAboveCounter { ino, current_inode });
+            }
+            let count = entries.get(&key).copied().unwrap_or(0);
+            if count == 0 {
+                errors.push(ConsistencyError::Unreachable { ino: key });
+            } else if count > self.tree_fs.link_count(key) {
+                errors.push(ConsistencyError::DuplicateInode { ino: key, entries: count });
+            }
+
+            let Some(attr) = item.extra.as_ref() else {
+                errors.push(ConsistencyError::MissingAttr { ino });
+                continue;
+            };
+            if item.is_dir != (attr.kind == FileType::Directory) {
+                errors.push(ConsistencyError::KindMismatch { ino, kind: attr.kind });
+            }
+            let expected = if item.is_dir { 2 + subdirs.get(&key).copied().unwrap_or(0) } else { count as u32 };
+            if attr.nlink != expected {
+                errors.push(ConsistencyError::NlinkMismatch { ino, nlink: attr.nlink, expected });
+            }
+            if item.is_dir {
+                // a directory takes one block
+                if attr.size != attr.blksize as u64 {
+                    errors.push(ConsistencyError::DirSizeMismatch { ino, size: attr.size, expected: attr.blksize as u64 });
+                }
+            } else if matches!(attr.kind, FileType::RegularFile | FileType::Symlink) && attr.size != item.data_len() {
+                errors.push(ConsistencyError::FileSizeMismatch { ino, size: attr.size, data_len: item.data_len() });
+            }
+        }
+
+        let actual = self.scan_usage();
+        let counted = self.usage.lock().unwrap();
+        if counted.bytes != actual.bytes {
+            errors.push(ConsistencyError::UsageMismatch { counted: counted.bytes, actual: actual.bytes });
+        }
+        for (is_group, counted, actual) in [(false, &counted.per_uid, &actual.per_uid), (true, &counted.per_gid, &actual.per_gid)] {
+            for &id in counted.keys().chain(actual.keys()).collect::<BTreeSet<_>>() {
+                let (counted, actual) = (counted.get(&id).copied().unwrap_or_default(), actual.get(&id).copied().unwrap_or_default());
+                if counted != actual {
+                    errors.push(ConsistencyError::OwnerUsageMismatch { id, is_group, counted, actual });
+                }
+            }
+        }
+
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+
+    // name of a new entry, NUL can't be part of a name on Linux
+    fn check_name(&self, name: &OsStr) -> Result<(), c_int> {
+        if name.len() > self.max_name_len {
+            return Err(sys::ENAMETOOLONG);
+        }
+        if name.as_encoded_bytes().contains(&0) {
+            return Err(sys::EINVAL);
+        }
+
+        Ok(())
+    }
+
+    /// Creates the node `name` of the kind in `mode` in `parent` for a caller with `uid`, `gid` and the supplementary `groups`.
+    /// `umask` is already resolved with `creation_umask`.
+    #[allow(clippy::too_many_arguments)]
+    fn create_nod(&mut self, parent: u64, mut mode: u32, umask: u32, uid: u32, gid: u32, groups: &[u32], name: &OsStr) -> Result<FileAttr, FsError> {
+        self.check_name(name).map_err(FsError::from_errno)?;
+        let parent_node = self.tree_fs.get_item(parent).ok_or(FsError::NotFound)?;
+        if !parent_node.value.read().unwrap().is_dir {
+            return Err(FsError::NotFound);
+        }
+        if self.tree_fs.find_child(&parent_node, name.to_str().unwrap()).is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        // no lock on the parent is held from here, allocating the inode looks up its path
+        let parent_attr = self.attr_of(parent).ok_or(FsError::NotFound)?;
+        if !check_access(parent_attr.uid, parent_attr.gid, parent_attr.perm, self.acl_of(parent).as_deref(), uid, gid, groups, sys::W_OK) {
+            return Err(FsError::AccessDenied);
+        }
+        self.check_quota(uid, creation_gid(&parent_attr, gid), 0, 0, 1).map_err(FsError::from_errno)?;
+        let default_acl = parent_node.value.read().unwrap().xattrs.get(ACL_DEFAULT_XATTR).cloned();
+
+        if uid != 0 {
+            mode &= !SETID_BITS;
+        }
+        // a default ACL replaces the umask
+        if default_acl.is_none() {
+            mode &= !umask;
+        }
+
+        let kind = as_file_kind(mode);
+        let ino = self.allocate_inode_for(parent, name.to_str().unwrap()).map_err(FsError::from_errno)?;
+        let mut attr = if kind == FileType::Directory {
+            dir_attr(ino, self.block_size)
+        } else {
+            file_attr(ino, 0, self.block_size)
+        };
+        attr.kind = kind;
+        attr.perm = self.creation_mode(mode);
+        attr.uid = uid;
+        attr.gid = creation_gid(&parent_attr, gid);
+
+        let mut item = Item::new(ino, name.to_str().unwrap().to_string(), kind == FileType::Directory, Some(attr));
+        inherit_default_acl(&mut item, default_acl.as_deref());
+        let attr = *item.extra.as_ref().unwrap();
+        self.push_item(&parent_node, item)?;
+        let now = SystemTime::now();
+        self.update_attr(parent, |parent_attr| {
+            if kind == FileType::Directory {
+                parent_attr.nlink += 1;
+            }
+            parent_attr.mtime = now;
+            parent_attr.ctime = now;
+        });
+
+        self.notify_dir_watchers(parent, |fs| DirEvent::Created { path: fs.entry_path(parent, Some(name)), kind });
+        self.emit(|fs| FsEvent::Created { ino, path: fs.entry_path(parent, Some(name)), kind });
+
+        Ok(attr)
+    }
+
+    /// Creates the directory `name` in `parent` for a caller with `uid`, `gid` and the supplementary `groups`, what `mkdir` does.
+    /// `umask` is the one the kernel sent, 0 for none.
+    #[allow(clippy::too_many_arguments)]
+    fn make_dir(&mut self, parent: u64, name: &OsStr, mut mode: u32, umask: u32, uid: u32, gid: u32, groups: &[u32]) -> Result<FileAttr, c_int> {
+        self.check_name(name)?;
+        let parent_node = self.tree_fs.get_item(parent).ok_or(ENOENT)?;
+        if self.tree_fs.find_child(&parent_node, name.to_str().unwrap()).is_some() {
+            return Err(sys::EEXIST);
+        }
+
+        let parent_attr = self.attr_of(parent).ok_or(ENOENT)?;
+        if !check_access(parent_attr.uid, parent_attr.gid, parent_attr.perm, self.acl_of(parent).as_deref(), uid, gid, groups, sys::W_OK) {
+            return Err(sys::EACCES);
+        }
+        let new_gid = creation_gid(&parent_attr, gid);
+        self.check_quota(uid, new_gid, 0, 0, 1)?;
+        let ino = self.allocate_inode_for(parent, name.to_str().unwrap())?;
+
+        let mut attr = dir_attr(ino, self.block_size);
+        if uid != 0 {
+            mode &= !SETID_BITS;
+        }
+        let default_acl = parent_node.value.read().unwrap().xattrs.get(ACL_DEFAULT_XATTR).cloned();
+        // a default ACL replaces the umask
+        if default_acl.is_none() {
+            mode &= !self.creation_umask(umask);
+        }
+        if parent_attr.perm & sys::S_ISGID as u16 != 0 {
+            mode |= SETGID_BIT;
+        }
+        attr.perm = self.creation_mode(mode);
+        attr.uid = uid;
+        attr.gid = new_gid;
+
+        // push the final attributes so getattr sees the same values as the reply
+        let mut item = Item::new(ino, name.to_str().unwrap().to_string(), true, Some(attr));
+        inherit_default_acl(&mut item, default_acl.as_deref());
+        let attr = *item.extra.as_ref().unwrap();
+        self.push_item(&parent_node, item).map_err(|err| FsError::from(err).errno())?;
+        let now = SystemTime::now();
+        self.update_attr(parent, |parent_attr| {
+            // ".." of the new directory links to the parent
+            parent_attr.nlink += 1;
+            parent_attr.mtime = now;
+            parent_attr.ctime = now;
+        });
+
+        self.notify_dir_watchers(parent, |fs| DirEvent::Created { path: fs.entry_path(parent, Some(name)), kind: FileType::Directory });
+        self.emit(|fs| FsEvent::Created { ino, path: fs.entry_path(parent, Some(name)), kind: FileType::Directory });
+
+        Ok(attr)
+    }
+
+    /// `fallocate(2)` of `ino` with `mode` for a caller with `uid`, `gid` and the supplementary `groups`.
+    #[allow(clippy::too_many_arguments)]
+    fn allocate_range(&mut self, ino: u64, fh: u64, offset: i64, length: i64, mode: i32, uid: u32, gid: u32, groups: &[u32]) -> Result<(), c_int> {
+        let node = self.tree_fs.get_item(ino).ok_or(ENOENT)?;
+        let (attr, len) = {
+            let item = node.value.read().unwrap();
+            (*item.extra.as_ref().unwrap(), item.data_len())
+        };
+        match attr.kind {
+            FileType::RegularFile => {}
+            FileType::Directory => return Err(sys::EISDIR),
+            _ => return Err(sys::ENODEV),
+        }
+        if offset < 0 || length <= 0 {
+            return Err(sys::EINVAL);
+        }
+        self.check_inode_flags_write(ino, fh)?;
+        if !check_access(attr.uid, attr.gid, attr.perm, self.acl_of(ino).as_deref(), uid, gid, groups, sys::W_OK) {
+            return Err(sys::EACCES);
+        }
+
+        let (offset, end) = (offset as u64, offset as u64 + length as u64);
+        match mode {
+            0 => {
+                if end > len {
+                    self.check_space(len, end).and_then(|_| self.check_quota(attr.uid, attr.gid, len, end, 0))?;
+                    let mut item = node.value.write().unwrap();
+                    Arc::make_mut(item.data.as_mut().unwrap()).truncate(end);
+                    let attr = item.extra.as_mut().unwrap();
+                    attr.size = end;
+                    attr.blocks = block_count(end, self.block_size);
+                    attr.ctime = SystemTime::now();
+                    attr.mtime = SystemTime::now();
+                }
+            }
+            FALLOC_FL_KEEP_SIZE => {
+                // pages are allocated on write, only reserve the space
+                self.check_space(len, end).and_then(|_| self.check_quota(attr.uid, attr.gid, len, end, 0))?;
+            }
+            mode if mode == FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE => {
+                let end = min(end, len);
+                if offset < end {
+                    let mut item = node.value.write().unwrap();
+                    mark_data_pages_dirty(&mut item, offset, end, self.page_size);
+                    Arc::make_mut(item.data.as_mut().unwrap()).zero_range(offset, end - offset);
+                    let attr = item.extra.as_mut().unwrap();
+                    attr.ctime = SystemTime::now();
+                    attr.mtime = SystemTime::now();
+                }
+            }
+            mode if mode & !FALLOC_FL_KEEP_SIZE == FALLOC_FL_ZERO_RANGE => {
+                // with KEEP_SIZE the range past the end is only reserved, like a punched hole but allocated
+                let new_len = if mode & FALLOC_FL_KEEP_SIZE == 0 { len.max(end) } else { len };
+                self.check_space(len, end.max(len)).and_then(|_| self.check_quota(attr.uid, attr.gid, len, end.max(len), 0))?;
+                let mut item = node.value.write().unwrap();
+                // zeroing a hole changes nothing stored
+                mark_data_pages_dirty(&mut item, offset, end, self.page_size);
+                let data = Arc::make_mut(item.data.as_mut().unwrap());
+                data.zero_range(offset, length as u64);
+                if new_len > len {
+                    data.truncate(new_len);
+                }
+                let attr = item.extra.as_mut().unwrap();
+                attr.size = new_len;
+                attr.blocks = block_count(new_len, self.block_size);
+                attr.ctime = SystemTime::now();
+                attr.mtime = SystemTime::now();
+            }
+            FALLOC_FL_COLLAPSE_RANGE | FALLOC_FL_INSERT_RANGE => {
+                // like on ext4 the range is whole blocks and, for a collapse, ends before the end of the file
+                let block_size = self.block_size as u64;
+                if !offset.is_multiple_of(block_size) || !(length as u64).is_multiple_of(block_size)
+                    || (mode == FALLOC_FL_COLLAPSE_RANGE && end >= len)
+                    || (mode == FALLOC_FL_INSERT_RANGE && offset >= len) {
+                    return Err(sys::EINVAL);
+                }
+                if mode == FALLOC_FL_INSERT_RANGE {
+                    let new_len = len + length as u64;
+                    self.check_space(len, new_len).and_then(|_| self.check_quota(attr.uid, attr.gid, len, new_len, 0))?;
+                }
+
+                let mut item = node.value.write().unwrap();
+                // everything from offset on moved, pages that held data and those it moved to are dirty
+                mark_data_pages_dirty(&mut item, offset, len, self.page_size);
+                let data = Arc::make_mut(item.data.as_mut().unwrap());
+                if mode == FALLOC_FL_INSERT_RANGE {
+                    data.insert_at(offset, length as u64);
+                } else {
+                    data.remove_at(offset, length as u64);
+                }
+                let new_len = data.len();
+                mark_data_pages_dirty(&mut item, offset, new_len, self.page_size);
+                let attr = item.extra.as_mut().unwrap();
+                attr.size = new_len;
+                attr.blocks = block_count(new_len, self.block_size);
+                attr.ctime = SystemTime::now();
+                attr.mtime = SystemTime::now();
+            }
+            _ => return Err(sys::EOPNOTSUPP),
+        }
+        self.charge_resize(attr.uid, attr.gid, attr.size, self.attr_of(ino).unwrap().size);
+
+        self.notify_modified(ino);
+        self.emit(|_| FsEvent::AttrChanged { ino });
+
+        Ok(())
+    }
+
+    /// `setattr` of `ino` for a caller with `uid`, `gid` and the supplementary `groups`. A new mode is applied alone like
+    /// `chmod(2)`, then a new owner like `chown(2)`, otherwise the size and times. Returns the attributes it was left with.
+    #[allow(clippy::too_many_arguments)]
+    fn set_attr(&mut self, ino: u64, mode: Option<u32>, new_uid: Option<u32>, new_gid: Option<u32>, size: Option<u64>, atime: Option<TimeOrNow>,
+                mtime: Option<TimeOrNow>, _crtime: Option<SystemTime>, uid: u32, gid: u32, groups: &[u32]) -> Result<FileAttr, c_int> {
+        let node = self.tree_fs.get_item(ino).ok_or(ENOENT)?;
+        // changes are made on a copy and stored back at the end
+        let (mut attr, inode_flags) = {
+            let item = node.value.read().unwrap();
+            (*item.extra.as_ref().unwrap(), item.inode_flags)
+        };
+        if inode_flags & FS_IMMUTABLE_FL != 0 || (inode_flags & FS_APPEND_FL != 0 && size.is_some()) {
+            return Err(sys::EPERM);
+        }
+
+        if let Some(mode) = mode {
+            debug!("chmod() called with {:?}, {:o}", ino, mode);
+
+            if uid != 0 && uid != attr.uid {
+                return Err(sys::EPERM);
+            }
+            if uid != 0 && gid != attr.gid && !groups.contains(&attr.gid) {
+                // If SGID is set and the file belongs to a group that the caller is not part of
+                // then the SGID bit is suppose to be cleared during chmod
+                attr.perm = (mode & !SETGID_BIT) as u16;
+            } else {
+                attr.perm = mode as u16;
+            }
+            attr.ctime = SystemTime::now();
+            {
+                let mut item = node.value.write().unwrap();
+                item.extra = Some(attr);
+                // the owner, group (or mask) and other entries of the ACL follow the mode
+                if let Some(Ok(mut entries)) = item.xattrs.get(ACL_ACCESS_XATTR).map(|acl| parse_posix_acl(acl)) {
+                    acl_chmod(&mut entries, attr.perm);
+                    item.xattrs.insert(ACL_ACCESS_XATTR.to_string(), serialize_posix_acl(&entries));
+                }
+            }
+            self.emit(|_| FsEvent::AttrChanged { ino });
+            self.dirty_inodes.insert(ino);
+            return Ok(attr);
+        }
+
+        if new_uid.is_some() || new_gid.is_some() {
+            debug!("chown() called with {:?} {:?} {:?}", ino, new_uid, new_gid);
+
+            if let Some(new_gid) = new_gid {
+                // Non-root users can only change gid to a group they're in
+                if uid != 0 && !groups.contains(&new_gid) {
+                    return Err(sys::EPERM);
+                }
+            }
+            if let Some(new_uid) = new_uid {
+                if uid != 0
+                    // but no-op changes by the owner are not an error
+                    && !(new_uid == attr.uid && uid == attr.uid)
+                {
+                    return Err(sys::EPERM);
+                }
+            }
+            // Only owner may change the group
+            if new_gid.is_some() && uid != 0 && uid != attr.uid {
+                return Err(sys::EPERM);
+            }
+
+            if attr.perm & (sys::S_IXUSR | sys::S_IXGRP | sys::S_IXOTH) as u16 != 0 {
+                // SUID & SGID are suppose to be cleared when chown'ing an executable file
+                clear_suid_sgid(&mut attr);
+            }
+
+            if let Some(new_uid) = new_uid {
+                attr.uid = new_uid;
+                // Clear SETUID on owner change
+                attr.perm &= !sys::S_ISUID as u16;
+            }
+            if let Some(new_gid) = new_gid {
+                attr.gid = new_gid;
+                // Clear SETGID unless user is root
+                if uid != 0 {
+                    attr.perm &= !sys::S_ISGID as u16;
+                }
+            }
+            attr.ctime = SystemTime::now();
+            self.update_attr(ino, |stored| *stored = attr);
+            self.emit(|_| FsEvent::AttrChanged { ino });
+            self.dirty_inodes.insert(ino);
+            return Ok(attr);
+        }
+
+        if let Some(size) = size {
+            debug!("truncate() called with {:?} {:?}", ino, size);
+
+            attr = self.truncate_inode(ino, size)?;
+        }
+
+        for time in [atime, mtime].into_iter().flatten() {
+            // a time other than now needs to own the file, now needs write access
+            if attr.uid != uid && uid != 0 && time != TimeOrNow::Now {
+                return Err(sys::EPERM);
+            }
+            if attr.uid != uid && !check_access(attr.uid, attr.gid, attr.perm, self.acl_of(ino).as_deref(), uid, gid, groups, sys::W_OK) {
+                return Err(sys::EACCES);
+            }
+        }
+        let resolve = |time| match time {
+            TimeOrNow::SpecificTime(time) => time,
+            TimeOrNow::Now => SystemTime::now(),
+        };
+        if let Some(atime) = atime {
+            debug!("utimens() called with {:?}, atime={:?}", ino, atime);
+            attr.atime = resolve(atime);
+            attr.ctime = SystemTime::now();
+        }
+        if let Some(mtime) = mtime {
+            debug!("utimens() called with {:?}, mtime={:?}", ino, mtime);
+            attr.mtime = resolve(mtime);
+            attr.ctime = SystemTime::now();
+        }
+
+        // macFUSE sends the creation time, Linux doesn't expose it through the VFS
+        #[cfg(target_os = "macos")]
+        if let Some(crtime) = _crtime {
+            debug!("setattr() crtime={:?}", crtime);
+
+            // only root may change the creation time
+            if uid != 0 {
+                return Err(sys::EPERM);
+            }
+
+            attr.crtime = crtime;
+            attr.ctime = SystemTime::now();
+        }
+
+        node.value.write().unwrap().extra = Some(attr);
+        self.emit(|_| FsEvent::AttrChanged { ino });
+        self.dirty_inodes.insert(ino);
+
+        Ok(attr)
+    }
+
+    /// Sets the size of `ino`, growing it with zeros. Returns the attributes it was left with.
+    fn truncate_inode(&mut self, ino: u64, size: u64) -> Result<FileAttr, c_int> {
+        let node = self.tree_fs.get_item(ino).ok_or(ENOENT)?;
+        let old = self.attr_of(ino).ok_or(ENOENT)?;
+        match old.kind {
+            FileType::RegularFile => {}
+            FileType::Directory => return Err(sys::EISDIR),
+            _ => return Err(sys::EINVAL),
+        }
+        // EFBIG past the largest file size, ENOSPC past the capacity
+        self.check_space(old.size, size)?;
+        self.check_quota(old.uid, old.gid, old.size, size, 0)?;
+
+        let attr = {
+            let mut item = node.value.write().unwrap();
+            if size == 0 {
+                // no need to copy data shared with other files just to drop it
+                item.data = Some(Arc::new(FileData::new()));
+            } else {
+                // copy-on-write if the data is shared with other files, growing the file reads back as zeros
+                Arc::make_mut(item.data.get_or_insert_with(|| Arc::new(FileData::new()))).truncate(size);
+            }
+            item.dirty_pages.retain(|page| *page < size);
+
+            let attr = item.extra.as_mut().unwrap();
+            attr.size = size;
+            attr.blocks = block_count(size, self.block_size);
+            attr.ctime = SystemTime::now();
+            attr.mtime = SystemTime::now();
+            // Clear SETUID & SETGID on truncate
+            clear_suid_sgid(attr);
+            *attr
+        };
+        self.charge_resize(attr.uid, attr.gid, old.size, size);
+        self.notify_modified(ino);
+
+        Ok(attr)
+    }
+
+    /// Whether a caller with `uid`, `gid` and the supplementary `groups` may take an entry out of `parent`, or add one
+    /// with `entry_uid` of `None`. It needs write and search access and, when the sticky bit is set, to own the directory
+    /// or the entry.
+    fn check_entry_access(&self, parent: u64, entry_uid: Option<u32>, uid: u32, gid: u32, groups: &[u32]) -> Result<(), c_int> {
+        let parent_attr = self.attr_of(parent).ok_or(ENOENT)?;
+        if parent_attr.kind != FileType::Directory {
+            return Err(sys::ENOTDIR);
+        }
+        if !check_access(parent_attr.uid, parent_attr.gid, parent_attr.perm, self.acl_of(parent).as_deref(),
+                         uid, gid, groups, sys::W_OK | sys::X_OK) {
+            return Err(sys::EACCES);
+        }
+        // "Sticky bit" handling
+        if let Some(entry_uid) = entry_uid {
+            if parent_attr.perm & sys::S_ISVTX as u16 != 0
+                && uid != 0
+                && uid != parent_attr.uid
+                && uid != entry_uid
+            {
+                return Err(sys::EACCES);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Permission checks of `rename`: the source leaves its directory, an existing destination is replaced or exchanged.
+    #[allow(clippy::too_many_arguments)]
+    fn check_rename_access(&self, parent: u64, name: &OsStr, new_parent: u64, new_name: &OsStr, uid: u32, gid: u32, groups: &[u32]) -> Result<(), c_int> {
+        let owner = |dir: u64, name: &OsStr| {
+            let dir = self.tree_fs.get_item(dir)?;
+            let ino = self.tree_fs.find_child(&dir, name.to_str().unwrap())?.value.read().unwrap().ino;
+            self.attr_of(ino).map(|attr| attr.uid)
+        };
+
+        self.check_entry_access(parent, Some(owner(parent, name).ok_or(ENOENT)?), uid, gid, groups)?;
+        match owner(new_parent, new_name) {
+            Some(entry_uid) => self.check_entry_access(new_parent, Some(entry_uid), uid, gid, groups),
+            None if new_parent != parent => self.check_entry_access(new_parent, None, uid, gid, groups),
+            None => Ok(()),
+        }
+    }
+
+    /// Attributes of the entry `name` in `parent`, for a caller with `uid`, `gid` and the supplementary `groups`.
+    fn lookup_entry(&self, parent: u64, name: &OsStr, uid: u32, gid: u32, groups: &[u32]) -> Result<FileAttr, c_int> {
+        let parent_node = self.tree_fs.get_item(parent).ok_or(ENOENT)?;
+        let parent_attr = *parent_node.value.read().unwrap().extra.as_ref().unwrap();
+        if !check_access(parent_attr.uid, parent_attr.gid, parent_attr.perm, self.acl_of(parent).as_deref(), uid, gid, groups, sys::X_OK) {
+            return Err(sys::EACCES);
+        }
+
+        let child = self.tree_fs.find_child(&parent_node, name.to_str().unwrap()).ok_or(ENOENT)?;
+        // hard links share the attributes of the inode
+        let ino = child.value.read().unwrap().ino;
+
+        self.attr_of(ino).ok_or(ENOENT)
+    }
+
+    /// `access(2)` of `ino` for a caller with `uid`, `gid` and the supplementary `groups`.
+    fn access_inode(&self, ino: u64, mask: i32, uid: u32, gid: u32, groups: &[u32]) -> Result<(), c_int> {
+        let attr = self.attr_of(ino).ok_or(ENOENT)?;
+        // F_OK only asks whether the file exists
+        if mask == sys::F_OK {
+            return Ok(());
+        }
+        if !check_access(attr.uid, attr.gid, attr.perm, self.acl_of(ino).as_deref(), uid, gid, groups, mask) {
+            return Err(sys::EACCES);
+        }
+
+        Ok(())
+    }
+
+    /// Opens `ino` with the `open(2)` `flags` for a caller with `uid`, `gid` and the supplementary `groups`, truncating it for
+    /// `O_TRUNC`. Returns the new file handle.
+    fn open_file(&mut self, ino: u64, flags: i32, uid: u32, gid: u32, groups: &[u32]) -> Result<u64, c_int> {
+        let (access_mask, read, write) = match flags & sys::O_ACCMODE {
+            // Behavior is undefined, but most filesystems return EACCES
+            sys::O_RDONLY if flags & sys::O_TRUNC != 0 => return Err(sys::EACCES),
+            // Open is from internal exec syscall
+            sys::O_RDONLY if flags & FMODE_EXEC != 0 => (sys::X_OK, true, false),
+            sys::O_RDONLY => (sys::R_OK, true, false),
+            sys::O_WRONLY => (sys::W_OK, false, true),
+            sys::O_RDWR => (sys::R_OK | sys::W_OK, true, true),
+            // Exactly one access mode flag must be specified
+            _ => return Err(sys::EINVAL),
+        };
+
+        let attr = self.attr_of(ino).ok_or(ENOENT)?;
+        // the kernel handles both before sending open, these cover callers that don't
+        if flags & (sys::O_CREAT | sys::O_EXCL) == sys::O_CREAT | sys::O_EXCL {
+            return Err(sys::EEXIST);
+        }
+        if flags & sys::O_NOFOLLOW != 0 && attr.kind == FileType::Symlink {
+            return Err(sys::ELOOP);
+        }
+
+        let inode_flags = self.inode_flags_of(ino);
+        if (write || flags & sys::O_TRUNC != 0) && (inode_flags & FS_IMMUTABLE_FL != 0
+            || (inode_flags & FS_APPEND_FL != 0 && (flags & sys::O_APPEND == 0 || flags & sys::O_TRUNC != 0))) {
+            return Err(sys::EPERM);
+        }
+        if !check_access(attr.uid, attr.gid, attr.perm, self.acl_of(ino).as_deref(), uid, gid, groups, access_mask) {
+            return Err(sys::EACCES);
+        }
+
+        if flags & sys::O_TRUNC != 0 && attr.kind == FileType::RegularFile {
+            let node = self.tree_fs.get_item(ino).unwrap();
+            {
+                let mut item = node.value.write().unwrap();
+                item.data = Some(Arc::new(FileData::new()));
+                item.dirty_pages.clear();
+                let attr = item.extra.as_mut().unwrap();
+                attr.size = 0;
+                attr.blocks = 0;
+                attr.ctime = SystemTime::now();
+                attr.mtime = SystemTime::now();
+                clear_suid_sgid(attr);
+            }
+            self.charge_resize(attr.uid, attr.gid, attr.size, 0);
+            self.notify_modified(ino);
+        }
+
+        let fh = self.allocate_next_file_handle();
+        self.open_files.insert(fh, OpenFileState { ino, flags, read, write, generation: self.generation_of(ino) });
+
+        Ok(fh)
+    }
+
+    /// Reads up to `size` bytes at `offset` through the handle `fh` for a caller with `uid`, `gid` and the supplementary `groups`.
+    #[allow(clippy::too_many_arguments)]
+    fn read_data(&mut self, ino: u64, fh: u64, offset: u64, size: u32, uid: u32, gid: u32, groups: &[u32]) -> Result<Vec<u8>, c_int> {
+        self.check_file_handle(fh, ino, false)?;
+        let attr = self.attr_of(ino).ok_or(ENOENT)?;
+        if !check_access(attr.uid, attr.gid, attr.perm, self.acl_of(ino).as_deref(), uid, gid, groups, sys::R_OK) {
+            return Err(sys::EACCES);
+        }
+        // there is no driver behind device nodes
+        if matches!(attr.kind, FileType::CharDevice | FileType::BlockDevice) {
+            return Err(sys::ENXIO);
+        }
+        if self.is_virtual(ino) {
+            let content = self.virtual_content(ino).unwrap_or_default();
+            let start = min(offset as usize, content.len());
+            let end = min(start + size as usize, content.len());
+            return Ok(content[start..end].to_vec());
+        }
+        if let Some(hook) = self.find_vfs_hook(ino) {
+            return Ok(self.vfs_hooks[hook].1.read(offset, size));
+        }
+
+        let node = self.tree_fs.get_item(ino).ok_or(ENOENT)?;
+        let (is_dir, data) = {
+            let item = node.value.read().unwrap();
+            (item.is_dir, item.data.clone())
+        };
+        if is_dir {
+            return Err(ENOENT);
+        }
+
+        // read straight from the data, it might be shared with other files
+        let data = data.unwrap();
+        let mut buf = vec![0; min(size as u64, data.len().saturating_sub(offset)) as usize];
+        let read_len = data.read(offset, &mut buf);
+        debug!("  read_len={}", read_len);
+        buf.truncate(read_len);
+
+        let now = SystemTime::now();
+        let atime_mode = self.atime_mode;
+        let noatime = self.inode_flags_of(ino) & FS_NOATIME_FL != 0;
+        self.update_attr(ino, |attr| {
+            if !noatime && atime_mode.needs_update(attr, now) {
+                attr.atime = now;
+            }
+        });
+
+        Ok(buf)
+    }
+
+    /// Writes `data` at `offset` through the handle `fh` for a caller with `uid`, `gid` and the supplementary `groups`,
+    /// at the end of the file instead when the handle was opened with `O_APPEND`. Returns how many bytes were written.
+    #[allow(clippy::too_many_arguments)]
+    fn write_data(&mut self, ino: u64, fh: u64, offset: u64, data: &[u8], uid: u32, gid: u32, groups: &[u32]) -> Result<u32, c_int> {
+        self.check_file_handle(fh, ino, true).and_then(|_| self.check_inode_flags_write(ino, fh))?;
+        let attr = self.attr_of(ino).ok_or(ENOENT)?;
+        if !check_access(attr.uid, attr.gid, attr.perm, self.acl_of(ino).as_deref(), uid, gid, groups, sys::W_OK) {
+            return Err(sys::EACCES);
+        }
+        // there is no driver behind device nodes
+        if matches!(attr.kind, FileType::CharDevice | FileType::BlockDevice) {
+            return Err(sys::ENXIO);
+        }
+        if self.is_virtual(ino) {
+            return Err(sys::EPERM);
+        }
+        if let Some(hook) = self.find_vfs_hook(ino) {
+            return Ok(self.vfs_hooks[hook].1.write(offset, data));
+        }
+
+        let node = self.tree_fs.get_item(ino).ok_or(ENOENT)?;
+        let (is_dir, old_size) = {
+            let item = node.value.read().unwrap();
+            (item.is_dir, item.data_len())
+        };
+        if is_dir {
+            return Err(ENOENT);
+        }
+
+        // with O_APPEND every write goes to the end, whatever offset the kernel sent
+        let append = self.open_files[&fh].flags & sys::O_APPEND != 0;
+        let start = if append { old_size } else { offset };
+        self.check_space(old_size, start + data.len() as u64)
+            .and_then(|_| self.check_quota(attr.uid, attr.gid, old_size, start + data.len() as u64, 0))?;
+
+        let (written_at, old_size, size) = {
+            let mut item = node.value.write().unwrap();
+            let offset = if append { item.data_len() } else { offset };
+            // copy-on-write if the data is shared with other files
+            Arc::make_mut(item.data.as_mut().unwrap()).write(offset, data);
+            mark_pages_dirty(&mut item, offset, data.len() as u64, self.page_size);
+
+            let size = item.data_len();
+            let attr = item.extra.as_mut().unwrap();
+            let old_size = attr.size;
+            attr.mtime = SystemTime::now();
+            attr.ctime = SystemTime::now();
+            attr.size = size;
+            attr.blocks = block_count(size, self.block_size);
+            clear_suid_sgid(attr);
+
+            (offset, old_size, size)
+        };
+        self.charge_resize(attr.uid, attr.gid, old_size, size);
+        self.dedup_data(ino);
+
+        self.trigger_poll_event(ino, (sys::POLLOUT | sys::POLLPRI) as u32);
+        self.notify_modified(ino);
+        self.emit(|_| FsEvent::Written { ino, offset: written_at as i64, len: data.len() });
+
+        Ok(data.len() as u32)
+    }
+
+    /// New offset of the handle `fh` after `lseek(2)` with `whence`, `SEEK_DATA` and `SEEK_HOLE` included.
+    fn seek(&self, ino: u64, fh: u64, offset: i64, whence: i32) -> Result<i64, c_int> {
+        if self.open_files.get(&fh).is_none_or(|state| state.ino != ino) {
+            return Err(sys::EBADF);
+        }
+        let node = self.tree_fs.get_item(ino).ok_or(ENOENT)?;
+        if offset < 0 && whence != sys::SEEK_END {
+            return Err(sys::EINVAL);
+        }
+
+        let item = node.value.read().unwrap();
+        let data = item.data.as_ref();
+        match whence {
+            sys::SEEK_SET | sys::SEEK_CUR => Ok(offset),
+            // past the largest offset is EINVAL too, like vfs_setpos
+            sys::SEEK_END => match (item.data_len() as i64).checked_add(offset) {
+                Some(offset) if offset >= 0 => Ok(offset),
+                _ => Err(sys::EINVAL),
+            },
+            sys::SEEK_DATA => data.and_then(|data| data.next_data(offset as u64)).map(|offset| offset as i64).ok_or(sys::ENXIO),
+            sys::SEEK_HOLE => data.and_then(|data| data.next_hole(offset as u64)).map(|offset| offset as i64).ok_or(sys::ENXIO),
+            _ => Err(sys::EINVAL),
+        }
+    }
+
+    fn check_file_handle(&self, fh: u64, ino: u64, write: bool) -> Result<(), c_int> {
+        match self.open_files.get(&fh) {
+            Some(state) if state.ino != ino => Err(sys::EBADF),
+            Some(state) if state.generation != self.generation_of(ino) => Err(sys::ESTALE),
+            Some(state) if (write && !state.write) || (!write && !state.read) => Err(sys::EACCES),
+            Some(_) => Ok(()),
+            None => Err(sys::EBADF),
+        }
+    }
+
+    /// Attributes of the file open as `fh`, like `fstat`. Fails with `EBADF` for an unknown handle.
+    pub fn fstat(&self, fh: u64) -> Result<FileAttr, c_int> {
+        let state = self.open_files.get(&fh).ok_or(sys::EBADF)?;
+        self.attr_of(state.ino).ok_or(sys::EBADF)
+    }
+
+    /// Reads the entries of a directory, including `.` and `..`, in a single call.
+    /// It stops after `max_dir_entries`, `readdir` goes on past them from the live directory.
+    pub fn atomic_dir_snapshot(&self, ino: u64) -> Option<Vec<(u64, FileType, String)>> {
+        self.dir_entries(ino, self.max_dir_entries)
+    }
+
+    fn dir_entries(&self, ino: u64, limit: usize) -> Option<Vec<(u64, FileType, String)>> {
+        let node = self.tree_fs.get_item(ino)?;
+        if !node.value.read().unwrap().is_dir {
+            return None;
+        }
+
+        let mut entries = vec![
+            (ino, FileType::Directory, String::from(".")),
+        ];
+        // root doesn't have parent
+        if let Some(parent) = node.get_parent() {
+            entries.push((parent.value.read().unwrap().ino, FileType::Directory, String::from("..")));
+        }
+        let mut children = vec![];
+        for child in node.children() {
+            let (child_ino, name) = {
+                let child = child.value.read().unwrap();
+                (child.ino, child.name.clone())
+            };
+            let attr = self.attr_of(child_ino);
+            let kind = attr.map_or(FileType::RegularFile, |attr| attr.kind);
+            let mtime = attr.map_or(UNIX_EPOCH, |attr| attr.mtime);
+            children.push((child_ino, kind, name, mtime));
+        }
+        match self.dir_entry_order {
+            DirEntryOrder::InsertionOrder => {}
+            DirEntryOrder::ByName => children.sort_by(|a, b| a.2.cmp(&b.2)),
+            DirEntryOrder::ByInode => children.sort_by_key(|child| child.0),
+            DirEntryOrder::ByMtime => children.sort_by_key(|child| child.3),
+        }
+        let limit = limit.saturating_sub(entries.len());
+        entries.extend(children.into_iter().take(limit).map(|(ino, kind, name, _)| (ino, kind, name)));
+
+        Some(entries)
+    }
+
+    // entries of the directory open as `fh` following `cookie`, from the snapshot taken in opendir so entries don't
+    // shift between calls, or from the live directory without one and past the `max_dir_entries` it holds
+    fn dir_page(&self, ino: u64, fh: u64, cookie: i64) -> Option<Cow<'_, [(u64, FileType, String)]>> {
+        if let Some(snapshot) = self.open_dirs.get(&fh) {
+            let start = readdir_seek(snapshot, cookie);
+            if start < snapshot.len() || snapshot.len() < self.max_dir_entries {
+                return Some(Cow::Borrowed(&snapshot[start..]));
+            }
+        }
+        let mut live = self.dir_entries(ino, usize::MAX)?;
+        live.drain(..readdir_seek(&live, cookie));
+
+        Some(Cow::Owned(live))
+    }
+
+    /// The latest FUSE operations, `None` unless enabled with `MemFsBuilder::audit_ring`.
+    pub fn audit_ring(&self) -> Option<&AuditLog> {
+        self.audit_ring.as_ref()
+    }
+
+    /// Appends a tab-separated record of every operation to the file at `path`:
+    /// `timestamp uid gid pid op ino path errno`.
+    #[cfg(feature = "audit")]
+    pub fn audit_log(&mut self, path: &Path) -> io::Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.audit_log = Some(BufWriter::new(file));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "audit")]
+    #[allow(clippy::too_many_arguments)]
+    fn write_audit(&mut self, uid: u32, gid: u32, pid: u32, op: OpKind, ino: u64, name: Option<&OsStr>, errno: c_int) {
+        if self.audit_log.is_none() {
+            return;
+        }
+
+        let path = self.entry_path(ino, name);
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        let log = self.audit_log.as_mut().unwrap();
+        let res = writeln!(log, "{}.{:09}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                           timestamp.as_secs(), timestamp.subsec_nanos(),
+                           uid, gid, pid, op, ino, path, errno)
+            .and_then(|_| log.flush());
+        if let Err(err) = res {
+            warn!("cannot write audit log: {}", err);
+        }
+    }
+
+    // with dedup on, points the file to a buffer with the same content if another file has one, else offers its own
+    fn dedup_data(&mut self, ino: u64) {
+        if !self.dedup {
+            return;
+        }
+        let Some(node) = self.tree_fs.get_item(ino) else { return };
+        let mut item = node.value.write().unwrap();
+        let data = match item.data.as_mut() {
+            Some(data) if !data.is_empty() => data,
+            _ => return,
+        };
+
+        let hash = content_hash(data);
+        match self.content_map.get(&hash).and_then(Weak::upgrade) {
+            Some(shared) if Arc::ptr_eq(&shared, data) => {}
+            Some(shared) if *shared == **data => *data = shared,
+            _ => {
+                self.content_map.insert(hash, Arc::downgrade(data));
+            }
+        }
+    }
+
+    /// Marks the request with id `unique` as interrupted, for a `FUSE_INTERRUPT` naming it.
+    /// The kernel sends one when the process waiting on a request gets a signal. A request that would wait, like
+    /// `setlk` for `F_SETLKW`, checks for it and fails with `EINTR`, the others just finish. The mark goes with the reply.
+    /// fuser 0.14 answers `FUSE_INTERRUPT` with `ENOSYS` itself, so this is for sessions that forward them.
+    pub fn interrupt(&mut self, unique: u64) {
+        self.interrupted.insert(unique);
+    }
+
+    /// Adds `events` (`POLLIN`, `POLLPRI`, ...) to what the next `poll` of the inode reports.
+    pub fn trigger_poll_event(&mut self, ino: u64, events: u32) {
+        *self.pending_events.entry(ino).or_default() |= events;
+    }
+
+    /// Number of hard links to the inode, `0` if it doesn't exist.
+    pub fn hardlink_count(&self, ino: u64) -> u32 {
+        self.attr_of(ino).map_or(0, |attr| attr.nlink)
+    }
+
+    /// Full scan making files with identical content share the same data buffer, returns the number of bytes saved.
+    /// A write to any of them copies the buffer first. Also refreshes the buffers `MemFsBuilder::dedup` shares new
+    /// content with.
+    pub fn dedup_now(&mut self) -> usize {
+        let mut by_hash: HashMap<u128, Vec<Arc<FileData>>> = HashMap::new();
+        let mut saved = 0;
+
+        for node in self.tree_fs.items() {
+            let mut item = node.value.write().unwrap();
+            if item.is_dir {
+                continue;
+            }
+            let data = match item.data.as_mut() {
+                Some(data) if !data.is_empty() => data,
+                _ => continue,
+            };
+
+            let candidates = by_hash.entry(content_hash(data)).or_default();
+            match candidates.iter().find(|c| ***c == **data) {
+                Some(shared) => {
+                    if !Arc::ptr_eq(shared, data) {
+                        *data = shared.clone();
+                        saved += data.memory_footprint();
+                    }
+                }
+                None => candidates.push(data.clone()),
+            }
+        }
+        self.content_map = by_hash.into_iter()
+            .filter_map(|(hash, candidates)| Some((hash, Arc::downgrade(candidates.first()?))))
+            .collect();
+        debug!("dedup_now() saved {} bytes", saved);
+
+        saved as usize
+    }
+
+    /// Creates the file at `path` with `data` as content, its parent directory must exist.
+    pub fn create_file(&mut self, path: &str, data: &[u8], mode: u32, uid: u32, gid: u32) -> Result<u64, FsError> {
+        self.ensure_root();
+        let (parent, name) = split_path(path, self.max_name_len)?;
+        let parent = self.tree_fs.resolve_path(parent).ok_or(FsError::NotFound)?.value.read().unwrap().ino;
+        self.check_space(0, data.len() as u64).map_err(FsError::from_errno)?;
+        self.check_quota(uid, gid, 0, data.len() as u64, 1).map_err(FsError::from_errno)?;
+
+        let mut attr = file_attr(0, data.len() as u64, self.block_size);
+        attr.perm = (mode & 0o7777) as u16;
+        attr.uid = uid;
+        attr.gid = gid;
+        let ino = self.insert_item(parent, name, attr, Some(Arc::new(FileData::from_bytes(data)))).map_err(FsError::from_errno)?;
+        self.dedup_data(ino);
+
+        Ok(ino)
+    }
+
+    /// Creates the directory at `path` and any missing parent, like `mkdir -p`. Returns the inode of the last one.
+    pub fn create_dir_all(&mut self, path: &str, mode: u32, uid: u32, gid: u32) -> Result<u64, FsError> {
+        self.ensure_root();
+        let mut parent = 1;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            if name == "." || name == ".." || name.len() > self.max_name_len {
+                return Err(FsError::InvalidPath);
+            }
+            let existing = self.tree_fs.get_item(parent).and_then(|node| self.tree_fs.find_child(&node, name));
+            parent = match existing {
+                Some(node) => {
+                    let item = node.value.read().unwrap();
+                    if !item.is_dir {
+                        return Err(FsError::NotADirectory);
+                    }
+                    item.ino
+                }
+                None => {
+                    self.check_quota(uid, gid, 0, 0, 1).map_err(FsError::from_errno)?;
+                    let mut attr = dir_attr(0, self.block_size);
+                    attr.perm = (mode & 0o7777) as u16;
+                    attr.uid = uid;
+                    attr.gid = gid;
+                    self.insert_item(parent, name, attr, None).map_err(FsError::from_errno)?
+                }
+            };
+        }
+
+        Ok(parent)
+    }
+
+    /// Creates the named pipe at `path`, like `mkfifo`. Its ends are opened with `open_fifo_reader` and `open_fifo_writer`.
+    pub fn create_fifo(&mut self, path: &str, mode: u32, uid: u32, gid: u32) -> Result<u64, FsError> {
+        self.ensure_root();
+        let (parent, name) = split_path(path, self.max_name_len)?;
+        let parent = self.tree_fs.resolve_path(parent).ok_or(FsError::NotFound)?.value.read().unwrap().ino;
+        self.check_quota(uid, gid, 0, 0, 1).map_err(FsError::from_errno)?;
+
+        let mut attr = file_attr(0, 0, self.block_size);
+        attr.kind = FileType::NamedPipe;
+        attr.perm = (mode & 0o7777) as u16;
+        attr.uid = uid;
+        attr.gid = gid;
+        self.insert_item(parent, name, attr, Some(Arc::new(FileData::new()))).map_err(FsError::from_errno)
+    }
+
+    /// Opens the read end of the named pipe at `path`, the reader can be moved to another thread.
+    /// Only this API reaches the pipe, on a mount the kernel keeps FIFOs to itself.
+    pub fn open_fifo_reader(&self, path: &str) -> Result<PipeReader, FsError> {
+        Ok(self.fifo_buffer(path)?.reader())
+    }
+
+    /// Opens the write end of the named pipe at `path`, a reader must be open before writing.
+    pub fn open_fifo_writer(&self, path: &str) -> Result<PipeWriter, FsError> {
+        Ok(self.fifo_buffer(path)?.writer())
+    }
+
+    fn fifo_buffer(&self, path: &str) -> Result<Arc<PipeBuffer>, FsError> {
+        let ino = self.tree_fs.find_by_path(path).ok_or(FsError::NotFound)?;
+        // a hard link shares the buffer of the node holding the metadata
+        let node = self.tree_fs.get_item(ino).ok_or(FsError::NotFound)?;
+        let mut item = node.value.write().unwrap();
+        if item.extra.as_ref().unwrap().kind != FileType::NamedPipe {
+            return Err(FsError::InvalidArgument);
+        }
+
+        Ok(item.pipe.get_or_insert_with(Default::default).clone())
+    }
+
+    pub fn read_file(&self, path: &str) -> Result<Vec<u8>, FsError> {
+        let node = self.tree_fs.resolve_path(path).ok_or(FsError::NotFound)?;
+        let item = node.value.read().unwrap();
+        if item.is_dir {
+            return Err(FsError::IsADirectory);
+        }
+
+        Ok(item.data.as_ref().map_or(vec![], |data| data.to_vec()))
+    }
+
+    /// Absolute path of the inode, `/` for the root. `None` once it was removed, even while its number waits to be reused.
+    /// A hard linked inode answers with the entry holding its metadata.
+    pub fn path_of(&self, ino: u64) -> Option<String> {
+        self.tree_fs.path_of(ino)
+    }
+
+    /// Entries of the directory at `path`, without `.` and `..`.
+    pub fn list_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        let node = self.tree_fs.resolve_path(path).ok_or(FsError::NotFound)?;
+        if !node.value.read().unwrap().is_dir {
+            return Err(FsError::NotADirectory);
+        }
+
+        let mut entries = vec![];
+        for child in node.children() {
+            let (ino, name) = {
+                let child = child.value.read().unwrap();
+                (child.ino, child.name.clone())
+            };
+            let kind = self.attr_of(ino).map_or(FileType::RegularFile, |attr| attr.kind);
+            entries.push(DirEntry { ino, name, kind });
+        }
+
+        Ok(entries)
+    }
+
+    /// Attributes of the entry at `path`, symlinks not followed.
+    pub fn stat(&self, path: &str) -> Result<FileAttr, FsError> {
+        let ino = self.tree_fs.find_by_path(path).ok_or(FsError::NotFound)?;
+        self.attr_of(ino).ok_or(FsError::NotFound)
+    }
+
+    /// Removes the file, symlink or other non-directory entry at `path`.
+    pub fn remove_file(&mut self, path: &str) -> Result<(), FsError> {
+        let (parent, name) = self.resolve_entry(path)?;
+        if self.stat(path)?.kind == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+
+        self.remove_tree(parent, name).map_err(FsError::from_errno)
+    }
+
+    /// Removes the empty directory at `path`.
+    pub fn remove_dir(&mut self, path: &str) -> Result<(), FsError> {
+        let (parent, name) = self.resolve_entry(path)?;
+        let ino = self.tree_fs.find_by_path(path).ok_or(FsError::NotFound)?;
+        match self.tree_fs.is_dir_empty(ino) {
+            Some(true) => self.remove_tree(parent, name).map_err(FsError::from_errno),
+            Some(false) => Err(FsError::NotEmpty),
+            None => Err(FsError::NotADirectory),
+        }
+    }
+
+    /// Moves the entry at `src` to `dst`, whose parent directory must exist. Unlike `rename(2)` an existing `dst` is not replaced.
+    pub fn rename(&mut self, src: &str, dst: &str) -> Result<(), FsError> {
+        let (parent, name) = self.resolve_entry(src)?;
+        let (new_parent, new_name) = split_path(dst, self.max_name_len)?;
+        let new_parent = self.tree_fs.find_by_path(new_parent).ok_or(FsError::NotFound)?;
+
+        self.rename_entry(parent, OsStr::new(name), new_parent, OsStr::new(new_name), RENAME_NOREPLACE, 0, 0)
+            .map_err(FsError::from_errno)
+    }
+
+    /// Copies the content, mode and owner of the file at `src` to a new file at `dst`.
+    pub fn copy_file(&mut self, src: &str, dst: &str) -> Result<u64, FsError> {
+        let attr = self.stat(src)?;
+        if attr.kind == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        let data = self.read_file(src)?;
+
+        self.create_file(dst, &data, attr.perm as u32, attr.uid, attr.gid)
+    }
+
+    // parent inode and name of the entry at `path`
+    fn resolve_entry<'a>(&self, path: &'a str) -> Result<(u64, &'a str), FsError> {
+        let (parent, name) = split_path(path, self.max_name_len)?;
+        let parent = self.tree_fs.find_by_path(parent).ok_or(FsError::NotFound)?;
+        if self.tree_fs.get_item(parent).and_then(|node| self.tree_fs.find_child(&node, name)).is_none() {
+            return Err(FsError::NotFound);
+        }
+
+        Ok((parent, name))
+    }
+
+    /// Moves the content of the root of `other` into the directory at `dest_path`, created with the mode and owner of that root if missing.
+    /// Entries get new inodes of this filesystem, file data is moved and hard links inside `other` stay links.
+    pub fn merge(&mut self, mut other: MemFs, dest_path: &str, on_conflict: MergeConflict) -> Result<(), FsError> {
+        self.ensure_root();
+        let Some(other_root) = other.tree_fs.get_root() else {
+            return self.create_dir_all(dest_path, 0o755, 0, 0).map(|_| ());
+        };
+        let root_attr = *other_root.value.read().unwrap().extra.as_ref().unwrap();
+        if self.tree_fs.resolve_path(dest_path).is_none() {
+            self.create_dir_all(dest_path, root_attr.perm as u32, root_attr.uid, root_attr.gid)?;
+        }
+        let dest_node = self.tree_fs.resolve_path(dest_path).ok_or(FsError::NotFound)?;
+        let dest = {
+            let dest = dest_node.value.read().unwrap();
+            if !dest.is_dir {
+                return Err(FsError::NotADirectory);
+            }
+            dest.ino
+        };
+
+        let mut merged = vec![];
+        for child in other_root.children() {
+            let name = child.value.read().unwrap().name.clone();
+            match self.tree_fs.find_child(&dest_node, &name) {
+                None => merged.push((child, false)),
+                Some(_) => match on_conflict {
+                    MergeConflict::Overwrite => merged.push((child, true)),
+                    MergeConflict::SkipExisting => {}
+                    MergeConflict::Error => return Err(FsError::AlreadyExists),
+                },
+            }
+        }
+        if self.capacity_bytes != u64::MAX
+            && self.total_used_bytes().saturating_add(other.total_used_bytes()) > self.capacity_bytes {
+            return Err(FsError::NoSpace);
+        }
+
+        let mut inodes = HashMap::new();
+        for (child, replace) in merged {
+            if replace {
+                let name = child.value.read().unwrap().name.clone();
+                self.remove_tree(dest, &name).map_err(FsError::from_errno)?;
+            }
+            self.graft(dest, &child, &other.tree_fs, &mut inodes)?;
+        }
+        other.tree_fs = other.tree_fs.empty_like();
+
+        Ok(())
+    }
+
+    // adds `node` of another tree and everything under it to `parent`, `inodes` maps the inodes of that tree to the new ones
+    fn graft(&mut self, parent: u64, node: &ItemNode<FileAttr>, other: &TreeFs<FileAttr>, inodes: &mut HashMap<u64, u64>) -> Result<(), FsError> {
+        let (old_ino, name) = {
+            let item = node.value.read().unwrap();
+            (item.ino, item.name.clone())
+        };
+        if let Some(&ino) = inodes.get(&old_ino) {
+            let parent_node = self.tree_fs.get_item(parent).ok_or(FsError::NotFound)?;
+            if self.tree_fs.find_child(&parent_node, &name).is_some() {
+                return Err(FsError::AlreadyExists);
+            }
+            let attr = self.attr_of(ino).ok_or(FsError::NotFound)?;
+            let mut link = Item::new(ino, name, false, Some(attr));
+            link.data = None;
+            self.tree_fs.push_link(&parent_node, link)?;
+            self.update_attr(ino, |attr| attr.nlink += 1);
+            return Ok(());
+        }
+
+        // the first entry met can be a link, the metadata and data are on the entry the inode maps to
+        let holder = other.get_item(old_ino).ok_or(FsError::NotFound)?;
+        let (mut attr, data, xattrs) = {
+            let mut item = holder.value.write().unwrap();
+            (*item.extra.as_ref().unwrap(), item.data.take(), std::mem::take(&mut item.xattrs))
+        };
+        // links and subdirectories count again as they are added
+        attr.nlink = if attr.kind == FileType::Directory { 2 } else { 1 };
+        let ino = self.insert_item(parent, &name, attr, data).map_err(FsError::from_errno)?;
+        inodes.insert(old_ino, ino);
+        if let Some(node) = self.tree_fs.get_item(ino) {
+            node.value.write().unwrap().xattrs = xattrs;
+        }
+        if attr.kind == FileType::RegularFile {
+            self.dedup_data(ino);
+        }
+
+        for child in node.children() {
+            self.graft(ino, &child, other, inodes)?;
+        }
+
+        Ok(())
+    }
+
+    // removes the entry `name` of `parent`, with everything under it for a directory
+    fn remove_tree(&mut self, parent: u64, name: &str) -> Result<(), c_int> {
+        let parent_node = self.tree_fs.get_item(parent).ok_or(ENOENT)?;
+        let child = self.tree_fs.find_child(&parent_node, name).ok_or(ENOENT)?;
+        let (ino, is_dir) = {
+            let item = child.value.read().unwrap();
+            (item.ino, item.is_dir)
+        };
+        if is_dir {
+            for grandchild in child.children() {
+                let name = grandchild.value.read().unwrap().name.clone();
+                self.remove_tree(ino, &name)?;
+            }
+            self.update_attr(parent, |attr| attr.nlink -= 1);
+        } else {
+            self.update_attr(ino, |attr| attr.nlink = attr.nlink.saturating_sub(1));
+        }
+        self.update_attr(parent, |attr| {
+            attr.ctime = SystemTime::now();
+            attr.mtime = SystemTime::now();
+        });
+
+        self.remove_entry(&parent_node, &child).map_err(|err| FsError::from(err).errno())?;
+        self.recycle_inode(ino);
+
+        let name = OsStr::new(name);
+        self.notify_dir_watchers(parent, |fs| DirEvent::Deleted { path: fs.entry_path(parent, Some(name)) });
+        self.emit(|fs| FsEvent::Deleted { ino, path: fs.entry_path(parent, Some(name)) });
+
+        Ok(())
+    }
+
+    /// `unlink(2)` of the entry `name` of `parent` for a caller with `uid`, `gid` and the supplementary `groups`. The inode
+    /// stays while other links or open handles refer to it.
+    fn unlink_entry(&mut self, parent: u64, name: &OsStr, uid: u32, gid: u32, groups: &[u32]) -> Result<(), c_int> {
+        let parent_node = self.tree_fs.get_item(parent).ok_or(ENOENT)?;
+        if !parent_node.value.read().unwrap().is_dir {
+            return Err(ENOENT);
+        }
+        let child = self.tree_fs.find_child(&parent_node, name.to_str().unwrap()).ok_or(ENOENT)?;
+        let child_ino = child.value.read().unwrap().ino;
+        let attr = self.attr_of(child_ino).ok_or(ENOENT)?;
+        self.check_entry_access(parent, Some(attr.uid), uid, gid, groups)?;
+        // the kernel sends rmdir for directories, this covers callers that don't
+        if attr.kind == FileType::Directory {
+            return Err(sys::EISDIR);
+        }
+        if self.inode_flags_of(child_ino) & (FS_IMMUTABLE_FL | FS_APPEND_FL) != 0 {
+            return Err(sys::EPERM);
+        }
+
+        self.remove_entry(&parent_node, &child).map_err(|err| FsError::from(err).errno())?;
+        self.update_attr(parent, |parent_attr| {
+            parent_attr.ctime = SystemTime::now();
+            parent_attr.mtime = SystemTime::now();
+        });
+        // a remaining link took over the attributes, with none left this finds nothing
+        self.update_attr(child_ino, |attr| {
+            attr.nlink = attr.nlink.saturating_sub(1);
+            attr.ctime = SystemTime::now();
+        });
+        self.recycle_inode(child_ino);
+
+        self.notify_dir_watchers(parent, |fs| DirEvent::Deleted { path: fs.entry_path(parent, Some(name)) });
+        self.emit(|fs| FsEvent::Deleted { ino: child_ino, path: fs.entry_path(parent, Some(name)) });
+
+        Ok(())
+    }
+
+    /// `rmdir(2)` of the empty directory `name` of `parent` for a caller with `uid`, `gid` and the supplementary `groups`.
+    fn rmdir_entry(&mut self, parent: u64, name: &OsStr, uid: u32, gid: u32, groups: &[u32]) -> Result<(), c_int> {
+        let parent_node = self.tree_fs.get_item(parent).ok_or(ENOENT)?;
+        let child = self.tree_fs.find_child(&parent_node, name.to_str().unwrap()).ok_or(ENOENT)?;
+        let (child_ino, child_is_dir, attr) = {
+            let child = child.value.read().unwrap();
+            (child.ino, child.is_dir, *child.extra.as_ref().unwrap())
+        };
+        self.check_entry_access(parent, Some(attr.uid), uid, gid, groups)?;
+        if !child_is_dir {
+            return Err(sys::EACCES);
+        }
+        if self.tree_fs.is_dir_empty(child_ino) != Some(true) {
+            return Err(sys::ENOTEMPTY);
+        }
+
+        self.remove_entry(&parent_node, &child).map_err(|err| FsError::from(err).errno())?;
+        self.update_attr(parent, |parent_attr| {
+            parent_attr.ctime = SystemTime::now();
+            parent_attr.mtime = SystemTime::now();
+            // the child's ".." no longer links to the parent
+            parent_attr.nlink -= 1;
+        });
+        self.recycle_inode(child_ino);
+
+        self.notify_dir_watchers(parent, |fs| DirEvent::Deleted { path: fs.entry_path(parent, Some(name)) });
+        self.emit(|fs| FsEvent::Deleted { ino: child_ino, path: fs.entry_path(parent, Some(name)) });
+
+        Ok(())
+    }
+
+    /// Moves the entry `name` of `parent` to `new_name` in `new_parent`, replacing what is there like `rename(2)` with
+    /// `RENAME_NOREPLACE`, `RENAME_EXCHANGE` or `RENAME_WHITEOUT` in `flags`. A whiteout belongs to `uid` and `gid`.
+    #[allow(clippy::too_many_arguments)]
+    fn rename_entry(&mut self, parent: u64, name: &OsStr, new_parent: u64, new_name: &OsStr, flags: u32, uid: u32, gid: u32) -> Result<(), c_int> {
+        let err = if flags & !(RENAME_NOREPLACE | RENAME_EXCHANGE | RENAME_WHITEOUT) != 0
+            || (flags & RENAME_EXCHANGE != 0 && flags != RENAME_EXCHANGE)
+            || (flags & RENAME_WHITEOUT != 0 && !self.whiteouts) {
+            sys::EINVAL
+        } else if flags & RENAME_WHITEOUT != 0 && self.tree_fs.len() as u64 >= self.max_inodes {
+            // the whiteout needs an inode, find out before anything moves
+            sys::ENOSPC
+        } else {
+            self.check_name(new_name).err().unwrap_or(0)
+        };
+        if err != 0 {
+            return Err(err);
+        }
+        if flags == RENAME_EXCHANGE {
+            return self.exchange_entries(parent, name, new_parent, new_name);
+        }
+
+        let parent_node = self.tree_fs.get_item(parent).ok_or(ENOENT)?;
+        let new_parent_node = self.tree_fs.get_item(new_parent).ok_or(ENOENT)?;
+        if !new_parent_node.value.read().unwrap().is_dir {
+            return Err(sys::ENOTDIR);
+        }
+        let child_node = self.tree_fs.find_child(&parent_node, name.to_str().unwrap()).ok_or(ENOENT)?;
+        let (child_ino, child_is_dir) = {
+            let item = child_node.value.read().unwrap();
+            (item.ino, item.is_dir)
+        };
+
+        // a directory can't be moved inside itself
+        if child_is_dir {
+            let mut current = Some(new_parent_node.clone());
+            while let Some(node) = current {
+                if node.value.read().unwrap().ino == child_ino {
+                    return Err(sys::EINVAL);
+                }
+                current = node.get_parent();
+            }
+        }
+
+        if parent == new_parent && name == new_name {
+            return Ok(());
+        }
+
+        let mut replaced = None;
+        // without case sensitivity a rename that only changes the case finds the entry itself
+        let existing = self.tree_fs.find_child(&new_parent_node, new_name.to_str().unwrap())
+            .filter(|existing| !Arc::ptr_eq(existing, &child_node));
+        if let Some(existing) = existing {
+            if flags & RENAME_NOREPLACE != 0 {
+                return Err(sys::EEXIST);
+            }
+            let (existing_ino, existing_is_dir) = {
+                let existing = existing.value.read().unwrap();
+                (existing.ino, existing.is_dir)
+            };
+            if existing_ino == child_ino {
+                // both names are links to the same file
+                return Ok(());
+            }
+            let err = match (child_is_dir, existing_is_dir) {
+                (true, false) => sys::ENOTDIR,
+                (false, true) => sys::EISDIR,
+                (true, true) if !self.tree_fs.is_dir_empty(existing_ino).unwrap() => sys::ENOTEMPTY,
+                _ => 0,
+            };
+            if err != 0 {
+                return Err(err);
+            }
+
+            // the replaced entry goes away like on unlink
+            self.update_attr(existing_ino, |attr| {
+                attr.nlink = attr.nlink.saturating_sub(1);
+                attr.ctime = SystemTime::now();
+            });
+            if existing_is_dir {
+                self.update_attr(new_parent, |attr| attr.nlink -= 1);
+            }
+            // move_node swaps it out so a concurrent lookup never misses the name
+            replaced = Some(existing_ino);
+        }
+
+        if child_is_dir && parent != new_parent {
+            self.update_attr(parent, |attr| attr.nlink -= 1);
+            self.update_attr(new_parent, |attr| attr.nlink += 1);
+        }
+
+        let replaced_attr = replaced.and_then(|replaced| self.attr_of(replaced));
+        self.tree_fs.move_node(parent, name.to_str().unwrap(), new_parent, new_name.to_str().unwrap());
+        if let Some(replaced) = replaced {
+            if let (None, Some(attr)) = (self.tree_fs.get_item(replaced), replaced_attr) {
+                self.charge_inode(&attr, false);
+            }
+            self.recycle_inode(replaced);
+        }
+
+        if flags & RENAME_WHITEOUT != 0 {
+            let mut attr = file_attr(0, 0, self.block_size);
+            attr.kind = FileType::CharDevice;
+            attr.perm = 0;
+            attr.rdev = 0;
+            attr.uid = uid;
+            attr.gid = gid;
+            if let Err(err) = self.insert_item(parent, name.to_str().unwrap(), attr, Some(Arc::new(FileData::new()))) {
+                warn!("rename() could not leave a whiteout at {:?}: {}", name, err);
+            }
+        }
+
+        for dir in [parent, new_parent] {
+            self.update_attr(dir, |dir_attr| {
+                dir_attr.ctime = SystemTime::now();
+                dir_attr.mtime = SystemTime::now();
+            });
+        }
+
+        self.update_attr(child_ino, |attr| {
+            attr.ctime = SystemTime::now();
+            attr.mtime = SystemTime::now();
+        });
+
+        self.notify_dir_watchers_of(&[parent, new_parent], |fs| DirEvent::Renamed {
+            from: fs.entry_path(parent, Some(name)),
+            to: fs.entry_path(new_parent, Some(new_name)),
+        });
+        self.emit(|fs| FsEvent::Renamed {
+            old_path: fs.entry_path(parent, Some(name)),
+            new_path: fs.entry_path(new_parent, Some(new_name)),
+        });
+
+        Ok(())
+    }
+
+    /// Swaps two entries like `rename` with `RENAME_EXCHANGE`, both must exist.
+    fn exchange_entries(&mut self, parent: u64, name: &OsStr, new_parent: u64, new_name: &OsStr) -> Result<(), c_int> {
+        let mut entries = vec![];
+        for (dir, entry_name) in [(parent, name), (new_parent, new_name)] {
+            let dir_node = self.tree_fs.get_item(dir).ok_or(ENOENT)?;
+            if !dir_node.value.read().unwrap().is_dir {
+                return Err(sys::ENOTDIR);
+            }
+            let child = self.tree_fs.find_child(&dir_node, entry_name.to_str().unwrap()).ok_or(ENOENT)?;
+            let child = child.value.read().unwrap();
+            entries.push((child.ino, child.is_dir));
+        }
+        let [(ino, is_dir), (new_ino, new_is_dir)] = [entries[0], entries[1]];
+        if ino == new_ino {
+            return Ok(());
+        }
+
+        // neither directory can end up inside itself
+        for (dir_ino, is_dir, dst_parent) in [(ino, is_dir, new_parent), (new_ino, new_is_dir, parent)] {
+            if !is_dir {
+                continue;
+            }
+            let mut current = self.tree_fs.get_item(dst_parent);
+            while let Some(node) = current {
+                if node.value.read().unwrap().ino == dir_ino {
+                    return Err(sys::EINVAL);
                 }
-                if flags & FMODE_EXEC != 0 {
-                    // Open is from internal exec syscall
-                    (libc::X_OK, true, false)
-                } else {
-                    (libc::R_OK, true, false)
+                current = node.get_parent();
+            }
+        }
+
+        // ".." of a directory changing parent moves its link along
+        if parent != new_parent && is_dir != new_is_dir {
+            let (from, to) = if is_dir { (parent, new_parent) } else { (new_parent, parent) };
+            self.update_attr(from, |attr| attr.nlink -= 1);
+            self.update_attr(to, |attr| attr.nlink += 1);
+        }
+
+        self.tree_fs.exchange_nodes(parent, name.to_str().unwrap(), new_parent, new_name.to_str().unwrap()).ok_or(ENOENT)?;
+
+        for dir in [parent, new_parent] {
+            self.update_attr(dir, |dir_attr| {
+                dir_attr.ctime = SystemTime::now();
+                dir_attr.mtime = SystemTime::now();
+            });
+        }
+        for ino in [ino, new_ino] {
+            self.update_attr(ino, |attr| attr.ctime = SystemTime::now());
+        }
+
+        for (from_dir, from, to_dir, to) in [(parent, name, new_parent, new_name), (new_parent, new_name, parent, name)] {
+            self.notify_dir_watchers_of(&[from_dir, to_dir], |fs| DirEvent::Renamed {
+                from: fs.entry_path(from_dir, Some(from)),
+                to: fs.entry_path(to_dir, Some(to)),
+            });
+            self.emit(|fs| FsEvent::Renamed {
+                old_path: fs.entry_path(from_dir, Some(from)),
+                new_path: fs.entry_path(to_dir, Some(to)),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Creates `name` in `dst_parent_ino` as a copy-on-write clone of `src_ino`, like `cp --reflink`.
+    /// The clone gets its own inode and attributes but shares the data until either file is written.
+    pub fn sparse_clone(&mut self, src_ino: u64, dst_parent_ino: u64, name: &str) -> Result<u64, c_int> {
+        self.check_name(OsStr::new(name))?;
+        let src = self.tree_fs.get_item(src_ino).ok_or(ENOENT)?;
+        let (mut attr, data) = {
+            let src = src.value.read().unwrap();
+            (*src.extra.as_ref().unwrap(), src.data.clone())
+        };
+        // same as clone_data, only regular files have data to share
+        match attr.kind {
+            FileType::RegularFile => {}
+            FileType::Directory => return Err(sys::EISDIR),
+            _ => return Err(sys::EINVAL),
+        }
+        // the shared data is charged to the clone too, like the blocks it takes once either is written
+        self.check_space(0, attr.size)?;
+        self.check_quota(attr.uid, attr.gid, 0, attr.size, 1)?;
+        attr.nlink = 1;
+        attr.atime = SystemTime::now();
+        attr.mtime = SystemTime::now();
+        attr.ctime = SystemTime::now();
+        attr.crtime = SystemTime::now();
+
+        self.insert_item(dst_parent_ino, name, attr, data)
+    }
+
+    /// Makes the file at `dst` a copy-on-write clone of the one at `src`, like `cp --reflink`. An existing `dst` gets the data
+    /// of `src` in place of its own and keeps its attributes, otherwise it's created like with `sparse_clone`.
+    pub fn reflink(&mut self, src_path: &str, dst_path: &str) -> Result<u64, FsError> {
+        let src = self.tree_fs.find_by_path(src_path).ok_or(FsError::NotFound)?;
+        if let Some(dst) = self.tree_fs.find_by_path(dst_path) {
+            self.clone_data(src, dst).map_err(FsError::from_errno)?;
+            return Ok(dst);
+        }
+        let (parent, name) = split_path(dst_path, self.max_name_len)?;
+        let parent = self.tree_fs.find_by_path(parent).ok_or(FsError::NotFound)?;
+
+        self.sparse_clone(src, parent, name).map_err(FsError::from_errno)
+    }
+
+    // dst shares the data of src until either is written
+    fn clone_data(&mut self, src_ino: u64, dst_ino: u64) -> Result<(), c_int> {
+        let src_attr = self.attr_of(src_ino).ok_or(ENOENT)?;
+        let dst_attr = self.attr_of(dst_ino).ok_or(ENOENT)?;
+        if src_attr.kind == FileType::Directory || dst_attr.kind == FileType::Directory {
+            return Err(sys::EISDIR);
+        }
+        if src_attr.kind != FileType::RegularFile || dst_attr.kind != FileType::RegularFile || src_ino == dst_ino {
+            return Err(sys::EINVAL);
+        }
+        let data = self.tree_fs.get_item(src_ino).ok_or(ENOENT)?.value.read().unwrap().data.clone();
+        let len = data.as_ref().map_or(0, |data| data.len());
+        self.check_space(dst_attr.size, len)?;
+        self.check_quota(dst_attr.uid, dst_attr.gid, dst_attr.size, len, 0)?;
+
+        {
+            let node = self.tree_fs.get_item(dst_ino).ok_or(ENOENT)?;
+            let mut item = node.value.write().unwrap();
+            item.data = data;
+            item.dirty_pages.clear();
+            mark_pages_dirty(&mut item, 0, len, self.page_size);
+            let attr = item.extra.as_mut().unwrap();
+            attr.size = len;
+            attr.blocks = block_count(len, self.block_size);
+            attr.ctime = SystemTime::now();
+            attr.mtime = SystemTime::now();
+            clear_suid_sgid(attr);
+        }
+        self.charge_resize(dst_attr.uid, dst_attr.gid, dst_attr.size, len);
+        self.notify_modified(dst_ino);
+        self.emit(|_| FsEvent::AttrChanged { ino: dst_ino });
+
+        Ok(())
+    }
+
+    /// Copies `size` bytes at `src_offset` of `src_ino` to `dest_offset` of `dest_ino`, written through `dest_fh`, like
+    /// `copy_file_range(2)`. The ranges may overlap in the same file. Returns how many bytes were copied.
+    fn copy_range(&mut self, src_ino: u64, src_offset: i64, dest_ino: u64, dest_fh: u64, dest_offset: i64, size: u64) -> Result<u64, c_int> {
+        let src = self.tree_fs.get_item(src_ino).ok_or(ENOENT)?;
+        let dest = self.tree_fs.get_item(dest_ino).ok_or(ENOENT)?;
+        for ino in [src_ino, dest_ino] {
+            match self.attr_of(ino).map(|attr| attr.kind) {
+                Some(FileType::RegularFile) => {}
+                Some(FileType::Directory) => return Err(sys::EISDIR),
+                _ => return Err(sys::EINVAL),
+            }
+        }
+        if let Some(len) = self.share_whole_file(src_ino, src_offset, dest_ino, dest_fh, dest_offset, size) {
+            return Ok(len);
+        }
+
+        // src and dest may be the same file, read before locking it for writing
+        let data = {
+            let src = src.value.read().unwrap();
+            let file_size = src.extra.as_ref().unwrap().size;
+            // Could underflow if file length is less than local_start
+            let read_size = min(size, file_size.saturating_sub(src_offset as u64));
+
+            let mut data = vec![0; read_size as usize];
+            let read_len = src.data.as_ref().unwrap().read(src_offset as u64, &mut data);
+            data.truncate(read_len);
+            data
+        };
+
+        let (dest_len, owner) = {
+            let dest = dest.value.read().unwrap();
+            let attr = dest.extra.as_ref().unwrap();
+            (dest.data_len(), (attr.uid, attr.gid))
+        };
+        let new_len = dest_offset as u64 + data.len() as u64;
+        self.check_inode_flags_write(dest_ino, dest_fh)
+            .and_then(|_| self.check_space(dest_len, new_len))
+            .and_then(|_| self.check_quota(owner.0, owner.1, dest_len, new_len, 0))?;
+
+        let (old_size, size) = {
+            let mut dest = dest.value.write().unwrap();
+            Arc::make_mut(dest.data.as_mut().unwrap()).write(dest_offset as u64, &data);
+            mark_pages_dirty(&mut dest, dest_offset as u64, data.len() as u64, self.page_size);
+
+            let size = dest.data_len();
+            let attr = dest.extra.as_mut().unwrap();
+            let old_size = attr.size;
+            attr.ctime = SystemTime::now();
+            attr.mtime = SystemTime::now();
+            // the copy may have extended the file
+            attr.size = size;
+            attr.blocks = block_count(size, self.block_size);
+            (old_size, size)
+        };
+        self.charge_resize(owner.0, owner.1, old_size, size);
+
+        self.notify_modified(dest_ino);
+        self.emit(|_| FsEvent::Written { ino: dest_ino, offset: dest_offset, len: data.len() });
+
+        Ok(data.len() as u64)
+    }
+
+    // a copy of all of src over all of dest, as `cp` does, shares the data instead. `None` for any other copy.
+    fn share_whole_file(&mut self, src_ino: u64, src_offset: i64, dst_ino: u64, dst_fh: u64, dst_offset: i64, size: u64) -> Option<u64> {
+        if src_offset != 0 || dst_offset != 0 || src_ino == dst_ino {
+            return None;
+        }
+        let src_len = self.attr_of(src_ino)?.size;
+        let dst_len = self.attr_of(dst_ino)?.size;
+        // what ReplyWrite can report
+        if size < src_len || dst_len > src_len || src_len > u32::MAX as u64 {
+            return None;
+        }
+        // errors are left for the byte copy to report
+        self.check_inode_flags_write(dst_ino, dst_fh).ok()?;
+        self.clone_data(src_ino, dst_ino).ok()?;
+        self.emit(|_| FsEvent::Written { ino: dst_ino, offset: 0, len: src_len as usize });
+
+        Some(src_len)
+    }
+
+    /// Mirrors `/proc/{pid}/fd`, `/proc/{pid}/maps` and `/proc/{pid}/environ` under `/{pid}/`.
+    /// Entries of `fd` become symlinks to the files the process has open, `environ` gets one variable per line.
+    #[cfg(unix)]
+    pub fn import_from_proc(&mut self, pid: u32) -> io::Result<()> {
+        self.ensure_root();
+        let proc_dir = PathBuf::from(format!("/proc/{pid}"));
+        let maps = fs::read(proc_dir.join("maps"))?;
+        let environ = fs::read(proc_dir.join("environ"))?;
+        let fds = fs::read_dir(proc_dir.join("fd"))?;
+
+        let dir = self.insert_item(1, &pid.to_string(), dir_attr(0, self.block_size), None).map_err(io::Error::from_raw_os_error)?;
+        let fd_dir = self.insert_item(dir, "fd", dir_attr(0, self.block_size), None).map_err(io::Error::from_raw_os_error)?;
+        for entry in fds {
+            let entry = entry?;
+            // the fd may have been closed since listing the directory
+            let Ok(target) = fs::read_link(entry.path()) else { continue };
+            let target = target.as_os_str().as_bytes();
+            let mut attr = file_attr(0, target.len() as u64, self.block_size);
+            attr.kind = FileType::Symlink;
+            attr.perm = 0o777;
+            self.insert_item(fd_dir, &entry.file_name().to_string_lossy(), attr, Some(Arc::new(FileData::from_bytes(target))))
+                .map_err(io::Error::from_raw_os_error)?;
+        }
+
+        let environ: Vec<u8> = environ.into_iter().map(|b| if b == 0 { b'\n' } else { b }).collect();
+        for (name, data) in [("maps", maps), ("environ", environ)] {
+            self.insert_item(dir, name, file_attr(0, data.len() as u64, self.block_size), Some(Arc::new(FileData::from_bytes(&data))))
+                .map_err(io::Error::from_raw_os_error)?;
+        }
+
+        Ok(())
+    }
+
+    /// Creates a filesystem holding a copy of the directory `src`, keeping mode, owner and times of its entries.
+    /// Entries other than regular files, directories and symlinks are skipped.
+    #[cfg(unix)]
+    pub fn from_directory(src: &Path, options: ImportOptions) -> io::Result<MemFs> {
+        MemFsBuilder::default().import_from_directory(src, options)
+    }
+
+    #[cfg(unix)]
+    fn import_directory(&mut self, src: &Path, options: ImportOptions) -> io::Result<()> {
+        let metadata = fs::metadata(src)?;
+        if !metadata.is_dir() {
+            return Err(io::Error::from_raw_os_error(sys::ENOTDIR));
+        }
+
+        self.ensure_root();
+        self.update_attr(1, |attr| copy_metadata(attr, &metadata));
+        let mut visited = vec![(metadata.dev(), metadata.ino())];
+        self.import_dir(1, src, 0, &options, &mut visited, &mut 0)
+    }
+
+    // `visited` holds the directories being imported, a followed symlink pointing back to one of them is skipped
+    // `imported` adds up the size of the files copied so far
+    #[cfg(unix)]
+    fn import_dir(&mut self, parent: u64, dir: &Path, depth: usize, options: &ImportOptions, visited: &mut Vec<(u64, u64)>, imported: &mut u64) -> io::Result<()> {
+        let mut subdirs = vec![];
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let metadata = if options.follow_symlinks { fs::metadata(&path)? } else { fs::symlink_metadata(&path)? };
+            let name = entry.file_name().to_string_lossy().into_owned();
+
+            let file_type = metadata.file_type();
+            let (kind, data) = if file_type.is_dir() {
+                if depth >= options.max_depth || visited.contains(&(metadata.dev(), metadata.ino())) {
+                    continue;
+                }
+                (FileType::Directory, None)
+            } else if file_type.is_symlink() {
+                let target = fs::read_link(&path)?;
+                (FileType::Symlink, Some(target.as_os_str().as_bytes().to_vec()))
+            } else if file_type.is_file() {
+                if metadata.len() > options.max_file_size {
+                    debug!("from_directory() skipped {:?}, {} bytes", path, metadata.len());
+                    continue;
+                }
+                *imported += metadata.len();
+                if *imported > options.max_total_size {
+                    return Err(io::Error::from_raw_os_error(sys::EFBIG));
+                }
+                (FileType::RegularFile, Some(fs::read(&path)?))
+            } else {
+                continue;
+            };
+
+            let mut attr = match &data {
+                Some(data) => file_attr(0, data.len() as u64, self.block_size),
+                None => dir_attr(0, self.block_size),
+            };
+            attr.kind = kind;
+            copy_metadata(&mut attr, &metadata);
+            let data = data.map(|data| Arc::new(FileData::from_bytes(&data)));
+            let ino = self.insert_item(parent, &name, attr, data).map_err(io::Error::from_raw_os_error)?;
+            if kind == FileType::Directory {
+                subdirs.push((ino, path, (metadata.dev(), metadata.ino())));
+            }
+        }
+
+        for (ino, path, id) in subdirs {
+            visited.push(id);
+            self.import_dir(ino, &path, depth + 1, options, visited, imported)?;
+            visited.pop();
+        }
+        // inserting the entries touched the times
+        let metadata = if options.follow_symlinks { fs::metadata(dir)? } else { fs::symlink_metadata(dir)? };
+        self.update_attr(parent, |attr| copy_metadata(attr, &metadata));
+
+        Ok(())
+    }
+
+    /// Writes the tree under `dst`, created if missing. Keeps mode and times, not ownership.
+    /// Hard links are recreated as hard links.
+    #[cfg(unix)]
+    pub fn export_to_directory(&self, dst: &Path) -> io::Result<()> {
+        let Some(root) = self.tree_fs.get_root() else { return Ok(()) };
+        fs::create_dir_all(dst)?;
+        let mut exported = HashMap::new();
+        self.export_dir(&root, dst, &mut exported)?;
+        if let Some(attr) = self.attr_of(1) {
+            apply_metadata(dst, &attr)?;
+        }
+
+        Ok(())
+    }
+
+    // `exported` maps inodes already written to their path, to link further entries to it
+    #[cfg(unix)]
+    fn export_dir(&self, dir: &ItemNode<FileAttr>, path: &Path, exported: &mut HashMap<u64, PathBuf>) -> io::Result<()> {
+        for child in dir.children() {
+            let (ino, name) = {
+                let item = child.value.read().unwrap();
+                (item.ino, item.name.clone())
+            };
+            let child_path = path.join(&name);
+            if let Some(existing) = exported.get(&ino) {
+                fs::hard_link(existing, &child_path)?;
+                continue;
+            }
+            // links share the node holding the metadata and data
+            let node = self.tree_fs.get_item(ino).ok_or(io::Error::from_raw_os_error(ENOENT))?;
+            let (attr, data) = {
+                let item = node.value.read().unwrap();
+                (*item.extra.as_ref().unwrap(), item.data.clone())
+            };
+
+            match attr.kind {
+                FileType::Directory => {
+                    fs::create_dir(&child_path)?;
+                    self.export_dir(&node, &child_path, exported)?;
+                }
+                FileType::Symlink => {
+                    let target = data.map_or(vec![], |data| data.to_vec());
+                    std::os::unix::fs::symlink(OsStr::from_bytes(&target), &child_path)?;
+                    continue;
+                }
+                FileType::RegularFile => {
+                    fs::write(&child_path, data.map_or(vec![], |data| data.to_vec()))?;
+                }
+                // devices, pipes and sockets have nothing to copy
+                _ => continue,
+            }
+            // after writing the directory's entries, its mode may not allow it
+            apply_metadata(&child_path, &attr)?;
+            exported.insert(ino, child_path);
+        }
+
+        Ok(())
+    }
+
+    /// Creates a filesystem holding the files, directories and links of a tar archive, keeping their mode and mtime.
+    /// Entries belong to root, use `MemFsBuilder::preserve_ownership` to keep the owner from the archive.
+    pub fn import_from_tar(reader: impl Read) -> io::Result<MemFs> {
+        MemFsBuilder::default().import_from_tar(reader)
+    }
+
+    // `owner` replaces the uid and gid of the entries when set
+    fn import_tar(&mut self, reader: impl Read, owner: Option<(u32, u32)>) -> io::Result<()> {
+        self.ensure_root();
+        let mut archive = tar::Archive::new(reader);
+        // adding entries touches the mtime of their directory, set them once all are in
+        let mut mtimes = vec![];
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let entry_path = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+            let header = entry.header();
+            let (kind, mode, mtime) = (header.entry_type(), header.mode()?, header.mtime()?);
+            let (uid, gid) = match owner {
+                Some(owner) => owner,
+                None => (header.uid()? as u32, header.gid()? as u32),
+            };
+            let path = entry_path.trim_start_matches("./").trim_matches('/');
+            let ino = if path.is_empty() || path == "." {
+                1
+            } else {
+                match self.import_tar_entry(path, &mut entry, mode, uid, gid) {
+                    Ok(Some(ino)) => ino,
+                    Ok(None) => continue,
+                    Err(FsError::Io(err)) => return Err(err),
+                    Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", entry_path, err))),
+                }
+            };
+            if kind.is_hard_link() {
+                continue;
+            }
+            self.update_attr(ino, |attr| {
+                attr.perm = (mode & 0o7777) as u16;
+                attr.uid = uid;
+                attr.gid = gid;
+            });
+            mtimes.push((ino, UNIX_EPOCH + Duration::from_secs(mtime)));
+        }
+        for (ino, mtime) in mtimes {
+            self.update_attr(ino, |attr| attr.mtime = mtime);
+        }
+
+        Ok(())
+    }
+
+    fn import_tar_entry(&mut self, path: &str, entry: &mut tar::Entry<impl Read>, mode: u32, uid: u32, gid: u32) -> Result<Option<u64>, FsError> {
+        let kind = entry.header().entry_type();
+        if kind.is_dir() {
+            return self.create_dir_all(path, mode, uid, gid).map(Some);
+        }
+        // archives don't always list the directories before their content
+        let (parent, name) = split_path(path, self.max_name_len)?;
+        let parent = self.create_dir_all(parent, 0o755, uid, gid)?;
+        let link_name = entry.link_name_bytes().map(|name| name.into_owned()).unwrap_or_default();
+
+        let ino = match kind {
+            tar::EntryType::Regular | tar::EntryType::Continuous | tar::EntryType::GNUSparse => {
+                let mut data = vec![];
+                entry.read_to_end(&mut data)?;
+                if data.len() as u64 != entry.size() {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+                self.create_file(path, &data, mode, uid, gid)?
+            }
+            tar::EntryType::Symlink => {
+                let mut attr = file_attr(0, link_name.len() as u64, self.block_size);
+                attr.kind = FileType::Symlink;
+                attr.perm = 0o777;
+                attr.uid = uid;
+                attr.gid = gid;
+                let target = Arc::new(FileData::from_bytes(&link_name));
+                self.insert_item(parent, name, attr, Some(target)).map_err(FsError::from_errno)?
+            }
+            tar::EntryType::Link => {
+                let target = String::from_utf8_lossy(&link_name);
+                let target = target.trim_start_matches("./");
+                let ino = self.tree_fs.resolve_path(target).ok_or(FsError::NotFound)?.value.read().unwrap().ino;
+                let parent_node = self.tree_fs.get_item(parent).ok_or(FsError::NotFound)?;
+                if self.tree_fs.find_child(&parent_node, name).is_some() {
+                    return Err(FsError::AlreadyExists);
+                }
+                let attr = self.attr_of(ino).ok_or(FsError::NotFound)?;
+                if attr.kind == FileType::Directory {
+                    return Err(FsError::IsADirectory);
+                }
+                let mut link = Item::new(ino, name.to_string(), false, Some(attr));
+                link.data = None;
+                self.tree_fs.push_link(&parent_node, link)?;
+                self.update_attr(ino, |attr| attr.nlink += 1);
+                ino
+            }
+            _ => {
+                debug!("import_from_tar() skipped {}", path);
+                return Ok(None);
+            }
+        };
+
+        Ok(Some(ino))
+    }
+
+    /// Writes the tree as a tar archive with mode, owner and mtime of the entries.
+    /// Hard links are stored as links to the first entry of the inode, devices, pipes and sockets are skipped.
+    pub fn export_to_tar(&self, mut writer: impl Write) -> io::Result<()> {
+        self.stream_tar(&mut writer)
+    }
+
+    /// Like `export_to_tar`, file content is copied to `writer` a chunk at a time instead of collected first.
+    pub fn stream_tar(&self, writer: &mut impl Write) -> io::Result<()> {
+        let mut archive = tar::Builder::new(writer);
+        let mut exported: HashMap<u64, String> = HashMap::new();
+        for node in self.tree_fs.iter_dfs() {
+            let ino = node.value.read().unwrap().ino;
+            // the root keeps its mode and mtime as `./`, like tar does
+            let path = if ino == 1 { String::from("./") } else { self.tree_fs.full_path(&node).trim_start_matches('/').to_string() };
+            let Some(attr) = self.attr_of(ino) else { continue };
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(attr.perm as u32);
+            header.set_uid(attr.uid as u64);
+            header.set_gid(attr.gid as u64);
+            header.set_mtime(attr.mtime.duration_since(UNIX_EPOCH).map_or(0, |mtime| mtime.as_secs()));
+            header.set_size(0);
+            if let Some(first) = exported.get(&ino) {
+                header.set_entry_type(tar::EntryType::Link);
+                archive.append_link(&mut header, &path, first)?;
+                continue;
+            }
+
+            // a clone of the Arc, the data is shared and writes meanwhile copy it
+            let data = self.tree_fs.get_item(ino).and_then(|node| node.value.read().unwrap().data.clone())
+                .unwrap_or_default();
+            match attr.kind {
+                FileType::Directory => {
+                    header.set_entry_type(tar::EntryType::Directory);
+                    archive.append_data(&mut header, &path, io::empty())?;
+                }
+                FileType::RegularFile => {
+                    header.set_entry_type(tar::EntryType::Regular);
+                    header.set_size(data.len());
+                    archive.append_data(&mut header, &path, DataReader { data: &data, offset: 0 })?;
+                }
+                FileType::Symlink => {
+                    header.set_entry_type(tar::EntryType::Symlink);
+                    archive.append_link(&mut header, &path, os_str_of(&data.to_vec()))?;
+                }
+                _ => continue,
+            }
+            exported.insert(ino, path);
+        }
+        archive.into_inner()?;
+
+        Ok(())
+    }
+
+    /// Routes `read` and `write` of the matching files to `hook`.
+    /// For `InodePattern::Path` the file and its parent directories are created if missing.
+    pub fn register_vfs_hook(&mut self, pattern: InodePattern, hook: Box<dyn VfsHook>) -> Result<(), c_int> {
+        if let InodePattern::Path(path) = &pattern {
+            self.ensure_root();
+            let mut parent = 1;
+            let mut components = path.split('/').filter(|name| !name.is_empty()).peekable();
+            while let Some(name) = components.next() {
+                let is_file = components.peek().is_none();
+                let existing = self.tree_fs.get_item(parent).and_then(|node| self.tree_fs.find_child(&node, name));
+                let existing = existing.map(|node| {
+                    let item = node.value.read().unwrap();
+                    (item.ino, item.is_dir)
+                });
+                parent = match existing {
+                    Some((ino, is_dir)) if is_file || is_dir => ino,
+                    Some(_) => return Err(sys::ENOTDIR),
+                    None if is_file => self.insert_item(parent, name, file_attr(0, 0, self.block_size), None)?,
+                    None => self.insert_item(parent, name, dir_attr(0, self.block_size), None)?,
+                };
+            }
+        }
+        self.vfs_hooks.push((pattern, hook));
+
+        Ok(())
+    }
+
+    /// Creates the read-only file at `path` whose content `provider` computes on every read, its parent directory must exist.
+    /// It reports a size of 0 and is opened with direct I/O so reads aren't cut at that size, writes fail with `EPERM`.
+    pub fn add_virtual_file(&mut self, path: &str, provider: VirtualFileProvider) -> Result<u64, FsError> {
+        self.insert_virtual_file(path, VirtualFile::Provider(provider))
+    }
+
+    /// Virtual file with `statistics()` as JSON, see `add_virtual_file`.
+    pub fn add_statistics_file(&mut self, path: &str) -> Result<u64, FsError> {
+        self.insert_virtual_file(path, VirtualFile::Statistics)
+    }
+
+    /// Virtual file with the `dump_tree()` text, see `add_virtual_file`.
+    pub fn add_tree_dump_file(&mut self, path: &str) -> Result<u64, FsError> {
+        self.insert_virtual_file(path, VirtualFile::TreeDump)
+    }
+
+    fn insert_virtual_file(&mut self, path: &str, file: VirtualFile) -> Result<u64, FsError> {
+        self.ensure_root();
+        let (parent, name) = split_path(path, self.max_name_len)?;
+        let parent = self.tree_fs.resolve_path(parent).ok_or(FsError::NotFound)?.value.read().unwrap().ino;
+
+        let mut attr = file_attr(0, 0, self.block_size);
+        attr.perm = 0o444;
+        if let Some(root) = self.attr_of(1) {
+            attr.uid = root.uid;
+            attr.gid = root.gid;
+        }
+        let ino = self.insert_item(parent, name, attr, None).map_err(FsError::from_errno)?;
+        self.tree_fs.get_item(ino).unwrap().value.write().unwrap().is_virtual = true;
+        self.virtual_files.insert(ino, file);
+
+        Ok(ino)
+    }
+
+    fn is_virtual(&self, ino: u64) -> bool {
+        self.tree_fs.get_item(ino).is_some_and(|node| node.value.read().unwrap().is_virtual)
+    }
+
+    fn virtual_content(&self, ino: u64) -> Option<Vec<u8>> {
+        let content = match self.virtual_files.get(&ino)? {
+            VirtualFile::Provider(provider) => provider(),
+            VirtualFile::Statistics => self.statistics().to_json().into_bytes(),
+            VirtualFile::TreeDump => {
+                let mut buf = vec![];
+                self.dump_tree(&mut buf).ok()?;
+                buf
+            }
+        };
+
+        Some(content)
+    }
+
+    fn find_vfs_hook(&mut self, ino: u64) -> Option<usize> {
+        if self.vfs_hooks.is_empty() {
+            return None;
+        }
+        let path = self.entry_path(ino, None);
+        self.vfs_hooks.iter().position(|(pattern, _)| match pattern {
+            InodePattern::Path(pattern) => *pattern == path,
+            InodePattern::InodeRange(range) => range.contains(&ino),
+        })
+    }
+
+    /// Resolves `ino` to the inode it points to if it is a symlink, following at most `max_symlink_depth` links.
+    pub fn follow_symlink(&mut self, ino: u64) -> Result<u64, c_int> {
+        let mut hops = 0;
+        self.follow_symlink_hops(ino, &mut hops)
+    }
+
+    fn follow_symlink_hops(&mut self, mut ino: u64, hops: &mut u32) -> Result<u64, c_int> {
+        loop {
+            let node = self.tree_fs.get_item(ino).ok_or(ENOENT)?;
+            let target = {
+                let item = node.value.read().unwrap();
+                if item.extra.as_ref().unwrap().kind != FileType::Symlink {
+                    return Ok(ino);
+                }
+                String::from_utf8_lossy(&item.data.as_ref().unwrap().to_vec()).into_owned()
+            };
+            *hops += 1;
+            if *hops > u32::from(self.max_symlink_depth) {
+                return Err(sys::ELOOP);
+            }
+
+            // relative targets start from the directory containing the link
+            let mut current = if target.starts_with('/') { 1 } else { node.get_parent().map_or(1, |parent| parent.value.read().unwrap().ino) };
+            for name in target.split('/').filter(|name| !name.is_empty()) {
+                current = self.follow_symlink_hops(current, hops)?;
+                let dir = self.tree_fs.get_item(current).ok_or(ENOENT)?;
+                if !dir.value.read().unwrap().is_dir {
+                    return Err(sys::ENOTDIR);
                 }
+                current = match name {
+                    "." => current,
+                    ".." => dir.get_parent().map_or(current, |parent| parent.value.read().unwrap().ino),
+                    _ => self.tree_fs.find_child(&dir, name).ok_or(ENOENT)?.value.read().unwrap().ino,
+                };
+            }
+            ino = current;
+        }
+    }
+
+    fn attr_of(&self, ino: u64) -> Option<FileAttr> {
+        self.tree_fs.get_item(ino).map(|node| *node.value.read().unwrap().extra.as_ref().unwrap())
+    }
+
+    /// Changes the attributes of `ino` in place, returns the updated copy.
+    fn update_attr(&self, ino: u64, f: impl FnOnce(&mut FileAttr)) -> Option<FileAttr> {
+        let node = self.tree_fs.get_item(ino)?;
+        let (old, new) = {
+            let mut item = node.value.write().unwrap();
+            let attr = item.extra.as_mut().unwrap();
+            let old = *attr;
+            f(attr);
+            (old, *attr)
+        };
+        self.charge_attr_change(&old, &new);
+
+        Some(new)
+    }
+
+    fn ensure_root(&mut self) {
+        if self.tree_fs.get_root().is_none() {
+            let attr = dir_attr(1, self.block_size);
+            let root = Item::new(1, String::from("root"), true, Some(attr));
+            self.tree_fs.set_root(root).expect("the root item is a directory");
+            self.charge_inode(&attr, true);
+        }
+    }
+
+    // adds `item` to the tree and charges it to its owner
+    fn push_item(&mut self, parent_node: &ItemNode<FileAttr>, item: Item<FileAttr>) -> Result<ItemNode<FileAttr>, TreeFsError> {
+        let attr = item.extra;
+        let node = self.tree_fs.push(parent_node, item)?;
+        if let Some(attr) = attr {
+            self.charge_inode(&attr, true);
+        }
+
+        Ok(node)
+    }
+
+    // takes the entry `child` out of `parent_node`, its inode stops being charged once its last entry is gone
+    fn remove_entry(&mut self, parent_node: &ItemNode<FileAttr>, child: &ItemNode<FileAttr>) -> Result<(), TreeFsError> {
+        let ino = child.value.read().unwrap().ino;
+        let attr = self.attr_of(ino);
+        self.tree_fs.remove_child(parent_node, child)?;
+        if let (None, Some(attr)) = (self.tree_fs.get_item(ino), attr) {
+            self.charge_inode(&attr, false);
+        }
+
+        Ok(())
+    }
+
+    /// Adds `name` to the directory `parent` with a newly allocated inode, used by the programmatic APIs.
+    fn insert_item(&mut self, parent: u64, name: &str, mut attr: FileAttr, data: Option<Arc<FileData>>) -> Result<u64, c_int> {
+        let parent_node = self.tree_fs.get_item(parent).ok_or(ENOENT)?;
+        if !parent_node.value.read().unwrap().is_dir {
+            return Err(sys::ENOTDIR);
+        }
+        if self.tree_fs.find_child(&parent_node, name).is_some() {
+            return Err(sys::EEXIST);
+        }
+
+        let ino = self.allocate_inode_for(parent, name)?;
+        attr.ino = ino;
+        let is_dir = attr.kind == FileType::Directory;
+        let mut item = Item::new(ino, name.to_string(), is_dir, Some(attr));
+        if !is_dir {
+            item.data = data;
+        }
+        self.push_item(&parent_node, item).map_err(|err| FsError::from(err).errno())?;
+
+        {
+            let mut parent_item = parent_node.value.write().unwrap();
+            let parent_attr = parent_item.extra.as_mut().unwrap();
+            parent_attr.mtime = SystemTime::now();
+            parent_attr.ctime = SystemTime::now();
+            if is_dir {
+                parent_attr.nlink += 1;
+            }
+        }
+
+        self.notify_dir_watchers(parent, |fs| DirEvent::Created {
+            path: fs.entry_path(parent, Some(OsStr::new(name))),
+            kind: attr.kind,
+        });
+        self.emit(|fs| FsEvent::Created { ino, path: fs.entry_path(parent, Some(OsStr::new(name))), kind: attr.kind });
+
+        Ok(ino)
+    }
+
+    /// Sends events for changes inside the directory and its subdirectories, up to `depth` levels down.
+    /// `depth` 0 only reports entries of the directory itself.
+    pub fn watch_directory(&mut self, ino: u64, depth: u32) -> Receiver<DirEvent> {
+        let (tx, rx) = channel();
+        self.dir_watchers.push((ino, depth, tx));
+
+        rx
+    }
+
+    /// Calls `listener` with every `FsEvent`, after the change is done.
+    pub fn add_listener(&mut self, listener: Box<dyn Fn(FsEvent) + Send + Sync>) {
+        self.listeners.push(listener);
+    }
+
+    pub fn remove_all_listeners(&mut self) {
+        self.listeners.clear();
+    }
+
+    /// Reports the events in `mask` about the entry at `path` and, for a directory, the entries inside it.
+    /// Like the listeners, only changes made through FUSE operations are seen.
+    pub fn watch(&mut self, path: &str, mask: WatchMask) -> Result<WatchHandle, FsError> {
+        let ino = self.tree_fs.resolve_path(path).ok_or(FsError::NotFound)?.value.read().unwrap().ino;
+        let (tx, rx) = channel();
+        self.watches.push((ino, mask, tx));
+
+        Ok(WatchHandle { receiver: rx })
+    }
+
+    fn emit(&mut self, event: impl FnOnce(&mut Self) -> FsEvent) {
+        if self.listeners.is_empty() && self.watches.is_empty() {
+            return;
+        }
+
+        let event = event(self);
+        self.notify_watches(&event);
+        for listener in &self.listeners {
+            listener(event.clone());
+        }
+    }
+
+    fn notify_dir_watchers(&mut self, parent: u64, event: impl FnOnce(&mut Self) -> DirEvent) {
+        self.notify_dir_watchers_of(&[parent], event);
+    }
+
+    /// Sends one event to every watcher covering any of `parents`, used when a change spans directories.
+    fn notify_dir_watchers_of(&mut self, parents: &[u64], event: impl FnOnce(&mut Self) -> DirEvent) {
+        if self.dir_watchers.is_empty() {
+            return;
+        }
+
+        let mut chains = vec![];
+        for parent in parents {
+            let mut ancestors = vec![];
+            let mut current = self.tree_fs.get_item(*parent);
+            while let Some(node) = current {
+                ancestors.push(node.value.read().unwrap().ino);
+                current = node.get_parent();
             }
-            libc::O_WRONLY => (libc::W_OK, false, true),
-            libc::O_RDWR => (libc::R_OK | libc::W_OK, true, true),
-            // Exactly one access mode flag must be specified
-            _ => {
-                reply.error(libc::EINVAL);
-                return;
+            chains.push(ancestors);
+        }
+
+        let event = event(self);
+        // drop watchers whose receiver is gone
+        self.dir_watchers.retain(|(ino, depth, tx)| {
+            let covered = chains.iter().any(|ancestors| {
+                matches!(ancestors.iter().position(|a| a == ino), Some(level) if level as u32 <= *depth)
+            });
+            if covered { tx.send(event.clone()).is_ok() } else { true }
+        });
+    }
+
+    fn notify_watches(&mut self, event: &FsEvent) {
+        if self.watches.is_empty() {
+            return;
+        }
+
+        // path of the entry the event is reported on, with the name of the entry inside it if any
+        let mut cookie = 0;
+        let changes = match event {
+            FsEvent::Created { path, .. } => vec![watch_change(path, WatchMask::IN_CREATE)],
+            FsEvent::Deleted { path, .. } => vec![watch_change(path, WatchMask::IN_DELETE)],
+            FsEvent::Written { ino, .. } | FsEvent::AttrChanged { ino } => {
+                let mask = if matches!(event, FsEvent::Written { .. }) { WatchMask::IN_MODIFY } else { WatchMask::IN_ATTRIB };
+                let path = self.entry_path(*ino, None);
+                vec![(path.clone(), mask, None), watch_change(&path, mask)]
+            }
+            FsEvent::Renamed { old_path, new_path } => {
+                self.move_cookie = self.move_cookie.wrapping_add(1);
+                cookie = self.move_cookie;
+                vec![watch_change(old_path, WatchMask::IN_MOVED_FROM), watch_change(new_path, WatchMask::IN_MOVED_TO)]
             }
         };
 
-        match self.tree_fs.get_item_mut(inode) {
-            Some(item) => {
-                let attr = item.extra.as_ref().unwrap();
-                if check_access(attr.uid, attr.gid, attr.perm, req.uid(), req.gid(), access_mask) {
-                    let open_flags = if self.direct_io { FOPEN_DIRECT_IO } else { 0 };
-                    reply.opened(self.allocate_next_file_handle(), open_flags);
-                } else {
-                    reply.error(libc::EACCES);
-                }
+        // drop watches whose receiver is gone
+        self.watches.retain(|(ino, mask, tx)| {
+            let watched = self.tree_fs.path_of(*ino).unwrap_or_default();
+            changes.iter()
+                .filter(|(path, change, _)| *path == watched && mask.contains(*change))
+                .all(|(_, change, name)| tx.send(InotifyEvent { mask: *change, cookie, name: name.clone() }).is_ok())
+        });
+    }
+
+    fn notify_modified(&mut self, ino: u64) {
+        self.dirty_inodes.insert(ino);
+        let parent = match self.tree_fs.get_item(ino).and_then(|node| node.get_parent()) {
+            Some(parent) => parent.value.read().unwrap().ino,
+            None => return,
+        };
+        self.notify_dir_watchers(parent, |fs| DirEvent::Modified { path: fs.entry_path(ino, None) });
+    }
+
+    /// Path of the inode, with `name` appended when it's an entry inside that directory.
+    fn entry_path(&mut self, ino: u64, name: Option<&OsStr>) -> String {
+        let mut path = self.tree_fs.path_of(ino).unwrap_or_default();
+        if let Some(name) = name {
+            if !path.ends_with('/') {
+                path.push('/');
             }
-            None => reply.error(ENOENT)
+            path.push_str(&name.to_string_lossy());
         }
+
+        path
     }
 
-    fn create(
-        &mut self,
-        req: &Request,
-        parent: u64,
-        name: &OsStr,
-        mode: u32,
-        _umask: u32,
-        flags: i32,
-        reply: ReplyCreate,
-    ) {
-        debug!("create() called with {:?} {:?}", parent, name);
+}
 
-        let (_read, _write) = match flags & libc::O_ACCMODE {
-            libc::O_RDONLY => (true, false),
-            libc::O_WRONLY => (false, true),
-            libc::O_RDWR => (true, true),
-            // Exactly one access mode flag must be specified
-            _ => {
-                reply.error(libc::EINVAL);
-                return;
-            }
-        };
+impl Drop for MemFs {
+    fn drop(&mut self) {
+        if let Some(Err(err)) = self.inode_map.as_mut().map(|map| map.save()) {
+            warn!("could not save the inode map: {}", err);
+        }
+    }
+}
+
+/// Splits `path` into its parent directory and the last component.
+fn split_path(path: &str, max_name_len: usize) -> Result<(&str, &str), FsError> {
+    let path = path.trim_end_matches('/');
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+    if name.is_empty() || name == "." || name == ".." || name.len() > max_name_len {
+        return Err(FsError::InvalidPath);
+    }
+
+    Ok((parent, name))
+}
+
+// `tree_fs` is an empty tree with the options to keep
+fn tree_from_snapshot(snapshot: &MemFsSnapshot, mut tree_fs: TreeFs<FileAttr>) -> Result<TreeFs<FileAttr>, SnapshotError> {
+    for entry in &snapshot.entries {
+        match entry {
+            SnapshotEntry::Inode { parent, name, is_dir, generation, attr, data, xattrs } => {
+                if attr.ino == 0 || attr.ino > snapshot.current_inode || tree_fs.get_item(attr.ino).is_some() {
+                    return Err(SnapshotError::Corrupt(format!("inode {} is invalid or duplicated", attr.ino)));
+                }
+                if *is_dir != (attr.kind == FileType::Directory) {
+                    return Err(SnapshotError::Corrupt(format!("inode {} has a mismatched type", attr.ino)));
+                }
 
-        match self.create_nod(parent, mode, req, name) {
-            Ok(attr) => {
-                // TODO: implement flags
-                reply.created(
-                    &Duration::new(0, 0),
-                    &attr,
-                    0,
-                    self.allocate_next_file_handle(),
-                    0,
-                );
-            }
-            Err(err) => reply.error(err)
-        }
-    }
-
-    fn copy_file_range(
-        &mut self,
-        _req: &Request<'_>,
-        src_inode: u64,
-        src_fh: u64,
-        src_offset: i64,
-        dest_inode: u64,
-        dest_fh: u64,
-        dest_offset: i64,
-        size: u64,
-        _flags: u32,
-        reply: ReplyWrite,
-    ) {
-        debug!(
-            "copy_file_range() called with src ({}, {}, {}) dest ({}, {}, {}) size={}",
-            src_fh, src_inode, src_offset, dest_fh, dest_inode, dest_offset, size
-        );
-
-        match self.tree_fs.get_item_mut(src_inode) {
-            Some(src) => {
-                match self.tree_fs.get_item_mut(dest_inode) {
-                    Some(dest) => {
-                        let file_size = src.extra.as_ref().unwrap().size;
-                        // Could underflow if file length is less than local_start
-                        let read_size = min(size, file_size.saturating_sub(src_offset as u64));
-
-                        let mut data = vec![0; read_size as usize];
-                        src.data.as_mut().unwrap().set_rpos(src_offset as usize);
-                        src.data.as_mut().unwrap().read(&mut data).unwrap();
-
-                        dest.data.as_mut().unwrap().set_wpos(dest_offset as usize);
-                        dest.data.as_mut().unwrap().write(&data).unwrap();
-
-                        let attr = dest.extra.as_mut().unwrap();
-                        attr.ctime = SystemTime::now();
-                        attr.mtime = SystemTime::now();
-
-                        reply.written(data.len() as u32);
+                let mut item = Item::new(attr.ino, name.clone(), *is_dir, Some(*attr));
+                // version 1 snapshots have none saved, keep the new random one
+                if *generation != 0 {
+                    item.generation = *generation;
+                }
+                item.data = data.clone();
+                item.xattrs = xattrs.clone();
+                if *parent == 0 {
+                    if attr.ino != 1 || tree_fs.get_root().is_some() {
+                        return Err(SnapshotError::Corrupt(String::from("invalid root")));
                     }
-                    None => reply.error(ENOENT)
+                    tree_fs.set_root(item).map_err(|err| SnapshotError::Corrupt(format!("root: {err}")))?;
+                } else {
+                    let parent_node = snapshot_entry_parent(&tree_fs, *parent, name)?;
+                    tree_fs.push(&parent_node, item).map_err(|err| SnapshotError::Corrupt(format!("inode {}: {}", attr.ino, err)))?;
                 }
             }
-            None => reply.error(ENOENT)
+            SnapshotEntry::Link { parent, ino, name } => {
+                let attr = tree_fs.get_item(*ino)
+                    .and_then(|node| node.value.read().unwrap().extra)
+                    .filter(|attr| attr.kind != FileType::Directory)
+                    .ok_or_else(|| SnapshotError::Corrupt(format!("link to missing inode or directory {ino}")))?;
+
+                let mut link = Item::new(*ino, name.clone(), false, Some(attr));
+                link.data = None;
+                let parent_node = snapshot_entry_parent(&tree_fs, *parent, name)?;
+                tree_fs.push_link(&parent_node, link).map_err(|err| SnapshotError::Corrupt(format!("link to inode {ino}: {err}")))?;
+            }
         }
     }
+    if tree_fs.get_root().is_none() {
+        return Err(SnapshotError::Corrupt(String::from("missing root")));
+    }
+
+    Ok(tree_fs)
+}
+
+// the directory `name` goes in, which must be a valid name not taken there yet
+fn snapshot_entry_parent(tree_fs: &TreeFs<FileAttr>, ino: u64, name: &str) -> Result<ItemNode<FileAttr>, SnapshotError> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') || name.contains('\0') {
+        return Err(SnapshotError::Corrupt(format!("invalid name {name:?}")));
+    }
+    let parent = tree_fs.get_item(ino)
+        .filter(|node| node.value.read().unwrap().is_dir)
+        .ok_or_else(|| SnapshotError::Corrupt(format!("parent {ino} is not a directory")))?;
+    if tree_fs.find_child(&parent, name).is_some() {
+        return Err(SnapshotError::Corrupt(format!("{name:?} is duplicated in directory {ino}")));
+    }
+
+    Ok(parent)
 }
 
-fn dir_attr(ino: u64) -> FileAttr {
+fn dir_attr(ino: u64, block_size: u32) -> FileAttr {
     let mut f = FileAttr {
         ino,
-        size: BLOCK_SIZE,
+        size: block_size as u64,
         blocks: 0,
         atime: SystemTime::now(),
         mtime: SystemTime::now(),
         ctime: SystemTime::now(),
-        crtime: UNIX_EPOCH,
+        crtime: SystemTime::now(),
         kind: FileType::Directory,
         perm: 0o777,
         nlink: 2,
@@ -964,22 +4018,55 @@ fn dir_attr(ino: u64) -> FileAttr {
         gid: 0,
         rdev: 0,
         flags: 0,
-        blksize: BLOCK_SIZE as u32,
+        blksize: block_size,
     };
-    f.blocks = (f.size + BLOCK_SIZE - 1) / BLOCK_SIZE;
+    f.blocks = block_count(f.size, block_size);
 
     f
 }
 
-fn file_attr(ino: u64, size: u64) -> FileAttr {
+// symlink targets are kept as bytes, outside unix a path has to be UTF-8
+#[cfg(unix)]
+fn os_str_of(bytes: &[u8]) -> Cow<'_, OsStr> {
+    Cow::Borrowed(OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+fn os_str_of(bytes: &[u8]) -> Cow<'_, OsStr> {
+    Cow::Owned(String::from_utf8_lossy(bytes).into_owned().into())
+}
+
+#[cfg(unix)]
+fn copy_metadata(attr: &mut FileAttr, metadata: &fs::Metadata) {
+    attr.perm = (metadata.mode() & 0o7777) as u16;
+    attr.uid = metadata.uid();
+    attr.gid = metadata.gid();
+    if let Ok(mtime) = metadata.modified() {
+        attr.mtime = mtime;
+    }
+    if let Ok(atime) = metadata.accessed() {
+        attr.atime = atime;
+    }
+    if let Ok(crtime) = metadata.created() {
+        attr.crtime = crtime;
+    }
+}
+
+#[cfg(unix)]
+fn apply_metadata(path: &Path, attr: &FileAttr) -> io::Result<()> {
+    File::open(path)?.set_times(fs::FileTimes::new().set_accessed(attr.atime).set_modified(attr.mtime))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(attr.perm as u32))
+}
+
+fn file_attr(ino: u64, size: u64, block_size: u32) -> FileAttr {
     let mut f = FileAttr {
         ino,
         size,
-        blocks: (size + BLOCK_SIZE - 1) / BLOCK_SIZE,
+        blocks: 0,
         atime: SystemTime::now(),
         mtime: SystemTime::now(),
         ctime: SystemTime::now(),
-        crtime: UNIX_EPOCH,
+        crtime: SystemTime::now(),
         kind: FileType::RegularFile,
         perm: 0o644,
         nlink: 1,
@@ -987,114 +4074,1207 @@ fn file_attr(ino: u64, size: u64) -> FileAttr {
         gid: 0,
         rdev: 0,
         flags: 0,
-        blksize: 512,
+        blksize: block_size,
     };
-    f.blocks = (f.size + BLOCK_SIZE - 1) / BLOCK_SIZE;
+    f.blocks = block_count(f.size, block_size);
 
     f
 }
 
+/// `st_blocks` of `size` bytes stored in whole blocks of `block_size`.
+fn block_count(size: u64, block_size: u32) -> u64 {
+    size.div_ceil(block_size as u64) * (block_size as u64 / STAT_BLOCK_SIZE)
+}
+
+fn mark_pages_dirty(item: &mut Item<FileAttr>, offset: u64, len: u64, page_size: u64) {
+    if len == 0 {
+        return;
+    }
+    let first = offset & !(page_size - 1);
+    let last = (offset + len - 1) & !(page_size - 1);
+    item.dirty_pages.extend((first..=last).step_by(page_size as usize));
+}
+
+/// Like `mark_pages_dirty` but skips the holes, for ranges as large as the file size.
+fn mark_data_pages_dirty(item: &mut Item<FileAttr>, offset: u64, end: u64, page_size: u64) {
+    let mut runs = vec![];
+    let data = item.data.as_ref().unwrap();
+    let mut pos = offset;
+    while let Some(start) = data.next_data(pos).filter(|start| *start < end) {
+        pos = min(data.next_hole(start).unwrap_or(end), end);
+        runs.push((start, pos));
+    }
+    for (start, end) in runs {
+        mark_pages_dirty(item, start, end - start, page_size);
+    }
+}
+
+/// Offset given to the kernel for a directory entry, from hashes of its inode and name and of those of the entry
+/// after it, so the listing resumes at that one if the entry itself is removed. Hard links in one directory share the
+/// inode, the name tells them apart.
+fn dir_cookie(entry: &(u64, FileType, String), next: Option<&(u64, FileType, String)>) -> i64 {
+    // 0 is reserved for the start of the directory
+    ((entry_hash(entry) >> 1).max(1) << 32) | next.map_or(0, entry_hash)
+}
+
+fn entry_hash(entry: &(u64, FileType, String)) -> i64 {
+    let mut hasher = DefaultHasher::new();
+    (entry.0, &entry.2).hash(&mut hasher);
+
+    (hasher.finish() & 0xffff_ffff) as i64
+}
+
+/// Index of the entry following the one `cookie` was given for, offset 0 means start from the beginning.
+/// If that entry was removed since, it's the index of the one that followed it. The listing ends once both are gone.
+fn readdir_seek(entries: &[(u64, FileType, String)], cookie: i64) -> usize {
+    if cookie == 0 {
+        return 0;
+    }
+
+    let (hash, next) = (cookie >> 32, cookie & 0xffff_ffff);
+    if let Some(position) = entries.iter().position(|entry| (entry_hash(entry) >> 1).max(1) == hash) {
+        return position + 1;
+    }
+    // past the last entry there was nothing to follow
+    if next == 0 {
+        return entries.len();
+    }
+
+    entries.iter().position(|entry| entry_hash(entry) == next).unwrap_or(entries.len())
+}
+
+// the change as seen from the directory holding `path`
+fn watch_change(path: &str, mask: WatchMask) -> (String, WatchMask, Option<String>) {
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+    let parent = if parent.is_empty() { "/" } else { parent };
+
+    (parent.to_string(), mask, Some(name.to_string()))
+}
+
+// the pages are hashed where they are, with their index and the length so holes count, without copying the content out
+fn content_hash(data: &FileData) -> u128 {
+    let mut hasher = Xxh3::new();
+    hasher.update(&data.len().to_le_bytes());
+    for (page, bytes) in data.pages() {
+        hasher.update(&page.to_le_bytes());
+        hasher.update(&bytes[..]);
+    }
+
+    hasher.digest128()
+}
+
+// a new entry in a directory with a default ACL gets it as access ACL, limited by its mode, and a directory also as default
+fn inherit_default_acl(item: &mut Item<FileAttr>, default_acl: Option<&[u8]>) {
+    let Some(Ok(entries)) = default_acl.map(parse_posix_acl) else { return };
+    let attr = item.extra.as_mut().unwrap();
+    let access = inherit_acl(&entries, attr.perm);
+    attr.perm = (attr.perm & !0o777) | acl_mode(&access);
+
+    if !is_minimal(&access) {
+        item.xattrs.insert(ACL_ACCESS_XATTR.to_string(), serialize_posix_acl(&access));
+    }
+    if item.is_dir {
+        item.xattrs.insert(ACL_DEFAULT_XATTR.to_string(), default_acl.unwrap().to_vec());
+    }
+}
+
 fn creation_gid(parent: &FileAttr, gid: u32) -> u32 {
-    if parent.perm & libc::S_ISGID as u16 != 0 {
+    if parent.perm & sys::S_ISGID as u16 != 0 {
         return parent.gid;
     }
 
     gid
 }
 
+/// `acl` is the raw `system.posix_acl_access` of the file, used instead of the mode when set and valid.
+#[allow(clippy::too_many_arguments)]
 pub fn check_access(
     file_uid: u32,
     file_gid: u32,
     file_mode: u16,
+    acl: Option<&[u8]>,
     uid: u32,
     gid: u32,
+    supplementary_gids: &[u32],
     mut access_mask: i32,
 ) -> bool {
-    // F_OK tests for existence of file
-    if access_mask == libc::F_OK {
-        return true;
-    }
     let file_mode = i32::from(file_mode);
 
     // root is allowed to read & write anything
     if uid == 0 {
         // root only allowed to exec if one of the X bits is set
-        access_mask &= libc::X_OK;
-        access_mask -= access_mask & (file_mode >> 6);
-        access_mask -= access_mask & (file_mode >> 3);
-        access_mask -= access_mask & file_mode;
+        access_mask &= sys::X_OK;
+        access_mask &= !(file_mode >> 6);
+        access_mask &= !(file_mode >> 3);
+        access_mask &= !file_mode;
         return access_mask == 0;
     }
 
+    if let Some(Ok(entries)) = acl.map(parse_posix_acl) {
+        return acl_permits(&entries, file_uid, file_gid, uid, gid, supplementary_gids, access_mask);
+    }
+
     if uid == file_uid {
-        access_mask -= access_mask & (file_mode >> 6);
-    } else if gid == file_gid {
-        access_mask -= access_mask & (file_mode >> 3);
+        access_mask &= !(file_mode >> 6);
+    } else if gid == file_gid || supplementary_gids.contains(&file_gid) {
+        access_mask &= !(file_mode >> 3);
     } else {
-        access_mask -= access_mask & file_mode;
+        access_mask &= !file_mode;
     }
 
-    return access_mask == 0;
+    access_mask == 0
 }
 
-fn get_groups(pid: u32) -> Vec<u32> {
+#[cfg(feature = "fuse")]
+fn get_groups(pid: u32, uid: u32, gid: u32) -> Vec<u32> {
     #[cfg(not(target_os = "macos"))]
     {
         let path = format!("/proc/{pid}/task/{pid}/status");
-        let file = File::open(path).unwrap();
-        for line in BufReader::new(file).lines() {
-            let line = line.unwrap();
-            if line.starts_with("Groups:") {
-                return line["Groups: ".len()..]
-                    .split(' ')
-                    .filter(|x| !x.trim().is_empty())
-                    .map(|x| x.parse::<u32>().unwrap())
-                    .collect();
+        if let Ok(file) = File::open(path) {
+            for line in BufReader::new(file).lines().map_while(Result::ok) {
+                if let Some(groups) = line.strip_prefix("Groups:") {
+                    return groups
+                        .split_whitespace()
+                        .filter_map(|x| x.parse::<u32>().ok())
+                        .collect();
+                }
             }
         }
     }
 
-    vec![]
+    // the process may be gone already, or there is no /proc, fall back to the groups of the user
+    user_groups(uid, gid)
 }
 
-fn as_file_kind(mut mode: u32) -> FileType {
-    mode &= libc::S_IFMT as u32;
+#[cfg(feature = "fuse")]
+fn user_groups(uid: u32, gid: u32) -> Vec<u32> {
+    unsafe {
+        let passwd = libc::getpwuid(uid);
+        if passwd.is_null() {
+            return vec![];
+        }
 
-    if mode == libc::S_IFREG as u32 {
-        return FileType::RegularFile;
-    } else if mode == libc::S_IFLNK as u32 {
-        return FileType::Symlink;
-    } else if mode == libc::S_IFDIR as u32 {
-        return FileType::Directory;
-    } else {
-        unimplemented!("{}", mode);
+        let mut count: c_int = 64;
+        loop {
+            let mut groups = vec![0; count as usize];
+            let res = libc::getgrouplist((*passwd).pw_name, gid as _, groups.as_mut_ptr(), &mut count);
+            if res >= 0 {
+                groups.truncate(count as usize);
+                return groups;
+            }
+            // count now holds the number of groups needed
+            if count as usize <= groups.len() {
+                count *= 2;
+            }
+        }
+    }
+}
+
+pub fn fuse_allow_other_enabled() -> io::Result<bool> {
+    let file = File::open("/etc/fuse.conf")?;
+    for line in BufReader::new(file).lines() {
+        if line?.trim_start().starts_with("user_allow_other") {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn as_file_kind(mode: u32) -> FileType {
+    match mode as sys::mode_t & sys::S_IFMT {
+        // no type creates a regular file, like mknod(2)
+        0 | sys::S_IFREG => FileType::RegularFile,
+        sys::S_IFLNK => FileType::Symlink,
+        sys::S_IFDIR => FileType::Directory,
+        sys::S_IFCHR => FileType::CharDevice,
+        sys::S_IFBLK => FileType::BlockDevice,
+        sys::S_IFIFO => FileType::NamedPipe,
+        sys::S_IFSOCK => FileType::Socket,
+        _ => unimplemented!("{}", mode),
     }
 }
 
 fn clear_suid_sgid(attr: &mut FileAttr) {
-    attr.perm &= !libc::S_ISUID as u16;
+    attr.perm &= !sys::S_ISUID as u16;
     // SGID is only suppose to be cleared if XGRP is set
-    if attr.perm & libc::S_IXGRP as u16 != 0 {
-        attr.perm &= !libc::S_ISGID as u16;
-    }
-}
-
-// fn generate_sample_tree<'a>() -> TreeFs<FileAttr> {
-//     let mut fs = TreeFs::new();
-//
-//     let root = Item::new(INO_GEN.fetch_add(1, Ordering::SeqCst), String::from("root"), true, Some(dir_attr(INO_GEN.load(Ordering::SeqCst))));
-//     let root = fs.set_root(root);
-//
-//     let dir1 = fs.push(root, Item::new(INO_GEN.fetch_add(1, Ordering::SeqCst), String::from("1"), true, Some(dir_attr(INO_GEN.load(Ordering::SeqCst)))));
-//     fs.push(dir1, Item::new(INO_GEN.fetch_add(1, Ordering::SeqCst), String::from("1.1"), false, Some(file_attr(INO_GEN.load(Ordering::SeqCst), 4))));
-//     fs.push(dir1, Item::new(INO_GEN.fetch_add(1, Ordering::SeqCst), String::from("1.2"), false, Some(file_attr(INO_GEN.load(Ordering::SeqCst), 4))));
-//     fs.push(dir1, Item::new(INO_GEN.fetch_add(1, Ordering::SeqCst), String::from("1.3"), false, Some(file_attr(INO_GEN.load(Ordering::SeqCst), 4))));
-//
-//     let dir2 = fs.push(root, Item::new(INO_GEN.fetch_add(1, Ordering::SeqCst), String::from("2"), true, Some(dir_attr(6))));
-//     fs.push(dir2, Item::new(INO_GEN.fetch_add(1, Ordering::SeqCst), String::from("2.1"), false, Some(file_attr(INO_GEN.load(Ordering::SeqCst), 4))));
-//     fs.push(dir2, Item::new(INO_GEN.fetch_add(1, Ordering::SeqCst), String::from("2.2"), false, Some(file_attr(INO_GEN.load(Ordering::SeqCst), 4))));
-//
-//     fs.push(root, Item::new(9, String::from("3"), false, Some(file_attr(INO_GEN.load(Ordering::SeqCst), 4))));
-//
-//     fs
-// }
+    if attr.perm & sys::S_IXGRP as u16 != 0 {
+        attr.perm &= !sys::S_ISGID as u16;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::acl::{AclEntry, AclTag};
+
+    fn symlink(fs: &mut MemFs, parent: u64, name: &str, target: &str) -> u64 {
+        let mut attr = file_attr(0, target.len() as u64, fs.block_size);
+        attr.kind = FileType::Symlink;
+        attr.perm = 0o777;
+        fs.insert_item(parent, name, attr, Some(Arc::new(FileData::from_bytes(target.as_bytes())))).unwrap()
+    }
+
+    #[test]
+    fn symlinks_resolve_relative_and_absolute_targets() {
+        let mut fs = MemFs::new(false, false);
+        let dir = fs.create_dir_all("/dir/sub", 0o755, 0, 0).unwrap();
+        let file = fs.create_file("/dir/sub/file", b"", 0o644, 0, 0).unwrap();
+        let absolute = symlink(&mut fs, 1, "absolute", "/dir/sub/file");
+        let relative = symlink(&mut fs, dir, "relative", "../sub/./file");
+        let through = symlink(&mut fs, 1, "through", "absolute");
+
+        for link in [absolute, relative, through] {
+            assert_eq!(fs.follow_symlink(link), Ok(file));
+        }
+        assert_eq!(fs.follow_symlink(file), Ok(file));
+        let dangling = symlink(&mut fs, 1, "dangling", "/dir/missing");
+        assert_eq!(fs.follow_symlink(dangling), Err(ENOENT));
+    }
+
+    #[test]
+    fn symlink_loops_fail_with_eloop() {
+        let mut fs = MemFs::new(false, false);
+        let a = symlink(&mut fs, 1, "a", "b");
+        symlink(&mut fs, 1, "b", "a");
+        assert_eq!(fs.follow_symlink(a), Err(sys::ELOOP));
+        let own = symlink(&mut fs, 1, "own", "/own/x");
+        assert_eq!(fs.follow_symlink(own), Err(sys::ELOOP));
+    }
+
+    #[test]
+    fn symlink_chains_stop_at_the_depth_limit() {
+        let mut fs = MemFsBuilder::default().max_symlink_depth(5).build();
+        let file = fs.create_file("/file", b"", 0o644, 0, 0).unwrap();
+        let mut links = vec![];
+        let mut target = String::from("file");
+        for i in 0..6 {
+            links.push(symlink(&mut fs, 1, &format!("l{i}"), &target));
+            target = format!("l{i}");
+        }
+        // l4 takes five hops to reach the file, l5 six
+        assert_eq!(fs.follow_symlink(links[4]), Ok(file));
+        assert_eq!(fs.follow_symlink(links[5]), Err(sys::ELOOP));
+    }
+
+    // what readdir hands the kernel, one page at a time from the cookie of the last entry
+    fn list_in_pages(fs: &MemFs, ino: u64, fh: u64, page: usize) -> Vec<String> {
+        let mut names = vec![];
+        let mut cookie = 0;
+        loop {
+            let entries = fs.dir_page(ino, fh, cookie).unwrap();
+            if entries.is_empty() {
+                return names;
+            }
+            for (i, entry) in entries.iter().take(page).enumerate() {
+                names.push(entry.2.clone());
+                cookie = dir_cookie(entry, entries.get(i + 1));
+            }
+        }
+    }
+
+    #[cfg(feature = "audit")]
+    #[test]
+    fn audit_log_appends_a_line_per_operation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.tsv");
+        let mut fs = MemFs::new(false, false);
+        let file = fs.create_file("/file", b"", 0o644, 0, 0).unwrap();
+
+        fs.audit_log(&path).unwrap();
+        fs.write_audit(1000, 100, 42, OpKind::Lookup, 1, Some(OsStr::new("file")), 0);
+        fs.write_audit(1000, 100, 42, OpKind::Write, file, None, sys::EACCES);
+        // opening it again keeps what was logged
+        fs.audit_log(&path).unwrap();
+        fs.write_audit(0, 0, 7, OpKind::Unlink, 1, Some(OsStr::new("file")), 0);
+
+        let log = fs::read_to_string(&path).unwrap();
+        let records: Vec<Vec<&str>> = log.lines().map(|line| line.split('\t').skip(1).collect()).collect();
+        assert_eq!(records, [
+            ["1000", "100", "42", "lookup", "1", "/file", "0"],
+            ["1000", "100", "42", "write", &file.to_string(), "/file", &sys::EACCES.to_string()],
+            ["0", "0", "7", "unlink", "1", "/file", "0"],
+        ]);
+    }
+
+    #[test]
+    fn mkdir_stores_the_attributes_it_replies_with() {
+        let mut fs = MemFs::new(false, false);
+        let shared = fs.create_dir_all("/shared", 0o2777, 0, 50).unwrap();
+        let attr = fs.make_dir(shared, OsStr::new("dir"), 0o777, 0o022, 1000, 100, &[]).unwrap();
+        assert_eq!(fs.stat("/shared/dir").unwrap(), attr);
+        // the group comes from the setgid parent
+        assert_eq!((attr.perm, attr.uid, attr.gid), (0o755, 1000, 50));
+        assert_eq!(fs.stat("/shared").unwrap().nlink, 3);
+
+        assert_eq!(fs.make_dir(shared, OsStr::new("dir"), 0o755, 0, 1000, 100, &[]), Err(sys::EEXIST));
+        let private = fs.create_dir_all("/private", 0o755, 0, 0).unwrap();
+        assert_eq!(fs.make_dir(private, OsStr::new("dir"), 0o755, 0, 1000, 100, &[]), Err(sys::EACCES));
+        fs.verify_consistency().unwrap();
+    }
+
+    fn lock(owner: u64, start: u64, end: u64, lock_type: i32) -> FileLock {
+        FileLock { owner, pid: owner as u32, start, end, lock_type }
+    }
+
+    #[test]
+    fn byte_range_locks_conflict_only_across_owners() {
+        let mut locks = LockTable::default();
+        locks.set(2, lock(1, 0, 99, sys::F_RDLCK));
+        // readers share, a writer waits for them
+        assert_eq!(locks.conflict(2, &lock(2, 50, 150, sys::F_RDLCK)), None);
+        assert_eq!(locks.conflict(2, &lock(2, 50, 150, sys::F_WRLCK)), Some(&lock(1, 0, 99, sys::F_RDLCK)));
+        assert_eq!(locks.conflict(2, &lock(2, 100, 150, sys::F_WRLCK)), None);
+        assert_eq!(locks.conflict(3, &lock(2, 0, 99, sys::F_WRLCK)), None);
+        // the owner upgrades its own lock
+        assert_eq!(locks.conflict(2, &lock(1, 0, 99, sys::F_WRLCK)), None);
+        locks.set(2, lock(1, 0, 99, sys::F_WRLCK));
+        assert!(locks.conflict(2, &lock(2, 0, 0, sys::F_RDLCK)).is_some());
+        assert_eq!(locks.len(), 1);
+    }
+
+    #[test]
+    fn unlocking_the_middle_of_a_range_splits_it() {
+        let mut locks = LockTable::default();
+        locks.set(2, lock(1, 0, 99, sys::F_WRLCK));
+        locks.set(2, lock(1, 40, 59, sys::F_UNLCK));
+        assert_eq!(locks.len(), 2);
+        assert_eq!(locks.conflict(2, &lock(2, 40, 59, sys::F_WRLCK)), None);
+        assert_eq!(locks.conflict(2, &lock(2, 39, 39, sys::F_RDLCK)), Some(&lock(1, 0, 39, sys::F_WRLCK)));
+        assert_eq!(locks.conflict(2, &lock(2, 60, 60, sys::F_RDLCK)), Some(&lock(1, 60, 99, sys::F_WRLCK)));
+
+        locks.set(2, lock(2, 200, 299, sys::F_RDLCK));
+        // closing the file drops every lock of the owner
+        locks.release_owner(2, 1);
+        assert_eq!(locks.len(), 1);
+        locks.set(2, lock(2, 200, 299, sys::F_UNLCK));
+        assert!(locks.is_empty());
+    }
+
+    #[test]
+    fn appending_handles_never_overwrite_each_other() {
+        let fs = Arc::new(Mutex::new(MemFs::new(false, false)));
+        let ino = fs.lock().unwrap().create_file("/log", b"", 0o666, 0, 0).unwrap();
+        let writers: Vec<_> = ["a", "b"].into_iter().map(|name| {
+            let fs = fs.clone();
+            thread::spawn(move || {
+                let fh = fs.lock().unwrap().open_file(ino, sys::O_WRONLY | sys::O_APPEND, 1000, 1000, &[]).unwrap();
+                for i in 0..100 {
+                    // the kernel sends the offset of its own view of the file
+                    fs.lock().unwrap().write_data(ino, fh, 0, format!("{name}{i:02}\n").as_bytes(), 1000, 1000, &[]).unwrap();
+                }
+            })
+        }).collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let mut fs = fs.lock().unwrap();
+        let log = String::from_utf8(fs.read_file("/log").unwrap()).unwrap();
+        let mut lines: Vec<&str> = log.lines().collect();
+        lines.sort();
+        let expected: Vec<String> = ["a", "b"].iter().flat_map(|name| (0..100).map(move |i| format!("{name}{i:02}"))).collect();
+        assert_eq!(lines, expected);
+        assert_eq!(fs.stat("/log").unwrap().size, log.len() as u64);
+
+        // without O_APPEND the offset is used
+        let fh = fs.open_file(ino, sys::O_WRONLY, 1000, 1000, &[]).unwrap();
+        fs.write_data(ino, fh, 0, b"X", 1000, 1000, &[]).unwrap();
+        assert_eq!(fs.read_file("/log").unwrap()[..4], *b"X00\n");
+    }
+
+    #[test]
+    fn opening_with_o_trunc_empties_the_file() {
+        let mut fs = MemFs::new(false, false);
+        let ino = fs.create_file("/file", &[1; 1024], 0o6775, 1000, 1000).unwrap();
+        let before = fs.stat("/file").unwrap();
+        fs.open_file(ino, sys::O_WRONLY | sys::O_TRUNC, 1000, 1000, &[]).unwrap();
+
+        let attr = fs.stat("/file").unwrap();
+        assert_eq!((attr.size, attr.blocks), (0, 0));
+        assert!(fs.read_file("/file").unwrap().is_empty());
+        assert!(attr.mtime >= before.mtime && attr.ctime >= before.ctime);
+        // writing to the file can't leave it setuid
+        assert_eq!(attr.perm, 0o775);
+        assert_eq!(fs.get_usage(1000).bytes, 0);
+
+        fs.create_file("/other", &[1; 10], 0o644, 1000, 1000).unwrap();
+        let other = fs.tree_fs.find_by_path("/other").unwrap();
+        // reading can't truncate
+        assert_eq!(fs.open_file(other, sys::O_RDONLY | sys::O_TRUNC, 1000, 1000, &[]), Err(sys::EACCES));
+        assert_eq!(fs.open_file(other, sys::O_RDWR | sys::O_TRUNC, 1001, 1001, &[]), Err(sys::EACCES));
+        assert_eq!(fs.read_file("/other").unwrap(), [1; 10]);
+    }
+
+    #[test]
+    fn reads_and_writes_check_the_current_mode() {
+        let mut fs = MemFs::new(false, false);
+        let ino = fs.create_file("/file", b"data", 0o640, 1000, 100).unwrap();
+        let fh = fs.open_file(ino, sys::O_RDWR, 1000, 100, &[]).unwrap();
+        assert_eq!(fs.read_data(ino, fh, 0, 10, 1000, 100, &[]).unwrap(), b"data");
+        assert_eq!(fs.write_data(ino, fh, 4, b"!", 1000, 100, &[]), Ok(1));
+        // a member of the group through its supplementary groups may only read
+        assert_eq!(fs.read_data(ino, fh, 0, 10, 1001, 200, &[100]).unwrap(), b"data!");
+        assert_eq!(fs.write_data(ino, fh, 0, b"x", 1001, 200, &[100]), Err(sys::EACCES));
+        assert_eq!(fs.read_data(ino, fh, 0, 10, 1002, 200, &[]), Err(sys::EACCES));
+
+        // taking the permission away stops an open handle too
+        fs.update_attr(ino, |attr| attr.perm = 0o200);
+        assert_eq!(fs.read_data(ino, fh, 0, 10, 1000, 100, &[]), Err(sys::EACCES));
+        assert_eq!(fs.write_data(ino, fh, 0, b"x", 1000, 100, &[]), Ok(1));
+
+        let read_only = fs.open_file(ino, sys::O_RDONLY, 0, 0, &[]).unwrap();
+        assert_eq!(fs.write_data(ino, read_only, 0, b"x", 0, 0, &[]), Err(sys::EACCES));
+        assert_eq!(fs.read_file("/file").unwrap(), b"xata!");
+    }
+
+    fn rename(fs: &mut MemFs, src: &str, dst: &str, flags: u32) -> Result<(), c_int> {
+        let (parent, name) = fs.resolve_entry(src).unwrap();
+        let (new_parent, new_name) = split_path(dst, fs.max_name_len).unwrap();
+        let new_parent = fs.tree_fs.find_by_path(new_parent).unwrap();
+        fs.rename_entry(parent, OsStr::new(name), new_parent, OsStr::new(new_name), flags, 0, 0)
+    }
+
+    #[test]
+    fn rename_replaces_an_existing_destination() {
+        let mut fs = MemFs::new(false, false);
+        let src = fs.create_file("/src", b"new", 0o644, 0, 0).unwrap();
+        let dst = fs.create_file("/dst", b"old", 0o644, 0, 0).unwrap();
+        rename(&mut fs, "/src", "/dst", 0).unwrap();
+        assert_eq!(fs.read_file("/dst").unwrap(), b"new");
+        assert_eq!(fs.stat("/dst").unwrap().ino, src);
+        assert!(fs.stat("/src").is_err());
+        assert_eq!(fs.path_of(dst), None);
+
+        fs.create_dir_all("/dir/sub", 0o755, 0, 0).unwrap();
+        fs.create_dir_all("/empty", 0o755, 0, 0).unwrap();
+        rename(&mut fs, "/dir", "/empty", 0).unwrap();
+        assert_eq!(fs.list_dir("/empty").unwrap()[0].name, "sub");
+        assert_eq!(fs.stat("/").unwrap().nlink, 3);
+        fs.verify_consistency().unwrap();
+    }
+
+    #[test]
+    fn rename_keeps_a_destination_it_cannot_replace() {
+        let mut fs = MemFs::new(false, false);
+        fs.create_dir_all("/dir", 0o755, 0, 0).unwrap();
+        fs.create_dir_all("/full/sub", 0o755, 0, 0).unwrap();
+        fs.create_file("/file", b"", 0o644, 0, 0).unwrap();
+        assert_eq!(rename(&mut fs, "/dir", "/full", 0), Err(sys::ENOTEMPTY));
+        assert_eq!(rename(&mut fs, "/file", "/dir", 0), Err(sys::EISDIR));
+        assert_eq!(rename(&mut fs, "/dir", "/file", 0), Err(sys::ENOTDIR));
+        assert_eq!(rename(&mut fs, "/full", "/full/sub/inside", 0), Err(sys::EINVAL));
+        assert!(fs.stat("/full/sub").is_ok() && fs.stat("/file").is_ok() && fs.stat("/dir").is_ok());
+        fs.verify_consistency().unwrap();
+    }
+
+    #[test]
+    fn removed_inodes_are_handed_out_again() {
+        let mut fs = MemFs::new(false, false);
+        let count = u16::MAX as u64;
+        // spread over directories, removing an entry scans the list of its directory
+        let path = |i: u64| format!("/{}/{i}", i / 256);
+        for dir in 0..=count / 256 {
+            fs.create_dir_all(&format!("/{dir}"), 0o755, 0, 0).unwrap();
+        }
+        let inodes: BTreeSet<u64> = (0..count).map(|i| fs.create_file(&path(i), b"", 0o644, 0, 0).unwrap()).collect();
+        for i in 0..count {
+            fs.remove_file(&path(i)).unwrap();
+        }
+        let reused: BTreeSet<u64> = (0..count).map(|i| fs.create_file(&format!("{}-new", path(i)), b"", 0o644, 0, 0).unwrap()).collect();
+        assert_eq!(reused, inodes);
+        // the counter only grows once the freed ones are gone
+        assert_eq!(fs.create_file("/last", b"", 0o644, 0, 0), Ok(inodes.last().unwrap() + 1));
+        assert!(!reused.contains(&1));
+    }
+
+    #[test]
+    fn running_out_of_inode_numbers_is_enospc() {
+        let mut fs = MemFs::new(false, false);
+        fs.current_inode.store(u64::MAX - 1, Ordering::Relaxed);
+        assert_eq!(fs.create_file("/a", b"", 0o644, 0, 0), Ok(u64::MAX));
+        assert_eq!(fs.create_file("/b", b"", 0o644, 0, 0), Err(FsError::NoSpace));
+        fs.remove_file("/a").unwrap();
+        assert_eq!(fs.create_file("/b", b"", 0o644, 0, 0), Ok(u64::MAX));
+    }
+
+    #[test]
+    fn inodes_in_use_are_skipped_in_the_free_pool() {
+        let mut fs = MemFs::new(false, false);
+        let a = fs.create_file("/a", b"", 0o644, 0, 0).unwrap();
+        fs.remove_file("/a").unwrap();
+        assert_eq!(fs.create_file("/b", b"", 0o644, 0, 0), Ok(a));
+        // like an inode the inode map handed out while it waited in the pool
+        fs.free_inodes.push(a);
+        let c = fs.create_file("/c", b"", 0o644, 0, 0).unwrap();
+        assert_ne!(c, a);
+        assert!(fs.free_inodes.is_empty());
+
+        let handles: HashSet<u64> = (0..100).map(|_| fs.allocate_next_file_handle()).collect();
+        assert_eq!(handles.len(), 100);
+        fs.verify_consistency().unwrap();
+    }
+
+    // the flags are ENOSYS elsewhere
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn rename_noreplace_and_exchange() {
+        let mut fs = MemFs::new(false, false);
+        let file = fs.create_file("/file", b"file", 0o644, 0, 0).unwrap();
+        let other = fs.create_file("/other", b"other", 0o644, 0, 0).unwrap();
+        assert_eq!(rename(&mut fs, "/file", "/other", RENAME_NOREPLACE), Err(sys::EEXIST));
+        rename(&mut fs, "/file", "/moved", RENAME_NOREPLACE).unwrap();
+        assert_eq!(fs.stat("/moved").unwrap().ino, file);
+
+        rename(&mut fs, "/moved", "/other", RENAME_EXCHANGE).unwrap();
+        assert_eq!((fs.stat("/moved").unwrap().ino, fs.stat("/other").unwrap().ino), (other, file));
+        assert_eq!(rename(&mut fs, "/moved", "/missing", RENAME_EXCHANGE), Err(ENOENT));
+
+        // a directory swapped into another one moves its link to the parent along
+        let dir = fs.create_dir_all("/dir/sub", 0o755, 0, 0).unwrap();
+        rename(&mut fs, "/other", "/dir/sub", RENAME_EXCHANGE).unwrap();
+        assert_eq!(fs.stat("/other").unwrap().ino, dir);
+        assert_eq!(fs.stat("/dir").unwrap().nlink, 2);
+        assert_eq!(fs.stat("/").unwrap().nlink, 4);
+        assert_eq!(fs.read_file("/dir/sub").unwrap(), b"file");
+        fs.verify_consistency().unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn rename_rejects_unknown_flag_combinations() {
+        let mut fs = MemFs::new(false, false);
+        fs.create_file("/a", b"", 0o644, 0, 0).unwrap();
+        fs.create_file("/b", b"", 0o644, 0, 0).unwrap();
+        for flags in [RENAME_NOREPLACE | RENAME_EXCHANGE, RENAME_EXCHANGE | RENAME_WHITEOUT, 8, RENAME_WHITEOUT] {
+            assert_eq!(rename(&mut fs, "/a", "/b", flags), Err(sys::EINVAL), "flags {flags}");
+        }
+        assert!(fs.stat("/a").is_ok());
+    }
+
+    #[test]
+    fn copy_file_range_within_a_file() {
+        let mut fs = MemFs::new(false, false);
+        let ino = fs.create_file("/file", b"0123456789abcdef", 0o644, 0, 0).unwrap();
+        let fh = fs.open_file(ino, sys::O_RDWR, 0, 0, &[]).unwrap();
+        // the destination overlaps the source, what is copied is what was there before
+        assert_eq!(fs.copy_range(ino, 0, ino, fh, 5, 10), Ok(10));
+        assert_eq!(fs.read_file("/file").unwrap(), b"012340123456789f");
+
+        assert_eq!(fs.copy_range(ino, 0, ino, fh, 20, 4), Ok(4));
+        assert_eq!(fs.read_file("/file").unwrap(), b"012340123456789f\x00\x00\x00\x000123");
+        assert_eq!(fs.stat("/file").unwrap().size, 24);
+        // nothing to copy past the end
+        assert_eq!(fs.copy_range(ino, 30, ino, fh, 0, 4), Ok(0));
+        fs.verify_consistency().unwrap();
+    }
+
+    #[test]
+    fn seek_data_and_hole_skip_a_sparse_region() {
+        const MIB: i64 = 1 << 20;
+        let mut fs = MemFs::new(false, false);
+        let ino = fs.create_file("/sparse", b"", 0o644, 0, 0).unwrap();
+        let fh = fs.open_file(ino, sys::O_RDWR, 0, 0, &[]).unwrap();
+        fs.write_data(ino, fh, MIB as u64, b"data", 0, 0, &[]).unwrap();
+
+        assert_eq!(fs.seek(ino, fh, 0, sys::SEEK_HOLE), Ok(0));
+        assert_eq!(fs.seek(ino, fh, 0, sys::SEEK_DATA), Ok(MIB));
+        // the end of the file counts as a hole
+        assert_eq!(fs.seek(ino, fh, MIB, sys::SEEK_HOLE), Ok(MIB + 4));
+        assert_eq!(fs.seek(ino, fh, MIB + 4, sys::SEEK_DATA), Err(sys::ENXIO));
+        assert_eq!(fs.seek(ino, fh, MIB + 4, sys::SEEK_HOLE), Err(sys::ENXIO));
+
+        assert_eq!(fs.seek(ino, fh, -4, sys::SEEK_END), Ok(MIB));
+        assert_eq!(fs.seek(ino, fh, -MIB - 5, sys::SEEK_END), Err(sys::EINVAL));
+        assert_eq!(fs.seek(ino, fh, -1, sys::SEEK_SET), Err(sys::EINVAL));
+        assert_eq!(fs.seek(ino, fh + 1, 0, sys::SEEK_SET), Err(sys::EBADF));
+    }
+
+    #[test]
+    fn watches_see_creates_writes_and_moves_in_the_directory() {
+        let mut fs = MemFs::new(false, false);
+        let dir = fs.create_dir_all("/dir", 0o777, 0, 0).unwrap();
+        let ino = fs.create_file("/dir/file", b"", 0o666, 0, 0).unwrap();
+        let dir_watch = fs.watch("/dir", WatchMask::IN_ALL_EVENTS).unwrap();
+        let file_watch = fs.watch("/dir/file", WatchMask::IN_MODIFY).unwrap();
+        let event = |mask, cookie, name: Option<&str>| InotifyEvent { mask, cookie, name: name.map(String::from) };
+
+        fs.make_dir(dir, OsStr::new("sub"), 0o755, 0, 0, 0, &[]).unwrap();
+        assert_eq!(dir_watch.next_event(), Some(event(WatchMask::IN_CREATE, 0, Some("sub"))));
+        let fh = fs.open_file(ino, sys::O_WRONLY, 0, 0, &[]).unwrap();
+        fs.write_data(ino, fh, 0, b"x", 0, 0, &[]).unwrap();
+        assert_eq!(dir_watch.next_event(), Some(event(WatchMask::IN_MODIFY, 0, Some("file"))));
+        assert_eq!(file_watch.next_event(), Some(event(WatchMask::IN_MODIFY, 0, None)));
+        assert_eq!(dir_watch.next_event(), None);
+
+        // both halves of a rename carry the same cookie
+        fs.rename_entry(dir, OsStr::new("file"), dir, OsStr::new("moved"), 0, 0, 0).unwrap();
+        let from = dir_watch.next_event().unwrap();
+        assert_eq!(from, event(WatchMask::IN_MOVED_FROM, from.cookie, Some("file")));
+        assert_eq!(dir_watch.next_event(), Some(event(WatchMask::IN_MOVED_TO, from.cookie, Some("moved"))));
+
+        // a dropped handle removes its watch on the next event
+        drop(dir_watch);
+        fs.make_dir(dir, OsStr::new("other"), 0o755, 0, 0, 0, &[]).unwrap();
+        assert_eq!(fs.watches.len(), 1);
+        assert!(matches!(fs.watch("/missing", WatchMask::IN_CREATE), Err(FsError::NotFound)));
+    }
+
+    #[test]
+    fn mkdir_inherits_the_default_acl_and_checks_it() {
+        let mut fs = MemFs::new(false, false);
+        let dir = fs.create_dir_all("/dir", 0o755, 0, 0).unwrap();
+        // setfacl -d -m u:1001:rwx /dir
+        let default = serialize_posix_acl(&[
+            AclEntry::new(AclTag::UserObj, 0o7),
+            AclEntry::new(AclTag::User(1001), 0o7),
+            AclEntry::new(AclTag::GroupObj, 0o5),
+            AclEntry::new(AclTag::Mask, 0o7),
+            AclEntry::new(AclTag::Other, 0o5),
+        ]);
+        fs.tree_fs.get_item(dir).unwrap().value.write().unwrap().xattrs.insert(ACL_DEFAULT_XATTR.to_string(), default.clone());
+
+        let sub = fs.make_dir(dir, OsStr::new("sub"), 0o775, 0, 0, 0, &[]).unwrap();
+        // the group bits show the mask
+        assert_eq!(sub.perm, 0o775);
+        let node = fs.tree_fs.get_item(sub.ino).unwrap();
+        let access = parse_posix_acl(&node.value.read().unwrap().xattrs[ACL_ACCESS_XATTR]).unwrap();
+        assert!(access.contains(&AclEntry::new(AclTag::User(1001), 0o7)));
+        assert_eq!(node.value.read().unwrap().xattrs[ACL_DEFAULT_XATTR], default);
+
+        // the named user may create entries, others only get r-x
+        fs.make_dir(sub.ino, OsStr::new("mine"), 0o755, 0, 1001, 1001, &[]).unwrap();
+        assert_eq!(fs.make_dir(sub.ino, OsStr::new("theirs"), 0o755, 0, 1002, 1002, &[]).map(|_| ()), Err(sys::EACCES));
+    }
+
+    #[test]
+    fn readdir_resumes_after_the_last_entry_read() {
+        let mut fs = MemFs::new(false, false);
+        let dir = fs.create_dir_all("/dir", 0o755, 0, 0).unwrap();
+        for i in 0..6 {
+            fs.create_file(&format!("/dir/{i}"), b"", 0o644, 0, 0).unwrap();
+        }
+        // ".", "..", "0", "1" and "2"
+        let first = fs.dir_page(dir, 0, 0).unwrap()[..6].to_vec();
+        let cookie = dir_cookie(&first[4], Some(&first[5]));
+        let names = |fs: &MemFs| fs.dir_page(dir, 0, cookie).unwrap().iter().map(|entry| entry.2.clone()).collect::<Vec<_>>();
+
+        // entries added or removed before the cookie don't shift the rest
+        fs.remove_file("/dir/0").unwrap();
+        fs.create_file("/dir/new", b"", 0o644, 0, 0).unwrap();
+        assert_eq!(names(&fs), ["3", "4", "5", "new"]);
+
+        // with the entry of the cookie gone it resumes at the one that followed, like rm -r removing what it read
+        fs.remove_file("/dir/1").unwrap();
+        fs.remove_file("/dir/2").unwrap();
+        assert_eq!(names(&fs), ["3", "4", "5", "new"]);
+        // and ends once that one is gone too
+        fs.remove_file("/dir/3").unwrap();
+        assert!(names(&fs).is_empty());
+        assert_eq!(readdir_seek(&first, 0), 0);
+    }
+
+    #[test]
+    fn access_checks_each_flag_against_the_mode() {
+        let mut fs = MemFs::new(false, false);
+        let ino = fs.create_file("/file", b"", 0o640, 1000, 100).unwrap();
+        let script = fs.create_file("/script", b"", 0o750, 1000, 100).unwrap();
+        let (read, write, exec) = (sys::R_OK, sys::W_OK, sys::X_OK);
+
+        // F_OK only needs the file to exist, whatever the mode says
+        assert_eq!(fs.access_inode(ino, sys::F_OK, 2000, 2000, &[]), Ok(()));
+        assert_eq!(fs.access_inode(ino + 100, sys::F_OK, 1000, 100, &[]), Err(ENOENT));
+
+        assert_eq!(fs.access_inode(ino, read | write, 1000, 1000, &[]), Ok(()));
+        assert_eq!(fs.access_inode(ino, exec, 1000, 1000, &[]), Err(sys::EACCES));
+        // the group can come from the supplementary groups
+        assert_eq!(fs.access_inode(ino, read, 2000, 2000, &[100]), Ok(()));
+        assert_eq!(fs.access_inode(ino, write, 2000, 2000, &[100]), Err(sys::EACCES));
+        assert_eq!(fs.access_inode(ino, read, 2000, 2000, &[]), Err(sys::EACCES));
+        assert_eq!(fs.access_inode(script, read | exec, 2000, 2000, &[100]), Ok(()));
+
+        // root may read and write anything, but only execute with an x bit set
+        assert_eq!(fs.access_inode(ino, read | write, 0, 0, &[]), Ok(()));
+        assert_eq!(fs.access_inode(ino, exec, 0, 0, &[]), Err(sys::EACCES));
+        assert_eq!(fs.access_inode(script, exec, 0, 0, &[]), Ok(()));
+    }
+
+    // the tree the first versions mounted for manual testing
+    fn generate_sample_tree() -> MemFs {
+        let mut fs = MemFs::new(false, false);
+        fs.create_dir_all("/1", 0o755, 0, 0).unwrap();
+        let dir = fs.create_dir_all("/2", 0o755, 0, 0).unwrap();
+        for path in ["/1/1.1", "/1/1.2", "/1/1.3", "/2/2.1", "/2/2.2", "/3"] {
+            fs.create_file(path, b"data", 0o644, 0, 0).unwrap();
+        }
+        symlink(&mut fs, dir, "link", "../1/1.1");
+
+        fs
+    }
+
+    #[test]
+    fn dump_tree_draws_the_tree_like_tree_1() {
+        let mut out = vec![];
+        generate_sample_tree().dump_tree(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "\
+/
+├── 1/
+│   ├── 1.1 (4 bytes)
+│   ├── 1.2 (4 bytes)
+│   └── 1.3 (4 bytes)
+├── 2/
+│   ├── 2.1 (4 bytes)
+│   ├── 2.2 (4 bytes)
+│   └── link -> ../1/1.1
+└── 3 (4 bytes)
+");
+
+        let mut out = vec![];
+        MemFs::new(false, false).dump_tree(&mut out).unwrap();
+        assert_eq!(out, b"/\n");
+    }
+
+    #[test]
+    fn verify_consistency_reports_each_broken_invariant() {
+        let mut fs = MemFs::new(false, false);
+        let dir = fs.create_dir_all("/dir", 0o755, 0, 0).unwrap();
+        let file = fs.create_file("/dir/file", b"data", 0o644, 0, 0).unwrap();
+        fs.verify_consistency().unwrap();
+
+        fs.update_attr(file, |attr| {
+            attr.nlink = 2;
+            attr.size = 10;
+        });
+        fs.update_attr(dir, |attr| attr.kind = FileType::RegularFile);
+        fs.current_inode.store(file - 1, Ordering::Relaxed);
+        let errors = fs.verify_consistency().unwrap_err();
+        assert!(errors.contains(&ConsistencyError::NlinkMismatch { ino: file, nlink: 2, expected: 1 }));
+        assert!(errors.contains(&ConsistencyError::FileSizeMismatch { ino: file, size: 10, data_len: 4 }));
+        assert!(errors.contains(&ConsistencyError::KindMismatch { ino: dir, kind: FileType::RegularFile }));
+        assert!(errors.contains(&ConsistencyError::InodeAboveCounter { ino: file, current_inode: file - 1 }));
+        assert_eq!(errors.len(), 4);
+    }
+
+    #[test]
+    fn verify_consistency_finds_detached_entries_and_wrong_usage() {
+        let mut fs = MemFs::new(false, false);
+        fs.create_dir_all("/dir", 0o755, 0, 0).unwrap();
+        let file = fs.create_file("/dir/file", b"data", 0o644, 1000, 1000).unwrap();
+        // removing the directory without its content leaves the file mapped
+        let node = fs.tree_fs.resolve_path("/dir").unwrap();
+        fs.tree_fs.remove_child(&fs.tree_fs.get_root().unwrap(), &node).unwrap();
+        fs.usage.lock().unwrap().bytes += 1;
+
+        let errors = fs.verify_consistency().unwrap_err();
+        assert!(errors.contains(&ConsistencyError::Unreachable { ino: file }));
+        assert!(errors.iter().any(|error| matches!(error, ConsistencyError::UsageMismatch { .. })));
+        assert!(errors.iter().all(|error| !error.to_string().is_empty()));
+    }
+
+    #[test]
+    fn truncate_shrinks_grows_and_empties() {
+        let mut fs = MemFsBuilder::default().max_file_size(1000).build();
+        let ino = fs.create_file("/file", b"0123456789", 0o644, 0, 0).unwrap();
+        fs.reflink("/file", "/clone").unwrap();
+
+        assert_eq!(fs.truncate_inode(ino, 5).unwrap().size, 5);
+        assert_eq!(fs.read_file("/file").unwrap(), b"01234");
+        assert_eq!(fs.truncate_inode(ino, 100).unwrap().size, 100);
+        let mut expected = b"01234".to_vec();
+        expected.resize(100, 0);
+        assert_eq!(fs.read_file("/file").unwrap(), expected);
+        assert_eq!(fs.truncate_inode(ino, 0).unwrap().size, 0);
+        assert_eq!(fs.read_file("/file").unwrap(), b"");
+
+        // the clone keeps its own copy
+        assert_eq!(fs.read_file("/clone").unwrap(), b"0123456789");
+        assert_eq!(fs.truncate_inode(ino, 1001).map(|attr| attr.size), Err(sys::EFBIG));
+        fs.verify_consistency().unwrap();
+    }
+
+    #[test]
+    fn reads_update_atime_by_mode_and_writes_update_mtime() {
+        let hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        let days_ago = SystemTime::now() - Duration::from_secs(3 * 24 * 3600);
+        for (mode, after_read) in [(AtimeMode::Strict, true), (AtimeMode::Relatime, false), (AtimeMode::NoAtime, false)] {
+            let mut fs = MemFsBuilder::default().atime_mode(mode).build();
+            let ino = fs.create_file("/file", b"data", 0o644, 0, 0).unwrap();
+            fs.update_attr(ino, |attr| (attr.atime, attr.mtime, attr.ctime) = (hour_ago, hour_ago, hour_ago));
+            let fh = fs.open_file(ino, sys::O_RDWR, 0, 0, &[]).unwrap();
+
+            fs.read_data(ino, fh, 0, 4, 0, 0, &[]).unwrap();
+            assert_eq!(fs.attr_of(ino).unwrap().atime != hour_ago, after_read, "{mode:?}");
+
+            fs.write_data(ino, fh, 0, b"new", 0, 0, &[]).unwrap();
+            let attr = fs.attr_of(ino).unwrap();
+            assert!(attr.mtime > hour_ago && attr.ctime > hour_ago, "{mode:?}");
+
+            // relatime catches up once the file changed since the last access
+            fs.read_data(ino, fh, 0, 4, 0, 0, &[]).unwrap();
+            assert_eq!(fs.attr_of(ino).unwrap().atime >= attr.mtime, mode != AtimeMode::NoAtime, "{mode:?}");
+        }
+
+        // or when the last access is more than a day old
+        let mut fs = MemFs::new(false, false);
+        let ino = fs.create_file("/file", b"data", 0o644, 0, 0).unwrap();
+        fs.update_attr(ino, |attr| (attr.atime, attr.mtime, attr.ctime) = (days_ago, days_ago, days_ago));
+        let fh = fs.open_file(ino, sys::O_RDONLY, 0, 0, &[]).unwrap();
+        fs.read_data(ino, fh, 0, 4, 0, 0, &[]).unwrap();
+        assert!(fs.attr_of(ino).unwrap().atime > hour_ago);
+    }
+
+    #[test]
+    fn names_and_paths_stop_at_their_limits() {
+        let mut fs = MemFs::new(false, false);
+        let longest = "n".repeat(255);
+        let too_long = "n".repeat(256);
+        let dir = fs.make_dir(1, OsStr::new(&longest), 0o755, 0, 0, 0, &[]).unwrap().ino;
+        assert_eq!(fs.make_dir(1, OsStr::new(&too_long), 0o755, 0, 0, 0, &[]).map(|_| ()), Err(sys::ENAMETOOLONG));
+        assert_eq!(fs.make_dir(1, OsStr::new("a\0b"), 0o755, 0, 0, 0, &[]).map(|_| ()), Err(sys::EINVAL));
+        assert_eq!(fs.rename_entry(1, OsStr::new(&longest), 1, OsStr::new(&too_long), 0, 0, 0), Err(sys::ENAMETOOLONG));
+        assert!(matches!(fs.create_file(&format!("/{too_long}"), b"", 0o644, 0, 0), Err(FsError::InvalidPath)));
+        assert_eq!(fs.stat(&format!("/{longest}")).unwrap().ino, dir);
+
+        let mut fs = MemFsBuilder::default().max_name_len(8).max_path_len(12).build();
+        assert_eq!(fs.make_dir(1, OsStr::new("ninechars"), 0o755, 0, 0, 0, &[]).map(|_| ()), Err(sys::ENAMETOOLONG));
+        // "/eightchr/ab" is 12 bytes, one more is past the limit though the kernel can still reach it by name
+        let dir = fs.create_dir_all("/eightchr", 0o755, 0, 0).unwrap();
+        fs.make_dir(dir, OsStr::new("ab"), 0o755, 0, 0, 0, &[]).unwrap();
+        fs.make_dir(dir, OsStr::new("abc"), 0o755, 0, 0, 0, &[]).unwrap();
+        assert!(fs.stat("/eightchr/ab").is_ok());
+        assert!(fs.stat("/eightchr/abc").is_err());
+    }
+
+    #[test]
+    fn case_insensitive_lookups_keep_the_original_case() {
+        for case_sensitive in [true, false] {
+            let mut fs = MemFsBuilder::default().case_sensitive(case_sensitive).build();
+            let ino = fs.create_file("/Foo.txt", b"", 0o644, 0, 0).unwrap();
+
+            assert_eq!(fs.lookup_entry(1, OsStr::new("Foo.txt"), 0, 0, &[]).map(|attr| attr.ino), Ok(ino));
+            let found = fs.lookup_entry(1, OsStr::new("foo.txt"), 0, 0, &[]).map(|attr| attr.ino);
+            assert_eq!(found, if case_sensitive { Err(ENOENT) } else { Ok(ino) });
+            let created = fs.make_dir(1, OsStr::new("FOO.TXT"), 0o755, 0, 0, 0, &[]).map(|_| ());
+            assert_eq!(created, if case_sensitive { Ok(()) } else { Err(sys::EEXIST) });
+
+            let names: Vec<_> = fs.list_dir("/").unwrap().into_iter().map(|entry| entry.name).collect();
+            assert!(names.contains(&String::from("Foo.txt")));
+        }
+    }
+
+    #[test]
+    fn new_nodes_get_the_umask() {
+        let mut fs = MemFs::new(false, false);
+        let file = fs.create_nod(1, sys::S_IFREG | 0o777, fs.creation_umask(0), 1000, 1000, &[], OsStr::new("file")).unwrap();
+        assert_eq!(file.perm, 0o755);
+        assert_eq!(fs.make_dir(1, OsStr::new("dir"), 0o777, 0, 1000, 1000, &[]).unwrap().perm, 0o755);
+        // the umask the kernel sends wins over ours
+        assert_eq!(fs.make_dir(1, OsStr::new("private"), 0o777, 0o077, 1000, 1000, &[]).unwrap().perm, 0o700);
+
+        fs.set_umask(0o027);
+        let file = fs.create_nod(1, sys::S_IFREG | 0o666, fs.creation_umask(0), 1000, 1000, &[], OsStr::new("other")).unwrap();
+        assert_eq!(file.perm, 0o640);
+        fs.verify_consistency().unwrap();
+    }
+
+    #[test]
+    fn create_with_a_persistent_inode_map() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut fs = MemFs::with_persistent_inode_map(&dir.path().join("inodes")).unwrap();
+        fs.ensure_root();
+        // allocating the inode looks up the path of the parent
+        let sub = fs.make_dir(1, OsStr::new("sub"), 0o755, 0, 0, 0, &[]).unwrap();
+        let file = fs.create_nod(sub.ino, sys::S_IFREG | 0o644, 0, 0, 0, &[], OsStr::new("file")).unwrap();
+        assert_eq!(fs.path_of(file.ino).as_deref(), Some("/sub/file"));
+    }
+
+    // rename with the permission checks of the handler
+    fn rename_as(fs: &mut MemFs, src: &str, dst: &str, uid: u32) -> Result<(), c_int> {
+        let (parent, name) = fs.resolve_entry(src).unwrap();
+        let (new_parent, new_name) = split_path(dst, fs.max_name_len).unwrap();
+        let new_parent = fs.tree_fs.find_by_path(new_parent).unwrap();
+        let (name, new_name) = (OsStr::new(name), OsStr::new(new_name));
+        fs.check_rename_access(parent, name, new_parent, new_name, uid, uid, &[])?;
+        fs.rename_entry(parent, name, new_parent, new_name, 0, uid, uid)
+    }
+
+    #[test]
+    fn sticky_directories_keep_others_from_renaming_entries() {
+        let mut fs = MemFs::new(false, false);
+        fs.create_dir_all("/tmp", 0o1777, 0, 0).unwrap();
+        fs.create_dir_all("/home", 0o755, 0, 0).unwrap();
+        fs.create_file("/tmp/theirs", b"", 0o644, 1000, 1000).unwrap();
+        fs.create_file("/tmp/mine", b"", 0o644, 1001, 1001).unwrap();
+
+        assert_eq!(rename_as(&mut fs, "/tmp/theirs", "/tmp/taken", 1001), Err(sys::EACCES));
+        // nor replace their entry with one of its own
+        assert_eq!(rename_as(&mut fs, "/tmp/mine", "/tmp/theirs", 1001), Err(sys::EACCES));
+        // without write access to the destination
+        assert_eq!(rename_as(&mut fs, "/tmp/mine", "/home/mine", 1001), Err(sys::EACCES));
+        rename_as(&mut fs, "/tmp/mine", "/tmp/renamed", 1001).unwrap();
+        rename_as(&mut fs, "/tmp/theirs", "/tmp/moved", 1000).unwrap();
+        rename_as(&mut fs, "/tmp/moved", "/tmp/by_root", 0).unwrap();
+        fs.verify_consistency().unwrap();
+    }
+
+    #[test]
+    fn path_of_every_level_down_to_ten() {
+        let mut fs = MemFs::new(false, false);
+        fs.ensure_root();
+        assert_eq!(fs.path_of(1).as_deref(), Some("/"));
+
+        let mut path = String::new();
+        for level in 1..=10 {
+            path.push_str(&format!("/{level}"));
+            let ino = fs.create_dir_all(&path, 0o755, 0, 0).unwrap();
+            assert_eq!(fs.path_of(ino).as_deref(), Some(path.as_str()));
+        }
+        let file = fs.create_file(&format!("{path}/file"), b"", 0o644, 0, 0).unwrap();
+        assert_eq!(fs.path_of(file), Some(format!("{path}/file")));
+
+        // a removed inode has no path while its number waits in the free pool
+        fs.remove_file(&format!("{path}/file")).unwrap();
+        assert_eq!(fs.path_of(file), None);
+        assert_eq!(fs.create_file("/file", b"", 0o644, 0, 0), Ok(file));
+    }
+
+    #[test]
+    fn fallocate_zeroes_inserts_and_collapses_ranges() {
+        const BLOCK: usize = 4096;
+        let mut fs = MemFs::new(false, false);
+        let content: Vec<u8> = (0..3 * BLOCK).map(|i| (i / BLOCK + 1) as u8).collect();
+        let ino = fs.create_file("/file", &content, 0o644, 0, 0).unwrap();
+        let fh = fs.open_file(ino, sys::O_RDWR, 0, 0, &[]).unwrap();
+        let block = |fs: &mut MemFs, i: usize| fs.read_file("/file").unwrap()[i * BLOCK..(i + 1) * BLOCK].to_vec();
+
+        fs.allocate_range(ino, fh, 10, 20, FALLOC_FL_ZERO_RANGE | FALLOC_FL_KEEP_SIZE, 0, 0, &[]).unwrap();
+        let data = fs.read_file("/file").unwrap();
+        assert_eq!(data.len(), 3 * BLOCK);
+        assert!(data[10..30].iter().all(|&byte| byte == 0));
+        assert_eq!((data[9], data[30]), (1, 1));
+
+        // a block of zeros pushes the second and third block up
+        fs.allocate_range(ino, fh, BLOCK as i64, BLOCK as i64, FALLOC_FL_INSERT_RANGE, 0, 0, &[]).unwrap();
+        assert_eq!(fs.stat("/file").unwrap().size, 4 * BLOCK as u64);
+        assert_eq!(block(&mut fs, 1), [0; BLOCK]);
+        assert_eq!(block(&mut fs, 2), [2; BLOCK]);
+        assert_eq!(block(&mut fs, 3), [3; BLOCK]);
+
+        fs.allocate_range(ino, fh, BLOCK as i64, 2 * BLOCK as i64, FALLOC_FL_COLLAPSE_RANGE, 0, 0, &[]).unwrap();
+        assert_eq!(fs.stat("/file").unwrap().size, 2 * BLOCK as u64);
+        assert_eq!(block(&mut fs, 1), [3; BLOCK]);
+
+        // ranges past the end or not of whole blocks
+        let collapse = |fs: &mut MemFs, offset: usize, len: usize| fs.allocate_range(ino, fh, offset as i64, len as i64, FALLOC_FL_COLLAPSE_RANGE, 0, 0, &[]);
+        assert_eq!(collapse(&mut fs, BLOCK, BLOCK), Err(sys::EINVAL));
+        assert_eq!(collapse(&mut fs, 0, 10), Err(sys::EINVAL));
+        assert_eq!(fs.allocate_range(ino, fh, 2 * BLOCK as i64, BLOCK as i64, FALLOC_FL_INSERT_RANGE, 0, 0, &[]), Err(sys::EINVAL));
+        assert_eq!(fs.allocate_range(ino, fh, 0, 1, FALLOC_FL_INSERT_RANGE | FALLOC_FL_KEEP_SIZE, 0, 0, &[]), Err(sys::EOPNOTSUPP));
+        fs.verify_consistency().unwrap();
+    }
+
+    // the no fuse build, `cargo test --no-default-features --target wasm32-wasip1`
+    #[cfg(target_arch = "wasm32")]
+    #[test]
+    fn creates_reads_and_writes_on_wasm32() {
+        let mut fs = MemFs::new(false, false);
+        fs.create_dir_all("/dir", 0o755, 1000, 1000).unwrap();
+        let ino = fs.create_file("/dir/file", b"hello", 0o644, 1000, 1000).unwrap();
+        assert_eq!(fs.read_file("/dir/file").unwrap(), b"hello");
+
+        let fh = fs.open_file(ino, sys::O_RDWR, 1000, 1000, &[]).unwrap();
+        assert_eq!(fs.write_data(ino, fh, 5, b" world", 1000, 1000, &[]), Ok(6));
+        assert_eq!(fs.read_data(ino, fh, 0, 64, 1000, 1000, &[]).unwrap(), b"hello world");
+        assert_eq!(fs.read_file("/dir/file").unwrap(), b"hello world");
+        assert_eq!(fs.open_file(ino, sys::O_WRONLY, 1001, 1001, &[]), Err(sys::EACCES));
+        fs.verify_consistency().unwrap();
+    }
+
+    #[test]
+    fn fuzz_calls_fail_cleanly() {
+        use fuzz::{Call, Op, Reply};
+
+        let mut fs = fuzz::mounted();
+        let mut apply = |op| Call { uid: 0, gid: 0, op }.apply(&mut fs);
+        let Reply::Data(file) = apply(Op::Mknod { parent: 1, name: "a", mode: sys::S_IFREG | 0o644 }) else { panic!() };
+        let Reply::Data(fh) = apply(Op::Open { ino: file, flags: sys::O_RDWR }) else { panic!() };
+        // past the largest offset, and reading past the end
+        assert_eq!(apply(Op::Write { ino: file, fh, offset: i64::MAX as u64 - 10, len: 100 }), Reply::Error(sys::EFBIG));
+        assert_eq!(apply(Op::Read { ino: file, fh, offset: 1 << 40, size: 4096 }), Reply::Data(0));
+        // the chmod is applied, the rest of the request isn't
+        let setattr = Op::Setattr { ino: file, mode: Some(0o600), uid: Some(1000), gid: None, size: Some(10), atime: None, mtime: None };
+        assert_eq!(apply(setattr), Reply::Ok);
+        let Reply::Data(dir) = apply(Op::Mkdir { parent: 1, name: "b", mode: 0o755 }) else { panic!() };
+        assert_eq!(apply(Op::Rename { parent: 1, name: "a", new_parent: 1, new_name: "a", flags: 0 }), Reply::Ok);
+        assert_eq!(apply(Op::Rename { parent: 1, name: "b", new_parent: dir, new_name: "c", flags: 0 }), Reply::Error(sys::EINVAL));
+        assert_eq!(apply(Op::Rmdir { parent: 99, name: "c" }), Reply::Error(ENOENT));
+        let attr = fs.attr_of(file).unwrap();
+        assert_eq!((attr.perm, attr.uid, attr.size), (0o600, 0, 0));
+
+        for data in [&[][..], &[0xff; 64], &[0, 0, 2, 0, 0, 0, 0, 0, 0, 5, 0, 0, 3, 0xff]] {
+            for call in fuzz::decode(data) {
+                call.apply(&mut fs);
+            }
+        }
+        fs.verify_consistency().unwrap();
+    }
+
+    #[test]
+    fn sparse_clone_checks_like_a_create() {
+        // room for the directories, the file and one clone
+        let mut fs = MemFsBuilder::default().capacity(16384).build();
+        let file = fs.create_file("/file", &[1; 4096], 0o644, 0, 0).unwrap();
+        let dir = fs.create_dir_all("/dir", 0o755, 0, 0).unwrap();
+        let fifo = fs.create_nod(1, sys::S_IFIFO | 0o644, 0, 0, 0, &[], OsStr::new("fifo")).unwrap().ino;
+        let watch = fs.watch("/", WatchMask::IN_CREATE).unwrap();
+
+        assert_eq!(fs.sparse_clone(dir, 1, "dir2"), Err(sys::EISDIR));
+        assert_eq!(fs.sparse_clone(fifo, 1, "fifo2"), Err(sys::EINVAL));
+        assert_eq!(fs.sparse_clone(file, 1, &"x".repeat(300)), Err(sys::ENAMETOOLONG));
+        let clone = fs.sparse_clone(file, 1, "clone").unwrap();
+        assert_eq!(watch.next_event(), Some(InotifyEvent { mask: WatchMask::IN_CREATE, cookie: 0, name: Some(String::from("clone")) }));
+        // the filesystem is full with the clone charged for its data
+        assert_eq!(fs.sparse_clone(clone, 1, "clone2"), Err(sys::ENOSPC));
+        assert_eq!(watch.next_event(), None);
+        fs.verify_consistency().unwrap();
+    }
+
+    #[test]
+    fn listeners_see_entries_created_and_renamed_through_the_api() {
+        let mut fs = MemFs::new(false, false);
+        fs.create_dir_all("/dir", 0o755, 0, 0).unwrap();
+        let events = Arc::new(Mutex::new(vec![]));
+        let seen = events.clone();
+        fs.add_listener(Box::new(move |event| seen.lock().unwrap().push(event)));
+
+        let ino = fs.create_file("/dir/file", b"data", 0o644, 0, 0).unwrap();
+        fs.rename("/dir/file", "/moved").unwrap();
+        assert_eq!(*events.lock().unwrap(), [
+            FsEvent::Created { ino, path: String::from("/dir/file"), kind: FileType::RegularFile },
+            FsEvent::Renamed { old_path: String::from("/dir/file"), new_path: String::from("/moved") },
+        ]);
+    }
+
+    #[test]
+    fn restoring_a_snapshot_drops_the_state_of_the_inodes() {
+        let mut fs = MemFs::new(false, false);
+        let dir = fs.create_dir_all("/dir", 0o755, 0, 0).unwrap();
+        let snapshot = fs.snapshot();
+        let virtual_file = fs.add_virtual_file("/dir/virtual", Box::new(|| b"virtual".to_vec())).unwrap();
+        let dir_events = fs.watch_directory(dir, 0);
+        fs.pending_events.insert(dir, sys::POLLPRI as u32);
+
+        fs.restore_from_snapshot(&snapshot).unwrap();
+        assert!(!fs.virtual_files.contains_key(&virtual_file));
+        assert!(fs.pending_events.is_empty());
+        assert_eq!(dir_events.try_recv(), Err(std::sync::mpsc::TryRecvError::Disconnected));
+        fs.verify_consistency().unwrap();
+    }
+
+    #[test]
+    fn rename_by_path_never_replaces() {
+        let mut fs = MemFs::new(false, false);
+        fs.create_dir_all("/dir/sub", 0o755, 0, 0).unwrap();
+        fs.create_file("/a", b"a", 0o644, 0, 0).unwrap();
+        fs.create_file("/b", b"b", 0o644, 0, 0).unwrap();
+
+        assert_eq!(fs.rename("/a", "/b"), Err(FsError::AlreadyExists));
+        assert_eq!(fs.rename("/dir", "/dir/sub/dir"), Err(FsError::InvalidArgument));
+        assert_eq!(fs.rename("/missing", "/c"), Err(FsError::NotFound));
+        fs.rename("/dir", "/moved").unwrap();
+        assert_eq!(fs.stat("/moved/sub").unwrap().kind, FileType::Directory);
+        assert_eq!(fs.read_file("/b").unwrap(), b"b");
+        fs.verify_consistency().unwrap();
+    }
+
+    #[test]
+    fn dedup_shares_sparse_files_without_reading_the_holes() {
+        let mut fs = MemFs::new(false, false);
+        // a copy of the content would take a TiB
+        let mut files = vec![];
+        for (name, content) in [("a", b"same"), ("b", b"same"), ("c", b"diff")] {
+            let ino = fs.create_file(&format!("/{name}"), content, 0o644, 0, 0).unwrap();
+            fs.truncate_inode(ino, 1 << 40).unwrap();
+            files.push(ino);
+        }
+
+        assert!(fs.dedup_now() > 0);
+        let data = |ino| fs.tree_fs.get_item(ino).unwrap().value.read().unwrap().data.clone().unwrap();
+        assert!(Arc::ptr_eq(&data(files[0]), &data(files[1])));
+        assert!(!Arc::ptr_eq(&data(files[0]), &data(files[2])));
+        fs.verify_consistency().unwrap();
+    }
+
+    #[test]
+    fn dirty_pages_follow_the_page_size() {
+        let mut fs = MemFs::new(false, false);
+        assert_eq!(fs.set_page_size(0), Err(FsError::InvalidArgument));
+        assert_eq!(fs.set_page_size(3000), Err(FsError::InvalidArgument));
+        fs.set_page_size(1024).unwrap();
+        let ino = fs.create_file("/file", b"", 0o644, 0, 0).unwrap();
+        let fh = fs.open_file(ino, sys::O_RDWR, 0, 0, &[]).unwrap();
+
+        fs.write_data(ino, fh, 1000, &[1; 100], 0, 0, &[]).unwrap();
+        assert_eq!(fs.dirty_pages_of(ino), 2);
+        assert_eq!(fs.dirty_page_count(), 2);
+    }
+
+    #[test]
+    fn builder_starts_from_a_snapshot() {
+        let mut fs = MemFs::new(false, false);
+        fs.create_file("/file", b"data", 0o644, 0, 0).unwrap();
+        let snapshot = fs.snapshot();
+
+        let restored = MemFsBuilder::default().root_uid(1000).build_from_snapshot(&snapshot).unwrap();
+        assert_eq!(restored.read_file("/file").unwrap(), b"data");
+        assert_eq!(restored.stat("/").unwrap().uid, 0);
+        restored.verify_consistency().unwrap();
+    }
+
+    #[test]
+    fn print_tree_adds_the_counts_to_the_dump() {
+        let mut fs = MemFs::new(false, false);
+        fs.create_dir_all("/dir/sub", 0o755, 0, 0).unwrap();
+        fs.create_file("/dir/file", b"data", 0o644, 0, 0).unwrap();
+
+        let mut dump = vec![];
+        fs.dump_tree(&mut dump).unwrap();
+        let mut printed = vec![];
+        fs.print_tree(&mut printed).unwrap();
+        assert_eq!(String::from_utf8(printed).unwrap(), format!("{}\n2 directories, 1 files\n", String::from_utf8(dump).unwrap()));
+    }
+
+    #[test]
+    fn readdir_goes_past_the_snapshot() {
+        let mut fs = MemFs::new(false, false);
+        fs.max_dir_entries = 10;
+        let dir = fs.create_dir_all("/dir", 0o755, 0, 0).unwrap();
+        for i in 0..25 {
+            fs.create_file(&format!("/dir/{i}"), b"", 0o644, 0, 0).unwrap();
+        }
+        let snapshot = fs.atomic_dir_snapshot(dir).unwrap();
+        assert_eq!(snapshot.len(), 10);
+        fs.open_dirs.insert(7, snapshot);
+
+        let mut expected = vec![String::from("."), String::from("..")];
+        expected.extend((0..25).map(|i| i.to_string()));
+        for page in [1, 3, 10, 100] {
+            assert_eq!(list_in_pages(&fs, dir, 7, page), expected, "pages of {page}");
+        }
+        // without a snapshot it lists the live directory
+        assert_eq!(list_in_pages(&fs, dir, 8, 4), expected);
+    }
+
+    #[test]
+    fn readdir_keeps_to_the_snapshot_within_the_limit() {
+        let mut fs = MemFs::new(false, false);
+        let dir = fs.create_dir_all("/dir", 0o755, 0, 0).unwrap();
+        fs.create_file("/dir/a", b"", 0o644, 0, 0).unwrap();
+        let snapshot = fs.atomic_dir_snapshot(dir).unwrap();
+        fs.open_dirs.insert(7, snapshot);
+        fs.create_file("/dir/b", b"", 0o644, 0, 0).unwrap();
+
+        assert_eq!(list_in_pages(&fs, dir, 7, 1), [".", "..", "a"]);
+    }
+}
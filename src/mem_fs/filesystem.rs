@@ -0,0 +1,1194 @@
+//! The `fuser::Filesystem` impl that serves a `MemFs` on a mount, everything it calls is in the parent module.
+
+use std::env;
+use std::sync::atomic::Ordering;
+
+#[cfg(feature = "abi-7-21")]
+use fuser::consts::{FUSE_DO_READDIRPLUS, FUSE_FLOCK_LOCKS};
+#[cfg(feature = "abi-7-26")]
+use fuser::consts::FUSE_HANDLE_KILLPRIV;
+use fuser::consts::FOPEN_DIRECT_IO;
+#[cfg(feature = "abi-7-21")]
+use fuser::ReplyPoll;
+use fuser::{Filesystem, KernelConfig, MountOption, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyLock, ReplyLseek, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow};
+
+use super::*;
+use crate::audit::{AuditEntry, OpKind};
+
+impl MemFs {
+    /// Checks the environment can mount with `options`, so a failed mount gets a clear error instead of an IO one.
+    pub fn check_mount_prerequisites(options: &[MountOption]) -> Result<(), PrereqError> {
+        OpenOptions::new().read(true).write(true).open("/dev/fuse").map_err(PrereqError::DevFuse)?;
+
+        // without CAP_SYS_ADMIN the mount goes through the setuid fusermount helper
+        if !has_cap_sys_admin() && find_in_path("fusermount3").or_else(|| find_in_path("fusermount")).is_none() {
+            if unsafe { libc::geteuid() } == 0 {
+                return Err(PrereqError::MissingCapSysAdmin);
+            }
+            return Err(PrereqError::FusermountNotFound);
+        }
+
+        if options.contains(&MountOption::AllowOther) && unsafe { libc::geteuid() } != 0
+            && !fuse_allow_other_enabled().unwrap_or(false) {
+            return Err(PrereqError::UserAllowOtherDisabled);
+        }
+
+        Ok(())
+    }
+
+    /// `FS_IOC_SETFLAGS` from `req`, the owner may change the flags but only root the immutable and append-only ones.
+    fn set_inode_flags(&mut self, req: &Request, ino: u64, in_data: &[u8]) -> Result<(), c_int> {
+        let flags = in_data.get(..4).map(|flags| u32::from_ne_bytes(flags.try_into().unwrap())).ok_or(libc::EINVAL)?;
+        if flags & !SUPPORTED_INODE_FLAGS != 0 {
+            return Err(libc::EOPNOTSUPP);
+        }
+        let attr = self.attr_of(ino).ok_or(ENOENT)?;
+        let changed = flags ^ self.inode_flags_of(ino);
+        if req.uid() != 0 && (req.uid() != attr.uid || changed & (FS_IMMUTABLE_FL | FS_APPEND_FL) != 0) {
+            return Err(libc::EPERM);
+        }
+
+        self.tree_fs.get_item(ino).ok_or(ENOENT)?.value.write().unwrap().inode_flags = flags;
+        self.update_attr(ino, |attr| attr.ctime = SystemTime::now());
+
+        Ok(())
+    }
+
+    fn check_interrupted(&self, req: &Request) -> bool {
+        self.interrupted.contains(&req.unique())
+    }
+
+    // error of a lock request that can't be granted now, an interrupted F_SETLKW gives up with EINTR
+    fn lock_wait_error(&mut self, req: &Request, sleep: bool) -> c_int {
+        if !sleep {
+            libc::EAGAIN
+        } else if self.check_interrupted(req) {
+            libc::EINTR
+        } else {
+            libc::ENOLCK
+        }
+    }
+
+    // called once by every request handler
+    fn audit(&mut self, req: &Request, op: OpKind, ino: u64, name: Option<&OsStr>, errno: c_int) {
+        // the request is answered, an interrupt for it has nothing left to stop
+        self.interrupted.remove(&req.unique());
+        if self.stats_trigger.is_some_and(|trigger| trigger.swap(false, Ordering::Relaxed)) {
+            eprintln!("{}", self.statistics());
+            if let Some(ring) = &self.audit_ring {
+                for entry in ring.last(STATS_AUDIT_ENTRIES) {
+                    eprintln!("{}", entry);
+                }
+            }
+        }
+        if self.audit_ring.is_some() {
+            let entry = AuditEntry {
+                timestamp: SystemTime::now(),
+                op,
+                uid: req.uid(),
+                gid: req.gid(),
+                ino,
+                path: self.entry_path(ino, name),
+                result: if errno == 0 { Ok(()) } else { Err(errno) },
+            };
+            self.audit_ring.as_mut().unwrap().record(entry);
+        }
+        #[cfg(feature = "audit")]
+        self.write_audit(req.uid(), req.gid(), req.pid(), op, ino, name, errno);
+    }
+}
+
+impl Filesystem for MemFs {
+    fn init(
+        &mut self,
+        _req: &Request,
+        #[allow(unused_variables)] config: &mut KernelConfig,
+    ) -> Result<(), c_int> {
+        #[cfg(feature = "abi-7-26")]
+        config.add_capabilities(FUSE_HANDLE_KILLPRIV).unwrap();
+        #[cfg(feature = "abi-7-21")]
+        config.add_capabilities(FUSE_DO_READDIRPLUS).unwrap();
+        #[cfg(feature = "abi-7-21")]
+        config.add_capabilities(FUSE_FLOCK_LOCKS).unwrap();
+
+        self.ensure_root();
+        Ok(())
+    }
+
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        debug!("lookup {}, {}", parent, name.to_str().unwrap());
+
+        let res = self.lookup_entry(parent, name, req.uid(), req.gid(), &request_groups(req));
+        self.audit(req, OpKind::Lookup, parent, Some(name), res.err().unwrap_or(0));
+        match res {
+            Ok(attr) => {
+                debug!("  {:?} {}", attr.kind, attr.ino);
+                reply.entry(&self.entry_ttl_of(attr.ino), &attr, self.generation_of(attr.ino));
+            }
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn forget(&mut self, _req: &Request<'_>, _ino: u64, _nlookup: u64) {
+        debug!("forget() called with {:?} {:?}", _ino, _nlookup);
+    }
+
+    fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
+        debug!("getattr {}", ino);
+
+        match self.attr_of(ino) {
+            Some(attr) => {
+                // the kernel now has the current attributes, they can be cached again
+                self.dirty_inodes.remove(&ino);
+                if attr.kind == FileType::Directory {
+                    debug!("  dir {}", ino);
+                    self.audit(req, OpKind::Getattr, ino, None, 0);
+                    reply.attr(&self.attr_ttl, &attr);
+                } else {
+                    debug!("  file {}", ino);
+                    self.audit(req, OpKind::Getattr, ino, None, 0);
+                    reply.attr(&self.attr_ttl, &attr);
+                }
+            }
+            None => {
+                debug!("  not found");
+                self.audit(req, OpKind::Getattr, ino, None, ENOENT);
+                reply.error(ENOENT)
+            }
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request,
+        inode: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        debug!("setattr() called with {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?}", inode, mode, uid, gid, size, atime, mtime, fh);
+
+        let res = self.set_attr(inode, mode, uid, gid, size, atime, mtime, _crtime, req.uid(), req.gid(), &request_groups(req));
+        self.audit(req, OpKind::Setattr, inode, None, res.err().unwrap_or(0));
+        match res {
+            Ok(attr) => reply.attr(&self.attr_ttl_of(inode), &attr),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn mknod(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        debug!("mknod() called with {:?} {:?} {:o} {:?}", parent, name, mode, rdev);
+
+        let err = match mode as libc::mode_t & libc::S_IFMT {
+            0 | libc::S_IFREG | libc::S_IFDIR | libc::S_IFIFO | libc::S_IFSOCK => 0,
+            // like CAP_MKNOD
+            libc::S_IFCHR | libc::S_IFBLK if req.uid() != 0 => libc::EPERM,
+            libc::S_IFCHR | libc::S_IFBLK => 0,
+            _ => libc::EINVAL,
+        };
+        if err != 0 {
+            self.audit(req, OpKind::Mknod, parent, Some(name), err);
+            reply.error(err);
+            return;
+        }
+
+        let umask = self.creation_umask(umask);
+        match self.create_nod(parent, mode, umask, req.uid(), req.gid(), &request_groups(req), name) {
+            Ok(mut attr) => {
+                if matches!(attr.kind, FileType::CharDevice | FileType::BlockDevice) {
+                    attr = self.update_attr(attr.ino, |attr| attr.rdev = rdev).unwrap();
+                }
+                // TODO: implement flags
+                self.audit(req, OpKind::Mknod, parent, Some(name), 0);
+                reply.entry(&self.entry_ttl, &attr, self.generation_of(attr.ino));
+            }
+            Err(err) => {
+                let err = err.into();
+                self.audit(req, OpKind::Mknod, parent, Some(name), err);
+                reply.error(err)
+            }
+        }
+    }
+    fn symlink(&mut self, req: &Request<'_>, parent: u64, link_name: &OsStr, target: &Path, reply: ReplyEntry) {
+        debug!("symlink() called with {:?} {:?} {:?}", parent, link_name, target);
+
+        // the mode of a symlink is always 0777
+        match self.create_nod(parent, libc::S_IFLNK | 0o777, 0, req.uid(), req.gid(), &request_groups(req), link_name) {
+            Ok(mut attr) => {
+                let target = target.as_os_str().as_bytes();
+                let node = self.tree_fs.get_item(attr.ino).unwrap();
+                let mut item = node.value.write().unwrap();
+                item.data = Some(Arc::new(FileData::from_bytes(target)));
+                attr.size = target.len() as u64;
+                attr.blocks = block_count(attr.size, self.block_size);
+                item.extra = Some(attr);
+                drop(item);
+                self.charge_resize(attr.uid, attr.gid, 0, attr.size);
+
+                self.audit(req, OpKind::Symlink, parent, Some(link_name), 0);
+                reply.entry(&self.entry_ttl, &attr, self.generation_of(attr.ino));
+            }
+            Err(err) => {
+                let err = err.into();
+                self.audit(req, OpKind::Symlink, parent, Some(link_name), err);
+                reply.error(err)
+            }
+        }
+    }
+
+    fn readlink(&mut self, req: &Request<'_>, ino: u64, reply: ReplyData) {
+        debug!("readlink() called on {:?}", ino);
+
+        match self.tree_fs.get_item(ino) {
+            Some(node) => {
+                let (kind, target) = {
+                    let item = node.value.read().unwrap();
+                    (item.extra.as_ref().unwrap().kind, item.data.as_ref().unwrap().to_vec())
+                };
+                // the permissions of a symlink are never checked
+                if kind != FileType::Symlink {
+                    self.audit(req, OpKind::Readlink, ino, None, libc::EINVAL);
+                    reply.error(libc::EINVAL);
+                    return;
+                }
+
+                self.audit(req, OpKind::Readlink, ino, None, 0);
+                reply.data(&target);
+            }
+            None => {
+                self.audit(req, OpKind::Readlink, ino, None, ENOENT);
+                reply.error(ENOENT)
+            }
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        debug!("mkdir() called with {:?} {:?} {:o}", parent, name, mode);
+
+        let res = self.make_dir(parent, name, mode, umask, req.uid(), req.gid(), &request_groups(req));
+        self.audit(req, OpKind::Mkdir, parent, Some(name), res.err().unwrap_or(0));
+        match res {
+            Ok(attr) => reply.entry(&self.entry_ttl, &attr, self.generation_of(attr.ino)),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        new_parent: u64,
+        new_name: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        debug!("rename() called with {:?} {:?} {:?} {:?} {:?}", parent, name, new_parent, new_name, flags);
+
+        // renameat2(2) flags only come from Linux
+        let res = if cfg!(not(target_os = "linux")) && flags != 0 {
+            Err(sys::ENOSYS)
+        } else {
+            self.check_rename_access(parent, name, new_parent, new_name, req.uid(), req.gid(), &request_groups(req))
+        };
+        let res = res.and_then(|_| self.rename_entry(parent, name, new_parent, new_name, flags, req.uid(), req.gid()));
+        self.audit(req, OpKind::Rename, parent, Some(name), res.err().unwrap_or(0));
+        match res {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn link(&mut self, req: &Request<'_>, ino: u64, new_parent: u64, new_name: &OsStr, reply: ReplyEntry) {
+        debug!("link() called with {:?} {:?} {:?}", ino, new_parent, new_name);
+
+        if let Err(err) = self.check_name(new_name) {
+            self.audit(req, OpKind::Link, new_parent, Some(new_name), err);
+            reply.error(err);
+            return;
+        }
+
+        let is_dir = match self.tree_fs.get_item(ino) {
+            Some(node) => node.value.read().unwrap().is_dir,
+            None => {
+                self.audit(req, OpKind::Link, new_parent, Some(new_name), ENOENT);
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        if is_dir || self.inode_flags_of(ino) & (FS_IMMUTABLE_FL | FS_APPEND_FL) != 0 {
+            self.audit(req, OpKind::Link, new_parent, Some(new_name), libc::EPERM);
+            reply.error(libc::EPERM);
+            return;
+        }
+
+        let parent_node = match self.tree_fs.get_item(new_parent) {
+            Some(parent_node) if parent_node.value.read().unwrap().is_dir => parent_node,
+            Some(_) => {
+                self.audit(req, OpKind::Link, new_parent, Some(new_name), libc::ENOTDIR);
+                reply.error(libc::ENOTDIR);
+                return;
+            }
+            None => {
+                self.audit(req, OpKind::Link, new_parent, Some(new_name), ENOENT);
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        let parent_attr = self.attr_of(new_parent).unwrap();
+        if !check_access(parent_attr.uid, parent_attr.gid, parent_attr.perm, self.acl_of(new_parent).as_deref(), req.uid(), req.gid(), &request_groups(req), libc::W_OK) {
+            self.audit(req, OpKind::Link, new_parent, Some(new_name), libc::EACCES);
+            reply.error(libc::EACCES);
+            return;
+        }
+        if self.tree_fs.find_child(&parent_node, new_name.to_str().unwrap()).is_some() {
+            self.audit(req, OpKind::Link, new_parent, Some(new_name), libc::EEXIST);
+            reply.error(libc::EEXIST);
+            return;
+        }
+
+        // the new entry only carries the name, data and attributes stay with the inode
+        let mut link = Item::new(ino, new_name.to_str().unwrap().to_string(), false, self.attr_of(ino));
+        link.data = None;
+        if let Err(err) = self.tree_fs.push_link(&parent_node, link) {
+            let err = FsError::from(err).errno();
+            self.audit(req, OpKind::Link, new_parent, Some(new_name), err);
+            reply.error(err);
+            return;
+        }
+
+        self.update_attr(new_parent, |parent_attr| {
+            parent_attr.ctime = SystemTime::now();
+            parent_attr.mtime = SystemTime::now();
+        });
+
+        let attr = self.update_attr(ino, |attr| {
+            attr.nlink += 1;
+            attr.ctime = SystemTime::now();
+        }).unwrap();
+
+        self.notify_dir_watchers(new_parent, |fs| DirEvent::Created { path: fs.entry_path(new_parent, Some(new_name)), kind: attr.kind });
+        self.emit(|fs| FsEvent::Created { ino, path: fs.entry_path(new_parent, Some(new_name)), kind: attr.kind });
+
+        self.audit(req, OpKind::Link, new_parent, Some(new_name), 0);
+        reply.entry(&self.entry_ttl_of(ino), &attr, self.generation_of(ino));
+    }
+
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!("unlink() called with {:?} {:?}", parent, name);
+
+        let res = self.unlink_entry(parent, name, req.uid(), req.gid(), &request_groups(req));
+        self.audit(req, OpKind::Unlink, parent, Some(name), res.err().unwrap_or(0));
+        match res {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!("rmdir() called with {:?} {:?}", parent, name);
+
+        let res = self.rmdir_entry(parent, name, req.uid(), req.gid(), &request_groups(req));
+        self.audit(req, OpKind::Rmdir, parent, Some(name), res.err().unwrap_or(0));
+        match res {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn read(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock: Option<u64>,
+        reply: ReplyData,
+    ) {
+        debug!("read {} {} {}", ino, offset, size);
+
+        let res = self.read_data(ino, fh, offset as u64, size, req.uid(), req.gid(), &request_groups(req));
+        self.audit(req, OpKind::Read, ino, None, res.as_ref().err().copied().unwrap_or(0));
+        match res {
+            Ok(data) => reply.data(&data),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn write(
+        &mut self,
+        req: &Request,
+        inode: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        #[allow(unused_variables)] flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        debug!("write() called with {:?} size={:?}", inode, data.len());
+
+        assert!(offset >= 0);
+
+        let res = self.write_data(inode, fh, offset as u64, data, req.uid(), req.gid(), &request_groups(req));
+        self.audit(req, OpKind::Write, inode, None, res.err().unwrap_or(0));
+        match res {
+            Ok(written) => reply.written(written),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn setxattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, value: &[u8], flags: i32, _position: u32, reply: ReplyEmpty) {
+        debug!("setxattr() called with {:?} {:?} {:?}", ino, name, flags);
+
+        match self.tree_fs.get_item(ino) {
+            Some(node) => {
+                let name = name.to_string_lossy();
+                let (attr, acl) = {
+                    let item = node.value.read().unwrap();
+                    (*item.extra.as_ref().unwrap(), item.xattrs.get(ACL_ACCESS_XATTR).cloned())
+                };
+                if let Err(err) = check_xattr_access(&attr, acl.as_deref(), &name, req) {
+                    self.audit(req, OpKind::Setxattr, ino, None, err);
+                    reply.error(err);
+                    return;
+                }
+                if value.len() > self.max_xattr_size {
+                    self.audit(req, OpKind::Setxattr, ino, None, libc::ERANGE);
+                    reply.error(libc::ERANGE);
+                    return;
+                }
+
+                let exists = node.value.read().unwrap().xattrs.contains_key(name.as_ref());
+                if flags & libc::XATTR_CREATE != 0 && exists {
+                    self.audit(req, OpKind::Setxattr, ino, None, libc::EEXIST);
+                    reply.error(libc::EEXIST);
+                    return;
+                }
+                if flags & libc::XATTR_REPLACE != 0 && !exists {
+                    self.audit(req, OpKind::Setxattr, ino, None, libc::ENODATA);
+                    reply.error(libc::ENODATA);
+                    return;
+                }
+
+                let acl = if name == ACL_ACCESS_XATTR || name == ACL_DEFAULT_XATTR {
+                    match parse_posix_acl(value) {
+                        Err(err) => Err(err),
+                        Ok(_) if name == ACL_DEFAULT_XATTR && attr.kind != FileType::Directory => Err(libc::EACCES),
+                        Ok(entries) => Ok(Some(entries)),
+                    }
+                } else {
+                    Ok(None)
+                };
+                let acl = match acl {
+                    Ok(acl) => acl,
+                    Err(err) => {
+                        self.audit(req, OpKind::Setxattr, ino, None, err);
+                        reply.error(err);
+                        return;
+                    }
+                };
+
+                {
+                    let mut item = node.value.write().unwrap();
+                    match acl {
+                        // the mode holds the owner, group and other entries, an ACL with only those isn't kept
+                        Some(entries) if name == ACL_ACCESS_XATTR => {
+                            let attr = item.extra.as_mut().unwrap();
+                            attr.perm = (attr.perm & !0o777) | acl_mode(&entries);
+                            if is_minimal(&entries) {
+                                item.xattrs.remove(ACL_ACCESS_XATTR);
+                            } else {
+                                item.xattrs.insert(name.into_owned(), serialize_posix_acl(&entries));
+                            }
+                        }
+                        _ => {
+                            item.xattrs.insert(name.into_owned(), value.to_vec());
+                        }
+                    }
+                    item.extra.as_mut().unwrap().ctime = SystemTime::now();
+                }
+
+                self.emit(|_| FsEvent::AttrChanged { ino });
+                self.audit(req, OpKind::Setxattr, ino, None, 0);
+                reply.ok();
+            }
+            None => {
+                self.audit(req, OpKind::Setxattr, ino, None, ENOENT);
+                reply.error(ENOENT)
+            }
+        }
+    }
+
+    fn getxattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        debug!("getxattr() called with {:?} {:?} {:?}", ino, name, size);
+
+        match self.tree_fs.get_item(ino) {
+            Some(node) => {
+                let name = name.to_string_lossy();
+                if name.starts_with("security.") && req.uid() != 0 {
+                    self.audit(req, OpKind::Getxattr, ino, None, libc::EPERM);
+                    reply.error(libc::EPERM);
+                    return;
+                }
+
+                let value = node.value.read().unwrap().xattrs.get(name.as_ref()).cloned();
+                match value {
+                    Some(value) => {
+                        self.audit(req, OpKind::Getxattr, ino, None, 0);
+                        reply_xattr(reply, &value, size);
+                    }
+                    None => {
+                        self.audit(req, OpKind::Getxattr, ino, None, libc::ENODATA);
+                        reply.error(libc::ENODATA);
+                    }
+                }
+            }
+            None => {
+                self.audit(req, OpKind::Getxattr, ino, None, ENOENT);
+                reply.error(ENOENT)
+            }
+        }
+    }
+
+    fn listxattr(&mut self, req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        debug!("listxattr() called with {:?} {:?}", ino, size);
+
+        match self.tree_fs.get_item(ino) {
+            Some(node) => {
+                let mut names = vec![];
+                for name in node.value.read().unwrap().xattrs.keys() {
+                    names.extend_from_slice(name.as_bytes());
+                    names.push(0);
+                }
+
+                self.audit(req, OpKind::Listxattr, ino, None, 0);
+                reply_xattr(reply, &names, size);
+            }
+            None => {
+                self.audit(req, OpKind::Listxattr, ino, None, ENOENT);
+                reply.error(ENOENT)
+            }
+        }
+    }
+
+    fn removexattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!("removexattr() called with {:?} {:?}", ino, name);
+
+        match self.tree_fs.get_item(ino) {
+            Some(node) => {
+                let name = name.to_string_lossy();
+                let (attr, acl) = {
+                    let item = node.value.read().unwrap();
+                    (*item.extra.as_ref().unwrap(), item.xattrs.get(ACL_ACCESS_XATTR).cloned())
+                };
+                if let Err(err) = check_xattr_access(&attr, acl.as_deref(), &name, req) {
+                    self.audit(req, OpKind::Removexattr, ino, None, err);
+                    reply.error(err);
+                    return;
+                }
+
+                let removed = {
+                    let mut item = node.value.write().unwrap();
+                    let removed = item.xattrs.remove(name.as_ref()).is_some();
+                    if removed {
+                        item.extra.as_mut().unwrap().ctime = SystemTime::now();
+                    }
+                    removed
+                };
+                if !removed {
+                    self.audit(req, OpKind::Removexattr, ino, None, libc::ENODATA);
+                    reply.error(libc::ENODATA);
+                    return;
+                }
+
+                self.emit(|_| FsEvent::AttrChanged { ino });
+                self.audit(req, OpKind::Removexattr, ino, None, 0);
+                reply.ok();
+            }
+            None => {
+                self.audit(req, OpKind::Removexattr, ino, None, ENOENT);
+                reply.error(ENOENT)
+            }
+        }
+    }
+
+    fn flush(&mut self, req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        debug!("flush() called with {:?} {:?} {:?}", ino, fh, lock_owner);
+
+        self.audit(req, OpKind::Flush, ino, None, 0);
+        reply.ok();
+    }
+
+    fn fsync(&mut self, req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        debug!("fsync() called with {:?} {:?} {:?}", ino, fh, datasync);
+
+        match self.tree_fs.get_item(ino) {
+            Some(node) => {
+                // there is no backing store, the pages are clean once the kernel asked for them to be synced
+                node.value.write().unwrap().dirty_pages.clear();
+
+                match &self.sync_policy {
+                    SyncPolicy::Noop => {}
+                    SyncPolicy::TriggerSnapshot => {
+                        if !datasync {
+                            self.last_snapshot = Some(self.snapshot());
+                        }
+                    }
+                    SyncPolicy::Custom(sync) => sync(ino),
+                }
+                if let Some(Err(err)) = self.inode_map.as_mut().map(|map| map.save()) {
+                    warn!("fsync() could not save the inode map: {}", err);
+                    self.audit(req, OpKind::Fsync, ino, None, libc::EIO);
+                    reply.error(libc::EIO);
+                    return;
+                }
+
+                self.audit(req, OpKind::Fsync, ino, None, 0);
+                reply.ok();
+            }
+            None => {
+                self.audit(req, OpKind::Fsync, ino, None, ENOENT);
+                reply.error(ENOENT);
+            }
+        }
+    }
+
+    fn release(&mut self, req: &Request<'_>, ino: u64, fh: u64, _flags: i32, lock_owner: Option<u64>, _flush: bool, reply: ReplyEmpty) {
+        debug!("release() called with {:?} {:?} {:?}", ino, fh, lock_owner);
+
+        if self.open_files.remove(&fh).is_none() {
+            self.audit(req, OpKind::Release, ino, None, libc::EBADF);
+            reply.error(libc::EBADF);
+            return;
+        }
+
+        if let Some(owner) = lock_owner {
+            self.locks.release_owner(ino, owner);
+        }
+        self.flock_table.remove(&fh);
+        // the file may have been unlinked while open
+        self.recycle_inode(ino);
+
+        self.audit(req, OpKind::Release, ino, None, 0);
+        reply.ok();
+    }
+
+    fn getlk(&mut self, req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: i32, pid: u32, reply: ReplyLock) {
+        debug!("getlk() called with {:?} {:?} {:?} {:?}-{:?} {:?}", ino, fh, lock_owner, start, end, typ);
+
+        if is_flock(start, end) {
+            self.audit(req, OpKind::Getlk, ino, None, 0);
+            match self.flock_conflict(ino, fh, typ) {
+                Some(FlockState::Shared(_)) => reply.locked(0, FLOCK_END, libc::F_RDLCK, 0),
+                Some(FlockState::Exclusive) => reply.locked(0, FLOCK_END, libc::F_WRLCK, 0),
+                None => reply.locked(start, end, libc::F_UNLCK, 0),
+            }
+            return;
+        }
+
+        let lock = FileLock { owner: lock_owner, pid, start, end, lock_type: typ };
+        self.audit(req, OpKind::Getlk, ino, None, 0);
+        match self.locks.conflict(ino, &lock) {
+            Some(held) => reply.locked(held.start, held.end, held.lock_type, held.pid),
+            None => reply.locked(start, end, libc::F_UNLCK, 0),
+        }
+    }
+
+    fn setlk(&mut self, req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: i32, pid: u32, sleep: bool, reply: ReplyEmpty) {
+        debug!("setlk() called with {:?} {:?} {:?} {:?}-{:?} {:?} {:?}", ino, fh, lock_owner, start, end, typ, sleep);
+
+        if is_flock(start, end) {
+            if self.flock_conflict(ino, fh, typ).is_some() {
+                let err = self.lock_wait_error(req, sleep);
+                self.audit(req, OpKind::Setlk, ino, None, err);
+                reply.error(err);
+                return;
+            }
+
+            match typ {
+                libc::F_RDLCK => {
+                    let count = match self.flock_table.get(&fh) {
+                        Some(FlockState::Shared(count)) => count + 1,
+                        _ => 1,
+                    };
+                    self.flock_table.insert(fh, FlockState::Shared(count));
+                }
+                libc::F_WRLCK => {
+                    self.flock_table.insert(fh, FlockState::Exclusive);
+                }
+                _ => {
+                    self.flock_table.remove(&fh);
+                }
+            }
+            self.audit(req, OpKind::Setlk, ino, None, 0);
+            reply.ok();
+            return;
+        }
+
+        let lock = FileLock { owner: lock_owner, pid, start, end, lock_type: typ };
+        if typ != libc::F_UNLCK && self.locks.conflict(ino, &lock).is_some() {
+            // waiting for the lock (F_SETLKW) is not supported yet
+            let err = self.lock_wait_error(req, sleep);
+            self.audit(req, OpKind::Setlk, ino, None, err);
+            reply.error(err);
+            return;
+        }
+
+        self.locks.set(ino, lock);
+        self.audit(req, OpKind::Setlk, ino, None, 0);
+        reply.ok();
+    }
+
+    fn fallocate(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, length: i64, mode: i32, reply: ReplyEmpty) {
+        debug!("fallocate() called with {:?} {:?} {:?} {:?} {:?}", ino, fh, offset, length, mode);
+
+        let res = self.allocate_range(ino, fh, offset, length, mode, req.uid(), req.gid(), &request_groups(req));
+        self.audit(req, OpKind::Fallocate, ino, None, res.err().unwrap_or(0));
+        match res {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    /// `FS_IOC_GETFLAGS` and `FS_IOC_SETFLAGS` read and change the inode flags (`chattr`), their 32-bit variants too.
+    /// Answers `QCMD(Q_GETQUOTA, USRQUOTA)` and `QCMD(Q_GETQUOTA, GRPQUOTA)` with a `struct dqblk`, for the id in `in_data` or the caller's.
+    /// Anything else fails with `ENOTTY`.
+    fn ioctl(&mut self, req: &Request<'_>, ino: u64, _fh: u64, _flags: u32, cmd: u32, in_data: &[u8], out_size: u32, reply: ReplyIoctl) {
+        debug!("ioctl() called with {:?} {:#x} {:?}", ino, cmd, out_size);
+
+        // both are restricted ioctls, the kernel sizes the buffers from cmd so there is nothing to retry
+        match cmd {
+            FS_IOC_GETFLAGS | FS_IOC32_GETFLAGS => {
+                let Some(node) = self.tree_fs.get_item(ino) else {
+                    self.audit(req, OpKind::Ioctl, ino, None, ENOENT);
+                    reply.error(ENOENT);
+                    return;
+                };
+                let flags = node.value.read().unwrap().inode_flags;
+                self.audit(req, OpKind::Ioctl, ino, None, 0);
+                reply.ioctl(0, &flags.to_ne_bytes());
+                return;
+            }
+            FS_IOC_SETFLAGS | FS_IOC32_SETFLAGS => {
+                let result = self.set_inode_flags(req, ino, in_data);
+                self.audit(req, OpKind::Ioctl, ino, None, result.err().unwrap_or(0));
+                match result {
+                    Ok(()) => reply.ioctl(0, &[]),
+                    Err(err) => reply.error(err),
+                }
+                return;
+            }
+            _ => {}
+        }
+
+        let quota_type = match cmd as c_int {
+            cmd if cmd == libc::QCMD(libc::Q_GETQUOTA, USRQUOTA) => USRQUOTA,
+            cmd if cmd == libc::QCMD(libc::Q_GETQUOTA, GRPQUOTA) => GRPQUOTA,
+            _ => {
+                self.audit(req, OpKind::Ioctl, ino, None, libc::ENOTTY);
+                reply.error(libc::ENOTTY);
+                return;
+            }
+        };
+        let id = match in_data.get(..4) {
+            Some(id) => u32::from_ne_bytes(id.try_into().unwrap()),
+            None if quota_type == USRQUOTA => req.uid(),
+            None => req.gid(),
+        };
+        let (limit, usage) = if quota_type == USRQUOTA {
+            (self.quotas.per_uid.get(&id).copied(), self.get_usage(id))
+        } else {
+            (self.quotas.per_gid.get(&id).copied(), self.get_group_usage(id))
+        };
+
+        // 0 means no limit
+        let (max_blocks, max_files) = limit.map_or((0, 0), |limit| (limit.max_bytes.div_ceil(QIF_DQBLKSIZE), limit.max_files));
+        let mut dqblk = vec![];
+        for field in [max_blocks, max_blocks, usage.bytes, max_files, max_files, usage.files, 0, 0] {
+            dqblk.extend_from_slice(&field.to_ne_bytes());
+        }
+        dqblk.extend_from_slice(&(libc::QIF_LIMITS | libc::QIF_USAGE).to_ne_bytes());
+        dqblk.resize(size_of::<libc::dqblk>(), 0);
+        dqblk.truncate(out_size as usize);
+
+        self.audit(req, OpKind::Ioctl, ino, None, 0);
+        reply.ioctl(0, &dqblk);
+    }
+
+    /// Always readable and writable, plus the events pending since the last poll, limited to the requested `events`.
+    #[cfg(feature = "abi-7-21")]
+    fn poll(&mut self, req: &Request<'_>, ino: u64, fh: u64, _kh: u64, events: u32, _flags: u32, reply: ReplyPoll) {
+        debug!("poll() called with {:?} {:?} {:#x}", ino, fh, events);
+
+        if self.tree_fs.get_item(ino).is_none() {
+            self.audit(req, OpKind::Poll, ino, None, ENOENT);
+            reply.error(ENOENT);
+            return;
+        }
+        let ready = (libc::POLLIN | libc::POLLOUT) as u32 | self.pending_events.remove(&ino).unwrap_or(0);
+
+        self.audit(req, OpKind::Poll, ino, None, 0);
+        reply.poll(ready & events);
+    }
+
+    /// The kernel resolves `SEEK_SET`, `SEEK_CUR` and `SEEK_END` itself, no file position is kept here so `SEEK_CUR` is taken from the start.
+    fn lseek(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, whence: i32, reply: ReplyLseek) {
+        debug!("lseek() called with {:?} {:?} {:?} {:?}", ino, fh, offset, whence);
+
+        let res = self.seek(ino, fh, offset, whence);
+        self.audit(req, OpKind::Lseek, ino, None, res.err().unwrap_or(0));
+        match res {
+            Ok(offset) => reply.offset(offset),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn statfs(&mut self, req: &Request<'_>, ino: u64, reply: ReplyStatfs) {
+        debug!("statfs() called with {:?}", ino);
+
+        let block_size = self.block_size as u64;
+        let blocks = self.capacity_bytes / block_size;
+        // what `check_space` counts, so the free blocks reported can be written
+        let free = blocks.saturating_sub(self.total_used_bytes() / block_size);
+        let files = self.tree_fs.len() as u64;
+
+        self.audit(req, OpKind::Statfs, ino, None, 0);
+        reply.statfs(
+            blocks,
+            free,
+            free,
+            files,
+            self.max_inodes.saturating_sub(files),
+            self.block_size,
+            self.max_name_len as u32,
+            self.block_size,
+        );
+    }
+
+    fn opendir(&mut self, req: &Request, inode: u64, flags: i32, reply: ReplyOpen) {
+        debug!("opendir() called on {:?}", inode);
+
+        let (access_mask, _read, _write) = match flags & libc::O_ACCMODE {
+            libc::O_RDONLY => {
+                // Behavior is undefined, but most filesystems return EACCES
+                if flags & libc::O_TRUNC != 0 {
+                    self.audit(req, OpKind::Opendir, inode, None, libc::EACCES);
+                    reply.error(libc::EACCES);
+                    return;
+                }
+                (libc::R_OK, true, false)
+            }
+            libc::O_WRONLY => (libc::W_OK, false, true),
+            libc::O_RDWR => (libc::R_OK | libc::W_OK, true, true),
+            // Exactly one access mode flag must be specified
+            _ => {
+                self.audit(req, OpKind::Opendir, inode, None, libc::EINVAL);
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+
+        match self.attr_of(inode) {
+            Some(attr) => {
+                if check_access(
+                    attr.uid,
+                    attr.gid,
+                    attr.perm,
+                    self.acl_of(inode).as_deref(),
+                    req.uid(),
+                    req.gid(),
+                    &request_groups(req),
+                    access_mask,
+                ) {
+                    let entries = match self.atomic_dir_snapshot(inode) {
+                        Some(entries) => entries,
+                        None => {
+                            self.audit(req, OpKind::Opendir, inode, None, libc::ENOTDIR);
+                            reply.error(libc::ENOTDIR);
+                            return;
+                        }
+                    };
+                    let fh = self.allocate_next_file_handle();
+                    self.open_dirs.insert(fh, entries);
+
+                    let open_flags = if self.direct_io { FOPEN_DIRECT_IO } else { 0 };
+                    self.audit(req, OpKind::Opendir, inode, None, 0);
+                    reply.opened(fh, open_flags);
+                } else {
+                    self.audit(req, OpKind::Opendir, inode, None, libc::EACCES);
+                    reply.error(libc::EACCES);
+                }
+            }
+            None => {
+                self.audit(req, OpKind::Opendir, inode, None, ENOENT);
+                reply.error(ENOENT)
+            }
+        }
+    }
+
+
+    fn readdir(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        debug!("readdir {} {} {}", ino, fh, offset);
+
+        let Some(entries) = self.dir_page(ino, fh, offset) else {
+            self.audit(req, OpKind::Readdir, ino, None, ENOENT);
+            reply.error(ENOENT);
+            return;
+        };
+
+        for (i, entry) in entries.iter().enumerate() {
+            if reply.add(entry.0, dir_cookie(entry, entries.get(i + 1)), entry.1, &entry.2) {
+                break;
+            }
+        }
+
+        self.audit(req, OpKind::Readdir, ino, None, 0);
+        reply.ok();
+    }
+
+    fn readdirplus(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        debug!("readdirplus {} {} {}", ino, fh, offset);
+
+        // same pagination as readdir
+        let Some(entries) = self.dir_page(ino, fh, offset) else {
+            self.audit(req, OpKind::Readdirplus, ino, None, ENOENT);
+            reply.error(ENOENT);
+            return;
+        };
+
+        for (i, entry) in entries.iter().enumerate() {
+            // entries removed since the snapshot was taken are skipped
+            let attr = match self.attr_of(entry.0) {
+                Some(attr) => attr,
+                None => continue,
+            };
+            if reply.add(entry.0, dir_cookie(entry, entries.get(i + 1)), &entry.2, &self.entry_ttl_of(entry.0), &attr, self.generation_of(entry.0)) {
+                break;
+            }
+        }
+
+        self.audit(req, OpKind::Readdirplus, ino, None, 0);
+        reply.ok();
+    }
+
+    fn releasedir(
+        &mut self,
+        req: &Request<'_>,
+        inode: u64,
+        fh: u64,
+        _flags: i32,
+        reply: ReplyEmpty,
+    ) {
+        debug!("releasedir() called with {:?} {:?}", inode, fh);
+
+        self.open_dirs.remove(&fh);
+
+        match self.tree_fs.get_item(inode) {
+            Some(_) => {
+                self.audit(req, OpKind::Releasedir, inode, None, 0);
+                reply.ok()
+            }
+            None => {
+                self.audit(req, OpKind::Releasedir, inode, None, ENOENT);
+                reply.error(ENOENT)
+            }
+        }
+    }
+
+    fn access(&mut self, req: &Request, inode: u64, mask: i32, reply: ReplyEmpty) {
+        debug!("access() called with {:?} {:?}", inode, mask);
+
+        let res = self.access_inode(inode, mask, req.uid(), req.gid(), &request_groups(req));
+        self.audit(req, OpKind::Access, inode, None, res.err().unwrap_or(0));
+        match res {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn open(&mut self, req: &Request, inode: u64, flags: i32, reply: ReplyOpen) {
+        debug!("open() called for {:?}", inode);
+
+        let res = self.open_file(inode, flags, req.uid(), req.gid(), &request_groups(req));
+        self.audit(req, OpKind::Open, inode, None, res.err().unwrap_or(0));
+        match res {
+            Ok(fh) => {
+                // virtual and hooked files report size 0, without direct I/O the kernel would not read past it
+                // and would serve hooked content from its cache
+                let open_flags = if self.direct_io || self.is_virtual(inode) || self.find_vfs_hook(inode).is_some() { FOPEN_DIRECT_IO } else { 0 };
+                reply.opened(fh, open_flags);
+            }
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn create(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        debug!("create() called with {:?} {:?}", parent, name);
+
+        let (read, write) = match flags & libc::O_ACCMODE {
+            libc::O_RDONLY => (true, false),
+            libc::O_WRONLY => (false, true),
+            libc::O_RDWR => (true, true),
+            // Exactly one access mode flag must be specified
+            _ => {
+                self.audit(req, OpKind::Create, parent, Some(name), libc::EINVAL);
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+
+        let umask = self.creation_umask(umask);
+        match self.create_nod(parent, mode, umask, req.uid(), req.gid(), &request_groups(req), name) {
+            Ok(attr) => {
+                let fh = self.allocate_next_file_handle();
+                self.open_files.insert(fh, OpenFileState { ino: attr.ino, flags, read, write, generation: self.generation_of(attr.ino) });
+                self.audit(req, OpKind::Create, parent, Some(name), 0);
+                // TODO: implement flags
+                reply.created(
+                    &self.entry_ttl,
+                    &attr,
+                    self.generation_of(attr.ino),
+                    fh,
+                    0,
+                );
+            }
+            Err(err) => {
+                let err = err.into();
+                self.audit(req, OpKind::Create, parent, Some(name), err);
+                reply.error(err)
+            }
+        }
+    }
+
+    fn copy_file_range(
+        &mut self,
+        req: &Request<'_>,
+        src_inode: u64,
+        src_fh: u64,
+        src_offset: i64,
+        dest_inode: u64,
+        dest_fh: u64,
+        dest_offset: i64,
+        size: u64,
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        debug!(
+            "copy_file_range() called with src ({}, {}, {}) dest ({}, {}, {}) size={}",
+            src_fh, src_inode, src_offset, dest_fh, dest_inode, dest_offset, size
+        );
+
+        let res = self.copy_range(src_inode, src_offset, dest_inode, dest_fh, dest_offset, size);
+        self.audit(req, OpKind::CopyFileRange, dest_inode, None, res.err().unwrap_or(0));
+        match res {
+            Ok(len) => reply.written(len as u32),
+            Err(err) => reply.error(err),
+        }
+    }
+}
+
+// size 0 asks only for the length of the value
+fn reply_xattr(reply: ReplyXattr, value: &[u8], size: u32) {
+    if size == 0 {
+        reply.size(value.len() as u32);
+    } else if value.len() > size as usize {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(value);
+    }
+}
+
+fn check_xattr_access(attr: &FileAttr, acl: Option<&[u8]>, name: &str, req: &Request) -> Result<(), c_int> {
+    if name.starts_with("security.") && req.uid() != 0 {
+        return Err(libc::EPERM);
+    }
+    // like chmod, only the owner can change the ACLs
+    if (name == ACL_ACCESS_XATTR || name == ACL_DEFAULT_XATTR) && req.uid() != 0 && req.uid() != attr.uid {
+        return Err(libc::EPERM);
+    }
+    if !check_access(attr.uid, attr.gid, attr.perm, acl, req.uid(), req.gid(), &request_groups(req), libc::W_OK) {
+        return Err(libc::EACCES);
+    }
+
+    Ok(())
+}
+
+fn request_groups(req: &Request) -> Vec<u32> {
+    get_groups(req.pid(), req.uid(), req.gid())
+}
+
+fn has_cap_sys_admin() -> bool {
+    #[cfg(not(target_os = "macos"))]
+    {
+        if let Ok(file) = File::open("/proc/self/status") {
+            for line in BufReader::new(file).lines().map_while(Result::ok) {
+                if let Some(caps) = line.strip_prefix("CapEff:") {
+                    return u64::from_str_radix(caps.trim(), 16)
+                        .is_ok_and(|caps| caps & (1 << CAP_SYS_ADMIN) != 0);
+                }
+            }
+        }
+    }
+
+    unsafe { libc::geteuid() == 0 }
+}
+
+fn find_in_path(program: &str) -> Option<PathBuf> {
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())
+}
@@ -0,0 +1,233 @@
+//! Request handler calls decoded from fuzzer bytes, for the `fuse_ops` target in `fuzz/`. A `Request` only comes from
+//! the kernel, so each call goes to the helper behind its handler with the caller's uid and gid taken from the bytes.
+
+use std::ffi::OsStr;
+use std::os::raw::c_int;
+use std::time::{Duration, UNIX_EPOCH};
+
+use super::{MemFs, RENAME_EXCHANGE, RENAME_NOREPLACE, RENAME_WHITEOUT};
+use crate::file_attr::TimeOrNow;
+use crate::sys;
+
+// few names, so calls keep finding the entries earlier ones created
+const NAMES: [&str; 4] = ["a", "b", "c", "d"];
+const CALLERS: [u32; 3] = [0, 1000, 1001];
+
+/// What the handler replied.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Reply {
+    Ok,
+    /// A reply carrying a value: the inode of an entry, a file handle, an offset, or the bytes read or written.
+    Data(u64),
+    Error(c_int),
+}
+
+#[derive(Clone, Debug)]
+pub enum Op {
+    Lookup { parent: u64, name: &'static str },
+    Mkdir { parent: u64, name: &'static str, mode: u32 },
+    Mknod { parent: u64, name: &'static str, mode: u32 },
+    Open { ino: u64, flags: i32 },
+    Read { ino: u64, fh: u64, offset: u64, size: u32 },
+    Write { ino: u64, fh: u64, offset: u64, len: usize },
+    Setattr {
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+    },
+    Rename { parent: u64, name: &'static str, new_parent: u64, new_name: &'static str, flags: u32 },
+    Unlink { parent: u64, name: &'static str },
+    Rmdir { parent: u64, name: &'static str },
+    Fallocate { ino: u64, fh: u64, offset: i64, length: i64, mode: i32 },
+    CopyFileRange { src_ino: u64, src_offset: i64, dst_ino: u64, dst_fh: u64, dst_offset: i64, size: u64 },
+    Lseek { ino: u64, fh: u64, offset: i64, whence: i32 },
+    Access { ino: u64, mask: i32 },
+}
+
+/// An op and the caller sending it.
+#[derive(Clone, Debug)]
+pub struct Call {
+    pub uid: u32,
+    pub gid: u32,
+    pub op: Op,
+}
+
+impl Call {
+    /// Runs the op on `fs` the way its handler does.
+    pub fn apply(&self, fs: &mut MemFs) -> Reply {
+        let (uid, gid, groups) = (self.uid, self.gid, &[self.gid][..]);
+        let res = match self.op {
+            Op::Lookup { parent, name } => fs.lookup_entry(parent, OsStr::new(name), uid, gid, groups).map(|attr| Reply::Data(attr.ino)),
+            Op::Mkdir { parent, name, mode } => {
+                fs.make_dir(parent, OsStr::new(name), mode, 0o022, uid, gid, groups).map(|attr| Reply::Data(attr.ino))
+            }
+            Op::Mknod { parent, name, mode } => {
+                fs.create_nod(parent, mode, 0o022, uid, gid, groups, OsStr::new(name)).map(|attr| Reply::Data(attr.ino)).map_err(|err| err.errno())
+            }
+            Op::Open { ino, flags } => fs.open_file(ino, flags, uid, gid, groups).map(Reply::Data),
+            Op::Read { ino, fh, offset, size } => {
+                fs.read_data(ino, fh, offset, size, uid, gid, groups).map(|data| Reply::Data(data.len() as u64))
+            }
+            Op::Write { ino, fh, offset, len } => {
+                fs.write_data(ino, fh, offset, &vec![0xaa; len], uid, gid, groups).map(|written| Reply::Data(written as u64))
+            }
+            Op::Setattr { ino, mode, uid: new_uid, gid: new_gid, size, atime, mtime } => {
+                fs.set_attr(ino, mode, new_uid, new_gid, size, atime, mtime, None, uid, gid, groups).map(|_| Reply::Ok)
+            }
+            Op::Rename { parent, name, new_parent, new_name, flags } => {
+                let (name, new_name) = (OsStr::new(name), OsStr::new(new_name));
+                fs.check_rename_access(parent, name, new_parent, new_name, uid, gid, groups)
+                    .and_then(|_| fs.rename_entry(parent, name, new_parent, new_name, flags, uid, gid))
+                    .map(|_| Reply::Ok)
+            }
+            Op::Unlink { parent, name } => fs.unlink_entry(parent, OsStr::new(name), uid, gid, groups).map(|_| Reply::Ok),
+            Op::Rmdir { parent, name } => fs.rmdir_entry(parent, OsStr::new(name), uid, gid, groups).map(|_| Reply::Ok),
+            Op::Fallocate { ino, fh, offset, length, mode } => {
+                fs.allocate_range(ino, fh, offset, length, mode, uid, gid, groups).map(|_| Reply::Ok)
+            }
+            Op::CopyFileRange { src_ino, src_offset, dst_ino, dst_fh, dst_offset, size } => {
+                fs.copy_range(src_ino, src_offset, dst_ino, dst_fh, dst_offset, size).map(Reply::Data)
+            }
+            Op::Lseek { ino, fh, offset, whence } => fs.seek(ino, fh, offset, whence).map(|offset| Reply::Data(offset as u64)),
+            Op::Access { ino, mask } => fs.access_inode(ino, mask, uid, gid, groups).map(|_| Reply::Ok),
+        };
+
+        res.unwrap_or_else(Reply::Error)
+    }
+}
+
+/// A filesystem with only its root, like right after `init`.
+pub fn mounted() -> MemFs {
+    let mut fs = MemFs::new(false, false);
+    fs.ensure_root();
+
+    fs
+}
+
+/// Decodes calls from `data` until it runs out. Values stay in what the kernel sends: offsets of reads and writes
+/// fit an `i64`, `mknod` gets a known file type, only root makes devices.
+pub fn decode(data: &[u8]) -> Vec<Call> {
+    let mut bytes = Bytes(data);
+    let mut calls = vec![];
+    while !bytes.0.is_empty() {
+        let uid = CALLERS[bytes.u8() as usize % CALLERS.len()];
+        let gid = CALLERS[bytes.u8() as usize % CALLERS.len()];
+        let op = match bytes.u8() % 14 {
+            0 => Op::Lookup { parent: bytes.ino(), name: bytes.name() },
+            1 => Op::Mkdir { parent: bytes.ino(), name: bytes.name(), mode: bytes.u16() as u32 & 0o7777 },
+            2 => {
+                let mut kinds = vec![0, sys::S_IFREG, sys::S_IFDIR, sys::S_IFIFO, sys::S_IFSOCK];
+                if uid == 0 {
+                    kinds.extend([sys::S_IFCHR, sys::S_IFBLK]);
+                }
+                // mode_t is u32 on Linux but u16 on macOS
+                #[allow(clippy::unnecessary_cast)]
+                let kind = kinds[bytes.u8() as usize % kinds.len()] as u32;
+                Op::Mknod { parent: bytes.ino(), name: bytes.name(), mode: kind | (bytes.u16() as u32 & 0o7777) }
+            }
+            3 => {
+                let access = [sys::O_RDONLY, sys::O_WRONLY, sys::O_RDWR][bytes.u8() as usize % 3];
+                let extra = [0, sys::O_TRUNC, sys::O_APPEND, sys::O_TRUNC | sys::O_APPEND][bytes.u8() as usize % 4];
+                Op::Open { ino: bytes.ino(), flags: access | extra }
+            }
+            4 => Op::Read { ino: bytes.ino(), fh: bytes.fh(), offset: bytes.offset() as u64, size: bytes.u16() as u32 },
+            5 => Op::Write { ino: bytes.ino(), fh: bytes.fh(), offset: bytes.offset() as u64, len: bytes.u16() as usize },
+            6 => {
+                // any mix of fields, a chmod along with a chown and a truncate included
+                let fields = bytes.u8();
+                let ino = bytes.ino();
+                let mode = (fields & 1 != 0).then(|| bytes.u16() as u32 & 0o7777);
+                let uid = (fields & 2 != 0).then(|| CALLERS[bytes.u8() as usize % CALLERS.len()]);
+                let gid = (fields & 4 != 0).then(|| CALLERS[bytes.u8() as usize % CALLERS.len()]);
+                let size = (fields & 8 != 0).then(|| bytes.offset() as u64);
+                let atime = (fields & 16 != 0).then(|| bytes.time());
+                let mtime = (fields & 32 != 0).then(|| bytes.time());
+                Op::Setattr { ino, mode, uid, gid, size, atime, mtime }
+            }
+            7 => {
+                let flags = [0, RENAME_NOREPLACE, RENAME_EXCHANGE, RENAME_WHITEOUT, bytes.u32()][bytes.u8() as usize % 5];
+                Op::Rename { parent: bytes.ino(), name: bytes.name(), new_parent: bytes.ino(), new_name: bytes.name(), flags }
+            }
+            8 => Op::Unlink { parent: bytes.ino(), name: bytes.name() },
+            9 => Op::Rmdir { parent: bytes.ino(), name: bytes.name() },
+            10 => Op::Fallocate { ino: bytes.ino(), fh: bytes.fh(), offset: bytes.offset(), length: bytes.offset(), mode: bytes.u8() as i32 },
+            11 => Op::CopyFileRange {
+                src_ino: bytes.ino(),
+                src_offset: bytes.offset(),
+                dst_ino: bytes.ino(),
+                dst_fh: bytes.fh(),
+                dst_offset: bytes.offset(),
+                size: bytes.u16() as u64,
+            },
+            12 => Op::Lseek { ino: bytes.ino(), fh: bytes.fh(), offset: bytes.offset(), whence: bytes.u8() as i32 % 6 },
+            _ => Op::Access { ino: bytes.ino(), mask: bytes.u8() as i32 % 8 },
+        };
+        calls.push(Call { uid, gid, op });
+    }
+
+    calls
+}
+
+// reads zeros once the data runs out
+struct Bytes<'a>(&'a [u8]);
+
+impl Bytes<'_> {
+    fn u8(&mut self) -> u8 {
+        let Some((first, rest)) = self.0.split_first() else { return 0 };
+        self.0 = rest;
+        *first
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_le_bytes([self.u8(), self.u8()])
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_le_bytes([self.u8(), self.u8(), self.u8(), self.u8()])
+    }
+
+    fn u64(&mut self) -> u64 {
+        (self.u32() as u64) << 32 | self.u32() as u64
+    }
+
+    // mostly inodes that may exist, sometimes ones that can't
+    fn ino(&mut self) -> u64 {
+        match self.u8() {
+            0xfe => 0,
+            0xff => u64::MAX,
+            b => (b % 16) as u64 + 1,
+        }
+    }
+
+    fn fh(&mut self) -> u64 {
+        match self.u8() {
+            0xff => u64::MAX,
+            b => (b % 8) as u64,
+        }
+    }
+
+    fn name(&mut self) -> &'static str {
+        NAMES[self.u8() as usize % NAMES.len()]
+    }
+
+    // small ones land in the data, the others far past it or right at the largest one
+    fn offset(&mut self) -> i64 {
+        match self.u8() % 4 {
+            0 => self.u8() as i64,
+            1 => self.u16() as i64,
+            2 => (self.u64() >> 1) as i64,
+            _ => i64::MAX - self.u8() as i64,
+        }
+    }
+
+    fn time(&mut self) -> TimeOrNow {
+        match self.u32() {
+            0 => TimeOrNow::Now,
+            secs => TimeOrNow::SpecificTime(UNIX_EPOCH + Duration::from_secs(secs as u64)),
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! Named pipes kept in memory, so programs talking through a `mkfifo` can be tested with the programmatic API.
+//! On a mount the kernel serves FIFOs itself, their reads and writes never reach the filesystem.
+
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// What a pipe holds before writers wait for a reader, like a Linux pipe.
+pub const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// Content and open ends of a named pipe, what was read is gone.
+#[derive(Debug, Default)]
+pub struct PipeBuffer {
+    state: Mutex<PipeState>,
+    // signalled on every read, write and close
+    changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct PipeState {
+    data: VecDeque<u8>,
+    readers: u32,
+    writers: u32,
+}
+
+impl PipeBuffer {
+    /// Opens a read end. Unlike `open(2)` it doesn't wait for a writer.
+    pub fn reader(self: &Arc<Self>) -> PipeReader {
+        self.update(|state| state.readers += 1);
+        PipeReader(self.clone())
+    }
+
+    /// Opens a write end. Unlike `open(2)` it doesn't wait for a reader, writing before one is open fails.
+    pub fn writer(self: &Arc<Self>) -> PipeWriter {
+        self.update(|state| state.writers += 1);
+        PipeWriter(self.clone())
+    }
+
+    fn update(&self, f: impl FnOnce(&mut PipeState)) {
+        f(&mut self.state.lock().unwrap());
+        self.changed.notify_all();
+    }
+}
+
+/// Read end of a named pipe. Reads wait for data while a writer is open and return 0, end of file, once none is left.
+#[derive(Debug)]
+pub struct PipeReader(Arc<PipeBuffer>);
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let pipe = &self.0;
+        let mut state = pipe.changed.wait_while(pipe.state.lock().unwrap(), |state| state.data.is_empty() && state.writers > 0).unwrap();
+        let len = min(buf.len(), state.data.len());
+        for (dst, src) in buf.iter_mut().zip(state.data.drain(..len)) {
+            *dst = src;
+        }
+        pipe.changed.notify_all();
+
+        Ok(len)
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.0.update(|state| state.readers -= 1);
+    }
+}
+
+/// Write end of a named pipe. Writes wait while the pipe is full and fail with `BrokenPipe` without a reader.
+#[derive(Debug)]
+pub struct PipeWriter(Arc<PipeBuffer>);
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let pipe = &self.0;
+        let mut state = pipe.changed.wait_while(pipe.state.lock().unwrap(), |state| state.data.len() >= PIPE_CAPACITY && state.readers > 0).unwrap();
+        if state.readers == 0 {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        let len = min(buf.len(), PIPE_CAPACITY - state.data.len());
+        state.data.extend(&buf[..len]);
+        pipe.changed.notify_all();
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.0.update(|state| state.writers -= 1);
+    }
+}
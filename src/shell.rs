@@ -0,0 +1,150 @@
+use std::io::{self, BufRead, IsTerminal, Write};
+
+use in_mem_fs::file_attr::FileType;
+use in_mem_fs::mem_fs::{FsError, MemFs};
+
+const HELP: &str = "\
+create <path> <content>  create a file, the content is the rest of the line
+mkdir <path>             create a directory and its missing parents
+ls [path]                list a directory, / by default
+cat <path>               print a file
+rm <path>                remove a file
+rmdir <path>             remove an empty directory
+stat <path>              print the attributes of an entry
+mv <src> <dst>           move an entry, dst must not exist
+cp <src> <dst>           copy a file
+exit                     leave the shell";
+
+/// Runs the commands read from `input` against `fs`, one per line, until `exit` or the end of input.
+/// Prompts only when stdin is a terminal, so a script can be piped in. Returns `false` if a command failed.
+pub fn run(fs: &mut MemFs, input: impl BufRead) -> bool {
+    let interactive = io::stdin().is_terminal();
+    let uid = unsafe { libc::getuid() };
+    let gid = unsafe { libc::getgid() };
+    let mut ok = true;
+
+    prompt(interactive);
+    for line in input.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => {
+                eprintln!("Cannot read command: {err}");
+                return false;
+            }
+        };
+        let line = line.trim();
+        let (command, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let args = args.trim_start();
+        if command == "exit" {
+            break;
+        }
+        if let Err(err) = execute(fs, command, args, uid, gid) {
+            match err {
+                CommandError::Usage(usage) => eprintln!("usage: {usage}"),
+                CommandError::Unknown => eprintln!("{command}: unknown command, try help"),
+                CommandError::Fs(err) => eprintln!("{command}: {err}"),
+            }
+            ok = false;
+        }
+        prompt(interactive);
+    }
+
+    ok
+}
+
+fn prompt(interactive: bool) {
+    if interactive {
+        print!("> ");
+        let _ = io::stdout().flush();
+    }
+}
+
+enum CommandError {
+    // wrong arguments, reported with the usage line of the command
+    Usage(&'static str),
+    Unknown,
+    Fs(FsError),
+}
+
+impl From<FsError> for CommandError {
+    fn from(err: FsError) -> Self {
+        CommandError::Fs(err)
+    }
+}
+
+impl From<io::Error> for CommandError {
+    fn from(err: io::Error) -> Self {
+        CommandError::Fs(FsError::Io(err))
+    }
+}
+
+fn execute(fs: &mut MemFs, command: &str, args: &str, uid: u32, gid: u32) -> Result<(), CommandError> {
+    match command {
+        "" => {}
+        "help" => println!("{HELP}"),
+        "create" => {
+            let (path, content) = match args.split_once(char::is_whitespace) {
+                Some((path, content)) => (path, content.trim_start()),
+                None if !args.is_empty() => (args, ""),
+                None => return Err(CommandError::Usage("create <path> <content>")),
+            };
+            fs.create_file(path, content.as_bytes(), 0o644, uid, gid)?;
+        }
+        "mkdir" => {
+            fs.create_dir_all(one_arg(args, "mkdir <path>")?, 0o755, uid, gid)?;
+        }
+        "ls" => list(fs, if args.is_empty() { "/" } else { args })?,
+        "cat" => {
+            let data = fs.read_file(one_arg(args, "cat <path>")?)?;
+            io::stdout().write_all(&data)?;
+        }
+        "rm" => fs.remove_file(one_arg(args, "rm <path>")?)?,
+        "rmdir" => fs.remove_dir(one_arg(args, "rmdir <path>")?)?,
+        "stat" => stat(fs, one_arg(args, "stat <path>")?)?,
+        "mv" => {
+            let (src, dst) = two_args(args, "mv <src> <dst>")?;
+            fs.rename(src, dst)?;
+        }
+        "cp" => {
+            let (src, dst) = two_args(args, "cp <src> <dst>")?;
+            fs.copy_file(src, dst)?;
+        }
+        _ => return Err(CommandError::Unknown),
+    }
+
+    Ok(())
+}
+
+fn one_arg<'a>(args: &'a str, usage: &'static str) -> Result<&'a str, CommandError> {
+    match args.split_whitespace().collect::<Vec<_>>()[..] {
+        [path] => Ok(path),
+        _ => Err(CommandError::Usage(usage)),
+    }
+}
+
+fn two_args<'a>(args: &'a str, usage: &'static str) -> Result<(&'a str, &'a str), CommandError> {
+    match args.split_whitespace().collect::<Vec<_>>()[..] {
+        [src, dst] => Ok((src, dst)),
+        _ => Err(CommandError::Usage(usage)),
+    }
+}
+
+fn list(fs: &MemFs, path: &str) -> Result<(), FsError> {
+    for entry in fs.list_dir(path)? {
+        let suffix = match entry.kind {
+            FileType::Directory => "/",
+            FileType::Symlink => "@",
+            _ => "",
+        };
+        println!("{}{}", entry.name, suffix);
+    }
+
+    Ok(())
+}
+
+fn stat(fs: &MemFs, path: &str) -> Result<(), FsError> {
+    let attr = fs.stat(path)?;
+    println!("inode {}, {:?}, size {}, mode {:o}, links {}, uid {}, gid {}", attr.ino, attr.kind, attr.size, attr.perm, attr.nlink, attr.uid, attr.gid);
+
+    Ok(())
+}
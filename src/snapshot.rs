@@ -0,0 +1,432 @@
+use std::{fmt, io};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::file_attr::{FileAttr, FileType};
+use crate::file_data::{FileData, PAGE_SIZE};
+
+pub(crate) const MAGIC: &[u8; 4] = b"IMFS";
+pub(crate) const VERSION: u32 = 2;
+// before inode generations were saved
+const VERSION_1: u32 = 1;
+
+// entry tags of the format written by MemFs::save
+const SNAPSHOT_INODE: u8 = 0;
+const SNAPSHOT_LINK: u8 = 1;
+const SNAPSHOT_END: u8 = 2;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u32),
+    Corrupt(String),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(err) => write!(f, "snapshot IO error: {err}"),
+            SnapshotError::BadMagic => write!(f, "not a filesystem snapshot"),
+            SnapshotError::UnsupportedVersion(version) => write!(f, "unsupported snapshot version {version}"),
+            SnapshotError::Corrupt(msg) => write!(f, "corrupt snapshot: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> Self {
+        SnapshotError::Io(err)
+    }
+}
+
+/// Copy of the whole tree taken by `MemFs::snapshot`. File data is shared copy-on-write with the filesystem,
+/// later writes don't change the snapshot.
+#[derive(Debug, Clone)]
+pub struct MemFsSnapshot {
+    // parents come before their entries, links after all inodes
+    pub(crate) entries: Vec<SnapshotEntry>,
+    pub(crate) current_inode: u64,
+}
+
+// only the trees are compared, the inode counter of a restored filesystem doesn't go back
+impl PartialEq for MemFsSnapshot {
+    fn eq(&self, other: &Self) -> bool {
+        self.entries == other.entries
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum SnapshotEntry {
+    Inode {
+        // 0 for the root
+        parent: u64,
+        name: String,
+        is_dir: bool,
+        generation: u64,
+        attr: FileAttr,
+        data: Option<Arc<FileData>>,
+        xattrs: HashMap<String, Vec<u8>>,
+    },
+    Link {
+        parent: u64,
+        ino: u64,
+        name: String,
+    },
+}
+
+/// Paths that differ between two snapshots, each list sorted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FsDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// Paths whose content, xattrs, kind, mode, owner, size or mtime changed.
+    pub modified: Vec<String>,
+}
+
+impl FsDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+
+    /// One line per path in path order marked `+`, `-` or `~` as added, removed or modified, then a summary, like `git diff --stat`.
+    pub fn print(&self, mut writer: impl Write) -> io::Result<()> {
+        let mut lines: Vec<(&str, char)> = self.added.iter().map(|path| (path.as_str(), '+'))
+            .chain(self.removed.iter().map(|path| (path.as_str(), '-')))
+            .chain(self.modified.iter().map(|path| (path.as_str(), '~')))
+            .collect();
+        lines.sort();
+        let width = lines.iter().map(|(path, _)| path.len()).max().unwrap_or(0);
+        for (path, mark) in &lines {
+            writeln!(writer, " {path:<width$} | {mark}")?;
+        }
+        writeln!(writer, " {} paths changed, {} added(+), {} removed(-), {} modified(~)",
+                 lines.len(), self.added.len(), self.removed.len(), self.modified.len())
+    }
+}
+
+impl MemFsSnapshot {
+    /// Changes from `self` to `other` by path. Access times are ignored.
+    pub fn diff(&self, other: &MemFsSnapshot) -> FsDiff {
+        let old = self.by_path();
+        let new = other.by_path();
+
+        // BTreeMap keys come sorted
+        let mut diff = FsDiff::default();
+        for (path, entry) in &old {
+            match new.get(path) {
+                None => diff.removed.push(path.clone()),
+                Some(other) if !same_content(entry, other) => diff.modified.push(path.clone()),
+                Some(_) => {}
+            }
+        }
+        diff.added = new.keys().filter(|path| !old.contains_key(*path)).cloned().collect();
+
+        diff
+    }
+
+    /// Every path with the inode entry it leads to, links resolve to their inode.
+    fn by_path(&self) -> BTreeMap<String, &SnapshotEntry> {
+        let mut paths: HashMap<u64, String> = HashMap::new();
+        let mut inodes = HashMap::new();
+        let mut by_path = BTreeMap::new();
+        for entry in &self.entries {
+            let (parent, name) = match entry {
+                SnapshotEntry::Inode { parent, name, .. } | SnapshotEntry::Link { parent, name, .. } => (*parent, name),
+            };
+            let path = match paths.get(&parent) {
+                Some(parent_path) if parent_path == "/" => format!("/{name}"),
+                Some(parent_path) => format!("{parent_path}/{name}"),
+                None => String::from("/"),
+            };
+            match entry {
+                SnapshotEntry::Inode { attr, .. } => {
+                    paths.insert(attr.ino, path.clone());
+                    inodes.insert(attr.ino, entry);
+                    by_path.insert(path, entry);
+                }
+                SnapshotEntry::Link { ino, .. } => {
+                    if let Some(inode) = inodes.get(ino) {
+                        by_path.insert(path, *inode);
+                    }
+                }
+            }
+        }
+
+        by_path
+    }
+
+    pub(crate) fn write(&self, w: &mut impl Write) -> io::Result<()> {
+        write_u64(w, self.current_inode)?;
+        for entry in &self.entries {
+            match entry {
+                SnapshotEntry::Inode { parent, name, is_dir, generation, attr, data, xattrs } => {
+                    write_u8(w, SNAPSHOT_INODE)?;
+                    write_u64(w, *parent)?;
+                    write_bytes(w, name.as_bytes())?;
+                    write_u8(w, *is_dir as u8)?;
+                    write_u64(w, *generation)?;
+                    write_attr(w, attr)?;
+                    match data {
+                        Some(data) => {
+                            write_u8(w, 1)?;
+                            write_data(w, data)?;
+                        }
+                        None => write_u8(w, 0)?,
+                    }
+                    write_u64(w, xattrs.len() as u64)?;
+                    for (name, value) in xattrs {
+                        write_bytes(w, name.as_bytes())?;
+                        write_bytes(w, value)?;
+                    }
+                }
+                SnapshotEntry::Link { parent, ino, name } => {
+                    write_u8(w, SNAPSHOT_LINK)?;
+                    write_u64(w, *parent)?;
+                    write_u64(w, *ino)?;
+                    write_bytes(w, name.as_bytes())?;
+                }
+            }
+        }
+
+        write_u8(w, SNAPSHOT_END)
+    }
+
+    /// Reads the entries of a snapshot written with `version`, generations missing from version 1 are 0.
+    pub(crate) fn read(r: &mut impl Read, version: u32) -> Result<Self, SnapshotError> {
+        let current_inode = read_u64(r)?;
+        let mut entries = vec![];
+        loop {
+            let entry = match read_u8(r)? {
+                SNAPSHOT_INODE => {
+                    let parent = read_u64(r)?;
+                    let name = read_string(r)?;
+                    let is_dir = read_u8(r)? != 0;
+                    let generation = if version == VERSION_1 { 0 } else { read_u64(r)? };
+                    let attr = read_attr(r)?;
+                    let data = match read_u8(r)? {
+                        0 => None,
+                        _ => Some(Arc::new(read_data(r)?)),
+                    };
+                    let mut xattrs = HashMap::new();
+                    for _ in 0..read_u64(r)? {
+                        let name = read_string(r)?;
+                        xattrs.insert(name, read_bytes(r)?);
+                    }
+                    SnapshotEntry::Inode { parent, name, is_dir, generation, attr, data, xattrs }
+                }
+                SNAPSHOT_LINK => SnapshotEntry::Link { parent: read_u64(r)?, ino: read_u64(r)?, name: read_string(r)? },
+                SNAPSHOT_END => break,
+                tag => return Err(SnapshotError::Corrupt(format!("unknown entry {tag}"))),
+            };
+            entries.push(entry);
+        }
+
+        Ok(MemFsSnapshot { entries, current_inode })
+    }
+}
+
+fn same_content(a: &SnapshotEntry, b: &SnapshotEntry) -> bool {
+    match (a, b) {
+        (
+            SnapshotEntry::Inode { attr: a_attr, data: a_data, xattrs: a_xattrs, .. },
+            SnapshotEntry::Inode { attr: b_attr, data: b_data, xattrs: b_xattrs, .. },
+        ) => {
+            a_attr.ino == b_attr.ino && a_attr.kind == b_attr.kind && a_attr.perm == b_attr.perm
+                && a_attr.uid == b_attr.uid && a_attr.gid == b_attr.gid
+                && a_attr.size == b_attr.size && a_attr.mtime == b_attr.mtime
+                && a_data == b_data && a_xattrs == b_xattrs
+        }
+        _ => false,
+    }
+}
+
+pub(crate) fn is_supported_version(version: u32) -> bool {
+    version == VERSION || version == VERSION_1
+}
+
+pub(crate) fn write_u8(w: &mut impl Write, v: u8) -> io::Result<()> {
+    w.write_all(&[v])
+}
+
+pub(crate) fn write_u32(w: &mut impl Write, v: u32) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+pub(crate) fn write_u64(w: &mut impl Write, v: u64) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+fn write_bytes(w: &mut impl Write, v: &[u8]) -> io::Result<()> {
+    write_u64(w, v.len() as u64)?;
+    w.write_all(v)
+}
+
+pub(crate) fn read_u8(r: &mut impl Read) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    r.read_exact(&mut buf)?;
+
+    Ok(buf[0])
+}
+
+pub(crate) fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+
+    Ok(u32::from_le_bytes(buf))
+}
+
+pub(crate) fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_bytes(r: &mut impl Read) -> Result<Vec<u8>, SnapshotError> {
+    let len = read_u64(r)?;
+    let mut buf = vec![];
+    // don't trust the length to preallocate, a corrupt one would abort on allocation
+    r.take(len).read_to_end(&mut buf)?;
+    if buf.len() as u64 != len {
+        return Err(SnapshotError::Io(io::ErrorKind::UnexpectedEof.into()));
+    }
+
+    Ok(buf)
+}
+
+fn read_string(r: &mut impl Read) -> Result<String, SnapshotError> {
+    String::from_utf8(read_bytes(r)?).map_err(|_| SnapshotError::Corrupt(String::from("name is not UTF-8")))
+}
+
+// seconds can be negative, utimens accepts times before the epoch
+fn write_time(w: &mut impl Write, time: SystemTime) -> io::Result<()> {
+    let (secs, nanos) = match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => (d.as_secs() as i64, d.subsec_nanos()),
+        Err(err) => {
+            let d = err.duration();
+            // wrapping, the earliest time is i64::MIN seconds whose negation doesn't fit
+            if d.subsec_nanos() == 0 {
+                ((d.as_secs() as i64).wrapping_neg(), 0)
+            } else {
+                ((d.as_secs() as i64).wrapping_neg() - 1, 1_000_000_000 - d.subsec_nanos())
+            }
+        }
+    };
+    write_u64(w, secs as u64)?;
+    write_u32(w, nanos)
+}
+
+fn read_time(r: &mut impl Read) -> Result<SystemTime, SnapshotError> {
+    let secs = read_u64(r)? as i64;
+    let nanos = read_u32(r)?;
+    if nanos >= 1_000_000_000 {
+        return Err(SnapshotError::Corrupt(format!("{nanos} nanoseconds in a time")));
+    }
+    let time = if secs >= 0 {
+        UNIX_EPOCH.checked_add(Duration::from_secs(secs as u64))
+    } else {
+        UNIX_EPOCH.checked_sub(Duration::from_secs(secs.unsigned_abs()))
+    };
+
+    time.and_then(|time| time.checked_add(Duration::from_nanos(nanos as u64)))
+        .ok_or_else(|| SnapshotError::Corrupt(format!("time {secs}s out of range")))
+}
+
+fn kind_to_u8(kind: FileType) -> u8 {
+    match kind {
+        FileType::NamedPipe => 0,
+        FileType::CharDevice => 1,
+        FileType::BlockDevice => 2,
+        FileType::Directory => 3,
+        FileType::RegularFile => 4,
+        FileType::Symlink => 5,
+        FileType::Socket => 6,
+    }
+}
+
+fn kind_from_u8(kind: u8) -> Result<FileType, SnapshotError> {
+    Ok(match kind {
+        0 => FileType::NamedPipe,
+        1 => FileType::CharDevice,
+        2 => FileType::BlockDevice,
+        3 => FileType::Directory,
+        4 => FileType::RegularFile,
+        5 => FileType::Symlink,
+        6 => FileType::Socket,
+        _ => return Err(SnapshotError::Corrupt(format!("unknown file type {kind}"))),
+    })
+}
+
+fn write_attr(w: &mut impl Write, attr: &FileAttr) -> io::Result<()> {
+    write_u64(w, attr.ino)?;
+    write_u64(w, attr.size)?;
+    write_u64(w, attr.blocks)?;
+    write_time(w, attr.atime)?;
+    write_time(w, attr.mtime)?;
+    write_time(w, attr.ctime)?;
+    write_time(w, attr.crtime)?;
+    write_u8(w, kind_to_u8(attr.kind))?;
+    write_u32(w, attr.perm as u32)?;
+    write_u32(w, attr.nlink)?;
+    write_u32(w, attr.uid)?;
+    write_u32(w, attr.gid)?;
+    write_u32(w, attr.rdev)?;
+    write_u32(w, attr.blksize)?;
+    write_u32(w, attr.flags)
+}
+
+fn read_attr(r: &mut impl Read) -> Result<FileAttr, SnapshotError> {
+    Ok(FileAttr {
+        ino: read_u64(r)?,
+        size: read_u64(r)?,
+        blocks: read_u64(r)?,
+        atime: read_time(r)?,
+        mtime: read_time(r)?,
+        ctime: read_time(r)?,
+        crtime: read_time(r)?,
+        kind: kind_from_u8(read_u8(r)?)?,
+        perm: read_u32(r)? as u16,
+        nlink: read_u32(r)?,
+        uid: read_u32(r)?,
+        gid: read_u32(r)?,
+        rdev: read_u32(r)?,
+        blksize: read_u32(r)?,
+        flags: read_u32(r)?,
+    })
+}
+
+/// Only the allocated pages are written, holes stay holes when loaded.
+fn write_data(w: &mut impl Write, data: &FileData) -> io::Result<()> {
+    write_u64(w, data.len())?;
+    let pages: Vec<_> = data.pages().collect();
+    write_u64(w, pages.len() as u64)?;
+    for (page, bytes) in pages {
+        write_u64(w, page)?;
+        w.write_all(&bytes[..])?;
+    }
+
+    Ok(())
+}
+
+fn read_data(r: &mut impl Read) -> Result<FileData, SnapshotError> {
+    let len = read_u64(r)?;
+    let pages = read_u64(r)?;
+    let mut data = FileData::new();
+    let mut buf = [0; PAGE_SIZE];
+    for _ in 0..pages {
+        let page = read_u64(r)?;
+        r.read_exact(&mut buf)?;
+        let offset = page.checked_mul(PAGE_SIZE as u64)
+            .filter(|offset| *offset < len)
+            .ok_or_else(|| SnapshotError::Corrupt(format!("page {page} past the end of the file")))?;
+        data.write(offset, &buf);
+    }
+    data.truncate(len);
+
+    Ok(data)
+}
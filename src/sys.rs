@@ -0,0 +1,87 @@
+//! The libc constants `MemFs` uses. On unix they are libc's own, elsewhere, like wasm32, the Linux values are defined
+//! here so the programmatic API builds without libc.
+
+#[cfg(unix)]
+pub use libc::{
+    mode_t, EACCES, EBADF, EDQUOT, EEXIST, EFBIG, EINVAL, EIO, EISDIR, ELOOP, ENAMETOOLONG, ENODEV, ENOENT, ENOSPC,
+    ENOSYS, ENOTDIR, ENOTEMPTY, ENOTSUP, ENXIO, EOPNOTSUPP, EPERM, ESTALE, F_OK, F_RDLCK, F_UNLCK, F_WRLCK, O_ACCMODE,
+    O_APPEND, O_CREAT, O_EXCL, O_NOFOLLOW, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY, POLLOUT, POLLPRI, R_OK, SEEK_CUR,
+    SEEK_DATA, SEEK_END, SEEK_HOLE, SEEK_SET, S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFMT, S_IFREG, S_IFSOCK,
+    S_ISGID, S_ISUID, S_ISVTX, S_IXGRP, S_IXOTH, S_IXUSR, W_OK, X_OK,
+};
+
+#[cfg(not(unix))]
+pub use self::linux::*;
+
+#[cfg(not(unix))]
+mod linux {
+    use std::os::raw::{c_int, c_short};
+
+    #[allow(non_camel_case_types)]
+    pub type mode_t = u32;
+
+    pub const EPERM: c_int = 1;
+    pub const ENOENT: c_int = 2;
+    pub const EIO: c_int = 5;
+    pub const ENXIO: c_int = 6;
+    pub const EBADF: c_int = 9;
+    pub const EACCES: c_int = 13;
+    pub const EEXIST: c_int = 17;
+    pub const ENODEV: c_int = 19;
+    pub const ENOTDIR: c_int = 20;
+    pub const EISDIR: c_int = 21;
+    pub const EINVAL: c_int = 22;
+    pub const EFBIG: c_int = 27;
+    pub const ENOSPC: c_int = 28;
+    pub const ENAMETOOLONG: c_int = 36;
+    pub const ENOSYS: c_int = 38;
+    pub const ENOTEMPTY: c_int = 39;
+    pub const ELOOP: c_int = 40;
+    pub const EOPNOTSUPP: c_int = 95;
+    pub const ENOTSUP: c_int = EOPNOTSUPP;
+    pub const ESTALE: c_int = 116;
+    pub const EDQUOT: c_int = 122;
+
+    pub const F_OK: c_int = 0;
+    pub const X_OK: c_int = 1;
+    pub const W_OK: c_int = 2;
+    pub const R_OK: c_int = 4;
+
+    pub const F_RDLCK: c_int = 0;
+    pub const F_WRLCK: c_int = 1;
+    pub const F_UNLCK: c_int = 2;
+
+    pub const O_RDONLY: c_int = 0;
+    pub const O_WRONLY: c_int = 1;
+    pub const O_RDWR: c_int = 2;
+    pub const O_ACCMODE: c_int = 3;
+    pub const O_CREAT: c_int = 0o100;
+    pub const O_EXCL: c_int = 0o200;
+    pub const O_TRUNC: c_int = 0o1000;
+    pub const O_APPEND: c_int = 0o2000;
+    pub const O_NOFOLLOW: c_int = 0o400000;
+
+    pub const POLLPRI: c_short = 0x2;
+    pub const POLLOUT: c_short = 0x4;
+
+    pub const SEEK_SET: c_int = 0;
+    pub const SEEK_CUR: c_int = 1;
+    pub const SEEK_END: c_int = 2;
+    pub const SEEK_DATA: c_int = 3;
+    pub const SEEK_HOLE: c_int = 4;
+
+    pub const S_IFMT: mode_t = 0o170000;
+    pub const S_IFSOCK: mode_t = 0o140000;
+    pub const S_IFLNK: mode_t = 0o120000;
+    pub const S_IFREG: mode_t = 0o100000;
+    pub const S_IFBLK: mode_t = 0o60000;
+    pub const S_IFDIR: mode_t = 0o40000;
+    pub const S_IFCHR: mode_t = 0o20000;
+    pub const S_IFIFO: mode_t = 0o10000;
+    pub const S_ISUID: mode_t = 0o4000;
+    pub const S_ISGID: mode_t = 0o2000;
+    pub const S_ISVTX: mode_t = 0o1000;
+    pub const S_IXUSR: mode_t = 0o100;
+    pub const S_IXGRP: mode_t = 0o10;
+    pub const S_IXOTH: mode_t = 0o1;
+}
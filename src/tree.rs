@@ -1,63 +1,128 @@
-use std::cell::RefCell;
-use std::rc::{Rc, Weak};
-use std::slice::{Iter, IterMut};
-use std::vec::IntoIter;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak};
 
+// value, children and parent are locked separately so a node's value can be held while walking the tree
 pub struct TreeNode<T> {
-    pub value: T,
-    children: Vec<Rc<RefCell<TreeNode<T>>>>,
-    parent: Weak<RefCell<TreeNode<T>>>,
+    pub value: RwLock<T>,
+    // insertion order is kept for listing, the index makes lookups by key constant time
+    children: RwLock<Vec<Arc<TreeNode<T>>>>,
+    children_index: RwLock<HashMap<String, Arc<TreeNode<T>>>>,
+    parent: RwLock<Weak<TreeNode<T>>>,
 }
 
 impl<T> TreeNode<T> {
-    pub fn new(value: T) -> Rc<RefCell<TreeNode<T>>> {
-        Rc::new(RefCell::new(TreeNode {
-            value,
-            children: Vec::new(),
-            parent: Weak::new(),
-        }))
+    pub fn new(value: T) -> Arc<TreeNode<T>> {
+        Arc::new(TreeNode {
+            value: RwLock::new(value),
+            children: RwLock::new(Vec::new()),
+            children_index: RwLock::new(HashMap::new()),
+            parent: RwLock::new(Weak::new()),
+        })
     }
 
-    pub fn get_parent(&self) -> Option<Rc<RefCell<TreeNode<T>>>> {
-        self.parent.upgrade()
+    pub fn get_parent(&self) -> Option<Arc<TreeNode<T>>> {
+        self.parent.read().unwrap().upgrade()
     }
 
-    pub fn iter(&mut self) -> Iter<'_, Rc<RefCell<TreeNode<T>>>> {
-        self.children.iter()
+    pub fn get_child(&self, key: &str) -> Option<Arc<TreeNode<T>>> {
+        self.children_index.read().unwrap().get(key).cloned()
     }
 
-    pub fn iter_mut(&mut self) -> IterMut<'_, Rc<RefCell<TreeNode<T>>>> {
-        self.children.iter_mut()
+    pub fn children_count(&self) -> usize {
+        self.children.read().unwrap().len()
     }
 
-    pub fn into_iter(self) -> IntoIter<Rc<RefCell<TreeNode<T>>>> {
-        self.children.into_iter()
+    pub fn iter(&self) -> RwLockReadGuard<'_, Vec<Arc<TreeNode<T>>>> {
+        self.children.read().unwrap()
+    }
+
+    pub fn iter_mut(&self) -> RwLockWriteGuard<'_, Vec<Arc<TreeNode<T>>>> {
+        self.children.write().unwrap()
     }
 }
 
 pub struct Tree<T> {
-    root: Option<Rc<RefCell<TreeNode<T>>>>,
+    root: Option<Arc<TreeNode<T>>>,
+}
+
+impl<T> Default for Tree<T> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<T> Tree<T> {
     pub fn new() -> Self {
         Tree { root: None }
     }
-    pub fn set_root(&mut self, root: Rc<RefCell<TreeNode<T>>>) {
+    pub fn set_root(&mut self, root: Arc<TreeNode<T>>) {
         self.root = Some(root);
     }
 
-    pub fn get_root(&self) -> Option<Rc<RefCell<TreeNode<T>>>> {
+    pub fn get_root(&self) -> Option<Arc<TreeNode<T>>> {
         self.root.clone()
     }
 
-    pub fn push_child(&self, parent: &Rc<RefCell<TreeNode<T>>>, child: &Rc<RefCell<TreeNode<T>>>) {
-        parent.borrow_mut().children.push(child.clone());
-        child.borrow_mut().parent = Rc::downgrade(parent);
+    pub fn push_child(&self, parent: &Arc<TreeNode<T>>, key: String, child: &Arc<TreeNode<T>>) {
+        parent.children.write().unwrap().push(child.clone());
+        parent.children_index.write().unwrap().insert(key, child.clone());
+        *child.parent.write().unwrap() = Arc::downgrade(parent);
     }
 
-    pub fn remove_child(&self, parent: &Rc<RefCell<TreeNode<T>>>, child: &Rc<RefCell<TreeNode<T>>>) {
-        parent.borrow_mut().children.retain(|c| !Rc::ptr_eq(c, &child));
-        child.borrow_mut().parent = Weak::new();
+    /// Puts `child` in place of `old` under `key`, lookups see either one or the other.
+    pub fn replace_child(&self, parent: &Arc<TreeNode<T>>, key: String, old: &Arc<TreeNode<T>>, child: &Arc<TreeNode<T>>) {
+        let mut children = parent.children.write().unwrap();
+        let mut index = parent.children_index.write().unwrap();
+        children.retain(|c| !Arc::ptr_eq(c, old));
+        children.push(child.clone());
+        index.insert(key, child.clone());
+        *old.parent.write().unwrap() = Weak::new();
+        *child.parent.write().unwrap() = Arc::downgrade(parent);
     }
-}
\ No newline at end of file
+
+    /// Puts `a` under `key_b` of `parent_b` and `b` under `key_a` of `parent_a` in one step.
+    pub fn exchange_children(&self, parent_a: &Arc<TreeNode<T>>, key_a: String, a: &Arc<TreeNode<T>>,
+                             parent_b: &Arc<TreeNode<T>>, key_b: String, b: &Arc<TreeNode<T>>) {
+        if Arc::ptr_eq(parent_a, parent_b) {
+            let mut children = parent_a.children.write().unwrap();
+            let mut index = parent_a.children_index.write().unwrap();
+            for child in children.iter_mut() {
+                if Arc::ptr_eq(child, a) {
+                    *child = b.clone();
+                } else if Arc::ptr_eq(child, b) {
+                    *child = a.clone();
+                }
+            }
+            index.insert(key_a, b.clone());
+            index.insert(key_b, a.clone());
+            return;
+        }
+
+        // lock the parents in a fixed order
+        let (first, second) = if Arc::as_ptr(parent_a) < Arc::as_ptr(parent_b) { (parent_a, parent_b) } else { (parent_b, parent_a) };
+        let mut locked = [
+            (first.children.write().unwrap(), first.children_index.write().unwrap()),
+            (second.children.write().unwrap(), second.children_index.write().unwrap()),
+        ];
+        if !Arc::ptr_eq(first, parent_a) {
+            locked.swap(0, 1);
+        }
+        let [(children_a, index_a), (children_b, index_b)] = &mut locked;
+        for child in children_a.iter_mut().filter(|child| Arc::ptr_eq(child, a)) {
+            *child = b.clone();
+        }
+        for child in children_b.iter_mut().filter(|child| Arc::ptr_eq(child, b)) {
+            *child = a.clone();
+        }
+        index_a.insert(key_a, b.clone());
+        index_b.insert(key_b, a.clone());
+        *a.parent.write().unwrap() = Arc::downgrade(parent_b);
+        *b.parent.write().unwrap() = Arc::downgrade(parent_a);
+    }
+
+    pub fn remove_child(&self, parent: &Arc<TreeNode<T>>, key: &str, child: &Arc<TreeNode<T>>) {
+        parent.children.write().unwrap().retain(|c| !Arc::ptr_eq(c, child));
+        parent.children_index.write().unwrap().remove(key);
+        *child.parent.write().unwrap() = Weak::new();
+    }
+}
@@ -1,16 +1,30 @@
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::rc::Rc;
-use bytebuffer::ByteBuffer;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+use std::io::{self, Write};
+use crate::file_attr::{FileAttr, FileType};
+use crate::file_data::FileData;
+use crate::pipe::PipeBuffer;
 use crate::tree::{Tree, TreeNode};
 
 pub struct Item<T> {
     pub ino: u64,
     pub name: String,
     pub is_dir: bool,
+    /// Tells apart inodes that got the same number, for NFS file handles. Links keep the one of the node holding the metadata.
+    pub generation: u64,
     pub extra: Option<T>,
-    pub data: Option<ByteBuffer>,
-    node: Option<Rc<RefCell<TreeNode<Item<T>>>>>,
+    pub data: Option<Arc<FileData>>,
+    pub dirty_pages: HashSet<u64>,
+    pub xattrs: HashMap<String, Vec<u8>>,
+    /// `FS_IOC_GETFLAGS` attributes set with `chattr`, like `FS_IMMUTABLE_FL`. Links keep the ones of the node holding the metadata.
+    pub inode_flags: u32,
+    /// Bytes written to a named pipe and not read yet, set up by the first open.
+    pub pipe: Option<Arc<PipeBuffer>>,
+    /// Content comes from a provider registered with the filesystem on every read, the data buffer stays empty.
+    pub is_virtual: bool,
 }
 
 impl<T> Item<T> {
@@ -19,39 +33,79 @@ impl<T> Item<T> {
             ino,
             name,
             is_dir,
+            generation: new_generation(),
             extra,
-            data: Some(ByteBuffer::new()),
-            node: None,
+            data: Some(Arc::new(FileData::new())),
+            dirty_pages: HashSet::new(),
+            xattrs: HashMap::new(),
+            inode_flags: 0,
+            pipe: None,
+            is_virtual: false,
         }
     }
-    pub fn children(&self) -> Vec<&Item<T>> {
-        if !self.is_dir {
-            return vec![];
-        }
 
-        self.node.as_ref().unwrap().borrow_mut().iter()
-            .map(|node| unsafe { &(*node.as_ptr()).value })
-            .collect()
+    pub fn data_len(&self) -> u64 {
+        self.data.as_ref().map_or(0, |data| data.len())
     }
+}
+
+// std has no random numbers, the keys of a RandomState are random per process and differ between instances
+fn new_generation() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+// PATH_MAX on Linux
+const DEFAULT_MAX_PATH_LEN: usize = 4096;
 
-    pub fn get_parent(&self) -> Option<&Item<T>> {
-        self.node.as_ref().unwrap().borrow_mut().get_parent().map(|parent| unsafe { &(*parent.as_ptr()).value })
+pub type ItemNode<T> = Arc<TreeNode<Item<T>>>;
+
+impl<T> TreeNode<Item<T>> {
+    pub fn children(&self) -> Vec<ItemNode<T>> {
+        self.iter().clone()
     }
+}
 
-    pub fn find_child_mut<'a, 'b>(&'b self, name: &str) -> Option<&'a mut Item<T>> {
-        if !self.is_dir {
-            return None;
-        }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TreeFsError {
+    /// The root or the parent of a new entry is not a directory.
+    NotADirectory,
+    /// `push` got an inode number that already has an entry, `push_link` adds more.
+    InodeAlreadyExists,
+    /// The parent is not in this tree.
+    ParentNotFound,
+    /// `remove_child` got an entry of another directory.
+    ChildNotFound,
+}
 
-        self.node.as_ref().unwrap().borrow_mut().iter()
-            .find(|node| node.borrow().value.name == name)
-            .map(|node| unsafe { &mut (*node.as_ptr()).value })
+impl fmt::Display for TreeFsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TreeFsError::NotADirectory => write!(f, "not a directory"),
+            TreeFsError::InodeAlreadyExists => write!(f, "inode already exists"),
+            TreeFsError::ParentNotFound => write!(f, "parent not found"),
+            TreeFsError::ChildNotFound => write!(f, "parent does not contain the child"),
+        }
     }
 }
 
+impl std::error::Error for TreeFsError {}
+
 pub struct TreeFs<T> {
     tree: Tree<Item<T>>,
-    ino_to_node: HashMap<u64, Rc<RefCell<TreeNode<Item<T>>>>>,
+    ino_to_node: HashMap<u64, ItemNode<T>>,
+    // extra directory entries of hard linked inodes, ino_to_node keeps the one holding the metadata
+    links: HashMap<u64, Vec<ItemNode<T>>>,
+    // generation of removed inodes, an inode number pushed again gets the next one
+    retired: HashMap<u64, u64>,
+    max_path_len: usize,
+    // when off the children index is keyed by the ASCII lowercase name, entries keep the name they were created with
+    case_sensitive: bool,
+}
+
+impl<T> Default for TreeFs<T> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<T> TreeFs<T> {
@@ -59,71 +113,491 @@ impl<T> TreeFs<T> {
         TreeFs {
             tree: Tree::new(),
             ino_to_node: HashMap::new(),
+            links: HashMap::new(),
+            retired: HashMap::new(),
+            max_path_len: DEFAULT_MAX_PATH_LEN,
+            case_sensitive: true,
         }
     }
 
-    pub fn set_root<'b, 'c>(&'c mut self, item: Item<T>) -> &'b Item<T> {
+    /// An empty tree with the same options.
+    pub fn empty_like(&self) -> Self {
+        let mut tree_fs = TreeFs::new();
+        tree_fs.max_path_len = self.max_path_len;
+        tree_fs.case_sensitive = self.case_sensitive;
+
+        tree_fs
+    }
+
+    pub fn is_case_sensitive(&self) -> bool {
+        self.case_sensitive
+    }
+
+    /// With `false` names match ignoring ASCII case. Set it before adding entries, existing ones are not reindexed.
+    pub fn set_case_sensitive(&mut self, case_sensitive: bool) {
+        self.case_sensitive = case_sensitive;
+    }
+
+    fn index_key(&self, name: &str) -> String {
+        if self.case_sensitive {
+            name.to_string()
+        } else {
+            name.to_ascii_lowercase()
+        }
+    }
+
+    /// Entry named `name` in the directory `parent`.
+    pub fn find_child(&self, parent: &ItemNode<T>, name: &str) -> Option<ItemNode<T>> {
+        parent.get_child(&self.index_key(name))
+    }
+
+    pub fn set_root(&mut self, item: Item<T>) -> Result<ItemNode<T>, TreeFsError> {
         match item {
             Item { name: _, is_dir: true, .. } => {
+                let ino = item.ino;
                 let root = TreeNode::new(item);
                 self.tree.set_root(root.clone());
 
-                // link Item to TreeNode
-                root.borrow_mut().value.node = Some(root.clone());
-
                 // add it to ino -> Item map
-                self.ino_to_node.insert(root.borrow().value.ino, root.clone());
+                self.ino_to_node.insert(ino, root.clone());
 
-                unsafe {
-                    &(*root.as_ptr()).value
-                }
+                Ok(root)
             }
-            _ => { panic!("Root must be a directory") }
+            _ => Err(TreeFsError::NotADirectory),
         }
     }
 
-    pub fn get_root(&self) -> Option<&Item<T>> {
-        self.tree.get_root().map(|root| unsafe { &(*root.as_ptr()).value })
+    pub fn get_root(&self) -> Option<ItemNode<T>> {
+        self.tree.get_root()
     }
 
-    pub fn push<'b, 'c>(&'c mut self, parent: &Item<T>, child: Item<T>) -> &'b Item<T> {
-        match parent {
-            Item { name: _, is_dir: true, .. } => {
-                let parent_node = parent.node.as_ref().unwrap().clone();
-                let child_node = TreeNode::new(child);
-                self.tree.push_child(&parent_node, &child_node);
+    pub fn push(&mut self, parent: &ItemNode<T>, mut child: Item<T>) -> Result<ItemNode<T>, TreeFsError> {
+        let ino = child.ino;
+        if self.ino_to_node.contains_key(&ino) {
+            return Err(TreeFsError::InodeAlreadyExists);
+        }
+        self.check_parent(parent)?;
+        if let Some(generation) = self.retired.remove(&ino) {
+            child.generation = generation.wrapping_add(1);
+        }
+        let child_node = self.push_node(parent, child);
 
-                // link Item to TreeNode
-                parent_node.borrow_mut().iter_mut().rev().next().unwrap().borrow_mut().value.node = Some(child_node.clone());
+        // add it to ino -> Item map
+        self.ino_to_node.insert(ino, child_node.clone());
 
-                // add it to ino -> Item map
-                self.ino_to_node.insert(child_node.borrow().value.ino, child_node.clone());
+        Ok(child_node)
+    }
+
+    /// Adds another directory entry for an existing inode, its metadata stays with the first entry.
+    pub fn push_link(&mut self, parent: &ItemNode<T>, child: Item<T>) -> Result<ItemNode<T>, TreeFsError> {
+        self.check_parent(parent)?;
+        let ino = child.ino;
+        let child_node = self.push_node(parent, child);
+
+        self.links.entry(ino).or_default().push(child_node.clone());
+
+        Ok(child_node)
+    }
+
+    // a directory of this tree, directories have no links so it is the node in the inode map
+    fn check_parent(&self, parent: &ItemNode<T>) -> Result<(), TreeFsError> {
+        let (ino, is_dir) = {
+            let item = parent.value.read().unwrap();
+            (item.ino, item.is_dir)
+        };
+        if !self.ino_to_node.get(&ino).is_some_and(|node| Arc::ptr_eq(node, parent)) {
+            return Err(TreeFsError::ParentNotFound);
+        }
+        if !is_dir {
+            return Err(TreeFsError::NotADirectory);
+        }
+
+        // walks up to the root, too slow to do on every push in release builds
+        #[cfg(debug_assertions)]
+        {
+            let mut current = parent.clone();
+            while let Some(up) = current.get_parent() {
+                current = up;
+            }
+            debug_assert!(self.get_root().is_some_and(|root| Arc::ptr_eq(&root, &current)), "directory {} is not attached to the root", ino);
+        }
+
+        Ok(())
+    }
+
+    fn push_node(&mut self, parent: &ItemNode<T>, child: Item<T>) -> ItemNode<T> {
+        let key = self.index_key(&child.name);
+        let child_node = TreeNode::new(child);
+        self.tree.push_child(parent, key, &child_node);
+
+        child_node
+    }
 
-                unsafe {
-                    &(*child_node.as_ptr()).value
+    pub fn remove_child(&mut self, parent: &ItemNode<T>, child: &ItemNode<T>) -> Result<(), TreeFsError> {
+        if !parent.value.read().unwrap().is_dir {
+            return Err(TreeFsError::NotADirectory);
+        }
+        // check if parent contains the child
+        if !child.get_parent().is_some_and(|p| Arc::ptr_eq(&p, parent)) {
+            return Err(TreeFsError::ChildNotFound);
+        }
+        let (ino, name) = {
+            let item = child.value.read().unwrap();
+            (item.ino, item.name.clone())
+        };
+        self.tree.remove_child(parent, &self.index_key(&name), child);
+        self.forget_entry(ino, child);
+
+        Ok(())
+    }
+
+    // drops a detached entry from the inode map, handing the inode over to a remaining link if there is one
+    fn forget_entry(&mut self, ino: u64, child: &ItemNode<T>) {
+        if !Arc::ptr_eq(&self.ino_to_node[&ino], child) {
+            // only a link was removed
+            let links = self.links.get_mut(&ino).unwrap();
+            links.retain(|link| !Arc::ptr_eq(link, child));
+            if links.is_empty() {
+                self.links.remove(&ino);
+            }
+            return;
+        }
+
+        match self.links.get_mut(&ino).and_then(|links| links.pop()) {
+            Some(link) => {
+                // hand the metadata and data over to a remaining link
+                {
+                    let mut removed = child.value.write().unwrap();
+                    let mut kept = link.value.write().unwrap();
+                    std::mem::swap(&mut removed.extra, &mut kept.extra);
+                    std::mem::swap(&mut removed.data, &mut kept.data);
+                    std::mem::swap(&mut removed.dirty_pages, &mut kept.dirty_pages);
+                    std::mem::swap(&mut removed.xattrs, &mut kept.xattrs);
+                    std::mem::swap(&mut removed.inode_flags, &mut kept.inode_flags);
+                    std::mem::swap(&mut removed.pipe, &mut kept.pipe);
+                    std::mem::swap(&mut removed.is_virtual, &mut kept.is_virtual);
                 }
+                if self.links[&ino].is_empty() {
+                    self.links.remove(&ino);
+                }
+                self.ino_to_node.insert(ino, link);
+            }
+            None => {
+                self.ino_to_node.remove(&ino);
+                self.retired.insert(ino, child.value.read().unwrap().generation);
             }
-            _ => { panic!("Parent must be a directory") }
         }
     }
 
-    pub fn remove_child(&mut self, parent: &Item<T>, child: &Item<T>) {
-        match parent {
-            Item { name: _, is_dir: true, .. } => {
-                let parent_node = child.node.as_ref().unwrap().borrow().get_parent().unwrap();
-                // check if parent contains the child
-                if !Rc::ptr_eq(&parent_node, &parent.node.as_ref().unwrap()) {
-                    panic!("Parent does not contain the child");
+    /// Moves the entry `child_name` of `src_parent_ino` under `dst_parent_ino` as `new_name`, the inode stays the same.
+    /// An entry already named `new_name` is replaced in one step, the caller checks it may be.
+    /// Returns `None` when a parent or the entry doesn't exist.
+    pub fn move_node(&mut self, src_parent_ino: u64, child_name: &str, dst_parent_ino: u64, new_name: &str) -> Option<()> {
+        let src_parent = self.ino_to_node.get(&src_parent_ino)?.clone();
+        let dst_parent = self.ino_to_node.get(&dst_parent_ino)?.clone();
+        if !dst_parent.value.read().unwrap().is_dir {
+            return None;
+        }
+        let child = self.find_child(&src_parent, child_name)?;
+
+        let existing = self.find_child(&dst_parent, new_name).filter(|existing| !Arc::ptr_eq(existing, &child));
+
+        self.tree.remove_child(&src_parent, &self.index_key(child_name), &child);
+        child.value.write().unwrap().name = new_name.to_string();
+        match existing {
+            Some(existing) => {
+                self.tree.replace_child(&dst_parent, self.index_key(new_name), &existing, &child);
+                let ino = existing.value.read().unwrap().ino;
+                self.forget_entry(ino, &existing);
+            }
+            None => self.tree.push_child(&dst_parent, self.index_key(new_name), &child),
+        }
+
+        Some(())
+    }
+
+    /// Swaps the entries `name_a` of `parent_a_ino` and `name_b` of `parent_b_ino`, each takes the other's name.
+    /// Returns `None` when a parent or an entry doesn't exist.
+    pub fn exchange_nodes(&mut self, parent_a_ino: u64, name_a: &str, parent_b_ino: u64, name_b: &str) -> Option<()> {
+        let parent_a = self.ino_to_node.get(&parent_a_ino)?.clone();
+        let parent_b = self.ino_to_node.get(&parent_b_ino)?.clone();
+        let a = self.find_child(&parent_a, name_a)?;
+        let b = self.find_child(&parent_b, name_b)?;
+        if Arc::ptr_eq(&a, &b) {
+            return Some(());
+        }
+
+        a.value.write().unwrap().name = name_b.to_string();
+        b.value.write().unwrap().name = name_a.to_string();
+        self.tree.exchange_children(&parent_a, self.index_key(name_a), &a, &parent_b, self.index_key(name_b), &b);
+
+        Some(())
+    }
+
+    pub fn get_children_count(&self, ino: u64) -> Option<usize> {
+        self.ino_to_node.get(&ino).map(|node| node.children_count())
+    }
+
+    pub fn is_dir_empty(&self, ino: u64) -> Option<bool> {
+        let node = self.ino_to_node.get(&ino)?;
+        if !node.value.read().unwrap().is_dir {
+            return None;
+        }
+
+        Some(node.children_count() == 0)
+    }
+
+    pub fn get_item(&self, ino: u64) -> Option<ItemNode<T>> {
+        self.ino_to_node.get(&ino).cloned()
+    }
+
+    /// Generation of the inode, random when created and one more than the last when its number is reused.
+    /// It only survives a remount when the tree is saved and loaded back with the snapshot API.
+    pub fn get_generation(&self, ino: u64) -> Option<u64> {
+        self.ino_to_node.get(&ino).map(|node| node.value.read().unwrap().generation)
+    }
+
+    /// Moves the inode to its next generation and returns it. An NFS export hands out `(ino, generation)` as file handle,
+    /// after a bump the handles given out before are rejected as stale even though the inode number still exists.
+    pub fn bump_generation(&self, ino: u64) -> Option<u64> {
+        let node = self.ino_to_node.get(&ino)?;
+        let mut item = node.value.write().unwrap();
+        item.generation = item.generation.wrapping_add(1);
+
+        Some(item.generation)
+    }
+
+    /// Longest path `resolve_path` and `find_by_path` accept, 4096 by default.
+    pub fn max_path_len(&self) -> usize {
+        self.max_path_len
+    }
+
+    pub fn set_max_path_len(&mut self, max_path_len: usize) {
+        self.max_path_len = max_path_len;
+    }
+
+    /// Walks `path` from the root, `.` and `..` included. Returns the node holding the inode's metadata,
+    /// `None` as well when the path is longer than `max_path_len`.
+    pub fn resolve_path(&self, path: &str) -> Option<ItemNode<T>> {
+        if path.len() > self.max_path_len {
+            return None;
+        }
+        let mut current = self.get_root()?;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            current = match name {
+                "." => current,
+                ".." => current.get_parent().unwrap_or(current),
+                _ => {
+                    let ino = self.find_child(&current, name)?.value.read().unwrap().ino;
+                    self.get_item(ino)?
                 }
-                self.tree.remove_child(&parent_node, &child.node.as_ref().unwrap());
+            };
+        }
+
+        Some(current)
+    }
+
+    pub fn find_by_path(&self, path: &str) -> Option<u64> {
+        self.resolve_path(path).map(|node| node.value.read().unwrap().ino)
+    }
+
+    /// Absolute path of `node`, `/` for the root. Empty for a node not in this tree, detached or below a detached one.
+    pub fn full_path(&self, node: &ItemNode<T>) -> String {
+        let mut names = vec![];
+        let mut current = node.clone();
+        while let Some(parent) = current.get_parent() {
+            names.push(current.value.read().unwrap().name.clone());
+            current = parent;
+        }
+        if !self.get_root().is_some_and(|root| Arc::ptr_eq(&root, &current)) {
+            return String::new();
+        }
+        names.reverse();
+
+        format!("/{}", names.join("/"))
+    }
+
+    /// Absolute path of the inode, through the entry holding its metadata when it has several links.
+    pub fn path_of(&self, ino: u64) -> Option<String> {
+        self.get_item(ino).map(|node| self.full_path(&node))
+    }
+
+    /// Number of inodes, links not counted.
+    pub fn len(&self) -> usize {
+        self.ino_to_node.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ino_to_node.is_empty()
+    }
+
+    pub fn items(&self) -> Vec<ItemNode<T>> {
+        self.ino_to_node.values().cloned().collect()
+    }
+
+    /// Inode map as `(ino, node)` pairs, the key may differ from the node's `ino` only in a corrupt tree.
+    pub fn inodes(&self) -> Vec<(u64, ItemNode<T>)> {
+        self.ino_to_node.iter().map(|(ino, node)| (*ino, node.clone())).collect()
+    }
+
+    /// Number of directory entries of the inode, `0` if it doesn't exist.
+    pub fn link_count(&self, ino: u64) -> usize {
+        if !self.ino_to_node.contains_key(&ino) {
+            return 0;
+        }
+
+        1 + self.links.get(&ino).map_or(0, |links| links.len())
+    }
+
+    /// Every entry from the root, depth-first with parents before their children. Links are visited once per entry.
+    pub fn iter_dfs(&self) -> DfsIter<T> {
+        DfsIter { stack: self.get_root().into_iter().collect() }
+    }
+
+    /// Every entry from the root, level by level.
+    pub fn iter_bfs(&self) -> BfsIter<T> {
+        BfsIter { queue: self.get_root().into_iter().collect() }
+    }
+}
+
+impl TreeFs<FileAttr> {
+    /// Writes the tree like `tree(1)`, depth-first in insertion order.
+    /// Directories end with `/`, files show their size in bytes and symlinks `-> target`.
+    pub fn serialize_text(&self, writer: &mut impl Write) -> io::Result<()> {
+        let Some(root) = self.get_root() else { return Ok(()) };
+        writeln!(writer, "/")?;
+
+        self.serialize_children(&root, "", writer)
+    }
+
+    fn serialize_children(&self, dir: &ItemNode<FileAttr>, prefix: &str, writer: &mut impl Write) -> io::Result<()> {
+        let children = dir.children();
+        for (i, child) in children.iter().enumerate() {
+            let last = i + 1 == children.len();
+            let (ino, name, is_dir) = {
+                let item = child.value.read().unwrap();
+                (item.ino, item.name.clone(), item.is_dir)
+            };
+            // links only carry the name, the inode's node has the attributes and data
+            let node = self.get_item(ino).unwrap_or_else(|| child.clone());
+            let (kind, size, data) = {
+                let item = node.value.read().unwrap();
+                let attr = item.extra.as_ref();
+                (attr.map(|attr| attr.kind), attr.map_or(item.data_len(), |attr| attr.size), item.data.clone())
+            };
 
-                self.ino_to_node.remove(&child.ino);
+            let entry = if is_dir {
+                format!("{}/", name)
+            } else if kind == Some(FileType::Symlink) {
+                let target = data.map_or(vec![], |data| data.to_vec());
+                format!("{} -> {}", name, String::from_utf8_lossy(&target))
+            } else {
+                format!("{} ({} bytes)", name, size)
+            };
+            writeln!(writer, "{}{}{}", prefix, if last { "└── " } else { "├── " }, entry)?;
+
+            if is_dir {
+                self.serialize_children(child, &format!("{}{}", prefix, if last { "    " } else { "│   " }), writer)?;
             }
-            _ => { panic!("Parent must be a directory") }
         }
+
+        Ok(())
     }
+}
+
+// values sit behind the node's lock, so the iterators hand out nodes instead of references to items
+pub struct DfsIter<T> {
+    stack: Vec<ItemNode<T>>,
+}
 
-    pub fn get_item_mut<'a, 'b>(&'b mut self, ino: u64) -> Option<&'a mut Item<T>> {
-        self.ino_to_node.get(&ino).map(|item| unsafe {&mut (*item.as_ptr()).value})
+impl<T> Iterator for DfsIter<T> {
+    type Item = ItemNode<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.stack.pop()?;
+        // reversed so children come out in insertion order
+        self.stack.extend(node.children().into_iter().rev());
+
+        Some(node)
     }
-}
\ No newline at end of file
+}
+
+pub struct BfsIter<T> {
+    queue: VecDeque<ItemNode<T>>,
+}
+
+impl<T> Iterator for BfsIter<T> {
+    type Item = ItemNode<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.queue.pop_front()?;
+        self.queue.extend(node.children());
+
+        Some(node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // /a/b/c with c a file
+    fn tree() -> TreeFs<()> {
+        let mut tree = TreeFs::new();
+        let root = tree.set_root(Item::new(1, String::from("root"), true, None)).unwrap();
+        let a = tree.push(&root, Item::new(2, String::from("a"), true, None)).unwrap();
+        let b = tree.push(&a, Item::new(3, String::from("b"), true, None)).unwrap();
+        tree.push(&b, Item::new(4, String::from("c"), false, None)).unwrap();
+
+        tree
+    }
+
+    #[test]
+    fn paths_resolve_to_inodes() {
+        let tree = tree();
+        assert_eq!(tree.find_by_path("/"), Some(1));
+        assert_eq!(tree.find_by_path(""), Some(1));
+        assert_eq!(tree.find_by_path("/a/b/c"), Some(4));
+        assert_eq!(tree.find_by_path("/a/b/"), Some(3));
+        assert_eq!(tree.find_by_path("//a//b"), Some(3));
+        assert_eq!(tree.find_by_path("/a/./b/../b/c"), Some(4));
+        // the root is its own parent
+        assert_eq!(tree.find_by_path("/../a"), Some(2));
+        assert_eq!(tree.find_by_path("/a/x"), None);
+        assert_eq!(tree.find_by_path("/a/b/c/d"), None);
+    }
+
+    #[test]
+    fn inodes_give_back_their_path() {
+        let tree = tree();
+        assert_eq!(tree.path_of(1).as_deref(), Some("/"));
+        assert_eq!(tree.path_of(2).as_deref(), Some("/a"));
+        assert_eq!(tree.path_of(4).as_deref(), Some("/a/b/c"));
+        assert_eq!(tree.path_of(5), None);
+    }
+
+    #[test]
+    fn full_path_walks_up_to_the_root() {
+        let mut tree = tree();
+        let root = tree.get_root().unwrap();
+        let a = tree.get_item(2).unwrap();
+        let c = tree.get_item(4).unwrap();
+        assert_eq!(tree.full_path(&root), "/");
+        assert_eq!(tree.full_path(&a), "/a");
+        assert_eq!(tree.full_path(&c), "/a/b/c");
+        assert_eq!(tree.full_path(&TreeNode::new(Item::new(5, String::from("new"), false, None))), "");
+
+        // nor is anything below a detached directory
+        tree.remove_child(&root, &a).unwrap();
+        assert_eq!(tree.full_path(&a), "");
+        assert_eq!(tree.full_path(&c), "");
+    }
+
+    #[test]
+    fn paths_longer_than_the_limit_resolve_to_nothing() {
+        let mut tree = tree();
+        tree.set_max_path_len(5);
+        assert_eq!(tree.find_by_path("/a/b"), Some(3));
+        assert_eq!(tree.find_by_path("/a/b/c"), None);
+    }
+}
@@ -0,0 +1,69 @@
+use std::io::{self, Read, Write};
+use std::thread;
+
+use in_mem_fs::mem_fs::{FsError, MemFs};
+use in_mem_fs::pipe::PIPE_CAPACITY;
+
+#[test]
+fn threads_talk_through_a_fifo() {
+    let mut fs = MemFs::new(false, false);
+    fs.create_fifo("/fifo", 0o600, 0, 0).unwrap();
+    let mut reader = fs.open_fifo_reader("/fifo").unwrap();
+    let mut writer = fs.open_fifo_writer("/fifo").unwrap();
+
+    // more than the pipe holds, the writer waits for the reader
+    let sent: Vec<u8> = (0..4 * PIPE_CAPACITY).map(|i| (i % 251) as u8).collect();
+    let expected = sent.clone();
+    let writing = thread::spawn(move || writer.write_all(&sent).unwrap());
+    let reading = thread::spawn(move || {
+        let mut received = vec![];
+        // returns once the writer is dropped
+        reader.read_to_end(&mut received).unwrap();
+        received
+    });
+
+    writing.join().unwrap();
+    assert_eq!(reading.join().unwrap(), expected);
+    fs.verify_consistency().unwrap();
+}
+
+#[test]
+fn writing_without_a_reader_is_a_broken_pipe() {
+    let mut fs = MemFs::new(false, false);
+    fs.create_fifo("/fifo", 0o600, 0, 0).unwrap();
+    let mut writer = fs.open_fifo_writer("/fifo").unwrap();
+    assert_eq!(writer.write(b"x").unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+
+    let reader = fs.open_fifo_reader("/fifo").unwrap();
+    assert_eq!(writer.write(b"x").unwrap(), 1);
+    drop(reader);
+    assert_eq!(writer.write(b"x").unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    fs.verify_consistency().unwrap();
+}
+
+#[test]
+fn reading_without_a_writer_is_the_end_of_the_file() {
+    let mut fs = MemFs::new(false, false);
+    fs.create_fifo("/fifo", 0o600, 0, 0).unwrap();
+    let mut reader = fs.open_fifo_reader("/fifo").unwrap();
+    let mut buf = [0; 8];
+    assert_eq!(reader.read(&mut buf).unwrap(), 0);
+
+    // what was written stays readable after the writer is gone
+    let mut writer = fs.open_fifo_writer("/fifo").unwrap();
+    writer.write_all(b"abc").unwrap();
+    drop(writer);
+    assert_eq!(reader.read(&mut buf).unwrap(), 3);
+    assert_eq!(&buf[..3], b"abc");
+    assert_eq!(reader.read(&mut buf).unwrap(), 0);
+    fs.verify_consistency().unwrap();
+}
+
+#[test]
+fn only_fifos_can_be_opened_as_pipes() {
+    let mut fs = MemFs::new(false, false);
+    fs.create_file("/file", b"", 0o644, 0, 0).unwrap();
+    assert!(matches!(fs.open_fifo_reader("/file"), Err(FsError::InvalidArgument)));
+    assert!(matches!(fs.open_fifo_writer("/missing"), Err(FsError::NotFound)));
+    fs.verify_consistency().unwrap();
+}
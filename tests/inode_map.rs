@@ -0,0 +1,28 @@
+use in_mem_fs::mem_fs::MemFs;
+
+#[test]
+fn paths_keep_their_inode_across_remounts() {
+    let dir = tempfile::tempdir().unwrap();
+    let map = dir.path().join("inodes");
+
+    let (a, b, file) = {
+        let mut fs = MemFs::with_persistent_inode_map(&map).unwrap();
+        let a = fs.create_dir_all("a", 0o755, 0, 0).unwrap();
+        let b = fs.create_dir_all("b", 0o755, 0, 0).unwrap();
+        let file = fs.create_file("a/file", b"x", 0o644, 0, 0).unwrap();
+        fs.remove_file("a/file").unwrap();
+        (a, b, file)
+        // saved when dropped, like after unmounting
+    };
+
+    let mut fs = MemFs::with_persistent_inode_map(&map).unwrap();
+    // a different order gets the same inodes
+    assert_eq!(fs.create_dir_all("b", 0o755, 0, 0).unwrap(), b);
+    let other = fs.create_dir_all("other", 0o755, 0, 0).unwrap();
+    assert_eq!(fs.create_dir_all("a", 0o755, 0, 0).unwrap(), a);
+    // a removed path keeps its inode, new paths don't get it
+    assert_ne!(other, file);
+    assert_eq!(fs.create_file("a/file", b"y", 0o644, 0, 0).unwrap(), file);
+    assert!(![a, b, file].contains(&other));
+    fs.verify_consistency().unwrap();
+}
@@ -0,0 +1,71 @@
+use std::io::Cursor;
+
+use in_mem_fs::mem_fs::{FsError, MemFs, MergeConflict};
+
+// shared fixtures
+fn base() -> MemFs {
+    let mut fs = MemFs::new(false, false);
+    fs.create_dir_all("/mnt/common", 0o755, 0, 0).unwrap();
+    fs.create_file("/mnt/common/config", b"base", 0o644, 0, 0).unwrap();
+    fs.create_file("/mnt/kept", b"base", 0o644, 0, 0).unwrap();
+
+    fs
+}
+
+// test specific files, overlapping the fixtures at common/ and with a hard link
+fn extra() -> MemFs {
+    let mut builder = tar::Builder::new(vec![]);
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_mode(0o644);
+    header.set_size(5);
+    builder.append_data(&mut header, "common/config", &b"extra"[..]).unwrap();
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Link);
+    header.set_mode(0o644);
+    header.set_size(0);
+    builder.append_link(&mut header, "link", "common/config").unwrap();
+
+    MemFs::import_from_tar(Cursor::new(builder.into_inner().unwrap())).unwrap()
+}
+
+#[test]
+fn merge_into_a_new_directory() {
+    let mut fs = base();
+    fs.merge(extra(), "/new/dir", MergeConflict::Error).unwrap();
+    assert_eq!(fs.read_file("/new/dir/common/config").unwrap(), b"extra");
+    // links inside the merged tree stay links, with inodes of this filesystem
+    let config = fs.stat("/new/dir/common/config").unwrap();
+    assert_eq!(fs.stat("/new/dir/link").unwrap().ino, config.ino);
+    assert_eq!(config.nlink, 2);
+    assert_eq!(fs.read_file("/mnt/common/config").unwrap(), b"base");
+    fs.verify_consistency().unwrap();
+}
+
+#[test]
+fn conflicts_are_resolved_as_asked() {
+    let mut fs = base();
+    assert!(matches!(fs.merge(extra(), "/mnt", MergeConflict::Error), Err(FsError::AlreadyExists)));
+    // nothing was merged
+    assert!(fs.stat("/mnt/link").is_err());
+
+    fs.merge(extra(), "/mnt", MergeConflict::SkipExisting).unwrap();
+    assert_eq!(fs.read_file("/mnt/common/config").unwrap(), b"base");
+    assert_eq!(fs.read_file("/mnt/link").unwrap(), b"extra");
+    fs.verify_consistency().unwrap();
+
+    let mut fs = base();
+    fs.merge(extra(), "/mnt", MergeConflict::Overwrite).unwrap();
+    assert_eq!(fs.read_file("/mnt/common/config").unwrap(), b"extra");
+    assert_eq!(fs.stat("/mnt/link").unwrap().ino, fs.stat("/mnt/common/config").unwrap().ino);
+    // entries of the destination the merge didn't name are kept
+    assert_eq!(fs.read_file("/mnt/kept").unwrap(), b"base");
+    fs.verify_consistency().unwrap();
+}
+
+#[test]
+fn merge_onto_a_file_fails() {
+    let mut fs = base();
+    assert!(matches!(fs.merge(extra(), "/mnt/kept", MergeConflict::Overwrite), Err(FsError::NotADirectory)));
+    fs.verify_consistency().unwrap();
+}
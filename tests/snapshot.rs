@@ -0,0 +1,119 @@
+use std::io::Cursor;
+
+use in_mem_fs::mem_fs::MemFs;
+use in_mem_fs::snapshot::SnapshotError;
+
+fn sample() -> MemFs {
+    let mut hole = vec![0; 2 * 4096 + 10];
+    hole[0] = 1;
+    *hole.last_mut().unwrap() = 2;
+
+    let mut builder = tar::Builder::new(vec![]);
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_mode(0o640);
+    header.set_size(4);
+    builder.append_data(&mut header, "dir/file", &b"data"[..]).unwrap();
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Link);
+    header.set_mode(0o640);
+    header.set_size(0);
+    builder.append_link(&mut header, "link", "dir/file").unwrap();
+
+    let mut fs = MemFs::import_from_tar(Cursor::new(builder.into_inner().unwrap())).unwrap();
+    fs.create_dir_all("dir/sub", 0o700, 1000, 1000).unwrap();
+    fs.create_file("dir/sub/hole", &hole, 0o600, 1000, 1000).unwrap();
+    fs.create_file("empty", b"", 0o644, 0, 0).unwrap();
+
+    fs
+}
+
+fn save(fs: &mut MemFs) -> Vec<u8> {
+    let mut saved = vec![];
+    fs.save(&mut saved).unwrap();
+
+    saved
+}
+
+#[test]
+fn save_and_load_round_trip() {
+    let mut fs = sample();
+    let saved = save(&mut fs);
+    let mut loaded = MemFs::load(Cursor::new(&saved)).unwrap();
+
+    assert_eq!(loaded.snapshot(), fs.snapshot());
+    assert_eq!(loaded.read_file("dir/sub/hole").unwrap(), fs.read_file("dir/sub/hole").unwrap());
+    assert_eq!(loaded.stat("link").unwrap().ino, loaded.stat("dir/file").unwrap().ino);
+    assert_eq!(loaded.stat("dir/file").unwrap().nlink, 2);
+    assert_eq!(save(&mut loaded), saved);
+
+    // new inodes continue after the loaded ones
+    let ino = loaded.create_file("new", b"", 0o644, 0, 0).unwrap();
+    assert!(fs.snapshot().diff(&loaded.snapshot()).added == ["/new"]);
+    assert!(ino > fs.stat("empty").unwrap().ino);
+    loaded.verify_consistency().unwrap();
+}
+
+#[test]
+fn restore_goes_back_to_the_snapshot() {
+    let mut fs = sample();
+    let snapshot = fs.snapshot();
+    fs.create_file("later", b"x", 0o644, 0, 0).unwrap();
+    fs.remove_file("empty").unwrap();
+    assert!(!snapshot.diff(&fs.snapshot()).is_empty());
+
+    fs.restore_from_snapshot(&snapshot).unwrap();
+    assert!(snapshot.diff(&fs.snapshot()).is_empty());
+    assert_eq!(fs.read_file("dir/file").unwrap(), b"data");
+    fs.verify_consistency().unwrap();
+}
+
+#[test]
+fn bad_header_is_rejected() {
+    let saved = save(&mut sample());
+
+    let mut bad_magic = saved.clone();
+    bad_magic[0] = b'X';
+    assert!(matches!(MemFs::load(Cursor::new(bad_magic)), Err(SnapshotError::BadMagic)));
+
+    let mut bad_version = saved.clone();
+    bad_version[4..8].copy_from_slice(&99u32.to_le_bytes());
+    assert!(matches!(MemFs::load(Cursor::new(bad_version)), Err(SnapshotError::UnsupportedVersion(99))));
+}
+
+#[test]
+fn truncated_snapshot_is_rejected() {
+    let saved = save(&mut sample());
+    for len in 0..saved.len() {
+        assert!(MemFs::load(Cursor::new(&saved[..len])).is_err(), "cut at {len}");
+    }
+}
+
+#[test]
+fn corrupt_snapshot_does_not_panic() {
+    let saved = save(&mut sample());
+    let find = |needle: &[u8]| saved.windows(needle.len()).position(|window| window == needle).unwrap();
+    // the name of dir/file with its length, then its is_dir flag, generation and the attributes starting with ino and size
+    let name = find(&[&4u64.to_le_bytes()[..], b"file"].concat());
+    let attr = name + 12 + 1 + 8;
+    // the page holding "data", after the file length, the page count and the page index
+    let page = find(b"data\0\0");
+
+    // the header: magic, version, flags, file handle counter and inode counter, then the root's tag, parent and name length
+    let mut offsets = vec![0, 3, 4, 8, 9, 10, 17, 18, 26, 27, 35];
+    offsets.extend([name, name + 8, name + 12, name + 13, attr, attr + 8, attr + 15]);
+    offsets.extend([page - 24, page - 17, page - 16, page - 8, page, page + 3, page + 4095]);
+    offsets.push(saved.len() - 1);
+    for i in offsets {
+        for flip in [0x01, 0x80, 0xff] {
+            let mut corrupt = saved.clone();
+            corrupt[i] ^= flip;
+            // either error or a consistent filesystem
+            if let Ok(mut fs) = MemFs::load(Cursor::new(corrupt)) {
+                fs.verify_consistency().unwrap();
+                fs.snapshot();
+                save(&mut fs);
+            }
+        }
+    }
+}
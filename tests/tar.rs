@@ -0,0 +1,107 @@
+use std::io::Cursor;
+
+use in_mem_fs::file_attr::FileType;
+use in_mem_fs::mem_fs::MemFs;
+
+fn header(kind: tar::EntryType, mode: u32, size: u64) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(kind);
+    header.set_mode(mode);
+    header.set_uid(1000);
+    header.set_gid(1000);
+    header.set_mtime(1_700_000_000);
+    header.set_size(size);
+
+    header
+}
+
+fn export(fs: &MemFs) -> Vec<u8> {
+    let mut archive = vec![];
+    fs.export_to_tar(&mut archive).unwrap();
+
+    archive
+}
+
+fn import(archive: &[u8]) -> MemFs {
+    MemFs::import_from_tar(Cursor::new(archive)).unwrap()
+}
+
+#[test]
+fn round_trip_keeps_content_and_mode() {
+    let long_dir = "d".repeat(80);
+    let long_name = format!("{long_dir}/{}", "f".repeat(90));
+    let mut sparse = vec![0; 3 * 4096 + 5];
+    *sparse.last_mut().unwrap() = 1;
+
+    let mut fs = MemFs::new(false, false);
+    fs.create_dir_all("a/b", 0o750, 0, 0).unwrap();
+    fs.create_file("a/b/file", b"hello", 0o640, 0, 0).unwrap();
+    fs.create_file("a/empty", b"", 0o600, 0, 0).unwrap();
+    fs.create_file("a/sparse", &sparse, 0o644, 0, 0).unwrap();
+    fs.create_dir_all(&long_dir, 0o755, 0, 0).unwrap();
+    fs.create_file(&long_name, b"long", 0o644, 0, 0).unwrap();
+
+    let imported = import(&export(&fs));
+    assert_eq!(imported.read_file("a/b/file").unwrap(), b"hello");
+    assert_eq!(imported.stat("a/b/file").unwrap().perm, 0o640);
+    assert_eq!(imported.stat("a/b").unwrap().perm, 0o750);
+    assert_eq!(imported.read_file("a/empty").unwrap(), b"");
+    assert_eq!(imported.stat("a/empty").unwrap().kind, FileType::RegularFile);
+    assert_eq!(imported.read_file("a/sparse").unwrap(), sparse);
+    assert_eq!(imported.read_file(&long_name).unwrap(), b"long");
+    imported.verify_consistency().unwrap();
+
+    // a second round gives the same archive
+    assert_eq!(export(&imported), export(&import(&export(&imported))));
+}
+
+#[test]
+fn symlinks_and_hard_links() {
+    let long_target = format!("{}/target", "t".repeat(120));
+    let mut builder = tar::Builder::new(vec![]);
+    builder.append_data(&mut header(tar::EntryType::Regular, 0o644, 4), "dir/file", &b"data"[..]).unwrap();
+    builder.append_link(&mut header(tar::EntryType::Link, 0o644, 0), "dir/link", "dir/file").unwrap();
+    builder.append_link(&mut header(tar::EntryType::Symlink, 0o777, 0), "dir/symlink", "file").unwrap();
+    builder.append_link(&mut header(tar::EntryType::Symlink, 0o777, 0), "dir/long", &long_target).unwrap();
+    let archive = builder.into_inner().unwrap();
+
+    for fs in [import(&archive), import(&export(&import(&archive)))] {
+        let file = fs.stat("dir/file").unwrap();
+        let link = fs.stat("dir/link").unwrap();
+        assert_eq!(file.ino, link.ino);
+        assert_eq!(link.nlink, 2);
+        assert_eq!(fs.read_file("dir/link").unwrap(), b"data");
+
+        assert_eq!(fs.stat("dir/symlink").unwrap().kind, FileType::Symlink);
+        assert_eq!(fs.stat("dir/long").unwrap().kind, FileType::Symlink);
+        assert_eq!(fs.stat("dir/long").unwrap().size, long_target.len() as u64);
+        fs.verify_consistency().unwrap();
+    }
+}
+
+#[test]
+fn ownership_is_kept_only_when_asked() {
+    let mut builder = tar::Builder::new(vec![]);
+    builder.append_data(&mut header(tar::EntryType::Regular, 0o644, 1), "file", &b"x"[..]).unwrap();
+    let archive = builder.into_inner().unwrap();
+
+    assert_eq!(import(&archive).stat("file").unwrap().uid, 0);
+    let fs = in_mem_fs::mem_fs::MemFsBuilder::default().preserve_ownership(true).import_from_tar(Cursor::new(&archive)).unwrap();
+    assert_eq!(fs.stat("file").unwrap().uid, 1000);
+    assert_eq!(fs.stat("file").unwrap().mtime, std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000));
+    fs.verify_consistency().unwrap();
+}
+
+#[test]
+fn truncated_archive_is_an_error() {
+    let mut fs = MemFs::new(false, false);
+    fs.create_file("first", &[7; 2000], 0o644, 0, 0).unwrap();
+    fs.create_file("second", &[8; 2000], 0o644, 0, 0).unwrap();
+    let archive = export(&fs);
+
+    // cut inside a header and inside file content
+    for len in [100, 512 + 100, 2 * 512 + 700, archive.len() - 1024 - 300] {
+        assert!(MemFs::import_from_tar(Cursor::new(&archive[..len])).is_err(), "cut at {len}");
+    }
+    fs.verify_consistency().unwrap();
+}
@@ -0,0 +1,81 @@
+use std::io::Cursor;
+
+use in_mem_fs::mem_fs::{FsError, MemFs, MemFsBuilder, QuotaLimit, UsageRecord};
+
+const BLOCK: u64 = 512;
+
+fn fs() -> MemFs {
+    MemFsBuilder::default().block_size(BLOCK as u32).build()
+}
+
+fn usage(bytes: u64, files: u64) -> UsageRecord {
+    UsageRecord { bytes, files }
+}
+
+#[test]
+fn usage_follows_creates_and_removes() {
+    let mut fs = fs();
+    fs.create_dir_all("/dir", 0o755, 1000, 100).unwrap();
+    fs.create_file("/dir/a", &[1; 1000], 0o644, 1000, 100).unwrap();
+    fs.create_file("/b", &[2; 10], 0o644, 1001, 100).unwrap();
+    // a directory takes a block, like the root owned by root
+    assert_eq!(fs.get_usage(1000), usage(3 * BLOCK, 2));
+    assert_eq!(fs.get_usage(1001), usage(BLOCK, 1));
+    assert_eq!(fs.get_group_usage(100), usage(4 * BLOCK, 3));
+    assert_eq!(fs.total_used_bytes(), 5 * BLOCK);
+    fs.verify_consistency().unwrap();
+
+    fs.remove_file("/dir/a").unwrap();
+    fs.remove_dir("/dir").unwrap();
+    assert_eq!(fs.get_usage(1000), UsageRecord::default());
+    assert_eq!(fs.get_group_usage(100), usage(BLOCK, 1));
+    assert_eq!(fs.total_used_bytes(), 2 * BLOCK);
+    fs.verify_consistency().unwrap();
+}
+
+#[test]
+fn usage_follows_copies_renames_and_clones() {
+    let mut fs = fs();
+    fs.create_file("/a", &[1; 600], 0o644, 1000, 1000).unwrap();
+    fs.copy_file("/a", "/b").unwrap();
+    fs.reflink("/a", "/c").unwrap();
+    fs.rename("/b", "/d").unwrap();
+    // a clone shares the data but is charged like a copy
+    assert_eq!(fs.get_usage(1000), usage(6 * BLOCK, 3));
+    fs.verify_consistency().unwrap();
+}
+
+#[test]
+fn usage_is_recounted_after_load_and_restore() {
+    let mut fs = fs();
+    fs.create_file("/a", &[1; 100], 0o644, 1000, 1000).unwrap();
+    let snapshot = fs.snapshot();
+    let mut saved = vec![];
+    fs.save(&mut saved).unwrap();
+
+    fs.create_file("/b", &[1; 100], 0o644, 1000, 1000).unwrap();
+    fs.restore_from_snapshot(&snapshot).unwrap();
+    assert_eq!(fs.get_usage(1000).files, 1);
+    fs.verify_consistency().unwrap();
+
+    let loaded = MemFs::load(Cursor::new(saved)).unwrap();
+    assert_eq!(loaded.get_usage(1000).files, 1);
+    loaded.verify_consistency().unwrap();
+}
+
+#[test]
+fn quota_and_capacity_use_the_running_usage() {
+    let mut fs = MemFsBuilder::default().block_size(BLOCK as u32).capacity(8 * BLOCK).build();
+    fs.set_quota(1000, QuotaLimit { max_bytes: 2 * BLOCK, max_files: 2 });
+    fs.create_file("/a", &[1; BLOCK as usize], 0o644, 1000, 1000).unwrap();
+    assert!(matches!(fs.create_file("/b", &[1; 2 * BLOCK as usize], 0o644, 1000, 1000), Err(FsError::QuotaExceeded)));
+    fs.create_file("/b", &[1; BLOCK as usize], 0o644, 1000, 1000).unwrap();
+    assert!(matches!(fs.create_file("/c", &[], 0o644, 1000, 1000), Err(FsError::QuotaExceeded)));
+
+    // space freed by a removal can be used again
+    fs.remove_file("/b").unwrap();
+    fs.create_file("/c", &[1; BLOCK as usize], 0o644, 1000, 1000).unwrap();
+
+    assert!(matches!(fs.create_file("/big", &[1; 8 * BLOCK as usize], 0o644, 0, 0), Err(FsError::NoSpace)));
+    fs.verify_consistency().unwrap();
+}